@@ -0,0 +1,426 @@
+//! Graph coloring register allocation, after Chaitin and Briggs.
+//!
+//! SSA form gives every assignment a variable of its own, and a jump passes
+//! its variables to the label's by moving them, so a loop variable ends up
+//! moved into a new register every time around unless the generations on
+//! either side of the move get the same one. Before coloring, we coalesce
+//! the two ends of each move (the variables a jump passes and the label's,
+//! and copies) into one node of the interference graph if they don't
+//! interfere, heaviest moves first. A merge that could make the graph harder
+//! to color is skipped: two ordinary nodes merge only if the result has
+//! fewer than K neighbors with K or more neighbors of their own (Briggs's
+//! test), and a node merges into one that has to be in a particular
+//! register only if each of its neighbors already interferes with that one
+//! or has fewer than K neighbors (George's test).
+//!
+//! Then, as long as there's a node with fewer than K neighbors left, it
+//! comes out of the graph, since it'll get a register whatever its neighbors
+//! get. When there isn't, the node that's cheapest to spill for how many
+//! neighbors it has comes out anyway: each use and assignment costs 10 to
+//! the power of its loop depth, except assignments of constants and
+//! addresses, which we can compute again where they're used instead.
+//! Putting the nodes back in the reverse order, each gets a register none of
+//! its neighbors have, if there is one, preferring one a variable it moves
+//! to or from already has. Nodes we had to take out might still get one if
+//! their neighbors share registers; otherwise they go on the stack, after
+//! the variables that have to be in memory, or are rematerialized.
+
+use codegen::*;
+use ir::*;
+use ir::cfg::CFG;
+use ir::util::{defs, uses};
+use mas::ast::Reg;
+use mc::ast::Identity;
+use util::Name;
+
+use std::collections::{BTreeMap, BTreeSet};
+
+pub struct BriggsColorer;
+
+struct Graph {
+    // Each coalesced variable's representative.
+    alias: BTreeMap<Var, Var>,
+    // The interference graph between representatives.
+    adj: BTreeMap<Var, BTreeSet<Var>>,
+    // Representatives that have to be in particular registers.
+    precolored: BTreeMap<Var, Reg>,
+    cost: BTreeMap<Var, u64>,
+    // The number of registers.
+    k: usize,
+}
+
+impl Graph {
+    fn find(&self, v: Var) -> Var {
+        let mut v = v;
+        while let Some(&next) = self.alias.get(&v) {
+            v = next;
+        }
+        v
+    }
+
+    fn degree(&self, v: &Var) -> usize {
+        if self.precolored.contains_key(v) {
+            return ::std::usize::MAX;
+        }
+        self.adj[v].len()
+    }
+
+    fn can_merge(&self, a: Var, b: Var) -> bool {
+        match (self.precolored.get(&a), self.precolored.get(&b)) {
+            (Some(_), Some(_)) => false,
+            (Some(&reg), None) => self.george(b, a, reg),
+            (None, Some(&reg)) => self.george(a, b, reg),
+            (None, None) => {
+                let significant = self.adj[&a].union(&self.adj[&b])
+                                              .filter(|n| self.degree(n) >= self.k)
+                                              .count();
+                significant < self.k
+            },
+        }
+    }
+
+    // Whether `v` can go in `p`'s register, `reg`, without making its
+    // neighbors harder to color.
+    fn george(&self, v: Var, p: Var, reg: Reg) -> bool {
+        self.adj[&v].iter().all(|t| match self.precolored.get(t) {
+            Some(&other) => other != reg,
+            None => self.adj[t].contains(&p) || self.degree(t) < self.k,
+        })
+    }
+
+    fn merge(&mut self, keep: Var, gone: Var) {
+        self.alias.insert(gone, keep);
+        let neighbors = self.adj.remove(&gone).unwrap();
+        for n in neighbors.iter() {
+            let adj = self.adj.get_mut(n).unwrap();
+            adj.remove(&gone);
+            adj.insert(keep);
+        }
+        self.adj.get_mut(&keep).unwrap().extend(neighbors.into_iter());
+        let cost = self.cost.remove(&gone).unwrap_or(0);
+        *self.cost.get_mut(&keep).unwrap() += cost;
+    }
+}
+
+impl BriggsColorer {
+    /// Color the variables in `ops`, given what interferes with what and
+    /// the registers some have to be in.
+    pub fn color(ops: &Vec<Op>,
+                 cfg: &CFG,
+                 conflicts: BTreeMap<Var, BTreeSet<Var>>,
+                 must_colors: BTreeMap<Var, RegisterColor>,
+                 mem_vars: BTreeSet<Name>,
+                 global_map: &BTreeMap<Name, StaticIRItem>,
+                 num_colors: usize
+                 ) -> BTreeMap<Var, RegisterColor> {
+        // Every generation of a variable that has to be in memory gets the
+        // same place on the stack, and globals other than functions stay in
+        // global storage. Those, and variables passed on the stack, aren't
+        // in the graph.
+        let mem_locs: BTreeMap<Name, usize> = mem_vars.into_iter()
+            .filter(|name| global_map.get(name).is_none())
+            .enumerate().map(|(i, name)| (name, i)).collect();
+        let mut coloring = BTreeMap::new();
+        let mut precolored = BTreeMap::new();
+        for (var, color) in must_colors.into_iter() {
+            match color {
+                RegColor(reg) => { precolored.insert(var, reg); },
+                _ => { coloring.insert(var, color); },
+            }
+        }
+
+        let weight = |i: usize| {
+            10u64.pow(::std::cmp::min(cfg.loop_depth(cfg.block_of[i]), 6) as u32)
+        };
+        let remat = remat_candidates(ops, global_map);
+        let mut nodes = BTreeSet::new();
+        let mut cost = BTreeMap::new();
+        for (i, op) in ops.iter().enumerate() {
+            let vars = defs(&op.val).into_iter().map(|v| (v, true))
+                .chain(uses(&op.val).into_iter().map(|v| (v, false)));
+            for (var, is_def) in vars {
+                if coloring.contains_key(var) {
+                    continue;
+                }
+                if !precolored.contains_key(var) {
+                    if global_map.get(&var.name).map_or(false, |info| !info.is_func) {
+                        coloring.insert(*var, GlobalColor);
+                        continue;
+                    }
+                    if let Some(&slot) = mem_locs.get(&var.name) {
+                        coloring.insert(*var, StackColor(slot as isize));
+                        continue;
+                    }
+                }
+                nodes.insert(*var);
+                let c = *cost.get(var).unwrap_or(&0);
+                let w = if is_def && remat.contains_key(var) { 0 } else { weight(i) };
+                cost.insert(*var, c + w);
+            }
+        }
+        for var in precolored.keys() {
+            nodes.insert(*var);
+            if !cost.contains_key(var) {
+                cost.insert(*var, 0);
+            }
+        }
+
+        let empty = BTreeSet::new();
+        let mut graph = Graph {
+            alias: BTreeMap::new(),
+            adj: nodes.iter().map(|v| {
+                let adj = conflicts.get(v).unwrap_or(&empty);
+                (*v, adj.iter().filter(|n| nodes.contains(n)).cloned().collect())
+            }).collect(),
+            precolored: precolored,
+            cost: cost,
+            k: allocatable_regs(num_colors).len(),
+        };
+
+        // The moves: copies, and what jumps pass to their labels' variables.
+        let mut label_vars = BTreeMap::new();
+        for op in ops.iter() {
+            if let OpNode::Label(label, ref vars) = op.val {
+                label_vars.insert(label, vars);
+            }
+        }
+        let mut moves = vec!();
+        for (i, op) in ops.iter().enumerate() {
+            match op.val {
+                OpNode::UnOp(dest, Identity, Variable(src)) => moves.push((weight(i), dest, src)),
+                OpNode::Goto(label, ref vars) |
+                OpNode::CondGoto(_, _, label, ref vars) => for var in vars.iter() {
+                    let dest = label_vars[&label].iter().filter(|v| v.name == var.name).next();
+                    moves.push((weight(i), *dest.unwrap(), *var));
+                },
+                _ => {},
+            }
+        }
+        moves.retain(|&(_, a, b)| nodes.contains(&a) && nodes.contains(&b));
+        moves.sort_by(|a, b| b.0.cmp(&a.0));
+
+        loop {
+            let mut changed = false;
+            for &(_, a, b) in moves.iter() {
+                let (a, b) = (graph.find(a), graph.find(b));
+                if a == b || graph.adj[&a].contains(&b) || !graph.can_merge(a, b) {
+                    continue;
+                }
+                if graph.precolored.contains_key(&b) {
+                    graph.merge(b, a);
+                } else {
+                    graph.merge(a, b);
+                }
+                changed = true;
+            }
+            if !changed {
+                break;
+            }
+        }
+
+        let mut partners = BTreeMap::new();
+        for &(_, a, b) in moves.iter() {
+            let (a, b) = (graph.find(a), graph.find(b));
+            if a != b {
+                for &(x, y) in [(a, b), (b, a)].iter() {
+                    if !partners.contains_key(&x) {
+                        partners.insert(x, vec!());
+                    }
+                    partners.get_mut(&x).unwrap().push(y);
+                }
+            }
+        }
+
+        // Take the nodes out, cheapest to spill last.
+        let mut degree: BTreeMap<Var, usize> = graph.adj.iter()
+            .filter(|&(v, _)| !graph.precolored.contains_key(v))
+            .map(|(v, adj)| (*v, adj.len())).collect();
+        let mut low: BTreeSet<Var> = degree.iter().filter(|&(_, &d)| d < graph.k)
+                                                  .map(|(v, _)| *v).collect();
+        let mut stack = vec!();
+        while !degree.is_empty() {
+            let v = match low.iter().next() {
+                Some(&v) => v,
+                None => {
+                    let mut best = None;
+                    for (v, &d) in degree.iter() {
+                        let spill_cost = graph.cost[v] as f64 / d as f64;
+                        if best.map_or(true, |(_, c)| spill_cost < c) {
+                            best = Some((*v, spill_cost));
+                        }
+                    }
+                    best.unwrap().0
+                },
+            };
+            low.remove(&v);
+            degree.remove(&v);
+            for n in graph.adj[&v].iter() {
+                if let Some(d) = degree.get_mut(n) {
+                    *d -= 1;
+                    if *d < graph.k {
+                        low.insert(*n);
+                    }
+                }
+            }
+            stack.push(v);
+        }
+
+        // Put them back, giving each a register if there's one left.
+        let regs = allocatable_regs(num_colors);
+        let spill_base = mem_locs.len() as isize;
+        let mut colors: BTreeMap<Var, RegisterColor> = graph.precolored.iter()
+            .map(|(v, &reg)| (*v, RegColor(reg))).collect();
+        while let Some(v) = stack.pop() {
+            let taken: BTreeSet<RegisterColor> = graph.adj[&v].iter()
+                .filter_map(|n| colors.get(n).cloned()).collect();
+            let preferred = partners.get(&v).and_then(|partners| {
+                partners.iter().filter_map(|p| match colors.get(p) {
+                    Some(&RegColor(reg)) if !taken.contains(&RegColor(reg)) => Some(reg),
+                    _ => None,
+                }).next()
+            });
+            let color = match preferred.or(regs.iter().cloned().filter(|reg| {
+                !taken.contains(&RegColor(*reg))
+            }).next()) {
+                Some(reg) => RegColor(reg),
+                None => {
+                    let mut slot = spill_base;
+                    while taken.contains(&StackColor(slot)) {
+                        slot += 1;
+                    }
+                    StackColor(slot)
+                },
+            };
+            colors.insert(v, color);
+        }
+
+        for v in nodes.iter() {
+            let color = match (colors[&graph.find(*v)], remat.get(v)) {
+                (StackColor(..), Some(&i)) => RematColor(i),
+                (color, _) => color,
+            };
+            coloring.insert(*v, color);
+        }
+        coloring
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use codegen::*;
+    use mas::ast::Reg;
+    use testing;
+
+    use std::collections::BTreeMap;
+
+    fn color(src: &str, num_colors: usize) -> BTreeMap<String, RegisterColor> {
+        testing::color(src, RegAlloc::BriggsAlloc, num_colors)
+    }
+
+    #[test]
+    fn test_coalescing() {
+        let coloring = color("fn %f(%n<1>)
+    %s<1> := 0
+    %i<1> := 0
+    goto 0(%s<1>, %i<1>)
+    label 0(%s<2>, %i<2>)
+    %c<1> := %i<2> <s %n<1>
+    if !%c<1> goto 1()
+    %s<3> := %s<2> +s %i<2>
+    %t<1> := %i<2> +s 1
+    %i<3> := %t<1>
+    goto 0(%s<3>, %i<3>)
+    label 1()
+    return %s<2>
+", NUM_USABLE_VARS);
+        // Each variable stays in one register all the way around the loop.
+        for name in ["s", "i"].iter() {
+            let first = coloring[&format!("{}<1>", name)];
+            for gen in 2..4 {
+                assert_eq!(coloring[&format!("{}<{}>", name, gen)], first);
+            }
+        }
+        assert_eq!(coloring["t<1>"], coloring["i<1>"]);
+        assert!(coloring["s<1>"] != coloring["i<1>"]);
+        assert_eq!(coloring["n<1>"], RegColor(Reg { index: 0 }));
+    }
+
+    #[test]
+    fn test_spill_costs() {
+        // Six variables live at once in the loop, and five registers: the
+        // one only used outside it goes on the stack.
+        let coloring = color("fn %f(%n<1>)
+    %d<1> := %n<1> +s 4
+    %a<1> := %n<1> +s 1
+    %b<1> := %n<1> +s 2
+    %c<1> := %n<1> +s 3
+    %i<1> := 0
+    goto 0(%i<1>)
+    label 0(%i<2>)
+    %x<1> := %a<1> +s %b<1>
+    %y<1> := %x<1> +s %c<1>
+    %i<3> := %i<2> +s %y<1>
+    %t<1> := %i<3> <s 100
+    if %t<1> goto 0(%i<3>)
+    %z<1> := %i<3> +s %d<1>
+    return %z<1>
+", 5);
+        assert_eq!(coloring["d<1>"], StackColor(0));
+        for v in ["a<1>", "b<1>", "c<1>", "i<2>", "x<1>", "y<1>"].iter() {
+            match coloring[*v] {
+                RegColor(..) => {},
+                color => panic!("{} is {}", v, color),
+            }
+        }
+    }
+
+    #[test]
+    fn test_rematerialization() {
+        // As above, but the one only used outside the loop is a constant,
+        // so it's computed again where it's used instead.
+        let coloring = color("fn %f()
+    %d<1> := 4
+    %a<1> := 1
+    %b<1> := 2
+    %c<1> := 3
+    %i<1> := 0
+    goto 0(%i<1>)
+    label 0(%i<2>)
+    %x<1> := %a<1> +s %b<1>
+    %y<1> := %x<1> +s %c<1>
+    %i<3> := %i<2> +s %y<1>
+    %t<1> := %i<3> <s 100
+    if %t<1> goto 0(%i<3>)
+    %z<1> := %i<3> +s %d<1>
+    return %z<1>
+", 5);
+        assert_eq!(coloring["d<1>"], RematColor(1));
+        for v in ["a<1>", "b<1>", "c<1>", "i<2>", "x<1>", "y<1>"].iter() {
+            match coloring[*v] {
+                RegColor(..) => {},
+                color => panic!("{} is {}", v, color),
+            }
+        }
+    }
+
+    #[test]
+    fn test_must_colors() {
+        // The copy into the argument's register goes away, but `k` has to
+        // stay out of it.
+        let coloring = color("global %g size 0 offset 0 extern func
+fn %f()
+    %a<1> := 5
+    %k<1> := 7
+    %x<1> := %a<1>
+    %r<1> := call %g<0>(%x<1>)
+    %s<1> := %r<1> +s %k<1>
+    return %s<1>
+fn %g(%x) extern \"C\"
+", NUM_USABLE_VARS);
+        let r0 = RegColor(Reg { index: 0 });
+        assert_eq!(coloring["x<1>"], r0);
+        assert_eq!(coloring["r<1>"], r0);
+        assert_eq!(coloring["a<1>"], r0);
+        assert!(coloring["k<1>"] != r0);
+    }
+}
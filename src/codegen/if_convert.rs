@@ -0,0 +1,341 @@
+//! If-conversion.
+//!
+//! Every instruction has a predicate, so rather than branching around a
+//! short stretch of code, we can run it with the opposite of the branch's
+//! predicate. We look for the code ir_to_asm generates for a conditional
+//! jump over a block to a label (an `if` with no `else`, or a conditional
+//! assignment), or for one over a block that ends by jumping past a second
+//! block that only the conditional jump reaches (an `if` with an `else`),
+//! and replace the jumps with predicates. Besides saving the branch, this
+//! gives the scheduler bigger blocks to pack.
+//!
+//! Each block has to be straight-line code that leaves the branch's
+//! predicate alone. The only instructions we generate that are predicated
+//! already, and the only ones that set predicates, are comparisons whose
+//! results get turned into booleans:
+//!
+//! ```text
+//! p0 <- r1 < r2; p0? r3 <- 1; !p0? r3 <- 0
+//! ```
+//!
+//! Those we can convert too, by allocating another predicate register for
+//! whether both the comparison and the block's predicate hold:
+//!
+//! ```text
+//! g? p1 <- r1 < r2; !g? p1 <- r0 <u 0; g? r3 <- 0; p1? r3 <- 1
+//! ```
+
+use mas::ast::*;
+use mas::scheduler::{pred, destpred, predicable, with_pred};
+
+use std::collections::{BTreeMap, BTreeSet};
+
+// Predicated instructions take up their slots whether they run or not, so
+// we only convert blocks about as short as the branches we save. For an
+// `if` with an `else`, this is the length of both blocks together.
+static MAX_CONVERTED_LEN: usize = 8;
+
+// p3 is always true, so these are the predicate registers we can use.
+static NUM_PRED_REGS: u8 = 3;
+
+fn with_destpred(inst: &InstNode, dp: Pred) -> InstNode {
+    match *inst {
+        CompareShortInst(p, _, rs, ct, val, rot) => CompareShortInst(p, dp, rs, ct, val, rot),
+        CompareRegInst(p, _, rs, ct, rt, st, amt) => CompareRegInst(p, dp, rs, ct, rt, st, amt),
+        CompareLongInst(p, _, rs, ct) => CompareLongInst(p, dp, rs, ct),
+        ref inst => panic!("{} is not a comparison", inst),
+    }
+}
+
+// Whether an instruction does nothing but compute something into a
+// register or memory, so that we can just change its predicate.
+fn is_plain(inst: &InstNode) -> bool {
+    predicable(inst) && destpred(inst).is_none()
+}
+
+fn is_mov(inst: &InstNode) -> Option<(Pred, Reg)> {
+    match *inst {
+        ALU1ShortInst(p, MovAluOp, rd, _, _) => Some((p, rd)),
+        _ => None,
+    }
+}
+
+// The block `insts`, made to run only if `guard` holds, or None if we
+// can't convert it.
+fn predicate_block(insts: &[InstNode], guard: Pred) -> Option<Vec<InstNode>> {
+    // Allocate a predicate register for comparisons in the block to use.
+    let cmp_pred = Pred {
+        inverted: false,
+        reg: (0 .. NUM_PRED_REGS).filter(|&reg| reg != guard.reg).next().unwrap(),
+    };
+    let not_guard = Pred { inverted: !guard.inverted, reg: guard.reg };
+
+    let mut result = vec!();
+    let mut i = 0;
+    while i < insts.len() {
+        let inst = &insts[i];
+        match *inst {
+            // Long values belong to the instruction before them, and nops
+            // don't need to be predicated.
+            LongInst(..) |
+            NopInst => {
+                result.push(inst.clone());
+                i += 1;
+                continue;
+            },
+            _ => {},
+        }
+        if pred(inst) != Some(TRUE_PRED) {
+            return None;
+        }
+        if is_plain(inst) {
+            result.push(with_pred(inst, guard));
+            i += 1;
+            continue;
+        }
+
+        // Anything else has to be a comparison turned into a boolean.
+        let p = match destpred(inst) {
+            Some(p) => p,
+            None => return None,
+        };
+        let cmp_len = match *inst {
+            CompareLongInst(..) => 2,
+            _ => 1,
+        };
+        let (set, unset) = match (insts.get(i + cmp_len).and_then(is_mov),
+                                  insts.get(i + cmp_len + 1).and_then(is_mov)) {
+            (Some((p1, rd1)), Some((p2, rd2))) if
+                p1.reg == p.reg && p2.reg == p.reg &&
+                p1.inverted != p2.inverted && rd1 == rd2 => {
+                let (mov1, mov2) = (&insts[i + cmp_len], &insts[i + cmp_len + 1]);
+                if p1.inverted { (mov2, mov1) } else { (mov1, mov2) }
+            },
+            _ => return None,
+        };
+        result.push(with_destpred(&with_pred(inst, guard), cmp_pred));
+        if cmp_len == 2 {
+            result.push(insts[i + 1].clone());
+        }
+        // Nothing is unsigned-less-than 0.
+        result.push(InstNode::compareshort(not_guard, cmp_pred,
+                                           Reg { index: 0 }, CmpLTU, 0, 0));
+        result.push(with_pred(unset, guard));
+        result.push(with_pred(set, cmp_pred));
+        i += cmp_len + 2;
+    }
+    Some(result)
+}
+
+fn label_target(inst: &InstNode) -> Option<(Pred, &String)> {
+    match *inst {
+        BranchImmInst(p, false, JumpLabel(ref label)) => Some((p, label)),
+        _ => None,
+    }
+}
+
+/// Replace short conditional stretches of `insts` with predicated code.
+/// `labels` says where each label is, and we return the new instructions
+/// and where the labels are in those.
+pub fn if_convert(insts: &Vec<InstNode>,
+                  labels: &BTreeMap<String, usize>
+                  ) -> (Vec<InstNode>, BTreeMap<String, usize>) {
+    // How many times each label is referred to.
+    let mut refs: BTreeMap<String, usize> = BTreeMap::new();
+    for inst in insts.iter() {
+        let label = match *inst {
+            BranchImmInst(_, _, JumpLabel(ref label)) |
+            LongInst(LabelOffs(ref label)) => label,
+            _ => continue,
+        };
+        let count = *refs.get(label).unwrap_or(&0);
+        refs.insert(label.clone(), count + 1);
+    }
+    // Whether anything can jump into insts[start..end] other than at start.
+    let entered = |start: usize, end: usize| {
+        labels.values().any(|&pos| pos > start && pos < end)
+    };
+
+    let mut result = vec!();
+    // Where each instruction ended up, or, if it's gone, where the one
+    // after it did.
+    let mut new_pos = vec!();
+    let mut dead_labels = BTreeSet::new();
+    let mut i = 0;
+    while i < insts.len() {
+        new_pos.push(result.len());
+        let (p, label, target) = match label_target(&insts[i]) {
+            Some((p, label)) if p != TRUE_PRED && labels.contains_key(label) =>
+                (p, label, labels[label]),
+            _ => {
+                result.push(insts[i].clone());
+                i += 1;
+                continue;
+            },
+        };
+        let not_p = Pred { inverted: !p.inverted, reg: p.reg };
+        if target <= i || entered(i, target) {
+            result.push(insts[i].clone());
+            i += 1;
+            continue;
+        }
+
+        // If the block we'd skip ends by jumping past another block that
+        // only we jump to, it's an `if` with an `else`.
+        let else_end = if target > i + 1 && refs[label] == 1 {
+            match label_target(&insts[target - 1]) {
+                Some((end_p, end_label)) if end_p == TRUE_PRED &&
+                    labels.contains_key(end_label) => {
+                    let end = labels[end_label];
+                    if end > target && !entered(target, end) &&
+                        labels.values().filter(|&&pos| pos == target).count() == 1 {
+                        Some(end)
+                    } else {
+                        None
+                    }
+                },
+                _ => None,
+            }
+        } else {
+            None
+        };
+
+        let converted = match else_end {
+            Some(end) => {
+                match (predicate_block(&insts[i + 1 .. target - 1], not_p),
+                       predicate_block(&insts[target .. end], p)) {
+                    (Some(mut then_block), Some(else_block)) => {
+                        then_block.extend(else_block.into_iter());
+                        Some((then_block, end))
+                    },
+                    _ => None,
+                }
+            },
+            None => predicate_block(&insts[i + 1 .. target], not_p)
+                .map(|block| (block, target)),
+        };
+        match converted {
+            Some((block, end)) if block.len() <= MAX_CONVERTED_LEN => {
+                if refs[label] == 1 {
+                    dead_labels.insert(label.clone());
+                }
+                for _ in i + 1 .. end {
+                    new_pos.push(result.len());
+                }
+                result.extend(block.into_iter());
+                i = end;
+            },
+            _ => {
+                result.push(insts[i].clone());
+                i += 1;
+            },
+        }
+    }
+    new_pos.push(result.len());
+
+    let new_labels = labels.iter()
+        .filter(|&(label, _)| !dead_labels.contains(label))
+        .map(|(label, &pos)| (label.clone(), new_pos[pos]))
+        .collect();
+    (result, new_labels)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::if_convert;
+    use mas::ast::*;
+    use testing::{p, r, labels};
+
+    fn mov(pred: Pred, rd: u8, val: u32) -> InstNode {
+        InstNode::alu1short(pred, MovAluOp, r(rd), val, 0)
+    }
+
+    fn add(pred: Pred, rd: u8, rs: u8, val: u32) -> InstNode {
+        InstNode::alu2short(pred, AddAluOp, r(rd), r(rs), val, 0)
+    }
+
+    fn b(pred: Pred, label: &str) -> InstNode {
+        InstNode::branchimm(pred, false, JumpLabel(label.to_string()))
+    }
+
+    #[test]
+    fn test_if() {
+        let insts = vec!(
+            InstNode::compareshort(TRUE_PRED, p(false, 0), r(1), CmpBS, 1, 0),
+            b(p(true, 0), "LABEL0"),
+            add(TRUE_PRED, 2, 2, 1),
+            mov(TRUE_PRED, 4, 7),
+            add(TRUE_PRED, 0, 2, 0),
+            InstNode::branchreg(TRUE_PRED, false, r(31), 1),
+            );
+        let (result, new_labels) = if_convert(&insts, &labels(&[("f", 0), ("LABEL0", 4)]));
+        assert_eq!(result, vec!(
+            InstNode::compareshort(TRUE_PRED, p(false, 0), r(1), CmpBS, 1, 0),
+            add(p(false, 0), 2, 2, 1),
+            mov(p(false, 0), 4, 7),
+            add(TRUE_PRED, 0, 2, 0),
+            InstNode::branchreg(TRUE_PRED, false, r(31), 1),
+            ));
+        assert_eq!(new_labels, labels(&[("f", 0)]));
+    }
+
+    #[test]
+    fn test_if_else() {
+        let insts = vec!(
+            InstNode::compareshort(TRUE_PRED, p(false, 0), r(1), CmpBS, 1, 0),
+            b(p(false, 0), "LABEL0"),
+            // r3 := r4 < r5
+            InstNode::comparereg(TRUE_PRED, p(false, 0), r(4), CmpLTS, r(5), SllShift, 0),
+            mov(p(false, 0), 3, 1),
+            mov(p(true, 0), 3, 0),
+            b(TRUE_PRED, "LABEL1"),
+            mov(TRUE_PRED, 3, 2),
+            add(TRUE_PRED, 0, 3, 0),
+            );
+        let (result, new_labels) = if_convert(&insts, &labels(&[("LABEL0", 6), ("LABEL1", 7)]));
+        assert_eq!(result, vec!(
+            InstNode::compareshort(TRUE_PRED, p(false, 0), r(1), CmpBS, 1, 0),
+            InstNode::comparereg(p(true, 0), p(false, 1), r(4), CmpLTS, r(5), SllShift, 0),
+            InstNode::compareshort(p(false, 0), p(false, 1), r(0), CmpLTU, 0, 0),
+            mov(p(true, 0), 3, 0),
+            mov(p(false, 1), 3, 1),
+            mov(p(false, 0), 3, 2),
+            add(TRUE_PRED, 0, 3, 0),
+            ));
+        assert_eq!(new_labels, labels(&[("LABEL1", 6)]));
+    }
+
+    #[test]
+    fn test_not_converted() {
+        // A call in the block.
+        let insts = vec!(
+            InstNode::compareshort(TRUE_PRED, p(false, 0), r(1), CmpBS, 1, 0),
+            b(p(true, 0), "LABEL0"),
+            InstNode::branchimm(TRUE_PRED, true, JumpLabel("g".to_string())),
+            add(TRUE_PRED, 0, 2, 0),
+            );
+        let l = labels(&[("LABEL0", 3), ("g", 4)]);
+        assert_eq!(if_convert(&insts, &l), (insts.clone(), l.clone()));
+
+        // Something else jumping into the block.
+        let insts = vec!(
+            InstNode::compareshort(TRUE_PRED, p(false, 0), r(1), CmpBS, 1, 0),
+            b(p(true, 0), "LABEL0"),
+            add(TRUE_PRED, 2, 2, 1),
+            add(TRUE_PRED, 2, 2, 1),
+            b(TRUE_PRED, "LABEL1"),
+            );
+        let l = labels(&[("LABEL0", 4), ("LABEL1", 3)]);
+        assert_eq!(if_convert(&insts, &l), (insts.clone(), l.clone()));
+
+        // A block that's too long.
+        let mut insts = vec!(
+            InstNode::compareshort(TRUE_PRED, p(false, 0), r(1), CmpBS, 1, 0),
+            b(p(true, 0), "LABEL0"));
+        for _ in 0 .. 9 {
+            insts.push(add(TRUE_PRED, 2, 2, 1));
+        }
+        let l = labels(&[("LABEL0", 11)]);
+        assert_eq!(if_convert(&insts, &l), (insts.clone(), l.clone()));
+    }
+}
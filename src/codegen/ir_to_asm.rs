@@ -1,8 +1,15 @@
 use codegen::*;
-use codegen::register_color::*;
+use codegen::briggs::BriggsColorer;
+use codegen::if_convert::if_convert;
+use codegen::linear_scan::LinearScanColorer;
+use codegen::magic;
+use codegen::split::split_around_calls;
+use codegen::stack_items::pack_stack_items;
 use ir::*;
+use ir::cfg::CFG;
 use ir::conflicts::ConflictAnalyzer;
 use ir::liveness::LivenessAnalyzer;
+use ir::util::defs;
 use mas::ast::*;
 use mas::util::pack_int;
 use mc::ast::*;
@@ -12,6 +19,7 @@ use std::mem::swap;
 use std::collections::{BTreeMap, BTreeSet};
 use std::iter::FromIterator;
 use std::cmp::max;
+use self::Remat::*;
 
 fn lit_to_longvalue(lit: &LitNode,
                     session: &mut Session,
@@ -84,23 +92,48 @@ fn width_to_lsuwidth(width: &Width) -> LsuWidth {
     }
 }
 
+// How to compute a rematerialized variable again.
+#[derive(Clone)]
+enum Remat {
+    // Redo the unop that defines it.
+    RematUnOp(UnOpNode, RValueElem),
+    // It's the address of a stack-allocated item, this far below the
+    // spilled variables.
+    RematStack(i32),
+}
+
 pub struct IrToAsm<'a> {
     global_mem_start: u32,
     global_map: &'a BTreeMap<Name, StaticIRItem>,
+    regalloc: RegAlloc,
+    // Whether to replace short branches with predicated code.
+    if_convert: bool,
+    // For the function we're converting, how to compute each value we
+    // rematerialize, by the index of the op that defines it.
+    remat: BTreeMap<usize, Remat>,
     pub session: Session<'a>,
     pub strings: BTreeSet<Name>,
+    // The size of the stack frame of each function we've converted, in
+    // bytes, by name.
+    pub frame_sizes: BTreeMap<String, u32>,
 }
 
 impl<'a> IrToAsm<'a> {
     pub fn new(global_map: &'a BTreeMap<Name, StaticIRItem>,
                session: Session<'a>,
                strings: BTreeSet<Name>,
-               global_mem_start: u32) -> IrToAsm<'a> {
+               global_mem_start: u32,
+               regalloc: RegAlloc,
+               if_convert: bool) -> IrToAsm<'a> {
         IrToAsm::<'a> {
             global_mem_start: global_mem_start,
             global_map: global_map,
+            regalloc: regalloc,
+            if_convert: if_convert,
+            remat: BTreeMap::new(),
             session: session,
             strings: strings,
+            frame_sizes: BTreeMap::new(),
         }
     }
 
@@ -151,6 +184,29 @@ impl<'a> IrToAsm<'a> {
         (insts, labels)
     }
 
+    // Work out the liveness for ops, and assign each variable a register or
+    // a place in memory.
+    fn color(&self, ops: &Vec<Op>) -> (Vec<OpInfo>, BTreeMap<Var, RegisterColor>) {
+        let cfg = CFG::new(ops);
+        let opinfo = LivenessAnalyzer::analyze_cfg(ops, &cfg);
+        let regmap = match self.regalloc {
+            RegAlloc::BriggsAlloc => {
+                let (conflicts, _, must_colors, mem_vars) =
+                    ConflictAnalyzer::conflicts(ops, &opinfo);
+                BriggsColorer::color(ops, &cfg, conflicts, must_colors,
+                                     mem_vars, self.global_map,
+                                     NUM_USABLE_VARS)
+            },
+            RegAlloc::LinearAlloc => {
+                let (must_colors, mem_vars) = ConflictAnalyzer::constraints(ops);
+                LinearScanColorer::color(ops, &opinfo, must_colors,
+                                         mem_vars, self.global_map,
+                                         NUM_USABLE_VARS)
+            },
+        };
+        (opinfo, regmap)
+    }
+
     pub fn ir_to_asm(&mut self,
                      ops: &Vec<Op>,
                      ) -> (Vec<InstNode>, BTreeMap<String, usize>) {
@@ -184,47 +240,58 @@ impl<'a> IrToAsm<'a> {
         keep track of where r30 is relative to the start of the stack. Together, these let
         us find things on the stack!
         */
-        let opinfo = LivenessAnalyzer::analyze(ops);
-        let (conflicts, counts, must_colors, mem_vars) =
-            ConflictAnalyzer::conflicts(ops, &opinfo);
-        let regmap = RegisterColorer::color(conflicts, counts, must_colors,
-                                            mem_vars, self.global_map,
-                                            NUM_USABLE_VARS);
+        let (mut opinfo, mut regmap) = self.color(ops);
+
+        // If some variables didn't get registers, see if splitting them
+        // around calls helps.
+        let (_, mem_vars) = ConflictAnalyzer::constraints(ops);
+        let spilled: BTreeSet<Var> = regmap.iter().filter(|&(v, c)| match *c {
+            StackColor(..) => !mem_vars.contains(&v.name),
+            _ => false,
+        }).map(|(v, _)| *v).collect();
+        let split = if spilled.is_empty() {
+            None
+        } else {
+            split_around_calls(ops, &opinfo, &spilled)
+        };
+        let split_ops;
+        let ops = match split {
+            Some(new_ops) => {
+                split_ops = new_ops;
+                let (new_opinfo, new_regmap) = self.color(&split_ops);
+                opinfo = new_opinfo;
+                regmap = new_regmap;
+                &split_ops
+            },
+            None => ops,
+        };
+
         // Does this function call any other function? If not, we can
         // avoid saving r31.
         let mut has_call = false;
 
-        // Figure out where objects allocated on the stack will go.
-        // stack_item_map is a map from instruction index (for an alloca
-        // instruction) to a stack offset, relative to the beginning
-        // of the region for stack-allocated structures.
-        let mut stack_item_map: BTreeMap<usize, u32> = BTreeMap::new();
-
-        let mut stack_items_len: u32 = 0;
-        for (inst, op) in ops.iter().enumerate() {
+        for op in ops.iter() {
             match op.val {
-                OpNode::Alloca(ref var, size) => {
-                    // We must be aligned on 4-byte boundaries.
-                    let size_adjust = (4 - (size % 4)) % 4;
-                    if !self.global_map.get(&var.name).is_some() {
-                        stack_item_map.insert(inst, stack_items_len);
-                        stack_items_len += (size + size_adjust) as u32;
-                    }
-                },
                 OpNode::Call(..) => { has_call = true; }
                 _ => {}
             }
         }
 
+        // Figure out where objects allocated on the stack will go.
+        // stack_item_map is a map from instruction index (for an alloca
+        // instruction) to a stack offset, relative to the beginning
+        // of the region for stack-allocated structures.
+        let (stack_item_map, stack_items_len) =
+            pack_stack_items(ops, &opinfo, self.global_map);
+
         let ret_addr_len = if has_call { 4 } else { 0 };
 
-        // Find the highest index of any register we use, so we know which
+        // Find the callee-save registers we write to, so we know which
         // ones we need to save.
-        let max_reg_index = regmap.iter().map(|(_, c)|
-                                              match *c {
-                                                  RegColor(ref r) => r.index,
-                                                  _ => 0,
-                                              }).max().unwrap_or(0);
+        let saved_regs: Vec<Reg> = regmap.values().filter_map(|c| match *c {
+            RegColor(r) if r.index >= FIRST_CALLEE_SAVED_REG.index => Some(r),
+            _ => None,
+        }).collect::<BTreeSet<Reg>>().into_iter().collect();
 
         // Find the length of the stack region for spilled variables.
         let spilled_regs_len = regmap.iter().map(|(_, c)|
@@ -234,13 +301,7 @@ impl<'a> IrToAsm<'a> {
                                                  }).max().unwrap_or(0) * 4;
 
         // Size of the storage for callee-save registers.
-        // TODO: avoid saving registers we don't need to save.
-        let callee_save_len =
-            if max_reg_index >= FIRST_CALLEE_SAVED_REG.index {
-                1 + max_reg_index - FIRST_CALLEE_SAVED_REG.index
-            } else {
-                0
-            } * 4;
+        let callee_save_len = saved_regs.len() * 4;
 
 
         // Here's where we figure out the offsets of each stack region, now that we know
@@ -264,8 +325,26 @@ impl<'a> IrToAsm<'a> {
         // beyond all of the regions we just defined.
         let stack_ptr_offs = spilled_regs_offs + spilled_regs_len as i32;
 
+        // A leaf function that keeps everything in registers it doesn't
+        // have to save doesn't need a stack frame at all.
+        let has_frame = stack_ptr_offs != 0;
+
         // Done figuring out stack sizes!
 
+        self.remat = regmap.values().filter_map(|c| match *c {
+            RematColor(i) => Some(i),
+            _ => None,
+        }).map(|i| {
+            let remat = match ops[i].val {
+                OpNode::UnOp(_, op, ref rve) => RematUnOp(op, rve.clone()),
+                OpNode::Alloca(..) =>
+                    RematStack(spilled_regs_offs - stack_items_offs -
+                               stack_item_map[&i] as i32),
+                ref op => panic!("Cannot rematerialize {:?}", op),
+            };
+            (i, remat)
+        }).collect();
+
         let mut targets: BTreeMap<String, usize> = BTreeMap::new();
 
         let mut labels: BTreeMap<usize, BTreeMap<Name, usize>> = BTreeMap::new();
@@ -293,6 +372,14 @@ impl<'a> IrToAsm<'a> {
 
         let mut result = vec!();
         for (pos, op) in ops.iter().enumerate() {
+            // Values we rematerialize get computed where they're used
+            // instead.
+            if defs(&op.val).into_iter().any(|v| match regmap.get(v) {
+                Some(&RematColor(..)) => true,
+                _ => false,
+            }) {
+                continue;
+            }
             match op.val {
                 OpNode::Func(ref name, _, ref abi) => {
                     if let Some(ref abi) = *abi {
@@ -302,6 +389,7 @@ impl<'a> IrToAsm<'a> {
                         }
                     }
                     targets.insert(format!("{}", name), result.len());
+                    self.frame_sizes.insert(format!("{}", name), stack_ptr_offs as u32);
                     if let Some(ref abi) = *abi {
                         if abi.to_string() == "bare" {
                             // For the bare API, we include the label, but we omit all of
@@ -310,21 +398,22 @@ impl<'a> IrToAsm<'a> {
                         }
                     }
 
-                    let (stack_ptr_offs_base, stack_ptr_offs_shift) =
-                        pack_int(stack_ptr_offs as u32, 10).expect(
-                            "Unable to pack literal.");
-
                     // Before we can save anything to the stack, we must advance the
                     // stack pointer.
-                    result.push(
-                        InstNode::alu2short(
-                            TRUE_PRED,
-                            AddAluOp,
-                            STACK_POINTER,
-                            STACK_POINTER,
-                            stack_ptr_offs_base,
-                            stack_ptr_offs_shift)
-                            );
+                    if has_frame {
+                        let (stack_ptr_offs_base, stack_ptr_offs_shift) =
+                            pack_int(stack_ptr_offs as u32, 10).expect(
+                                "Unable to pack literal.");
+                        result.push(
+                            InstNode::alu2short(
+                                TRUE_PRED,
+                                AddAluOp,
+                                STACK_POINTER,
+                                STACK_POINTER,
+                                stack_ptr_offs_base,
+                                stack_ptr_offs_shift)
+                                );
+                    }
 
                     // Save the return address.
                     if has_call {
@@ -338,15 +427,14 @@ impl<'a> IrToAsm<'a> {
                                 );
                     }
 
-                    // Save all callee-save registers.
-                    // TODO: don't save registers we know we can't use.
-                    for (x, i) in (FIRST_CALLEE_SAVED_REG.index .. max_reg_index+1).enumerate() {
+                    // Save the callee-save registers we use.
+                    for (x, &reg) in saved_regs.iter().enumerate() {
                         result.push(
                             InstNode::store(TRUE_PRED,
                                             store32_op,
                                             STACK_POINTER,
                                             -stack_ptr_offs + callee_save_offs + x as i32 * 4,
-                                            Reg { index: i })
+                                            reg)
                                 );
                     }
                 },
@@ -358,12 +446,12 @@ impl<'a> IrToAsm<'a> {
                                           -stack_ptr_offs,
                                           -stack_ptr_offs + spilled_regs_offs).into_iter());
 
-                    // Restore all callee-save registers
-                    for (x, i) in (FIRST_CALLEE_SAVED_REG.index .. max_reg_index+1).enumerate() {
+                    // Restore the callee-save registers we saved.
+                    for (x, &reg) in saved_regs.iter().enumerate() {
                         result.push(
                             InstNode::load(TRUE_PRED,
                                            load32_op,
-                                           Reg { index: i },
+                                           reg,
                                            STACK_POINTER,
                                            -stack_ptr_offs + callee_save_offs + x as i32* 4
                                            ));
@@ -379,20 +467,21 @@ impl<'a> IrToAsm<'a> {
                                            -stack_ptr_offs + ret_addr_offs));
                     }
 
-                    let (stack_ptr_offs_base, stack_ptr_offs_shift) =
-                        pack_int(stack_ptr_offs as u32, 10).expect(
-                            "Unable to pack literal.");
-
                     // Restore stack pointer
-                    result.push(
-                        InstNode::alu2short(
-                            TRUE_PRED,
-                            SubAluOp,
-                            STACK_POINTER,
-                            STACK_POINTER,
-                            stack_ptr_offs_base,
-                            stack_ptr_offs_shift)
-                            );
+                    if has_frame {
+                        let (stack_ptr_offs_base, stack_ptr_offs_shift) =
+                            pack_int(stack_ptr_offs as u32, 10).expect(
+                                "Unable to pack literal.");
+                        result.push(
+                            InstNode::alu2short(
+                                TRUE_PRED,
+                                SubAluOp,
+                                STACK_POINTER,
+                                STACK_POINTER,
+                                stack_ptr_offs_base,
+                                stack_ptr_offs_shift)
+                                );
+                    }
 
                     // Return
                     result.push(
@@ -766,7 +855,11 @@ impl<'a> IrToAsm<'a> {
             }
         }
 
-        (result, targets)
+        if self.if_convert {
+            if_convert(&result, &targets)
+        } else {
+            (result, targets)
+        }
     }
 
     // Given a variable, return the register corresponding to it.  Also
@@ -851,6 +944,25 @@ impl<'a> IrToAsm<'a> {
                          ),
                  )
             },
+            RematColor(i) => {
+                let reg = Reg { index: SPILL_REG_BASE + spill_pos };
+                let insts = match self.remat[&i].clone() {
+                    RematUnOp(op, rve) =>
+                        self.convert_unop(regmap, reg, &op, &rve, args_offs, spill_offs),
+                    RematStack(offs) => {
+                        let (offs_base, offs_shift) =
+                            pack_int((offs - spill_offs) as u32, 10).expect(
+                                "Unable to pack literal.");
+                        vec!(InstNode::alu2short(pred,
+                                                 SubAluOp,
+                                                 reg,
+                                                 STACK_POINTER,
+                                                 offs_base,
+                                                 offs_shift))
+                    },
+                };
+                (reg, insts, vec!())
+            },
             GlobalColor => {
                 let global_info = self.global_map.get(&var.name).unwrap();
                 let offs = global_info.offset.expect("No offset for global item");
@@ -897,6 +1009,8 @@ impl<'a> IrToAsm<'a> {
         }
     }
 
+    // Move the variables a jump passes into the ones its label expects, all
+    // at once, if pred holds.
     fn assign_vars(&mut self,
                    regmap: &BTreeMap<Var, RegisterColor>,
                    pred: &Pred,
@@ -906,11 +1020,9 @@ impl<'a> IrToAsm<'a> {
                    spill_offs: i32) -> Vec<InstNode> {
         let mut result = vec!();
 
-        let mut reg_transformations: BTreeMap<Reg, (Reg,
-                                                    Vec<InstNode>,
-                                                    Vec<InstNode>)> = BTreeMap::new();
-        let mut rev_map: BTreeMap<Reg, Reg> = BTreeMap::new();
-
+        // The moves left to make. A source of None means GLOBAL_REG, which
+        // we use to break cycles.
+        let mut moves: Vec<(Option<Var>, Var)> = vec!();
         for var in vars.iter() {
             // Globals don't need assignments.
             if self.global_map.get(&var.name).is_some() {
@@ -921,116 +1033,100 @@ impl<'a> IrToAsm<'a> {
                 name: var.name.clone(),
                 generation: Some(*gens.get(&var.name).unwrap())
             };
-            let (src_reg, src_insts, _) = self.var_to_reg(regmap, var, 1, args_offs, spill_offs);
-            let (dest_reg, _, dest_insts) = self.var_to_reg(regmap, &new_var, 1,
-                                                            args_offs, spill_offs);
-            if src_reg != dest_reg {
-                rev_map.insert(dest_reg.clone(), src_reg.clone());
-                reg_transformations.insert(src_reg,
-                                           (dest_reg.clone(),
-                                            src_insts,
-                                            dest_insts));
+            if regmap[var] != regmap[&new_var] {
+                moves.push((Some(*var), new_var));
             }
         }
 
         // This is a little like a toposort, except that we might have cycles we need
         // to break.
-        while !reg_transformations.is_empty() {
-            // See if there's any assignment rA->rB where rB is not also the source
-            // of an assignment. If so, it's safe to store rA in rB.
-            let res = reg_transformations.iter()
-                .filter(|&(_, &(dest, _, _))|
-                        !reg_transformations.contains_key(&dest))
-                .next().map(|(a, b)| (a.clone(), b.clone())) ;
-            match res {
-                Some((src_reg, (dest_reg, src_insts, dest_insts))) =>
-                {
-                    // There's a leaf; it's safe to just assign it.
-                    reg_transformations.remove(&src_reg);
-                    result.extend(src_insts.into_iter());
-
-                    result.push(
-                        InstNode::alu1reg(
-                            pred.clone(),
-                            MovAluOp,
-                            dest_reg,
-                            src_reg,
-                            SllShift,
-                            0));
-                    result.extend(dest_insts.into_iter());
+        while !moves.is_empty() {
+            // See if there's any move to somewhere no other move still
+            // reads from. If so, it's safe to make.
+            let ready = (0 .. moves.len()).filter(|&i| {
+                let dest = regmap[&moves[i].1];
+                !moves.iter().any(|&(src, _)| src.map(|v| regmap[&v]) == Some(dest))
+            }).next();
+            match ready {
+                Some(i) => {
+                    let (src, dest) = moves.remove(i);
+                    result.extend(self.move_var(regmap, pred, src, &dest,
+                                                args_offs, spill_offs).into_iter());
                 },
                 None => {
-                    // This is more exciting! There's a cycle. We need to
-                    // use a temp register to break it, and then follow
-                    // the cycle around.
-
-                    // Start with any assignment at all. They're all part
-                    // of cycles.
-                    let (src_reg, (dest_reg, src_insts, dest_insts)) =
-                        reg_transformations.iter()
-                        .map(|(x, y)| (x.clone(), y.clone())).next().unwrap();
-                    reg_transformations.remove(&src_reg);
-                    result.extend(src_insts.into_iter());
+                    // This is more exciting! Everything left is part of a
+                    // cycle. We copy one value into the temp register, and
+                    // everything that wanted it takes it from there, which
+                    // lets the cycle unwind.
+                    let src = moves.iter().filter_map(|&(src, _)| src).next().unwrap();
+                    let (reg, before, _) = self.var_to_reg(regmap, &src, 0,
+                                                           args_offs, spill_offs);
+                    result.extend(before.into_iter());
                     result.push(
                         InstNode::alu1reg(
                             pred.clone(),
                             MovAluOp,
                             GLOBAL_REG.clone(),
-                            src_reg.clone(),
+                            reg,
                             SllShift,
                             0));
-
-                    // GLOBAL_REG now contains the value that should
-                    // ultimately go into dest_reg.
-
-                    let mut this_src = &src_reg;
-                    loop {
-                        // Find the register that's stored into our
-                        // previous source register. That source register
-                        // has been moved to its destination (or the temp
-                        // register), so it's safe to move over it now.
-                        this_src = rev_map.get(this_src).unwrap();
-
-                        // If the register we're trying to move from is
-                        // the original register we were moving from,
-                        // we're at the end of the loop. Its *actual*
-                        // value is in the temp register.
-                        if *this_src == src_reg {
-                            break;
+                    for m in moves.iter_mut() {
+                        if m.0.map(|v| regmap[&v]) == Some(regmap[&src]) {
+                            m.0 = None;
                         }
-
-                        let (this_dest, src_insts, dest_insts)
-                            = reg_transformations.get(this_src).unwrap().clone();
-                        reg_transformations.remove(this_src);
-                        result.extend(src_insts.into_iter());
-                        result.push(
-                            InstNode::alu1reg(
-                                pred.clone(),
-                                MovAluOp,
-                                this_dest,
-                                this_src.clone(),
-                                SllShift,
-                                0));
-                        result.extend(dest_insts.into_iter());
                     }
-
-                    // Now it's just a matter of assigning the temporary
-                    // variable to the original destination.
-                    result.push(
-                        InstNode::alu1reg(
-                            pred.clone(),
-                            MovAluOp,
-                            dest_reg,
-                            GLOBAL_REG.clone(),
-                            SllShift,
-                            0));
-                    result.extend(dest_insts.into_iter());
                 }
             }
         }
         result
     }
 
+    // Move src, or GLOBAL_REG if it's None, into dest, if pred holds.
+    fn move_var(&mut self,
+                regmap: &BTreeMap<Var, RegisterColor>,
+                pred: &Pred,
+                src: Option<Var>,
+                dest: &Var,
+                args_offs: i32,
+                spill_offs: i32) -> Vec<InstNode> {
+        let mut result = vec!();
+        let src_reg = match src {
+            Some(ref var) => {
+                let (reg, before, _) = self.var_to_reg(regmap, var, 0, args_offs, spill_offs);
+                result.extend(before.into_iter());
+                reg
+            },
+            None => GLOBAL_REG,
+        };
+        let (dest_reg, _, _) = self.var_to_reg(regmap, dest, 1, args_offs, spill_offs);
+        if src_reg != dest_reg {
+            result.push(
+                InstNode::alu1reg(
+                    pred.clone(),
+                    MovAluOp,
+                    dest_reg,
+                    src_reg,
+                    SllShift,
+                    0));
+        }
+        match regmap[dest] {
+            RegColor(..) => {},
+            StackColor(pos) => {
+                // Only write to the stack if we're taking the jump: the
+                // slot might belong to something else if we aren't.
+                result.push(
+                    InstNode::store(pred.clone(),
+                                    LsuOp { store: true,
+                                            width: LsuWidthL },
+                                    STACK_POINTER,
+                                    spill_offs + pos as i32 * 4,
+                                    dest_reg));
+            },
+            ref color => panic!("Cannot pass a variable to a label in {}", color),
+        }
+        result
+    }
+
     /// Convert a binop into the internal asm representation.
     fn convert_binop<'b>(
         &mut self,
@@ -1059,8 +1155,6 @@ impl<'a> IrToAsm<'a> {
         let (reg_l, before_l, _) = self.var_to_reg(regmap, &var_l, 1, args_offs, spill_offs);
         result.extend(before_l.into_iter());
 
-        // TODO: handle shifts, multiplication, division.
-
         match *op_r {
             Variable(ref var) => {
                 assert!(!swapped);
@@ -1177,6 +1271,11 @@ impl<'a> IrToAsm<'a> {
                     Immediate(num) => pack_int(num, 10),
                     _ => None,
                 };
+                let magic_div = match longval {
+                    Immediate(num) if *op == DivideOp && !swapped =>
+                        magic::divide(dest, reg_l, num, signed),
+                    _ => None,
+                };
 
                 match binop_to_cmpop(op, signed, swapped) {
                     Some((cmptype, negated)) => {
@@ -1246,6 +1345,8 @@ impl<'a> IrToAsm<'a> {
                                         dest,
                                         reg_l,
                                         GLOBAL_REG)).into_iter()),
+                            DivideOp if magic_div.is_some() =>
+                                result.extend(magic_div.unwrap().into_iter()),
                             DivideOp =>
                                 result.extend(vec!(
                                     // TODO: don't always use longs here, and
@@ -1446,6 +1547,7 @@ impl<'a> IrToAsm<'a> {
                             }
                         },
                         GlobalReferenceColor => unimplemented!(),
+                        RematColor(..) => panic!("Cannot take the address of a temporary."),
                         RegColor(ref reg) => {
                             // If we're taking the address of something with a
                             // register color, it had better be a global function.
@@ -0,0 +1,353 @@
+//! Linear scan register allocation, after Poletto and Sarkar.
+//!
+//! Building the interference graph is the expensive part of graph coloring,
+//! and at -O0 we'd rather compile quickly. Instead, we number the ops in
+//! order, giving each one a point where its operands are read and a point
+//! after it where its results are written, and approximate each variable's
+//! lifetime by one interval from the first point it's live at to the last.
+//! Two variables whose intervals don't overlap can share a register; ones
+//! whose intervals do might not actually interfere, since an interval
+//! covers every hole in the lifetime, but we don't look any closer.
+//!
+//! Going through the intervals in order of where they start, each takes a
+//! register no interval still running has, preferring one a variable it
+//! moves to or from already has, and avoiding the registers variables that
+//! have to be in a particular one are in at any point it covers. When
+//! there's nothing left, a constant or address among it and the running
+//! intervals gets computed again wherever it's used instead; failing that,
+//! whichever of them ends last goes on the stack, after the variables that
+//! have to be in memory.
+
+use codegen::*;
+use ir::*;
+use ir::util::{defs, uses};
+use mas::ast::Reg;
+use mc::ast::Identity;
+use std::collections::{BTreeMap, BTreeSet};
+use util::Name;
+
+pub struct LinearScanColorer;
+
+fn overlaps(a: (usize, usize), b: (usize, usize)) -> bool {
+    a.0 <= b.1 && b.0 <= a.1
+}
+
+impl LinearScanColorer {
+    /// Assign each variable in ops a register or a place in memory. Same
+    /// contract as `BriggsColorer::color`, but from the liveness alone.
+    pub fn color(ops: &Vec<Op>,
+                 opinfo: &Vec<OpInfo>,
+                 must_colors: BTreeMap<Var, RegisterColor>,
+                 mem_vars: BTreeSet<Name>,
+                 global_map: &BTreeMap<Name, StaticIRItem>,
+                 num_colors: usize
+                 ) -> BTreeMap<Var, RegisterColor> {
+        // Memory variables and globals are handled as in BriggsColorer.
+        let mem_locs: BTreeMap<Name, usize> = mem_vars.into_iter()
+            .filter(|name| global_map.get(name).is_none())
+            .enumerate().map(|(i, name)| (name, i)).collect();
+        let mut coloring = BTreeMap::new();
+        let mut precolored = BTreeMap::new();
+        for (var, color) in must_colors.into_iter() {
+            match color {
+                RegColor(reg) => { precolored.insert(var, reg); },
+                _ => { coloring.insert(var, color); },
+            }
+        }
+        let mut vars = BTreeSet::new();
+        for op in ops.iter() {
+            for var in defs(&op.val).into_iter().chain(uses(&op.val).into_iter()) {
+                if coloring.contains_key(var) || precolored.contains_key(var) {
+                    continue;
+                }
+                if global_map.get(&var.name).map_or(false, |info| !info.is_func) {
+                    coloring.insert(*var, GlobalColor);
+                } else if let Some(&slot) = mem_locs.get(&var.name) {
+                    coloring.insert(*var, StackColor(slot as isize));
+                } else {
+                    vars.insert(*var);
+                }
+            }
+        }
+
+        // Op i reads at point 2*i and writes at point 2*i + 1.
+        let mut intervals: BTreeMap<Var, (usize, usize)> = BTreeMap::new();
+        {
+            let mut extend = |var: &Var, point: usize| {
+                if !vars.contains(var) && !precolored.contains_key(var) {
+                    return;
+                }
+                let interval = match intervals.get(var) {
+                    Some(&(start, end)) => (::std::cmp::min(start, point),
+                                            ::std::cmp::max(end, point)),
+                    None => (point, point),
+                };
+                intervals.insert(*var, interval);
+            };
+            for (i, info) in opinfo.iter().enumerate() {
+                for var in info.live.iter().chain(info.used.iter()) {
+                    extend(var, 2 * i);
+                }
+                for var in info.def.iter() {
+                    extend(var, 2 * i + 1);
+                }
+                for &succ in info.succ.iter() {
+                    for var in opinfo[succ].live.iter() {
+                        extend(var, 2 * i + 1);
+                    }
+                }
+            }
+        }
+
+        // Where each register is spoken for.
+        let mut fixed: BTreeMap<Reg, Vec<(usize, usize)>> = BTreeMap::new();
+        for (var, &reg) in precolored.iter() {
+            coloring.insert(*var, RegColor(reg));
+            if let Some(&interval) = intervals.get(var) {
+                if !fixed.contains_key(&reg) {
+                    fixed.insert(reg, vec!());
+                }
+                fixed.get_mut(&reg).unwrap().push(interval);
+            }
+        }
+        let usable = |reg: &Reg, interval: (usize, usize)| {
+            fixed.get(reg).map_or(true, |fixed| {
+                !fixed.iter().any(|&other| overlaps(other, interval))
+            })
+        };
+
+        // The moves: copies, and what jumps pass to their labels' variables.
+        let mut label_vars = BTreeMap::new();
+        for op in ops.iter() {
+            if let OpNode::Label(label, ref vars) = op.val {
+                label_vars.insert(label, vars);
+            }
+        }
+        let mut partners: BTreeMap<Var, Vec<Var>> = BTreeMap::new();
+        {
+            let mut add = |a: Var, b: Var| {
+                for &(x, y) in [(a, b), (b, a)].iter() {
+                    if !partners.contains_key(&x) {
+                        partners.insert(x, vec!());
+                    }
+                    partners.get_mut(&x).unwrap().push(y);
+                }
+            };
+            for op in ops.iter() {
+                match op.val {
+                    OpNode::UnOp(dest, Identity, Variable(src)) => add(dest, src),
+                    OpNode::Goto(label, ref vars) |
+                    OpNode::CondGoto(_, _, label, ref vars) => for var in vars.iter() {
+                        for other in label_vars[&label].iter() {
+                            if other.name == var.name {
+                                add(*var, *other);
+                            }
+                        }
+                    },
+                    _ => {},
+                }
+            }
+        }
+
+        let mut order: Vec<(usize, usize, Var)> = vars.iter()
+            .map(|var| {
+                let (start, end) = *intervals.get(var).unwrap_or(&(0, 0));
+                (start, end, *var)
+            }).collect();
+        order.sort();
+
+        let remat = remat_candidates(ops, global_map);
+        let spill_base = mem_locs.len() as isize;
+        let mut slots: Vec<Vec<(usize, usize)>> = vec!();
+        let mut free = allocatable_regs(num_colors);
+        let mut active: Vec<(usize, Var, Reg)> = vec!();
+        let mut spill = |coloring: &mut BTreeMap<Var, RegisterColor>,
+                         var: Var, interval: (usize, usize)| {
+            if let Some(&i) = remat.get(&var) {
+                coloring.insert(var, RematColor(i));
+                return;
+            }
+            let mut slot = 0;
+            while slot < slots.len() &&
+                slots[slot].iter().any(|&other| overlaps(other, interval)) {
+                slot += 1;
+            }
+            if slot == slots.len() {
+                slots.push(vec!());
+            }
+            slots[slot].push(interval);
+            coloring.insert(var, StackColor(spill_base + slot as isize));
+        };
+
+        for &(start, end, var) in order.iter() {
+            let mut i = 0;
+            while i < active.len() {
+                if active[i].0 < start {
+                    free.push(active[i].2);
+                    active.swap_remove(i);
+                } else {
+                    i += 1;
+                }
+            }
+
+            let preferred = partners.get(&var).and_then(|partners| {
+                partners.iter().filter_map(|p| match coloring.get(p) {
+                    Some(&RegColor(reg)) if free.contains(&reg) => Some(reg),
+                    _ => None,
+                }).filter(|reg| usable(reg, (start, end))).next()
+            });
+            let reg = preferred.or(free.iter().cloned().filter(|reg| {
+                usable(reg, (start, end))
+            }).min());
+            if let Some(reg) = reg {
+                free.retain(|&r| r != reg);
+                active.push((end, var, reg));
+                coloring.insert(var, RegColor(reg));
+                continue;
+            }
+
+            // Out of registers. Unless this is something we can compute
+            // again, take a register we can use from a running interval
+            // that is, or else from whichever ends last, if that's later
+            // than this one.
+            let mut victim = None;
+            if !remat.contains_key(&var) {
+                for (i, &(other_end, other, reg)) in active.iter().enumerate() {
+                    let key = (remat.contains_key(&other), other_end);
+                    if (key.0 || other_end > end) && usable(&reg, (start, end)) &&
+                        victim.map_or(true, |(_, best)| key > best) {
+                        victim = Some((i, key));
+                    }
+                }
+            }
+            match victim {
+                Some((i, _)) => {
+                    let (other_end, other, reg) = active[i];
+                    let other_start = intervals[&other].0;
+                    spill(&mut coloring, other, (other_start, other_end));
+                    active[i] = (end, var, reg);
+                    coloring.insert(var, RegColor(reg));
+                },
+                None => spill(&mut coloring, var, (start, end)),
+            }
+        }
+        coloring
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use codegen::*;
+    use mas::ast::Reg;
+    use testing;
+
+    use std::collections::BTreeMap;
+
+    fn color(src: &str, num_colors: usize) -> BTreeMap<String, RegisterColor> {
+        testing::color(src, RegAlloc::LinearAlloc, num_colors)
+    }
+
+    #[test]
+    fn test_loop() {
+        let coloring = color("fn %f(%n<1>)
+    %s<1> := 0
+    %i<1> := 0
+    goto 0(%s<1>, %i<1>)
+    label 0(%s<2>, %i<2>)
+    %c<1> := %i<2> <s %n<1>
+    if !%c<1> goto 1()
+    %s<3> := %s<2> +s %i<2>
+    %t<1> := %i<2> +s 1
+    %i<3> := %t<1>
+    goto 0(%s<3>, %i<3>)
+    label 1()
+    return %s<2>
+", NUM_USABLE_VARS);
+        // The variables going into the loop are dead by the time the
+        // label's start, so they get the same registers.
+        assert_eq!(coloring["s<2>"], coloring["s<1>"]);
+        assert_eq!(coloring["i<2>"], coloring["i<1>"]);
+        assert_eq!(coloring["i<3>"], coloring["t<1>"]);
+        assert_eq!(coloring["n<1>"], RegColor(Reg { index: 0 }));
+    }
+
+    #[test]
+    fn test_spill() {
+        // Six variables live at once in the loop, and five registers: the
+        // one that lives longest goes on the stack.
+        let coloring = color("fn %f(%n<1>)
+    %d<1> := %n<1> +s 4
+    %a<1> := %n<1> +s 1
+    %b<1> := %n<1> +s 2
+    %c<1> := %n<1> +s 3
+    %i<1> := 0
+    goto 0(%i<1>)
+    label 0(%i<2>)
+    %x<1> := %a<1> +s %b<1>
+    %y<1> := %x<1> +s %c<1>
+    %i<3> := %i<2> +s %y<1>
+    %t<1> := %i<3> <s 100
+    if %t<1> goto 0(%i<3>)
+    %z<1> := %i<3> +s %d<1>
+    return %z<1>
+", 5);
+        assert_eq!(coloring["d<1>"], StackColor(0));
+        for (var, color) in coloring.iter() {
+            if var != "d<1>" {
+                match *color {
+                    RegColor(..) => {},
+                    _ => panic!("{} is {}", var, color),
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn test_rematerialization() {
+        // As above, but with constants, the one that lives longest is
+        // computed again where it's used instead.
+        let coloring = color("fn %f()
+    %d<1> := 4
+    %a<1> := 1
+    %b<1> := 2
+    %c<1> := 3
+    %i<1> := 0
+    goto 0(%i<1>)
+    label 0(%i<2>)
+    %x<1> := %a<1> +s %b<1>
+    %y<1> := %x<1> +s %c<1>
+    %i<3> := %i<2> +s %y<1>
+    %t<1> := %i<3> <s 100
+    if %t<1> goto 0(%i<3>)
+    %z<1> := %i<3> +s %d<1>
+    return %z<1>
+", 5);
+        assert_eq!(coloring["d<1>"], RematColor(1));
+        for (var, color) in coloring.iter() {
+            if var != "d<1>" {
+                match *color {
+                    RegColor(..) => {},
+                    _ => panic!("{} is {}", var, color),
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn test_must_colors() {
+        // k is live across the call, so it can't be in r0 even though it
+        // isn't live while the result is.
+        let coloring = color("fn %f(%x<1>)
+    %k<1> := %x<1> +s 1
+    %a<1> := 5
+    %r<1> := call %g(%a<1>)
+    %s<1> := %r<1> +s %k<1>
+    return %s<1>
+fn %g(%y<1>)
+    return %y<1>
+", NUM_USABLE_VARS);
+        assert_eq!(coloring["a<1>"], RegColor(Reg { index: 0 }));
+        assert_eq!(coloring["r<1>"], RegColor(Reg { index: 0 }));
+        assert!(coloring["k<1>"] != RegColor(Reg { index: 0 }));
+    }
+}
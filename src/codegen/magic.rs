@@ -0,0 +1,202 @@
+//! Division by a constant, by multiplying by its reciprocal.
+//!
+//! Dividing by a constant d is the same as multiplying by about 2^(32+s)/d
+//! and keeping the top bits, where s is picked so that the rounding error
+//! never shows up in the quotient (Granlund and Montgomery, "Division by
+//! invariant integers using multiplication"; see also Hacker's Delight,
+//! chapter 10). The multiplier doesn't always fit in 32 bits, in which case
+//! the dividend gets added back in to make up for the missing top bit.
+
+use codegen::*;
+use mas::ast::*;
+
+#[derive(Debug, PartialEq)]
+pub struct Magic {
+    pub multiplier: u32,
+    /// Unsigned: the multiplier is really 2^32 + `multiplier`. Signed: the
+    /// multiplier is negative as an i32, and the dividend has to be added
+    /// back after the multiply.
+    pub add: bool,
+    pub shift: u32,
+}
+
+// The smallest l with 2^l >= d.
+fn ceil_log2(d: u32) -> u32 {
+    let mut l = 0;
+    while l < 32 && (1u64 << l) < d as u64 {
+        l += 1;
+    }
+    l
+}
+
+// The multiplier for shift p, if it fits in 32 bits and its error,
+// m * d - 2^(32+p), is at most 2^limit.
+fn try_shift(d: u32, p: u32, limit: u32) -> Option<u32> {
+    let two_p = 1u64 << (32 + p);
+    let m = two_p / d as u64 + 1;
+    if m >> 32 == 0 && m * d as u64 - two_p <= 1u64 << limit {
+        Some(m as u32)
+    } else {
+        None
+    }
+}
+
+/// The magic number for unsigned division by `d`, which must be at least 2
+/// and not a power of two.
+pub fn unsigned_magic(d: u32) -> Magic {
+    assert!(d >= 2 && d & (d - 1) != 0);
+    let l = ceil_log2(d);
+    for p in 0..l {
+        match try_shift(d, p, p) {
+            Some(m) => return Magic { multiplier: m, add: false, shift: p },
+            None => {},
+        }
+    }
+    // The multiplier needs 33 bits; this is 2^32 less than it.
+    let m = ((1u64 << 32) * ((1u64 << l) - d as u64)) / d as u64 + 1;
+    Magic { multiplier: m as u32, add: true, shift: l }
+}
+
+/// The magic number for signed division by `d`, which must be at least 2,
+/// less than 2^31, and not a power of two.
+pub fn signed_magic(d: u32) -> Magic {
+    assert!(d >= 2 && d < 0x80000000 && d & (d - 1) != 0);
+    let mut p = 0;
+    loop {
+        // The error bound is one bit looser than in the unsigned case, since
+        // dividends are only 31 bits in magnitude. This always succeeds by
+        // the time 2^(p+1) >= d.
+        match try_shift(d, p, p + 1) {
+            Some(m) => return Magic { multiplier: m, add: m >= 0x80000000, shift: p },
+            None => p += 1,
+        }
+    }
+}
+
+fn pred() -> Pred {
+    Pred { inverted: false, reg: 3 }
+}
+
+fn sequence(dest: Reg, src: Reg, d: u32, signed: bool) -> Option<Vec<InstNode>> {
+    if d < 2 || d & (d - 1) == 0 || (signed && d >= 0x80000000) {
+        return None;
+    }
+
+    let magic = if signed { signed_magic(d) } else { unsigned_magic(d) };
+    let mut result = vec!(
+        InstNode::alu1long(pred(), MovAluOp, GLOBAL_REG),
+        InstNode::anylong(Immediate(magic.multiplier)),
+        InstNode::mult(pred(), signed, GLOBAL_REG, src, GLOBAL_REG),
+        InstNode::mfhi(pred(), GLOBAL_REG));
+
+    if signed {
+        // GLOBAL_REG holds the high word of the product. Round toward zero
+        // by adding one when the dividend is negative.
+        if magic.add {
+            result.push(InstNode::alu2reg(pred(), AddAluOp, GLOBAL_REG, GLOBAL_REG, src,
+                                          SllShift, 0));
+        }
+        if magic.shift > 0 {
+            result.push(InstNode::alu1reg(pred(), MovAluOp, GLOBAL_REG, GLOBAL_REG,
+                                          SraShift, magic.shift as u8));
+        }
+        result.push(InstNode::alu2reg(pred(), AddAluOp, dest, GLOBAL_REG, src,
+                                      SrlShift, 31));
+    } else if magic.add {
+        // (hi + ((src - hi) >> 1)) >> (shift - 1) is (src * m) >> (32 +
+        // shift) without overflowing.
+        result.push(InstNode::alu2reg(pred(), SubAluOp, dest, src, GLOBAL_REG, SllShift, 0));
+        result.push(InstNode::alu2reg(pred(), AddAluOp, dest, GLOBAL_REG, dest, SrlShift, 1));
+        if magic.shift > 1 {
+            result.push(InstNode::alu1reg(pred(), MovAluOp, dest, dest, SrlShift,
+                                          (magic.shift - 1) as u8));
+        }
+    } else {
+        result.push(InstNode::alu1reg(pred(), MovAluOp, dest, GLOBAL_REG, SrlShift,
+                                      magic.shift as u8));
+    }
+
+    Some(result)
+}
+
+fn cost(insts: &Vec<InstNode>) -> usize {
+    insts.iter().fold(0, |total, inst| total + cycles(inst))
+}
+
+/// Instructions that set `dest` to `src` divided by `d`, by multiplying by a
+/// magic number. This is None if `d` is a power of two (the IR turns those
+/// into shifts) or out of range, or if loading `d` and dividing would be
+/// cheaper anyway. `src` is left alone until the last instruction reads it,
+/// so `dest` and `src` may be the same register; GLOBAL_REG gets clobbered.
+pub fn divide(dest: Reg, src: Reg, d: u32, signed: bool) -> Option<Vec<InstNode>> {
+    sequence(dest, src, d, signed).and_then(|insts| {
+        if cost(&insts) < 2 + DIV_COST { Some(insts) } else { None }
+    })
+}
+
+/// How many cycles `divide` takes, if it applies to `d`.
+pub fn divide_cost(d: u32, signed: bool) -> Option<usize> {
+    divide(RETURN_REG, RETURN_REG, d, signed).map(|insts| cost(&insts))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{unsigned_magic, signed_magic};
+
+    static DIVISORS: [u32; 10] = [3, 5, 6, 7, 10, 11, 100, 641, 1000000007, 0x7fffffff];
+
+    fn dividends() -> Vec<u32> {
+        let mut result = vec!(0, 1, 2, 0x7fffffff, 0x80000000, 0x80000001, 0xffffffff);
+        let mut x = 12345u32;
+        for _ in 0..2000 {
+            x = x.wrapping_mul(1103515245).wrapping_add(12345);
+            result.push(x);
+            result.push(x >> 16);
+        }
+        result
+    }
+
+    // What the instructions from `divide` compute.
+    fn unsigned_divide(n: u32, d: u32) -> u32 {
+        let magic = unsigned_magic(d);
+        let hi = ((n as u64 * magic.multiplier as u64) >> 32) as u32;
+        if magic.add {
+            (hi + ((n - hi) >> 1)) >> (magic.shift - 1)
+        } else {
+            hi >> magic.shift
+        }
+    }
+
+    fn signed_divide(n: i32, d: u32) -> i32 {
+        let magic = signed_magic(d);
+        let mut hi = ((n as i64 * magic.multiplier as i32 as i64) >> 32) as i32;
+        if magic.add {
+            hi = hi.wrapping_add(n);
+        }
+        (hi >> magic.shift as usize) + ((n as u32) >> 31) as i32
+    }
+
+    #[test]
+    fn test_unsigned() {
+        assert_eq!(unsigned_magic(3).multiplier, 0xaaaaaaab);
+        assert!(unsigned_magic(7).add);
+        for &d in DIVISORS.iter().chain([0x80000001u32, 0xfffffffe].iter()) {
+            for &n in dividends().iter().chain([d - 1, d, d + 1].iter()) {
+                assert_eq!(unsigned_divide(n, d), n / d, "{} / {}", n, d);
+            }
+        }
+    }
+
+    #[test]
+    fn test_signed() {
+        assert_eq!(signed_magic(3).multiplier, 0x55555556);
+        assert!(signed_magic(7).add);
+        for &d in DIVISORS.iter() {
+            for &n in dividends().iter().chain([d - 1, d, d + 1].iter()) {
+                for &n in [n as i32, (n as i32).wrapping_neg()].iter() {
+                    assert_eq!(signed_divide(n, d), n / d as i32, "{} / {}", n, d);
+                }
+            }
+        }
+    }
+}
@@ -1,13 +1,22 @@
-use mas::ast::Reg;
+use ir::*;
+use mas::ast::{Reg, InstNode, MultInst, DivInst};
+use mc::ast::{Identity, AddrOf, NumLit, BoolLit, StringLit};
+use util::Name;
 
+use std::collections::BTreeMap;
 use std::fmt;
 use std::fmt::{Formatter, Display};
 pub use codegen::ir_to_asm::IrToAsm;
 pub use self::RegisterColor::*;
 
-pub mod register_color;
+pub mod briggs;
+pub mod if_convert;
+pub mod linear_scan;
+pub mod split;
+pub mod stack_items;
 pub mod ir_to_asm;
 pub mod combine;
+pub mod magic;
 
 /// How many variables are available to the register allocator.
 pub static NUM_USABLE_VARS: usize = 30;
@@ -27,10 +36,98 @@ pub static NUM_PARAM_REGS: usize = 8;
 // We use three registers, starting at this index, for spilled registers.
 pub static SPILL_REG_BASE: u8 = 8;
 
+/// The registers the register allocators can hand out, out of the first
+/// `num_colors`.
+pub fn allocatable_regs(num_colors: usize) -> Vec<Reg> {
+    (0 .. num_colors as u8).filter(|&n| {
+        // No matter what, we're not allowed to assign these registers.
+        n != SPILL_REG_BASE &&
+            n != SPILL_REG_BASE + 1 &&
+            n != SPILL_REG_BASE + 2 &&
+            n != LINK_REGISTER.index &&
+            n != STACK_POINTER.index &&
+            n != GLOBAL_REG.index
+    }).map(|n| Reg { index: n }).collect()
+}
+
+/// Which register allocator to use.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum RegAlloc {
+    /// Graph coloring with move coalescing; see `briggs`.
+    BriggsAlloc,
+    /// Linear scan over live intervals, which is quicker but assigns
+    /// registers less well; see `linear_scan`.
+    LinearAlloc,
+}
+
+impl RegAlloc {
+    /// The allocator asked for with `regalloc`. Without one, we use linear
+    /// scan at -O0 and graph coloring otherwise.
+    pub fn from_args(args: &Vec<(String, Option<String>)>) -> RegAlloc {
+        let mut regalloc = None;
+        let mut fast = false;
+        for arg in args.iter() {
+            if arg.0 == "regalloc" {
+                regalloc = match &arg.1.clone().unwrap()[..] {
+                    "briggs" => Some(RegAlloc::BriggsAlloc),
+                    "linear" => Some(RegAlloc::LinearAlloc),
+                    other => panic!("Unknown register allocator {}", other),
+                };
+            } else if arg.0 == "opt_level" {
+                fast = arg.1 == Some("0".to_string());
+            }
+        }
+        regalloc.unwrap_or(if fast { RegAlloc::LinearAlloc } else { RegAlloc::BriggsAlloc })
+    }
+}
+
+/// The variables it's cheaper to compute again wherever they're used than
+/// to spill, and the ops that define them: constants, and the addresses of
+/// globals and of things on the stack.
+pub fn remat_candidates(ops: &Vec<Op>,
+                        global_map: &BTreeMap<Name, StaticIRItem>) -> BTreeMap<Var, usize> {
+    let mut candidates = BTreeMap::new();
+    for (i, op) in ops.iter().enumerate() {
+        match op.val {
+            OpNode::UnOp(v, Identity, Constant(ref lit)) => match *lit {
+                NumLit(..) | BoolLit(..) | StringLit(..) => { candidates.insert(v, i); },
+                _ => {},
+            },
+            OpNode::UnOp(v, AddrOf, Variable(ref x)) => {
+                if global_map.get(&x.name).map_or(true, |info| !info.is_func) {
+                    candidates.insert(v, i);
+                }
+            },
+            OpNode::Alloca(v, _) => {
+                if global_map.get(&v.name).is_none() {
+                    candidates.insert(v, i);
+                }
+            },
+            _ => {},
+        }
+    }
+    candidates
+}
+
 pub static GLOBAL_MEM_START: u32 = 0xa0000;
 
 pub static STACK_START: u32 = 0x100000;
 
+// The simulator runs every instruction in a cycle, but a real multiplier
+// takes several, and a divider that produces a bit at a time takes dozens.
+// Strength reduction weighs its replacements against these.
+pub static MULT_COST: usize = 4;
+pub static DIV_COST: usize = 34;
+
+/// Roughly how many cycles an instruction takes.
+pub fn cycles(inst: &InstNode) -> usize {
+    match *inst {
+        MultInst(..) => MULT_COST,
+        DivInst(..) => DIV_COST,
+        _ => 1,
+    }
+}
+
 #[derive(Ord, PartialOrd, PartialEq, Eq, Debug, Clone, Copy)]
 pub enum RegisterColor {
     RegColor(Reg),
@@ -42,6 +139,9 @@ pub enum RegisterColor {
     GlobalColor,
     // This is a structure in global storage; we access it by reference.
     GlobalReferenceColor,
+    // This value is cheap to compute, so rather than keep it anywhere, we
+    // redo the op at this index, which defines it, wherever it's used.
+    RematColor(usize),
 }
 
 allow_string!(RegisterColor);
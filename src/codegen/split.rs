@@ -0,0 +1,208 @@
+//! Live range splitting around calls.
+//!
+//! A variable the register allocator can't find a register for stays on the
+//! stack for its whole lifetime, and every use of it loads it again. Often
+//! what gets in the way is a call in the middle, across which it would have
+//! to be saved somewhere anyway. So, after each call one of those variables
+//! is live across, we copy it into a new generation and use that wherever
+//! the copy dominates: the parts of its lifetime on either side of the call
+//! can then get registers, or not, separately.
+
+use ir::*;
+use ir::cfg::CFG;
+use ir::util::{defs, uses, rename, rename_uses};
+use mc::ast::*;
+use util::Name;
+
+use std::cmp::max;
+use std::collections::{BTreeMap, BTreeSet};
+
+// Whether the copy we'd put right after op `c` is available at op `i`.
+fn copy_dominates(cfg: &CFG, c: usize, i: usize) -> bool {
+    let (b, bi) = (cfg.block_of[c], cfg.block_of[i]);
+    if b == bi { c < i } else { cfg.dominates(b, bi) }
+}
+
+// The generation of a variable that's current at op `i`: the copy closest
+// above it, if any.
+fn current(cfg: &CFG, var: Var, copies: &Vec<(usize, Var)>, i: usize) -> Var {
+    let mut best: Option<(usize, Var)> = None;
+    for &(c, copy) in copies.iter() {
+        if copy_dominates(cfg, c, i) &&
+            best.map_or(true, |(b, _)| copy_dominates(cfg, b, c + 1)) {
+            best = Some((c, copy));
+        }
+    }
+    best.map_or(var, |(_, copy)| copy)
+}
+
+// Rename the uses in `op`, the variables jumps and calls pass included.
+fn rename_all_uses(op: &mut OpNode, renamed: &BTreeMap<Var, Var>) {
+    rename_uses(op, renamed);
+    match *op {
+        OpNode::Call(_, _, ref mut args) => {
+            for arg in args.iter_mut() {
+                rename(arg, renamed);
+            }
+        },
+        OpNode::Goto(_, ref mut vars) |
+        OpNode::CondGoto(_, _, _, ref mut vars) => {
+            *vars = vars.iter().map(|v| *renamed.get(v).unwrap_or(v)).collect();
+        },
+        _ => {},
+    }
+}
+
+/// Split the live ranges of the `spilled` variables around the calls
+/// they're live across. Returns None if there's nothing to split.
+pub fn split_around_calls(ops: &Vec<Op>,
+                          opinfo: &Vec<OpInfo>,
+                          spilled: &BTreeSet<Var>) -> Option<Vec<Op>> {
+    let cfg = CFG::new(ops);
+    let mut gens: BTreeMap<Name, usize> = BTreeMap::new();
+    let mut used_at: BTreeMap<Var, Vec<usize>> = BTreeMap::new();
+    for (i, op) in ops.iter().enumerate() {
+        for v in defs(&op.val).into_iter().chain(uses(&op.val).into_iter()) {
+            let gen = max(*gens.get(&v.name).unwrap_or(&0), v.generation.unwrap_or(0));
+            gens.insert(v.name, gen);
+        }
+        for v in uses(&op.val).into_iter() {
+            if spilled.contains(v) {
+                if !used_at.contains_key(v) {
+                    used_at.insert(*v, vec!());
+                }
+                used_at.get_mut(v).unwrap().push(i);
+            }
+        }
+    }
+
+    // Dominators come first in reverse postorder, so by the time we get to
+    // a call, we've seen every copy above it.
+    let mut copies: BTreeMap<Var, Vec<(usize, Var)>> = BTreeMap::new();
+    for &b in cfg.rpo.iter() {
+        for c in cfg.blocks[b].start .. cfg.blocks[b].end {
+            let result = match ops[c].val {
+                OpNode::Call(result, _, _) => result,
+                _ => continue,
+            };
+            let mut live_after = BTreeSet::new();
+            for &s in opinfo[c].succ.iter() {
+                live_after.extend(opinfo[s].live.iter().cloned());
+            }
+            for v in live_after.iter() {
+                if *v == result || !spilled.contains(v) {
+                    continue;
+                }
+                // Only bother if something will use the copy.
+                if !used_at.get(v).map_or(false, |uses| {
+                    uses.iter().any(|&u| copy_dominates(&cfg, c, u))
+                }) {
+                    continue;
+                }
+                let gen = gens[&v.name] + 1;
+                gens.insert(v.name, gen);
+                if !copies.contains_key(v) {
+                    copies.insert(*v, vec!());
+                }
+                copies.get_mut(v).unwrap().push(
+                    (c, Var { name: v.name, generation: Some(gen) }));
+            }
+        }
+    }
+    if copies.is_empty() {
+        return None;
+    }
+
+    let mut result = vec!();
+    for (i, op) in ops.iter().enumerate() {
+        let mut new_op = op.clone();
+        let renamed = uses(&op.val).into_iter().filter_map(|v| {
+            copies.get(v).map(|list| (*v, current(&cfg, *v, list, i)))
+        }).collect();
+        rename_all_uses(&mut new_op.val, &renamed);
+        result.push(new_op);
+        for (v, list) in copies.iter() {
+            for &(c, copy) in list.iter() {
+                if c == i {
+                    let from = current(&cfg, *v, list, i);
+                    result.push(WithId {
+                        id: op.id,
+                        val: OpNode::UnOp(copy, Identity, Variable(from)),
+                    });
+                }
+            }
+        }
+    }
+    Some(result)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::split_around_calls;
+    use ir::cfg::CFG;
+    use ir::liveness::LivenessAnalyzer;
+    use ir::text::parse_program;
+    use testing::text;
+
+    use std::collections::BTreeSet;
+
+    fn split(src: &str, spilled: &[&str]) -> Option<String> {
+        let (program, _) = parse_program(src).unwrap();
+        let ops = &program[0];
+        let cfg = CFG::new(ops);
+        let opinfo = LivenessAnalyzer::analyze_cfg(ops, &cfg);
+        let mut vars = BTreeSet::new();
+        for op in ops.iter() {
+            for v in ::ir::util::defs(&op.val).into_iter() {
+                if spilled.contains(&&format!("{}", v)[..]) {
+                    vars.insert(*v);
+                }
+            }
+        }
+        split_around_calls(ops, &opinfo, &vars).map(|ops| text(&ops))
+    }
+
+    #[test]
+    fn test_split() {
+        let split = split("fn %f(%n<1>)
+    %a<1> := %n<1> +s 1
+    %r<1> := call %g(%n<1>)
+    %b<1> := %a<1> +s %r<1>
+    %c<1> := %b<1> <s 10
+    if !%c<1> goto 0()
+    %r<2> := call %g(%b<1>)
+    %d<1> := %a<1> +s %r<2>
+    return %d<1>
+    label 0()
+    return %a<1>
+fn %g(%x<1>)
+    return %x<1>
+", &["a<1>"]).unwrap();
+        // Each call gets a copy, and the uses below it use that.
+        assert!(split.contains("%r<1> := call %g(%n<1>)
+    %a<2> := %a<1>
+    %b<1> := %a<2> +s %r<1>"), "{}", split);
+        assert!(split.contains("%r<2> := call %g(%b<1>)
+    %a<3> := %a<2>
+    %d<1> := %a<3> +s %r<2>"), "{}", split);
+        assert!(split.contains("label 0()
+    return %a<2>"), "{}", split);
+    }
+
+    #[test]
+    fn test_nothing_to_split() {
+        // Nothing after the call uses the copy the join needs, so it isn't
+        // worth making.
+        assert_eq!(split("fn %f(%n<1>)
+    %a<1> := %n<1> +s 1
+    %c<1> := %n<1> <s 10
+    if !%c<1> goto 0()
+    %r<1> := call %g(%n<1>)
+    goto 0()
+    label 0()
+    return %a<1>
+fn %g(%x<1>)
+    return %x<1>
+", &["a<1>"]), None);
+    }
+}
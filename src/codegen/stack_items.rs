@@ -0,0 +1,212 @@
+//! Laying out stack-allocated items.
+//!
+//! Each alloca gets a region of the stack frame for the structure it
+//! allocates. The region only has to hold on to its contents for as long as
+//! something might still get at them through a pointer into it: the variable
+//! the alloca assigns, or anything computed from that. So two regions whose
+//! pointers are never live at the same time can share the same bytes. If a
+//! pointer gets anywhere we can't follow it, we treat its region as live for
+//! the whole function. That's when it's stored to memory, assigned to a
+//! global or a variable in memory, returned, or passed to a call: the callee
+//! might keep it. The exception is rt_memcpy, which is how structures get
+//! copied, and which only uses its arguments while it runs.
+
+use ir::*;
+use ir::util::{defs, uses, memory_names};
+use util::Name;
+
+use std::collections::{BTreeMap, BTreeSet};
+
+fn is_memcpy(f: &RValueElem) -> bool {
+    match *f {
+        Variable(ref v) => format!("{}", v.name) == "rt_memcpy",
+        Constant(..) => false,
+    }
+}
+
+// The names of the variables that might point into the item that `var` is
+// allocated to, and whether any of those pointers escapes.
+fn pointers(ops: &Vec<Op>, var: &Var, memory: &BTreeSet<Name>) -> (BTreeSet<Name>, bool) {
+    let mut names = BTreeSet::new();
+    names.insert(var.name);
+    let mut changed = true;
+    while changed {
+        changed = false;
+        for op in ops.iter() {
+            match op.val {
+                OpNode::UnOp(..) |
+                OpNode::BinOp(..) |
+                OpNode::Call(..) => {},
+                _ => continue,
+            }
+            if uses(&op.val).into_iter().any(|v| names.contains(&v.name)) {
+                for v in defs(&op.val).into_iter() {
+                    changed |= names.insert(v.name);
+                }
+            }
+        }
+    }
+
+    let escapes = names.iter().any(|name| memory.contains(name)) ||
+        ops.iter().any(|op| match op.val {
+            OpNode::Store(_, ref v, _) |
+            OpNode::Return(Variable(ref v)) => names.contains(&v.name),
+            OpNode::Call(_, ref f, ref args) =>
+                !is_memcpy(f) && args.iter().any(|v| names.contains(&v.name)),
+            _ => false,
+        });
+    (names, escapes)
+}
+
+/// Find where each alloca in `ops` goes, relative to the start of the
+/// region for stack-allocated items, packing items that are never needed
+/// at the same time into the same place. Returns a map from the index of
+/// each alloca to its offset, and the length of the region.
+pub fn pack_stack_items(ops: &Vec<Op>,
+                        opinfo: &Vec<OpInfo>,
+                        global_map: &BTreeMap<Name, StaticIRItem>
+                        ) -> (BTreeMap<usize, u32>, u32) {
+    let memory = memory_names(ops, global_map);
+
+    // For each item, its index, its size, and whether it's live at each op.
+    let mut items: Vec<(usize, u32, Vec<bool>)> = vec!();
+    for (i, op) in ops.iter().enumerate() {
+        match op.val {
+            OpNode::Alloca(ref var, size) => {
+                // Allocas of globals are in global storage.
+                if global_map.get(&var.name).is_some() {
+                    continue;
+                }
+                // We must be aligned on 4-byte boundaries.
+                let size_adjust = (4 - (size % 4)) % 4;
+                let (names, escapes) = pointers(ops, var, &memory);
+                let live = (0 .. ops.len()).map(|j| {
+                    escapes ||
+                        opinfo[j].live.iter()
+                        .chain(defs(&ops[j].val).into_iter())
+                        .any(|v| names.contains(&v.name))
+                }).collect();
+                items.push((i, (size + size_adjust) as u32, live));
+            },
+            _ => {},
+        }
+    }
+
+    // Biggest first, so that smaller items can fill in around them.
+    let mut order: Vec<usize> = (0 .. items.len()).collect();
+    order.sort_by(|&a, &b| (items[b].1, items[a].0).cmp(&(items[a].1, items[b].0)));
+
+    let mut offsets: BTreeMap<usize, u32> = BTreeMap::new();
+    let mut placed: Vec<usize> = vec!();
+    let mut len = 0;
+    for &item in order.iter() {
+        let (i, size, ref live) = items[item];
+        let mut offs = 0;
+        loop {
+            // Move past anything in the way that's live at the same time.
+            let in_the_way = placed.iter().filter(|&&other| {
+                let (j, other_size, ref other_live) = items[other];
+                let other_offs = offsets[&j];
+                other_offs < offs + size && offs < other_offs + other_size &&
+                    live.iter().zip(other_live.iter()).any(|(&a, &b)| a && b)
+            }).map(|&other| offsets[&items[other].0] + items[other].1).max();
+            match in_the_way {
+                Some(end) => offs = end,
+                None => break,
+            }
+        }
+        offsets.insert(i, offs);
+        placed.push(item);
+        len = ::std::cmp::max(len, offs + size);
+    }
+    (offsets, len)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::pack_stack_items;
+    use ir::cfg::CFG;
+    use ir::liveness::LivenessAnalyzer;
+    use ir::text::parse_program;
+
+    use std::collections::BTreeMap;
+
+    fn pack(src: &str) -> (BTreeMap<usize, u32>, u32) {
+        let (program, global_map) = parse_program(src).unwrap();
+        let ops = program.iter().filter(|ops| ops.len() > 1).next().unwrap();
+        let cfg = CFG::new(ops);
+        let opinfo = LivenessAnalyzer::analyze_cfg(ops, &cfg);
+        pack_stack_items(ops, &opinfo, &global_map)
+    }
+
+    #[test]
+    fn test_sequential_items_share() {
+        let (_, len) = pack("fn %f()
+    %a<1> := alloca 40
+    store32 %a<1>, %a<1>
+    %p<1> := %a<1> +u 4u32
+    %x<1> := load32 %p<1>
+    %b<1> := alloca 16
+    %c<1> := alloca 6
+    store32 %b<1>, %x<1>
+    store32 %c<1>, %x<1>
+    %y<1> := load32 %b<1>
+    %z<1> := load32 %c<1>
+    %r<1> := %y<1> +s %z<1>
+    return %r<1>
+");
+        // The first store makes %a escape, so nothing shares with it. The
+        // second and third items are live at the same time, so they don't
+        // share either.
+        assert_eq!(len, 40 + 16 + 8);
+
+        let (offsets, len) = pack("fn %f()
+    %a<1> := alloca 40
+    %p<1> := %a<1> +u 4u32
+    %one<1> := 1
+    store32 %p<1>, %one<1>
+    %x<1> := load32 %a<1>
+    %b<1> := alloca 16
+    %c<1> := alloca 6
+    store32 %b<1>, %x<1>
+    store32 %c<1>, %x<1>
+    %y<1> := load32 %b<1>
+    %z<1> := load32 %c<1>
+    %r<1> := %y<1> +s %z<1>
+    return %r<1>
+");
+        // Now %a is done with before the others are allocated, and they
+        // fit in the space it used.
+        assert_eq!(len, 40);
+        assert_eq!(offsets[&1], 0);
+        assert_eq!(offsets[&6], 0);
+        assert_eq!(offsets[&7], 16);
+    }
+
+    #[test]
+    fn test_calls() {
+        let src = "global %rt_memcpy size 0 offset 0 extern func
+global %g size 0 offset 0 func
+fn %rt_memcpy(%d, %s, %n) extern \"C\"
+fn %f(%s<1>)
+    %a<1> := alloca 8
+    %n<1> := 8u32
+    %t<1> := call %F(%a<1>, %s<1>, %n<1>)
+    %x<1> := load32 %a<1>
+    %b<1> := alloca 8
+    %m<1> := 8u32
+    %u<1> := call %F(%b<1>, %s<1>, %m<1>)
+    %y<1> := load32 %b<1>
+    %r<1> := %x<1> +s %y<1>
+    return %r<1>
+fn %g(%x)
+    return %x
+";
+        // Copying into an item doesn't keep it alive afterwards...
+        let (_, len) = pack(&src.replace("%F", "%rt_memcpy")[..]);
+        assert_eq!(len, 8);
+        // ...but passing it to anything else might.
+        let (_, len) = pack(&src.replace("%F", "%g")[..]);
+        assert_eq!(len, 16);
+    }
+}
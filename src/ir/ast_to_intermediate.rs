@@ -151,10 +151,11 @@ impl<'a, 'b> ASTToIntermediate<'a, 'b> {
         }
     }
 
+    // A `$` keeps temporaries from clashing with the program's own names.
     fn gen_temp(&mut self) -> Var {
         let res = Var {
             name: self.session.interner.intern(
-                format!("TEMP{}", self.var_count)),
+                format!("TEMP${}", self.var_count)),
             generation: None,
         };
         self.var_count += 1;
@@ -251,7 +252,7 @@ impl<'a, 'b> ASTToIntermediate<'a, 'b> {
     pub fn convert_item(&mut self, item: &Item) -> (Vec<Vec<Op>>,
                                                     Vec<StaticIRItem>) {
         match item.val {
-            FuncItem(ref id, ref args, _, ref block, _) => {
+            FuncItem(ref id, ref args, _, ref block, _, inline) => {
                 let (new_ops, v) = match *block {
                     LocalFn(ref block) => self.convert_block(block),
                     // For externs with no block, we'll generate a Func() op
@@ -315,6 +316,8 @@ impl<'a, 'b> ASTToIntermediate<'a, 'b> {
                         is_func: true,
                         is_extern: block.is_extern(),
                         expr: None,
+                        const_value: None,
+                        inline: inline,
                     }
                     ))
             },
@@ -351,6 +354,8 @@ impl<'a, 'b> ASTToIntermediate<'a, 'b> {
              is_func: false,
              is_extern: is_extern,
              expr: exp.clone(),
+             const_value: None,
+             inline: None,
          }))
     }
 
@@ -1145,6 +1150,13 @@ impl<'a, 'b> ASTToIntermediate<'a, 'b> {
                                              &this_ty).into_iter());
                     result_var = new_result_var;
                 }
+                // Nothing after a call that doesn't return can run, so end
+                // the block here, as `return` does, and let dead code
+                // elimination clear out the rest.
+                match this_ty {
+                    BottomTy => ops.push(self.add_id(OpNode::Return(Variable(result_var)))),
+                    _ => {},
+                }
                 match this_ty {
                     UnitTy => (ops, None),
                     _ => (ops, Some(result_var))
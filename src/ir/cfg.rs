@@ -0,0 +1,384 @@
+//! Control flow graphs for a function's ops.
+//!
+//! A block starts at the function's first op, at each label, and after each
+//! jump or return. Blocks are numbered in the order their ops appear, so the
+//! entry block is always block 0. Dominators are computed with the
+//! Cooper-Harvey-Kennedy iterative algorithm, and loops are the natural
+//! loops of back edges, with loops that share a header merged.
+
+use ir::*;
+
+use std::cmp::Ordering::Equal;
+use std::collections::{BTreeMap, BTreeSet};
+
+pub struct Block {
+    /// The ops in the block are `ops[start .. end]`.
+    pub start: usize,
+    pub end: usize,
+    pub succ: Vec<usize>,
+    pub pred: Vec<usize>,
+}
+
+pub struct Loop {
+    pub header: usize,
+    /// Every block in the loop, including those of loops nested in it.
+    pub blocks: BTreeSet<usize>,
+    /// The innermost loop this one is nested in.
+    pub parent: Option<usize>,
+    /// 1 for an outermost loop.
+    pub depth: usize,
+}
+
+pub struct CFG {
+    pub blocks: Vec<Block>,
+    /// Which block each op is in.
+    pub block_of: Vec<usize>,
+    /// Which block each label starts.
+    pub label_block: BTreeMap<usize, usize>,
+    /// The blocks reachable from the entry, in reverse postorder.
+    pub rpo: Vec<usize>,
+    /// Immediate dominators. None for the entry and for unreachable blocks.
+    pub idom: Vec<Option<usize>>,
+    /// The children of each block in the dominator tree.
+    pub dom_children: Vec<Vec<usize>>,
+    pub frontier: Vec<BTreeSet<usize>>,
+    /// Outer loops come before the loops nested in them.
+    pub loops: Vec<Loop>,
+    /// The innermost loop each block is in.
+    pub loop_of: Vec<Option<usize>>,
+}
+
+fn intersect(doms: &Vec<Option<usize>>, order: &Vec<usize>,
+             mut a: usize, mut b: usize) -> usize {
+    while a != b {
+        while order[a] > order[b] {
+            a = doms[a].unwrap();
+        }
+        while order[b] > order[a] {
+            b = doms[b].unwrap();
+        }
+    }
+    a
+}
+
+impl CFG {
+    pub fn new(ops: &Vec<Op>) -> CFG {
+        let len = ops.len();
+
+        let mut leaders = BTreeSet::new();
+        if len > 0 {
+            leaders.insert(0);
+        }
+        for (i, op) in ops.iter().enumerate() {
+            match op.val {
+                OpNode::Label(..) => { leaders.insert(i); },
+                OpNode::Goto(..) |
+                OpNode::CondGoto(..) |
+                OpNode::Return(..) => if i + 1 < len { leaders.insert(i + 1); },
+                _ => {},
+            }
+        }
+
+        let starts: Vec<usize> = leaders.into_iter().collect();
+        let mut blocks = vec!();
+        let mut block_of = vec!();
+        let mut label_block = BTreeMap::new();
+        for (b, &start) in starts.iter().enumerate() {
+            let end = if b + 1 < starts.len() { starts[b + 1] } else { len };
+            for _ in start .. end {
+                block_of.push(b);
+            }
+            match ops[start].val {
+                OpNode::Label(label, _) => { label_block.insert(label, b); },
+                _ => {},
+            }
+            blocks.push(Block { start: start, end: end, succ: vec!(), pred: vec!() });
+        }
+
+        let n = blocks.len();
+        for b in 0 .. n {
+            let target = |label: usize| *label_block.get(&label).unwrap_or_else(
+                || panic!("Jump to nonexistent label {}", label));
+            let mut succ = vec!();
+            match ops[blocks[b].end - 1].val {
+                OpNode::Goto(label, _) => succ.push(target(label)),
+                OpNode::CondGoto(_, _, label, _) => {
+                    succ.push(target(label));
+                    if b + 1 < n && b + 1 != target(label) {
+                        succ.push(b + 1);
+                    }
+                },
+                OpNode::Return(..) => {},
+                _ => if b + 1 < n { succ.push(b + 1); },
+            }
+            for &s in succ.iter() {
+                blocks[s].pred.push(b);
+            }
+            blocks[b].succ = succ;
+        }
+
+        // Depth-first search from the entry, for the postorder.
+        let mut visited: Vec<bool> = (0 .. n).map(|_| false).collect();
+        let mut postorder = vec!();
+        let mut stack = vec!();
+        if n > 0 {
+            visited[0] = true;
+            stack.push((0, 0));
+        }
+        loop {
+            let (b, i) = match stack.last() {
+                Some(&(b, i)) => (b, i),
+                None => break,
+            };
+            if i < blocks[b].succ.len() {
+                stack.last_mut().unwrap().1 += 1;
+                let s = blocks[b].succ[i];
+                if !visited[s] {
+                    visited[s] = true;
+                    stack.push((s, 0));
+                }
+            } else {
+                stack.pop();
+                postorder.push(b);
+            }
+        }
+        let mut rpo = postorder;
+        rpo.reverse();
+
+        // Position of each block in rpo; n for unreachable ones.
+        let mut order: Vec<usize> = (0 .. n).map(|_| n).collect();
+        for (i, &b) in rpo.iter().enumerate() {
+            order[b] = i;
+        }
+
+        // The entry is its own dominator while we're working these out.
+        let mut doms: Vec<Option<usize>> = (0 .. n).map(|_| None).collect();
+        if n > 0 {
+            doms[0] = Some(0);
+        }
+        let mut changed = true;
+        while changed {
+            changed = false;
+            for &b in rpo.iter().skip(1) {
+                let mut new_idom = None;
+                for &p in blocks[b].pred.iter() {
+                    if doms[p].is_none() {
+                        continue;
+                    }
+                    new_idom = Some(match new_idom {
+                        None => p,
+                        Some(d) => intersect(&doms, &order, p, d),
+                    });
+                }
+                if new_idom != doms[b] {
+                    doms[b] = new_idom;
+                    changed = true;
+                }
+            }
+        }
+
+        let mut frontier: Vec<BTreeSet<usize>> = (0 .. n).map(|_| BTreeSet::new()).collect();
+        for &b in rpo.iter() {
+            if blocks[b].pred.len() < 2 {
+                continue;
+            }
+            for &p in blocks[b].pred.iter() {
+                if doms[p].is_none() {
+                    continue;
+                }
+                let mut runner = p;
+                while Some(runner) != doms[b] {
+                    frontier[runner].insert(b);
+                    if runner == 0 {
+                        break;
+                    }
+                    runner = doms[runner].unwrap();
+                }
+            }
+        }
+
+        let mut idom = doms;
+        if n > 0 {
+            idom[0] = None;
+        }
+        let mut dom_children: Vec<Vec<usize>> = (0 .. n).map(|_| vec!()).collect();
+        for b in 0 .. n {
+            match idom[b] {
+                Some(d) => dom_children[d].push(b),
+                None => {},
+            }
+        }
+
+        let mut cfg = CFG {
+            blocks: blocks,
+            block_of: block_of,
+            label_block: label_block,
+            rpo: rpo,
+            idom: idom,
+            dom_children: dom_children,
+            frontier: frontier,
+            loops: vec!(),
+            loop_of: (0 .. n).map(|_| None).collect(),
+        };
+        cfg.find_loops();
+        cfg
+    }
+
+    fn find_loops(&mut self) {
+        // Find the blocks of each header's loop by walking back from the
+        // sources of its back edges.
+        let mut bodies = BTreeMap::<usize, BTreeSet<usize>>::new();
+        for &b in self.rpo.iter() {
+            for &h in self.blocks[b].succ.iter() {
+                if !self.dominates(h, b) {
+                    continue;
+                }
+                if !bodies.contains_key(&h) {
+                    let mut body = BTreeSet::new();
+                    body.insert(h);
+                    bodies.insert(h, body);
+                }
+                let body = bodies.get_mut(&h).unwrap();
+                let mut work = vec!(b);
+                while let Some(x) = work.pop() {
+                    if body.insert(x) {
+                        work.extend(self.blocks[x].pred.iter().cloned());
+                    }
+                }
+            }
+        }
+
+        // Natural loops are either nested or disjoint, so sorting by size
+        // puts every loop after the loops it's nested in.
+        let mut loops: Vec<(usize, BTreeSet<usize>)> = bodies.into_iter().collect();
+        loops.sort_by(|a, b| match b.1.len().cmp(&a.1.len()) {
+            Equal => a.0.cmp(&b.0),
+            order => order,
+        });
+        for (header, blocks) in loops.into_iter() {
+            let parent = (0 .. self.loops.len()).rev()
+                .filter(|&i| self.loops[i].blocks.contains(&header))
+                .next();
+            let depth = match parent {
+                Some(p) => self.loops[p].depth + 1,
+                None => 1,
+            };
+            for &b in blocks.iter() {
+                self.loop_of[b] = Some(self.loops.len());
+            }
+            self.loops.push(Loop { header: header, blocks: blocks, parent: parent,
+                                   depth: depth });
+        }
+    }
+
+    pub fn is_reachable(&self, b: usize) -> bool {
+        b == 0 || self.idom[b].is_some()
+    }
+
+    /// Whether every path from the entry to `b` goes through `a`.
+    pub fn dominates(&self, a: usize, b: usize) -> bool {
+        let mut b = b;
+        loop {
+            if a == b {
+                return true;
+            }
+            match self.idom[b] {
+                Some(d) => b = d,
+                None => return false,
+            }
+        }
+    }
+
+    /// The ops that can run right after op `i`.
+    pub fn op_succ(&self, i: usize) -> Vec<usize> {
+        let ref block = self.blocks[self.block_of[i]];
+        if i + 1 < block.end {
+            vec!(i + 1)
+        } else {
+            block.succ.iter().map(|&s| self.blocks[s].start).collect()
+        }
+    }
+
+    /// The iterated dominance frontier of a set of blocks: where values
+    /// defined in them may need to be merged with others.
+    pub fn iterated_frontier(&self, blocks: &BTreeSet<usize>) -> BTreeSet<usize> {
+        let mut result = BTreeSet::new();
+        let mut work: Vec<usize> = blocks.iter().cloned().collect();
+        while let Some(b) = work.pop() {
+            for &f in self.frontier[b].iter() {
+                if result.insert(f) {
+                    work.push(f);
+                }
+            }
+        }
+        result
+    }
+
+    pub fn loop_depth(&self, b: usize) -> usize {
+        match self.loop_of[b] {
+            Some(l) => self.loops[l].depth,
+            None => 0,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::CFG;
+    use ir::text::parse_program;
+
+    use std::collections::BTreeSet;
+
+    fn set(v: Vec<usize>) -> BTreeSet<usize> {
+        v.into_iter().collect()
+    }
+
+    #[test]
+    fn test_nested_loops() {
+        let (program, _) = parse_program("
+fn %f(%n)
+    %i := 0
+    goto 0()
+    label 0()
+    %c := %i <s %n
+    if !%c goto 3()
+    %j := 0
+    goto 1()
+    label 1()
+    %d := %j <s %n
+    if !%d goto 2()
+    %j := %j +s 1
+    goto 1()
+    label 2()
+    %i := %i +s 1
+    goto 0()
+    label 3()
+    return %i
+").unwrap();
+        let cfg = CFG::new(&program[0]);
+
+        // 0: entry, 1: label 0, 2: the inner loop's preheader, 3: label 1,
+        // 4: the inner loop's body, 5: label 2, 6: label 3.
+        assert_eq!(cfg.blocks.len(), 7);
+        assert_eq!(cfg.blocks[1].succ, vec!(6, 2));
+        assert_eq!(cfg.blocks[1].pred, vec!(0, 5));
+        assert_eq!(cfg.rpo[0], 0);
+        assert_eq!(cfg.idom, vec!(None, Some(0), Some(1), Some(2), Some(3), Some(3),
+                                  Some(1)));
+        assert_eq!(cfg.frontier[4], set(vec!(3)));
+        assert_eq!(cfg.frontier[5], set(vec!(1)));
+        assert_eq!(cfg.iterated_frontier(&set(vec!(4))), set(vec!(1, 3)));
+
+        assert_eq!(cfg.loops.len(), 2);
+        assert_eq!(cfg.loops[0].header, 1);
+        assert_eq!(cfg.loops[0].blocks, set(vec!(1, 2, 3, 4, 5)));
+        assert_eq!(cfg.loops[1].header, 3);
+        assert_eq!(cfg.loops[1].blocks, set(vec!(3, 4)));
+        assert_eq!(cfg.loops[1].parent, Some(0));
+        assert_eq!(cfg.loop_depth(4), 2);
+        assert_eq!(cfg.loop_depth(5), 1);
+        assert_eq!(cfg.loop_depth(6), 0);
+        assert_eq!(cfg.op_succ(4), vec!(5));
+        assert_eq!(cfg.op_succ(5), vec!(16, 6));
+    }
+}
@@ -24,43 +24,7 @@ impl ConflictAnalyzer {
                            BTreeSet<Name>) {
         let mut conflict_map = BTreeMap::<Var, BTreeSet<Var>>::new();
         let mut counts = BTreeMap::<Var, u32>::new();
-        let mut referenced_vars = BTreeSet::<Name>::new();
-        let mut must_colors = BTreeMap::new();
-
-        for op in ops.iter() {
-            match op.val {
-                OpNode::UnOp(_, AddrOf, ref rve) => {
-                    match *rve {
-                        Variable(ref v) => { referenced_vars.insert(v.name); },
-                        _ => panic!("Should have a variable here."),
-                    }
-                },
-                OpNode::Call(ref v, _, ref args) => {
-                    for (i, arg) in args.iter().enumerate()
-                        .take(NUM_PARAM_REGS)
-                    {
-                        must_colors.insert(*arg,
-                                           RegColor(Reg { index: i as u8 }));
-                    }
-                    must_colors.insert(*v, RegColor(Reg { index: 0 as u8 }));
-                },
-                OpNode::Func(_, ref args, ref abi) => {
-                    if abi.is_some() { break; }
-                    for (i, arg) in args.iter().enumerate()
-                        .take(NUM_PARAM_REGS)
-                    {
-                        must_colors.insert(*arg,
-                                           RegColor(Reg { index: i as u8 }));
-                    }
-                    for i in NUM_PARAM_REGS .. args.len() {
-                        must_colors.insert(args[i],
-                                           StackArgColor((i as isize) -
-                                                         (args.len() as isize)));
-                    }
-                }
-                _ => {}
-            }
-        }
+        let (must_colors, referenced_vars) = ConflictAnalyzer::constraints(ops);
 
         for info in opinfo.iter() {
             for var1 in info.live.iter() {
@@ -109,4 +73,50 @@ impl ConflictAnalyzer {
 
         (conflict_map, counts, must_colors, referenced_vars)
     }
+
+    /// Work out which variables have to be in particular registers or on
+    /// the stack, and which names are referenced and so have to be in
+    /// memory. This is the part of `conflicts` that doesn't need liveness.
+    pub fn constraints(ops: &Vec<Op>) -> (BTreeMap<Var, RegisterColor>,
+                                          BTreeSet<Name>) {
+        let mut referenced_vars = BTreeSet::<Name>::new();
+        let mut must_colors = BTreeMap::new();
+
+        for op in ops.iter() {
+            match op.val {
+                OpNode::UnOp(_, AddrOf, ref rve) => {
+                    match *rve {
+                        Variable(ref v) => { referenced_vars.insert(v.name); },
+                        _ => panic!("Should have a variable here."),
+                    }
+                },
+                OpNode::Call(ref v, _, ref args) => {
+                    for (i, arg) in args.iter().enumerate()
+                        .take(NUM_PARAM_REGS)
+                    {
+                        must_colors.insert(*arg,
+                                           RegColor(Reg { index: i as u8 }));
+                    }
+                    must_colors.insert(*v, RegColor(Reg { index: 0 as u8 }));
+                },
+                OpNode::Func(_, ref args, ref abi) => {
+                    if abi.is_some() { break; }
+                    for (i, arg) in args.iter().enumerate()
+                        .take(NUM_PARAM_REGS)
+                    {
+                        must_colors.insert(*arg,
+                                           RegColor(Reg { index: i as u8 }));
+                    }
+                    for i in NUM_PARAM_REGS .. args.len() {
+                        must_colors.insert(args[i],
+                                           StackArgColor((i as isize) -
+                                                         (args.len() as isize)));
+                    }
+                }
+                _ => {}
+            }
+        }
+
+        (must_colors, referenced_vars)
+    }
 }
@@ -1,6 +1,17 @@
-// Constant folding.
+//! Constant folding, by sparse conditional constant propagation.
+//!
+//! This is Wegman and Zadeck's algorithm over SSA form. Every variable starts
+//! out undefined, and only the blocks that can be reached given what's known
+//! so far are looked at. A label's variable is the meet of what the jumps
+//! that can be taken pass it, so a value that only changes along a path that
+//! can't be taken is still a constant. Once nothing changes, constants are
+//! substituted for the variables known to hold them, conditional jumps on
+//! constants become gotos (or go away), and blocks that can't be reached are
+//! deleted.
+
 use util::Name;
-use ir::util::subst;
+use ir::cfg::CFG;
+use ir::util::{defs, memory_names};
 use std::collections::{BTreeSet, BTreeMap};
 use mc::ast::*;
 use ir::*;
@@ -8,6 +19,25 @@ use values::{eval_binop, eval_unop};
 
 pub struct ConstantFolder;
 
+#[derive(Clone, PartialEq, Debug)]
+enum Value {
+    // Nothing that defines it has been reached yet.
+    Undef,
+    Const(LitNode),
+    // It can have more than one value.
+    Varying,
+}
+
+use self::Value::*;
+
+fn meet(a: &Value, b: &Value) -> Value {
+    match (a, b) {
+        (&Undef, x) | (x, &Undef) => x.clone(),
+        (&Const(ref x), &Const(ref y)) if x == y => a.clone(),
+        _ => Varying,
+    }
+}
+
 fn assert_signedness(l: &LitNode, signed: bool) {
     match *l {
         NumLit(_, ref kind) =>
@@ -18,201 +48,537 @@ fn assert_signedness(l: &LitNode, signed: bool) {
     }
 }
 
-fn fold(op: &BinOpNode, e1: &RValueElem, e2: &RValueElem, signed: bool) ->
-    Option<LitNode> {
-    let lit1 = match *e1 {
-        Constant(ref l) => {
-            assert_signedness(l, signed);
-            l.clone()
+// Literals are evaluated with 64 bits, but registers only have 32, so a
+// result has to wrap around the way it would at run time before we compare
+// or divide it.
+fn wrap(lit: LitNode) -> LitNode {
+    match lit {
+        NumLit(n, kind) => if kind.is_signed() {
+            NumLit(n as i32 as i64 as u64, kind)
+        } else {
+            NumLit(n as u32 as u64, kind)
         },
-        _ => return None,
-    };
-    let lit2 = match *e2 {
-        Constant(ref l) => {
-            assert_signedness(l, signed);
-            l.clone()
-        }
-        _ => return None,
-    };
+        lit => lit,
+    }
+}
 
-    Some(eval_binop(*op, lit1, lit2))
+fn fold(op: BinOpNode, lit1: &LitNode, lit2: &LitNode, signed: bool) ->
+    Option<LitNode> {
+    assert_signedness(lit1, signed);
+    assert_signedness(lit2, signed);
+    match (op, lit2) {
+        // Leave division by zero to happen at run time.
+        (DivideOp, &NumLit(0, _)) |
+        (ModOp, &NumLit(0, _)) => None,
+        _ => Some(wrap(eval_binop(op, lit1.clone(), lit2.clone()))),
+    }
 }
 
-fn fold_unary(op: &UnOpNode, e: &RValueElem) -> Option<LitNode> {
-    let lit = match *e {
-        Constant(ref l) => {
-            match *l {
-                NumLit(..) |
-                BoolLit(..) => l.clone(),
-                StringLit(..) => return None,
-                _ => panic!(),
-            }
-        }
-        _ => return None,
-    };
+fn fold_unary(op: UnOpNode, lit: &LitNode) -> Option<LitNode> {
+    eval_unop(op, lit.clone()).map(wrap)
+}
 
-    eval_unop(*op, lit)
+// Only numbers and booleans are propagated. Strings have to be put in
+// memory somewhere, so they stay where they are.
+fn value_of_lit(lit: &LitNode) -> Value {
+    match *lit {
+        NumLit(..) | BoolLit(..) => Const(lit.clone()),
+        _ => Varying,
+    }
 }
 
-fn constant_fold_once<T>(ops: &mut Vec<Op>, vars_to_avoid: &BTreeSet<Var>,
-                         globals: &BTreeMap<Name, T>,
-                         verbose: bool) -> bool {
-    // Variables to replace with constants.
-    let mut changes = vec!();
-    let mut immediate_changes = vec!();
-
-    for (pos, op) in ops.iter().enumerate() {
-        match op.val {
-            OpNode::BinOp(ref v, ref op, ref v1, ref v2, signed) => {
-                match fold(op, v1, v2, signed) {
-                    Some(c) => {
-                        changes.push((v.clone(),
-                                      Constant(c.clone())));
-                        immediate_changes.push((pos,
-                                                OpNode::UnOp(v.clone(),
-                                                     Identity,
-                                                     Constant(c))));
-                    },
-                    None => {
-                        // TODO: convert multiplications and divisions by
-                        // powers of two into shifts.
-
-                        // We can't directly fold, but if we're
-                        // applying the operator to an identity
-                        // element, we can optimize out the operation.
-                        let ident = match *op {
-                            TimesOp => Some(1),
-                            PlusOp => Some(0),
-                            _ => None,
-                        };
-                        // Some operators have a right identity that is not
-                        // a left identity.
-                        let rhs_ident = match *op {
-                            DivideOp => Some(1),
-                            MinusOp => Some(0),
-                            _ => None,
-                        };
-                        // Note: we won't match in *both* of these, because
-                        // if that could have happened we instead would have
-                        // folded above and never landed here at all.
-                        match *v1 {
-                            Constant(NumLit(x, _)) if Some(x) == ident => {
-                                immediate_changes.push(
-                                    (pos,
-                                     OpNode::UnOp(v.clone(),
-                                          Identity,
-                                          v2.clone())));
-                            },
-                            _ => {}
-                        }
-                        match *v2 {
-                            Constant(NumLit(x, _)) if (Some(x) == ident ||
-                                                       Some(x) == rhs_ident
-                                                       ) => {
-                                immediate_changes.push(
-                                    (pos,
-                                     OpNode::UnOp(v.clone(),
-                                          Identity,
-                                          v1.clone())));
-                            }
-                            _ => {}
-                        }
+struct Propagation<'a> {
+    ops: &'a Vec<Op>,
+    cfg: CFG,
+    globals: &'a BTreeMap<Name, StaticIRItem>,
+    values: BTreeMap<Var, Value>,
+    // Variables that are never constants; see `memory_names`.
+    varying: BTreeSet<Name>,
+    // The jumps to each label.
+    jumps: BTreeMap<usize, Vec<usize>>,
+    executable: Vec<bool>,
+    // Jumps that can be taken.
+    taken: BTreeSet<usize>,
+    // Blocks that can be fallen into, rather than jumped to.
+    fallen_into: BTreeSet<usize>,
+    changed: bool,
+}
+
+impl<'a> Propagation<'a> {
+    fn new(ops: &'a Vec<Op>, globals: &'a BTreeMap<Name, StaticIRItem>) -> Propagation<'a> {
+        let cfg = CFG::new(ops);
+        let mut jumps = BTreeMap::<usize, Vec<usize>>::new();
+        for (i, op) in ops.iter().enumerate() {
+            match op.val {
+                OpNode::Goto(label, _) |
+                OpNode::CondGoto(_, _, label, _) => {
+                    if !jumps.contains_key(&label) {
+                        jumps.insert(label, vec!());
                     }
-                }
-            },
-            OpNode::UnOp(ref v, ref op, ref rv) => {
-                match fold_unary(op, rv) {
-                    Some(c) => {
-                        changes.push((v.clone(),
-                                      Constant(c.clone())));
-                        if *op != Identity {
-                            immediate_changes.push((pos,
-                                                    OpNode::UnOp(v.clone(),
-                                                         Identity,
-                                                         Constant(c))));
-                        }
-                    },
-                    _ => {}
-                }
+                    jumps.get_mut(&label).unwrap().push(i);
+                },
+                _ => {},
             }
-            // TODO: unary ops, and anything else.
-            _ => {},
+        }
+        let n = cfg.blocks.len();
+        Propagation {
+            ops: ops,
+            cfg: cfg,
+            globals: globals,
+            values: BTreeMap::new(),
+            varying: memory_names(ops, globals),
+            jumps: jumps,
+            executable: (0 .. n).map(|_| false).collect(),
+            taken: BTreeSet::new(),
+            fallen_into: BTreeSet::new(),
+            changed: false,
         }
     }
 
-    let mut changed = false;
+    fn value_of_var(&self, v: &Var) -> Value {
+        // Generation 0 comes from outside the function. That's only known
+        // for globals that nothing but their initializer writes.
+        if v.generation == Some(0) {
+            return match self.globals.get(&v.name) {
+                Some(&StaticIRItem { const_value: Some(ref c), .. }) => value_of_lit(c),
+                _ => Varying,
+            };
+        }
+        if self.varying.contains(&v.name) {
+            return Varying;
+        }
+        self.values.get(v).cloned().unwrap_or(Undef)
+    }
 
-    // These are changes we can do unconditionally.
-    for (a, b) in immediate_changes.into_iter() {
-        ops.get_mut(a).unwrap().val = b;
-        changed = true;
+    fn value_of(&self, rve: &RValueElem) -> Value {
+        match *rve {
+            Variable(ref v) => self.value_of_var(v),
+            Constant(ref c) => value_of_lit(c),
+        }
     }
 
-    for (a, b) in changes.into_iter() {
-        if !vars_to_avoid.contains(&a) && !globals.get(&a.name).is_some() {
-            if verbose {
-                print!("Applying {}->{}\n", a, b);
-            }
-            subst(ops, &a, &b);
-            if verbose {
-                for op in ops.iter() {
-                    print!("{}", op);
-                }
-            }
-            changed = true;
+    fn set(&mut self, v: &Var, val: Value) {
+        let old = self.values.get(v).cloned().unwrap_or(Undef);
+        let new = meet(&old, &val);
+        if new != old {
+            self.values.insert(*v, new);
+            self.changed = true;
         }
     }
 
-    changed
-}
+    fn mark(&mut self, b: usize) {
+        if !self.executable[b] {
+            self.executable[b] = true;
+            self.changed = true;
+        }
+    }
 
+    fn take(&mut self, i: usize, label: usize) {
+        if self.taken.insert(i) {
+            self.changed = true;
+        }
+        let b = self.cfg.label_block[&label];
+        self.mark(b);
+    }
 
-impl ConstantFolder {
+    fn fall_into(&mut self, b: usize) {
+        if b < self.cfg.blocks.len() {
+            if self.fallen_into.insert(b) {
+                self.changed = true;
+            }
+            self.mark(b);
+        }
+    }
 
-    pub fn fold<T>(ops: &mut Vec<Op>, globals: &BTreeMap<Name, T>,
-                   verbose: bool) {
-        // There are certain variables we are prohibited from substituting.
-        // Those include any that appear in labels/gotos, as well as any
-        // that is dereferenced as part of the left hand side of an assignment.
-        let mut vars_to_avoid = BTreeSet::<Var>::new();
-        for op in ops.iter() {
-            match op.val {
-                OpNode::Label(_, ref vars) |
+    // What the jumps that can be taken pass a label for `v`.
+    fn label_value(&self, b: usize, label: usize, v: &Var) -> Value {
+        if self.fallen_into.contains(&b) {
+            return Varying;
+        }
+        let mut val = Undef;
+        for &j in self.jumps.get(&label).map_or(&[][..], |js| &js[..]).iter() {
+            if !self.taken.contains(&j) {
+                continue;
+            }
+            let passed = match self.ops[j].val {
                 OpNode::Goto(_, ref vars) |
-                OpNode::CondGoto(_, _, _, ref vars) => {
-                    for var in vars.iter() {
-                        vars_to_avoid.insert(var.clone());
+                OpNode::CondGoto(_, _, _, ref vars) =>
+                    vars.iter().filter(|p| p.name == v.name).next().cloned(),
+                _ => unreachable!(),
+            };
+            let passed = match passed {
+                Some(p) => self.value_of_var(&p),
+                None => Varying,
+            };
+            val = meet(&val, &passed);
+        }
+        val
+    }
+
+    fn visit_block(&mut self, b: usize) {
+        let (start, end) = (self.cfg.blocks[b].start, self.cfg.blocks[b].end);
+        let mut falls = true;
+        for i in start .. end {
+            let ops = self.ops;
+            match ops[i].val {
+                OpNode::UnOp(ref v, op, ref rve) => {
+                    let val = match self.value_of(rve) {
+                        Const(ref c) => match fold_unary(op, c) {
+                            Some(c) => value_of_lit(&c),
+                            None => Varying,
+                        },
+                        _ if op == AddrOf || op == Deref => Varying,
+                        val => val,
+                    };
+                    self.set(v, val);
+                },
+                OpNode::BinOp(ref v, op, ref rve1, ref rve2, signed) => {
+                    let val = match (self.value_of(rve1), self.value_of(rve2)) {
+                        (Const(ref c1), Const(ref c2)) => match fold(op, c1, c2, signed) {
+                            Some(c) => value_of_lit(&c),
+                            None => Varying,
+                        },
+                        (Varying, _) | (_, Varying) => Varying,
+                        _ => Undef,
+                    };
+                    self.set(v, val);
+                },
+                OpNode::Alloca(ref v, _) |
+                OpNode::Call(ref v, _, _) |
+                OpNode::Load(ref v, _, _) => self.set(v, Varying),
+                OpNode::Func(_, ref params, _) => {
+                    for v in params.iter() {
+                        self.set(v, Varying);
                     }
                 },
-                // We can't fold anything we take the address of.
-                OpNode::UnOp(_, AddrOf, ref rv) => {
-                    match *rv {
-                        Variable(ref v) => { vars_to_avoid.insert(v.clone()); },
-                        _ => {},
+                OpNode::Label(label, ref vars) => {
+                    for v in vars.iter() {
+                        let val = self.label_value(b, label, v);
+                        self.set(v, val);
                     }
                 },
-                OpNode::Store(ref v1, ref v2, _) |
-                OpNode::Load(ref v1, ref v2, _) => {
-                    vars_to_avoid.insert(v1.clone());
-                    vars_to_avoid.insert(v2.clone());
+                OpNode::Goto(label, _) => {
+                    self.take(i, label);
+                    falls = false;
                 },
-                OpNode::Call(_, _, ref vars) => {
-                    for var in vars.iter() {
-                        vars_to_avoid.insert(var.clone());
+                OpNode::CondGoto(negated, ref rve, label, _) => {
+                    match self.value_of(rve) {
+                        Const(BoolLit(c)) => if c != negated {
+                            self.take(i, label);
+                            falls = false;
+                        },
+                        // We can't know yet, so assume either way; it's
+                        // only undefined if the IR is broken anyway.
+                        _ => self.take(i, label),
                     }
                 },
-                OpNode::Func(_, ref vars, ref abi) => {
-                    if abi.is_some() { return; }
-                    for var in vars.iter() {
-                        vars_to_avoid.insert(var.clone());
-                    }
+                OpNode::Return(..) => falls = false,
+                OpNode::Store(..) |
+                OpNode::AsmOp(..) |
+                OpNode::Nop => {},
+            }
+        }
+        if falls {
+            self.fall_into(b + 1);
+        }
+    }
+
+    fn propagate(&mut self) {
+        self.mark(0);
+        self.changed = true;
+        while self.changed {
+            self.changed = false;
+            let rpo = self.cfg.rpo.clone();
+            for &b in rpo.iter() {
+                if self.executable[b] {
+                    self.visit_block(b);
+                }
+            }
+        }
+    }
+
+    fn constant(&self, rve: &RValueElem) -> Option<RValueElem> {
+        match *rve {
+            Variable(ref v) => match self.value_of_var(v) {
+                Const(c) => Some(Constant(c)),
+                _ => None,
+            },
+            Constant(..) => None,
+        }
+    }
+
+    fn substitute(&self, rve: &RValueElem) -> RValueElem {
+        self.constant(rve).unwrap_or_else(|| rve.clone())
+    }
+
+    // The op with what we've learned applied.
+    fn rewrite(&self, node: &OpNode) -> OpNode {
+        match *node {
+            OpNode::UnOp(ref v, op, ref rve) =>
+                match self.constant(&Variable(*v)) {
+                    Some(c) => OpNode::UnOp(*v, Identity, c),
+                    // We can't take the address of a constant.
+                    None if op == AddrOf => OpNode::UnOp(*v, op, rve.clone()),
+                    None => OpNode::UnOp(*v, op, self.substitute(rve)),
                 },
+            OpNode::BinOp(ref v, op, ref rve1, ref rve2, signed) => {
+                match self.constant(&Variable(*v)) {
+                    Some(c) => return OpNode::UnOp(*v, Identity, c),
+                    None => {},
+                }
+                let rve1 = self.substitute(rve1);
+                let rve2 = self.substitute(rve2);
+
+                // We can't fold, but if we're applying the operator to an
+                // identity element, we can optimize out the operation.
+                let ident = match op {
+                    TimesOp => Some(1),
+                    PlusOp => Some(0),
+                    _ => None,
+                };
+                // Some operators have a right identity that is not a left
+                // identity.
+                let rhs_ident = match op {
+                    DivideOp => Some(1),
+                    MinusOp => Some(0),
+                    _ => None,
+                };
+                match rve1 {
+                    Constant(NumLit(x, _)) if Some(x) == ident =>
+                        return OpNode::UnOp(*v, Identity, rve2),
+                    _ => {},
+                }
+                match rve2 {
+                    Constant(NumLit(x, _)) if Some(x) == ident || Some(x) == rhs_ident =>
+                        return OpNode::UnOp(*v, Identity, rve1),
+                    _ => {},
+                }
+                OpNode::BinOp(*v, op, rve1, rve2, signed)
+            },
+            OpNode::CondGoto(negated, ref rve, label, ref vars) =>
+                match self.value_of(rve) {
+                    // TODO: emit a warning that the condition is always
+                    // false/true.
+                    Const(BoolLit(c)) => if c != negated {
+                        OpNode::Goto(label, vars.clone())
+                    } else {
+                        OpNode::Nop
+                    },
+                    _ => OpNode::CondGoto(negated, self.substitute(rve), label,
+                                          vars.clone()),
+                },
+            OpNode::Return(ref rve) => OpNode::Return(self.substitute(rve)),
+            _ => node.clone(),
+        }
+    }
+}
+
+impl ConstantFolder {
+
+    pub fn fold(ops: &mut Vec<Op>, globals: &BTreeMap<Name, StaticIRItem>,
+                verbose: bool) {
+        // Externs have nothing to fold, and we need SSA form.
+        if ops.len() <= 1 ||
+            ops.iter().any(|op| defs(&op.val).iter().any(|v| v.generation.is_none())) {
+            return;
+        }
+
+        let new_ops = {
+            let mut prop = Propagation::new(ops, globals);
+            prop.propagate();
+            if verbose {
+                for (v, val) in prop.values.iter() {
+                    match *val {
+                        Const(ref c) => print!("{} is {}\n", v, c),
+                        _ => {},
+                    }
+                }
+            }
+
+            let mut new_ops = vec!();
+            for (i, op) in ops.iter().enumerate() {
+                if prop.executable[prop.cfg.block_of[i]] {
+                    new_ops.push(WithId { id: op.id, val: prop.rewrite(&op.val) });
+                } else if verbose {
+                    print!("Removing unreachable {}", op);
+                }
+            }
+            new_ops
+        };
+        *ops = new_ops;
+    }
+}
+
+/// Find the globals that are initialized to a constant and never written to
+/// again, and record their values. The program must not be in SSA form yet,
+/// so that the initializers are easy to follow.
+pub fn find_const_globals(program: &Vec<Vec<Op>>,
+                          globals: &mut BTreeMap<Name, StaticIRItem>) {
+    let mut written = BTreeSet::<Name>::new();
+    let mut values = BTreeMap::<Name, LitNode>::new();
+    for ops in program.iter() {
+        let is_init = match ops[0].val {
+            OpNode::Func(ref name, _, _) => format!("{}", name) == "_INIT_GLOBALS",
+            _ => false,
+        };
+        // The temporaries holding constants, in the initializer.
+        let mut temps = BTreeMap::<Var, LitNode>::new();
+        for op in ops.iter() {
+            match op.val {
+                OpNode::UnOp(_, AddrOf, Variable(ref v)) => { written.insert(v.name); },
                 _ => {},
             }
+            for v in defs(&op.val).into_iter() {
+                if !globals.contains_key(&v.name) {
+                    continue;
+                }
+                let value = match op.val {
+                    OpNode::UnOp(_, Identity, Constant(ref c)) => Some(c.clone()),
+                    OpNode::UnOp(_, Identity, Variable(ref t)) => temps.get(t).cloned(),
+                    _ => None,
+                };
+                match value {
+                    Some(c) if is_init && !values.contains_key(&v.name) => {
+                        values.insert(v.name, c);
+                    },
+                    _ => { written.insert(v.name); },
+                }
+            }
+            if is_init {
+                match op.val {
+                    OpNode::UnOp(ref t, Identity, Constant(ref c)) => {
+                        temps.insert(*t, c.clone());
+                    },
+                    _ => {},
+                }
+            }
         }
-        if verbose {
-            print!("avoid: {:?}\n", vars_to_avoid);
-        }
-        while constant_fold_once(ops, &vars_to_avoid, globals, verbose) {}
+    }
+
+    for (name, item) in globals.iter_mut() {
+        item.const_value = if item.is_ref || item.is_func || item.is_extern ||
+            written.contains(name) {
+            None
+        } else {
+            values.get(name).cloned()
+        };
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{ConstantFolder, find_const_globals};
+    use ir::text::parse_program;
+    use testing::run_pass;
+    use mc::ast::NumLit;
+    use util::IntKind::UnsignedInt;
+    use util::Width::Width32;
+
+    fn fold(src: &str) -> String {
+        run_pass(src, true, false, |program, global_map| {
+            ConstantFolder::fold(&mut program[0], global_map, false);
+        })
+    }
+
+    #[test]
+    fn test_conditional_propagation() {
+        // %x is only ever changed on a path that can't be taken, so it stays
+        // 1 around the loop.
+        let result = fold("fn %f(%n<1>)
+    %x<1> := 1
+    %i<1> := 0
+    goto 0(%x<1>, %i<1>)
+    label 0(%x<2>, %i<2>)
+    %c<1> := %i<2> <s %n<1>
+    if !%c<1> goto 2(%x<2>)
+    %d<1> := %x<2> ==s 1
+    if %d<1> goto 1(%x<2>)
+    %x<3> := 2
+    goto 1(%x<3>)
+    label 1(%x<4>)
+    %i<3> := %i<2> +s 1
+    goto 0(%x<4>, %i<3>)
+    label 2(%x<5>)
+    return %x<5>
+");
+        assert!(result.contains("return 1\n"), "{}", result);
+        assert!(!result.contains("%x<3>"), "{}", result);
+        assert!(!result.contains("if %d"), "{}", result);
+        // The loop counter isn't a constant.
+        assert!(result.contains("%i<3> := %i<2> +s 1"), "{}", result);
+    }
+
+    #[test]
+    fn test_globals_and_unops() {
+        let src = "global %g size 4 offset 0 const 200
+global %h size 4 offset 4
+fn %f()
+    %a<1> := sxb %g<0>
+    %b<1> := %a<1> &u 255
+    %c<1> := %b<1> /u 0
+    %d<1> := %h<0> +u %b<1>
+    return %c<1>
+";
+        let result = fold(src);
+        assert!(result.contains("%b<1> := 200\n"), "{}", result);
+        assert!(result.contains("%c<1> := 200 /u 0"), "{}", result);
+        assert!(result.contains("%d<1> := %h<0> +u 200"), "{}", result);
+    }
+
+    #[test]
+    fn test_overflow() {
+        let result = fold("fn %f()
+    %a<1> := 65536
+    %b<1> := %a<1> *s %a<1>
+    %c<1> := %b<1> ==s 0
+    %d<1> := 2147483647
+    %e<1> := %d<1> +s 1
+    %f<1> := %e<1> <s 0
+    return %b<1>
+");
+        // Both wrap around, as they would at run time.
+        assert!(result.contains("%c<1> := true\n"), "{}", result);
+        assert!(result.contains("%f<1> := true\n"), "{}", result);
+    }
+
+    #[test]
+    fn test_division_by_zero() {
+        let result = fold("fn %f()
+    %a<1> := 7
+    %z<1> := 0
+    %b<1> := %a<1> /s %z<1>
+    %c<1> := %a<1> %s %z<1>
+    %d<1> := %b<1> +s %c<1>
+    return %d<1>
+");
+        // These have to trap when they run, so they stay, with both operands
+        // constant; the verifier allows that for division by zero only.
+        assert!(result.contains("%b<1> := 7 /s 0\n"), "{}", result);
+        assert!(result.contains("%c<1> := 7 %s 0\n"), "{}", result);
+    }
+
+    #[test]
+    fn test_find_const_globals() {
+        let (program, mut global_map) = parse_program("global %a size 4 offset 0
+global %b size 4 offset 4
+global %c size 4 offset 8
+fn %_INIT_GLOBALS()
+    %t := 5u32
+    %a := %t
+    %b := 6u32
+    %c := 7u32
+    return %t
+fn %f()
+    %b := 1u32
+    %p := addrof %c
+    return %a
+").unwrap();
+        find_const_globals(&program, &mut global_map);
+        let value = |name: &str| global_map.values().filter(|g| format!("{}", g.name) == name)
+                                          .next().unwrap().const_value.clone();
+        assert_eq!(value("a"), Some(NumLit(5, UnsignedInt(Width32))));
+        // Written outside the initializer, and address taken.
+        assert_eq!(value("b"), None);
+        assert_eq!(value("c"), None);
     }
 }
@@ -0,0 +1,359 @@
+//! Dead code elimination.
+//!
+//! First, blocks that can't be reached from the start of the function go,
+//! which takes care of code after a `return` or a call that never returns.
+//! Jumps to a label whose block does nothing but jump on go straight to
+//! where it jumps, which can leave more blocks unreachable.
+//!
+//! Then, in SSA form, we find the ops whose results matter: those with side
+//! effects (stores, calls, inline asm, returns and jumps, and anything that
+//! assigns a variable living in memory), and then, working backwards, the
+//! ops that compute what those use. A label's variable only matters if what
+//! the label's block does with it matters, so values that only go around a
+//! loop to feed themselves are found dead too. Everything else goes, along
+//! with the label variables nothing needs and `Nop`s.
+
+use ir::*;
+use ir::cfg::CFG;
+use ir::util::{defs, uses, memory_names, is_nop};
+use util::Name;
+
+use std::collections::{BTreeMap, BTreeSet};
+use std::mem::replace;
+
+pub struct DeadCodeEliminator;
+
+fn remove_unreachable(ops: &mut Vec<Op>, verbose: bool) {
+    let cfg = CFG::new(ops);
+    for (i, op) in replace(ops, vec!()).into_iter().enumerate() {
+        if cfg.is_reachable(cfg.block_of[i]) {
+            ops.push(op);
+        } else if verbose {
+            print!("Removing unreachable {}", op);
+        }
+    }
+}
+
+// Point jumps to labels that just jump on at where they end up. The jump
+// passes its variables to the new label in place of the old label's. That
+// only works if the old label's variables aren't used anywhere else, since
+// nothing defines them once we stop going through it.
+fn fold_jump_chains(ops: &mut Vec<Op>, verbose: bool) -> bool {
+    let mut num_uses = BTreeMap::<Var, usize>::new();
+    for op in ops.iter() {
+        for v in uses(&op.val).into_iter() {
+            let n = *num_uses.get(v).unwrap_or(&0);
+            num_uses.insert(*v, n + 1);
+        }
+    }
+
+    let mut forwards = BTreeMap::<usize, (BTreeSet<Var>, usize, BTreeSet<Var>)>::new();
+    for (i, op) in ops.iter().enumerate() {
+        let (label, vars) = match op.val {
+            OpNode::Label(label, ref vars) => (label, vars),
+            _ => continue,
+        };
+        match ops[i + 1 ..].iter().filter(|op| !is_nop(op)).next() {
+            Some(&Op { val: OpNode::Goto(target, ref target_vars), .. }) if target != label &&
+                vars.iter().all(|v| {
+                    *num_uses.get(v).unwrap_or(&0) == if target_vars.contains(v) { 1 } else { 0 }
+                }) => {
+                forwards.insert(label, (vars.clone(), target, target_vars.clone()));
+            },
+            _ => {},
+        }
+    }
+
+    let mut changed = false;
+    for op in ops.iter_mut() {
+        match op.val {
+            OpNode::Goto(ref mut label, ref mut vars) |
+            OpNode::CondGoto(_, _, ref mut label, ref mut vars) => {
+                // A chain of these could go around in a circle.
+                for _ in 0 .. forwards.len() {
+                    let &(ref label_vars, target, ref target_vars) = match forwards.get(&*label) {
+                        Some(forward) => forward,
+                        None => break,
+                    };
+                    if verbose {
+                        print!("Jumping to {} instead of {}\n", target, label);
+                    }
+                    let new_vars: BTreeSet<Var> = target_vars.iter().map(|v| {
+                        if label_vars.contains(v) {
+                            *vars.iter().filter(|arg| arg.name == v.name).next().unwrap()
+                        } else {
+                            *v
+                        }
+                    }).collect();
+                    *vars = new_vars;
+                    *label = target;
+                    changed = true;
+                }
+            },
+            _ => {},
+        }
+    }
+    changed
+}
+
+fn has_side_effects(op: &OpNode, memory: &BTreeSet<Name>) -> bool {
+    match *op {
+        OpNode::Store(..) |
+        OpNode::Call(..) |
+        OpNode::AsmOp(..) |
+        OpNode::Return(..) |
+        OpNode::Goto(..) |
+        OpNode::CondGoto(..) |
+        OpNode::Func(..) => true,
+        OpNode::Label(..) => false,
+        _ => defs(op).iter().any(|v| memory.contains(&v.name)),
+    }
+}
+
+// The variables that have to be computed for `op` to do what it does. The
+// variables a jump passes are only needed if the label's block needs them.
+fn needs(op: &OpNode) -> Vec<&Var> {
+    match *op {
+        OpNode::Goto(..) => vec!(),
+        OpNode::CondGoto(_, Variable(ref v), _, _) => vec!(v),
+        OpNode::CondGoto(..) => vec!(),
+        _ => uses(op),
+    }
+}
+
+fn remove_dead_defs(ops: &mut Vec<Op>, globals: &BTreeMap<Name, StaticIRItem>,
+                    verbose: bool) {
+    let memory = memory_names(ops, globals);
+    let mut def_of = BTreeMap::<Var, usize>::new();
+    let mut jumps = BTreeMap::<usize, Vec<usize>>::new();
+    let mut live_ops = BTreeSet::<usize>::new();
+    let mut live_vars = BTreeSet::<Var>::new();
+    let mut work = vec!();
+    for (i, op) in ops.iter().enumerate() {
+        for v in defs(&op.val).into_iter() {
+            def_of.insert(*v, i);
+        }
+        match op.val {
+            OpNode::Goto(label, _) |
+            OpNode::CondGoto(_, _, label, _) => {
+                if !jumps.contains_key(&label) {
+                    jumps.insert(label, vec!());
+                }
+                jumps.get_mut(&label).unwrap().push(i);
+            },
+            _ => {},
+        }
+        if has_side_effects(&op.val, &memory) {
+            live_ops.insert(i);
+            work.extend(needs(&op.val).into_iter().cloned());
+        }
+    }
+
+    while let Some(v) = work.pop() {
+        if !live_vars.insert(v) {
+            continue;
+        }
+        let i = match def_of.get(&v) {
+            Some(&i) => i,
+            None => continue,
+        };
+        match ops[i].val {
+            // What a label's variable needs is whatever each jump to it
+            // passes in its place.
+            OpNode::Label(label, _) => match jumps.get(&label) {
+                Some(js) => for &j in js.iter() {
+                    match ops[j].val {
+                        OpNode::Goto(_, ref args) |
+                        OpNode::CondGoto(_, _, _, ref args) =>
+                            work.extend(args.iter().filter(|arg| arg.name == v.name).cloned()),
+                        _ => unreachable!(),
+                    }
+                },
+                None => {},
+            },
+            ref op => if live_ops.insert(i) {
+                work.extend(needs(op).into_iter().cloned());
+            },
+        }
+    }
+
+    // Label variables that aren't needed go from the label and from the
+    // jumps to it.
+    let mut dead_params = BTreeMap::<usize, BTreeSet<Name>>::new();
+    for op in ops.iter_mut() {
+        match op.val {
+            OpNode::Label(label, ref mut vars) => {
+                let dead: BTreeSet<Name> = vars.iter().filter(|v| !live_vars.contains(v))
+                                               .map(|v| v.name).collect();
+                let live: BTreeSet<Var> = vars.iter().filter(|v| live_vars.contains(v))
+                                              .cloned().collect();
+                *vars = live;
+                dead_params.insert(label, dead);
+            },
+            _ => {},
+        }
+    }
+
+    for (i, mut op) in replace(ops, vec!()).into_iter().enumerate() {
+        match op.val {
+            OpNode::Goto(label, ref mut vars) |
+            OpNode::CondGoto(_, _, label, ref mut vars) => {
+                let dead = &dead_params[&label];
+                let live: BTreeSet<Var> = vars.iter().filter(|v| !dead.contains(&v.name))
+                                              .cloned().collect();
+                *vars = live;
+            },
+            _ => {},
+        }
+        let keep = match op.val {
+            OpNode::Label(..) => true,
+            _ => live_ops.contains(&i),
+        };
+        if keep {
+            ops.push(op);
+        } else if verbose {
+            print!("Removing dead {}", op);
+        }
+    }
+}
+
+impl DeadCodeEliminator {
+    pub fn eliminate(ops: &mut Vec<Op>, globals: &BTreeMap<Name, StaticIRItem>,
+                     verbose: bool) {
+        // Externs have no code.
+        if ops.len() <= 1 {
+            return;
+        }
+
+        remove_unreachable(ops, verbose);
+        if fold_jump_chains(ops, verbose) {
+            remove_unreachable(ops, verbose);
+        }
+
+        // Without SSA form, a variable can be assigned in more than one
+        // place, and the marking above doesn't work.
+        if ops.iter().any(|op| defs(&op.val).iter().any(|v| v.generation.is_none())) {
+            ops.retain(|op| !is_nop(op));
+        } else {
+            remove_dead_defs(ops, globals, verbose);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::DeadCodeEliminator;
+    use testing::{run_pass, optimize_source, find_func, called_functions};
+
+    fn eliminate(src: &str) -> String {
+        run_pass(src, false, false, |program, global_map| {
+            DeadCodeEliminator::eliminate(&mut program[0], global_map, false);
+        })
+    }
+
+    #[test]
+    fn test_dead_defs() {
+        let result = eliminate("global %g size 4 offset 0
+fn %f(%a<1>, %p<1>)
+    %x<1> := %a<1> +s 1
+    %y<1> := %x<1> *s 2
+    %g<1> := %a<1>
+    store32 %p<1>, %a<1>
+    %z<1> := call %f<0>(%a<1>, %p<1>)
+    %w<1> := load32 %p<1>
+    nop
+    return %x<1>
+");
+        assert_eq!(result, "fn %f(%a<1>, %p<1>)
+    %x<1> := %a<1> +s 1
+    %g<1> := %a<1>
+    store32 %p<1>, %a<1>
+    %z<1> := call %f<0>(%a<1>, %p<1>)
+    return %x<1>
+");
+    }
+
+    #[test]
+    fn test_dead_loop_variables() {
+        // %s only feeds itself around the loop, so it goes, label
+        // variables and all.
+        let result = eliminate("fn %f(%n<1>)
+    %i<1> := 0
+    %s<1> := 0
+    goto 0(%i<1>, %s<1>)
+    label 0(%i<2>, %s<2>)
+    %c<1> := %i<2> <s %n<1>
+    if !%c<1> goto 1()
+    %s<3> := %s<2> +s %i<2>
+    %i<3> := %i<2> +s 1
+    goto 0(%i<3>, %s<3>)
+    label 1()
+    return %i<2>
+");
+        assert!(!result.contains("%s"), "{}", result);
+        assert!(result.contains("label 0(%i<2>)\n"), "{}", result);
+        assert!(result.contains("goto 0(%i<3>)\n"), "{}", result);
+    }
+
+    #[test]
+    fn test_unreachable_and_jump_chains() {
+        let result = eliminate("fn %f(%a<1>)
+    if %a<1> goto 1(%a<1>)
+    %a<2> := %a<1> +s 1
+    goto 1(%a<2>)
+    label 1(%a<3>)
+    goto 2(%a<3>)
+    label 3()
+    return 5
+    label 2(%a<4>)
+    return %a<4>
+    %c<1> := 1
+    return %c<1>
+");
+        assert_eq!(result, "fn %f(%a<1>)
+    if %a<1> goto 2(%a<1>)
+    %a<2> := %a<1> +s 1
+    goto 2(%a<2>)
+    label 2(%a<4>)
+    return %a<4>
+");
+    }
+
+    #[test]
+    fn test_jump_chain_keeps_used_label_vars() {
+        // Label 1's %a is used after label 2, so jumps to label 1 have to
+        // keep going through it.
+        let src = "fn %f(%a<1>)
+    if %a<1> goto 1(%a<1>)
+    %a<2> := %a<1> +s 1
+    goto 1(%a<2>)
+    label 1(%a<3>)
+    goto 2()
+    label 2()
+    return %a<3>
+";
+        let result = eliminate(src);
+        assert!(result.contains("goto 1(%a<2>)\n"), "{}", result);
+        assert!(result.contains("label 1(%a<3>)\n"), "{}", result);
+    }
+
+    #[test]
+    fn test_diverging_call() {
+        let program = optimize_source("
+            #[inline(never)]
+            fn check(x: u32) -> u32 {
+                if x > 9 {
+                    rt_abort();
+                    print_uint(x);
+                }
+                x
+            }
+
+            fn main() -> u32 {
+                print_uint(check(3));
+                0
+            }", 1);
+        // Nothing is left after the call to `rt_abort`.
+        assert_eq!(called_functions(find_func(&program, "__check")), vec!("rt_abort"));
+    }
+}
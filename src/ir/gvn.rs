@@ -0,0 +1,279 @@
+//! Global value numbering.
+//!
+//! A pure computation (a binop, or a unop other than deref and addrof) that
+//! has the same operands as one that dominates it computes the same value,
+//! so it can use that value instead. Lowering recomputes struct field and
+//! array element addresses on every access, so there's a lot of this. We
+//! walk the dominator tree keeping a table of the expressions available in
+//! each block, as in Briggs, Cooper and Simpson's dominator-based value
+//! numbering, and look through copies as we go.
+//!
+//! A redundant op becomes a copy of the earlier value, and its uses are
+//! changed to use the earlier value directly. Jumps are the exception: they
+//! have to pass variables under the names their labels take, so they keep
+//! using the copy. So are calls, whose arguments have to be in particular
+//! registers; lowering copies them into fresh variables so that the register
+//! allocator can put them there, and we leave those copies alone. Copies
+//! that nothing uses any more are dropped.
+
+use ir::*;
+use ir::cfg::CFG;
+use ir::util::{defs, uses, memory_names, rename_uses};
+use mc::ast::*;
+use util::Name;
+
+use std::collections::{BTreeMap, BTreeSet};
+
+pub struct ValueNumberer;
+
+#[derive(PartialEq, Eq, PartialOrd, Ord, Clone)]
+enum Operand {
+    Var(Var),
+    // A literal, as it's written, so that its kind is part of it.
+    Lit(String),
+}
+
+// What an op computes: the operator, the operands, and for binops, whether
+// it's signed.
+#[derive(PartialEq, Eq, PartialOrd, Ord, Clone)]
+enum Key {
+    Bin(usize, Operand, Operand, bool),
+    Un(usize, Operand),
+}
+
+fn commutes(op: BinOpNode) -> bool {
+    match op {
+        PlusOp | TimesOp | EqualsOp | NotEqualsOp | AndAlsoOp | OrElseOp |
+        BitAndOp | BitOrOp | BitXorOp => true,
+        _ => false,
+    }
+}
+
+struct Numbering {
+    cfg: CFG,
+    // These don't hold fixed values; see `memory_names`.
+    memory: BTreeSet<Name>,
+    // Parameters and call results, which have to be in particular registers.
+    // Looking through copies of them would make them live for longer.
+    pinned: BTreeSet<Var>,
+    // The variable holding each expression available at this point of the
+    // walk.
+    available: BTreeMap<Key, Var>,
+    // The keys added to `available`, to take back out when we leave the
+    // block they were added in.
+    undo: Vec<Key>,
+    // The value each copy or redundant computation stands for.
+    leader: BTreeMap<Var, Var>,
+    // Ops that are now copies, which can go if nothing uses them.
+    copies: BTreeSet<usize>,
+    verbose: bool,
+}
+
+impl Numbering {
+    fn lookup(&self, v: &Var) -> Var {
+        *self.leader.get(v).unwrap_or(v)
+    }
+
+    fn operand(&self, rve: &RValueElem) -> Option<Operand> {
+        match *rve {
+            Variable(ref v) if self.memory.contains(&v.name) => None,
+            Variable(ref v) => Some(Operand::Var(*v)),
+            Constant(ref lit @ NumLit(..)) |
+            Constant(ref lit @ BoolLit(..)) => Some(Operand::Lit(format!("{}", lit))),
+            Constant(..) => None,
+        }
+    }
+
+    // What `op` computes, if it's pure and has a fixed value.
+    fn key(&self, op: &OpNode) -> Option<(Var, Key)> {
+        let (v, key) = match *op {
+            OpNode::BinOp(v, op, ref rve1, ref rve2, signed) => {
+                let (a, b) = match (self.operand(rve1), self.operand(rve2)) {
+                    (Some(a), Some(b)) => (a, b),
+                    _ => return None,
+                };
+                let (a, b) = if commutes(op) && b < a { (b, a) } else { (a, b) };
+                (v, Key::Bin(op as usize, a, b, signed))
+            },
+            OpNode::UnOp(_, Identity, Variable(ref x)) if self.pinned.contains(x) => return None,
+            // Constants are cheaper to load again than to keep around.
+            OpNode::UnOp(v, op, ref rve @ Variable(..)) if op != Deref && op != AddrOf => {
+                match self.operand(rve) {
+                    Some(a) => (v, Key::Un(op as usize, a)),
+                    None => return None,
+                }
+            },
+            _ => return None,
+        };
+        if self.memory.contains(&v.name) {
+            None
+        } else {
+            Some((v, key))
+        }
+    }
+
+    fn visit_block(&mut self, ops: &mut Vec<Op>, b: usize) {
+        let undo_len = self.undo.len();
+
+        for i in self.cfg.blocks[b].start .. self.cfg.blocks[b].end {
+            // Use leaders instead of the variables they stand for.
+            rename_uses(&mut ops[i].val, &self.leader);
+            let (v, key) = match self.key(&ops[i].val) {
+                Some(found) => found,
+                None => continue,
+            };
+            let leader = match key {
+                Key::Un(op, Operand::Var(x)) if op == Identity as usize => Some(x),
+                _ => self.available.get(&key).cloned(),
+            };
+            let leader = match leader {
+                Some(leader) => leader,
+                None => {
+                    self.available.insert(key.clone(), v);
+                    self.undo.push(key);
+                    continue;
+                },
+            };
+            if self.verbose {
+                print!("{} is the same as {}\n", v, leader);
+            }
+            self.leader.insert(v, leader);
+            ops[i].val = OpNode::UnOp(v, Identity, Variable(leader));
+            self.copies.insert(i);
+        }
+
+        let children = self.cfg.dom_children[b].clone();
+        for child in children.into_iter() {
+            self.visit_block(ops, child);
+        }
+
+        while self.undo.len() > undo_len {
+            let key = self.undo.pop().unwrap();
+            self.available.remove(&key);
+        }
+    }
+}
+
+impl ValueNumberer {
+    pub fn number(ops: &mut Vec<Op>, globals: &BTreeMap<Name, StaticIRItem>, verbose: bool) {
+        // Externs have nothing to number, and we need SSA form.
+        if ops.len() <= 1 ||
+            ops.iter().any(|op| defs(&op.val).iter().any(|v| v.generation.is_none())) {
+            return;
+        }
+
+        let mut pinned = BTreeSet::new();
+        for op in ops.iter() {
+            match op.val {
+                OpNode::Call(v, _, _) => { pinned.insert(v); },
+                OpNode::Func(_, ref params, _) => pinned.extend(params.iter().cloned()),
+                _ => {},
+            }
+        }
+
+        let mut numbering = Numbering {
+            cfg: CFG::new(ops),
+            memory: memory_names(ops, globals),
+            pinned: pinned,
+            available: BTreeMap::new(),
+            undo: vec!(),
+            leader: BTreeMap::new(),
+            copies: BTreeSet::new(),
+            verbose: verbose,
+        };
+        numbering.visit_block(ops, 0);
+
+        let used: BTreeSet<Var> = ops.iter()
+            .flat_map(|op| uses(&op.val).into_iter().cloned())
+            .collect();
+        let old_ops = ::std::mem::replace(ops, vec!());
+        for (i, op) in old_ops.into_iter().enumerate() {
+            let unused = numbering.copies.contains(&i) &&
+                defs(&op.val).iter().all(|v| !used.contains(v));
+            if !unused {
+                ops.push(op);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::ValueNumberer;
+    use testing::run_pass;
+
+    fn number(src: &str) -> String {
+        run_pass(src, true, false, |program, global_map| {
+            ValueNumberer::number(&mut program[0], global_map, false);
+        })
+    }
+
+    #[test]
+    fn test_addresses() {
+        // The same array element's address, computed three times.
+        let result = number("fn %f(%a<1>, %i<1>, %c<1>)
+    %t<1> := %i<1> *u 4u32
+    %p<1> := %a<1> +u %t<1>
+    %x<1> := load32 %p<1>
+    if %c<1> goto 0()
+    %t<2> := %i<1> *u 4u32
+    %p<2> := %t<2> +u %a<1>
+    store32 %p<2>, %x<1>
+    goto 0()
+    label 0()
+    %t<3> := %i<1> *u 4u32
+    %p<3> := %a<1> +u %t<3>
+    %y<1> := load32 %p<3>
+    return %y<1>
+");
+        assert!(result.contains("store32 %p<1>, %x<1>"), "{}", result);
+        assert!(result.contains("%y<1> := load32 %p<1>"), "{}", result);
+        assert!(!result.contains("%t<2>") && !result.contains("%p<3>"), "{}", result);
+    }
+
+    #[test]
+    fn test_dominance_and_memory() {
+        let result = number("global %g size 4 offset 0
+fn %f(%a<1>, %c<1>)
+    if %c<1> goto 0(%a<1>)
+    %a<2> := %a<1> +s 1
+    goto 0(%a<2>)
+    label 0(%a<3>)
+    %b<1> := %a<1> +s 1
+    %h<1> := %g<0> +s 1
+    %d<1> := %a<1>
+    %i<1> := call %f<0>(%d<1>, %c<1>)
+    %e<1> := %i<1>
+    %j<1> := %g<0> +s 1
+    %k<1> := %b<1>
+    %l<1> := %k<1> +s %h<1>
+    %m<1> := %b<1> +s %h<1>
+    %n<1> := %m<1> +s %e<1>
+    return %n<1>
+");
+        // %a<2> doesn't dominate %b<1>, so %b<1> has to compute it again.
+        assert!(result.contains("%b<1> := %a<1> +s 1"), "{}", result);
+        // The call could change %g.
+        assert!(result.contains("%j<1> := %g<0> +s 1"), "{}", result);
+        // Copies are looked through, and dropped once they're unused.
+        assert!(result.contains("%l<1> := %b<1> +s %h<1>"), "{}", result);
+        assert!(result.contains("%n<1> := %l<1> +s %e<1>"), "{}", result);
+        assert!(!result.contains("%k<1>") && !result.contains("%m<1>"), "{}", result);
+        // But not copies into call arguments or out of call results.
+        assert!(result.contains("call %f<0>(%d<1>, %c<1>)"), "{}", result);
+        assert!(result.contains("%e<1> := %i<1>\n"), "{}", result);
+    }
+
+    #[test]
+    fn test_jumps_keep_copies() {
+        let result = number("fn %f(%a<1>, %c<1>)
+    %x<1> := %a<1> *s 3
+    if %c<1> goto 0(%x<1>)
+    %x<2> := %a<1> *s 3
+    goto 0(%x<2>)
+    label 0(%x<3>)
+    return %x<3>
+");
+        assert!(result.contains("%x<2> := %x<1>\n"), "{}", result);
+    }
+}
@@ -0,0 +1,553 @@
+//! Function inlining.
+//!
+//! A direct call to a small function is replaced by a copy of the function's
+//! body, which saves the call sequence and lets the other passes optimize the
+//! body for the arguments it's given. Functions marked `#[inline]` are always
+//! inlined, those marked `#[inline(never)]` never are, and the rest are when
+//! they're small, or only called from one place and not too big. Functions
+//! containing inline asm can't be, since the asm might depend on being in a
+//! function of its own, and neither can recursive ones. Nor are functions
+//! that allocate on the stack inlined into loops: the C target's allocas
+//! aren't freed until the function they're in returns.
+//!
+//! In the copy, the callee's variables get names the caller doesn't use and
+//! its labels get numbers no function uses (the asm target puts every
+//! function's labels in one namespace), so that it can be inlined more than
+//! once. Globals keep their names, but their generations are moved past the
+//! caller's so that each is still assigned only once. Parameters become
+//! copies of the arguments. If the body returns at its end and nowhere
+//! else, the return becomes a copy into the call's result; otherwise each
+//! return jumps to a new label after the body, passing the value along.
+//!
+//! Functions are inlined into before they're inlined anywhere, so what gets
+//! inlined has already had its own calls inlined.
+
+use ir::*;
+use ir::cfg::CFG;
+use ir::util::{defs, uses, label_count, program_label_count, is_nop, Temps};
+use mc::ast::*;
+use util::Name;
+
+use std::collections::{BTreeMap, BTreeSet};
+use std::mem::replace;
+
+pub struct Inliner;
+
+// Functions with at most this many ops are inlined wherever they're called.
+static SMALL_SIZE: usize = 12;
+// Functions that are only called from one place are inlined if they have at
+// most this many ops.
+static SINGLE_CALL_SIZE: usize = 60;
+// Once a function has grown to this many ops, only `#[inline]` functions
+// are inlined into it.
+static MAX_CALLER_SIZE: usize = 2000;
+
+fn is_return(op: &Op) -> bool {
+    match op.val {
+        OpNode::Return(..) => true,
+        _ => false,
+    }
+}
+
+// The number of ops that do something.
+fn size(ops: &Vec<Op>) -> usize {
+    ops.iter().filter(|op| match op.val {
+        OpNode::Func(..) | OpNode::Label(..) | OpNode::Nop => false,
+        _ => true,
+    }).count()
+}
+
+fn max_generation(ops: &Vec<Op>) -> usize {
+    ops.iter().flat_map(|op| {
+        defs(&op.val).into_iter().chain(uses(&op.val).into_iter())
+            .filter_map(|v| v.generation).collect::<Vec<usize>>().into_iter()
+    }).max().unwrap_or(0)
+}
+
+// The function a call calls by name, if that's what it does.
+fn direct_callee(op: &OpNode) -> Option<Name> {
+    match *op {
+        OpNode::Call(_, Variable(ref f), _) if f.generation.unwrap_or(0) == 0 => Some(f.name),
+        _ => None,
+    }
+}
+
+// Whether `from` calls `to`, possibly by way of other functions.
+fn reaches(from: usize, to: usize, calls: &Vec<BTreeSet<usize>>) -> bool {
+    let mut seen = BTreeSet::new();
+    let mut work = vec!(from);
+    while let Some(i) = work.pop() {
+        for &j in calls[i].iter() {
+            if j == to {
+                return true;
+            }
+            if seen.insert(j) {
+                work.push(j);
+            }
+        }
+    }
+    false
+}
+
+fn post_order(i: usize, calls: &Vec<BTreeSet<usize>>, visited: &mut BTreeSet<usize>,
+              order: &mut Vec<usize>) {
+    if !visited.insert(i) {
+        return;
+    }
+    for &j in calls[i].iter() {
+        post_order(j, calls, visited, order);
+    }
+    order.push(i);
+}
+
+// What we know about a function that might be inlined.
+struct Candidate {
+    // Whether it can be inlined at all.
+    inlinable: bool,
+    // `#[inline]`.
+    forced: bool,
+    allocates: bool,
+    call_sites: usize,
+}
+
+// Renames the variables and labels of one inlined copy of a function.
+struct Renamer<'a> {
+    // Globals and functions, which keep their names.
+    outside: &'a BTreeSet<Name>,
+    temps: &'a mut Temps,
+    names: BTreeMap<Name, Name>,
+    label_base: usize,
+    gen_offset: usize,
+}
+
+impl<'a> Renamer<'a> {
+    fn var(&mut self, v: &Var) -> Var {
+        if self.outside.contains(&v.name) {
+            let gen_offset = self.gen_offset;
+            return Var {
+                name: v.name,
+                generation: v.generation.map(|g| if g == 0 { 0 } else { g + gen_offset }),
+            };
+        }
+        if !self.names.contains_key(&v.name) {
+            let name = self.temps.name();
+            self.names.insert(v.name, name);
+        }
+        Var { name: self.names[&v.name], generation: v.generation }
+    }
+
+    fn vars(&mut self, vars: &BTreeSet<Var>) -> BTreeSet<Var> {
+        vars.iter().map(|v| self.var(v)).collect()
+    }
+
+    fn rve(&mut self, rve: &RValueElem) -> RValueElem {
+        match *rve {
+            Variable(ref v) => Variable(self.var(v)),
+            Constant(ref c) => Constant(c.clone()),
+        }
+    }
+
+    fn op(&mut self, op: &OpNode) -> OpNode {
+        match *op {
+            OpNode::UnOp(ref v, unop, ref rve) =>
+                OpNode::UnOp(self.var(v), unop, self.rve(rve)),
+            OpNode::BinOp(ref v, binop, ref rve1, ref rve2, signed) =>
+                OpNode::BinOp(self.var(v), binop, self.rve(rve1), self.rve(rve2), signed),
+            OpNode::Alloca(ref v, size) => OpNode::Alloca(self.var(v), size),
+            OpNode::Call(ref v, ref f, ref args) => {
+                let args = args.iter().map(|a| self.var(a)).collect();
+                OpNode::Call(self.var(v), self.rve(f), args)
+            },
+            OpNode::Store(ref ptr, ref v, width) =>
+                OpNode::Store(self.var(ptr), self.var(v), width),
+            OpNode::Load(ref v, ref ptr, width) =>
+                OpNode::Load(self.var(v), self.var(ptr), width),
+            OpNode::Label(label, ref vars) =>
+                OpNode::Label(label + self.label_base, self.vars(vars)),
+            OpNode::Goto(label, ref vars) =>
+                OpNode::Goto(label + self.label_base, self.vars(vars)),
+            OpNode::CondGoto(negated, ref rve, label, ref vars) =>
+                OpNode::CondGoto(negated, self.rve(rve), label + self.label_base, self.vars(vars)),
+            OpNode::Return(..) |
+            OpNode::Func(..) |
+            OpNode::AsmOp(..) => unreachable!(),
+            OpNode::Nop => OpNode::Nop,
+        }
+    }
+}
+
+// Where the next inlined copy in a function gets its fresh names, labels and
+// generations from.
+struct Caller<'a> {
+    outside: &'a BTreeSet<Name>,
+    temps: Temps,
+    next_label: usize,
+    // The highest generation of anything in the function so far.
+    max_gen: usize,
+}
+
+impl<'a> Caller<'a> {
+    // Append `callee`'s body to `result` in place of `call`.
+    fn inline(&mut self, call: &Op, callee: &Vec<Op>, result: &mut Vec<Op>) {
+        let (dest, args) = match call.val {
+            OpNode::Call(dest, _, ref args) => (dest, args),
+            _ => unreachable!(),
+        };
+        let params = match callee[0].val {
+            OpNode::Func(_, ref params, _) => params,
+            _ => unreachable!(),
+        };
+        let ssa = dest.generation.is_some();
+        let id = call.id;
+
+        let end_label = self.next_label + label_count(callee);
+        let mut renamer = Renamer {
+            outside: self.outside,
+            temps: &mut self.temps,
+            names: BTreeMap::new(),
+            label_base: self.next_label,
+            gen_offset: self.max_gen,
+        };
+        self.next_label = end_label + 1;
+        self.max_gen += max_generation(callee);
+
+        for (param, arg) in params.iter().zip(args.iter()) {
+            let param = renamer.var(param);
+            result.push(WithId { id: id, val: OpNode::UnOp(param, Identity, Variable(*arg)) });
+        }
+
+        let returns = callee.iter().filter(|op| is_return(op)).count();
+        let returns_at_end = returns == 1 &&
+            callee.iter().rev().filter(|op| !is_nop(op)).next().map_or(false, is_return);
+        if returns_at_end {
+            for op in callee[1..].iter() {
+                let val = match op.val {
+                    OpNode::Return(ref rve) => OpNode::UnOp(dest, Identity, renamer.rve(rve)),
+                    ref val => renamer.op(val),
+                };
+                result.push(WithId { id: op.id, val: val });
+            }
+            return;
+        }
+
+        // Each return passes its value to the end label under the same name.
+        let value = renamer.temps.name();
+        let value_var = |n: usize| {
+            let v = Var { name: value, generation: if ssa { Some(n) } else { None } };
+            (v, vec!(v).into_iter().collect::<BTreeSet<Var>>())
+        };
+        let mut n = 0;
+        for op in callee[1..].iter() {
+            match op.val {
+                OpNode::Return(ref rve) => {
+                    n += 1;
+                    let (v, vars) = value_var(n);
+                    let rve = renamer.rve(rve);
+                    result.push(WithId { id: op.id, val: OpNode::UnOp(v, Identity, rve) });
+                    result.push(WithId { id: op.id, val: OpNode::Goto(end_label, vars) });
+                },
+                ref val => result.push(WithId { id: op.id, val: renamer.op(val) }),
+            }
+        }
+        let (v, vars) = value_var(n + 1);
+        result.push(WithId { id: id, val: OpNode::Label(end_label, vars) });
+        result.push(WithId { id: id, val: OpNode::UnOp(dest, Identity, Variable(v)) });
+    }
+}
+
+impl Inliner {
+    pub fn inline(program: &mut Vec<Vec<Op>>, globals: &BTreeMap<Name, StaticIRItem>,
+                  verbose: bool) {
+        let mut funcs = BTreeMap::<Name, usize>::new();
+        for (i, ops) in program.iter().enumerate() {
+            match ops.first() {
+                Some(&WithId { val: OpNode::Func(name, _, _), .. }) => { funcs.insert(name, i); },
+                _ => {},
+            }
+        }
+
+        // The functions each function calls directly.
+        let mut calls = vec!();
+        let mut call_sites = vec!(0; program.len());
+        for ops in program.iter() {
+            let mut called = BTreeSet::new();
+            for op in ops.iter() {
+                match direct_callee(&op.val).and_then(|f| funcs.get(&f)) {
+                    Some(&j) => {
+                        called.insert(j);
+                        call_sites[j] += 1;
+                    },
+                    None => {},
+                }
+            }
+            calls.push(called);
+        }
+
+        let mut candidates = vec!();
+        for (i, ops) in program.iter().enumerate() {
+            let (name, has_abi) = match ops[0].val {
+                OpNode::Func(name, _, ref abi) => (name, abi.is_some()),
+                _ => unreachable!(),
+            };
+            let attr = globals.get(&name).and_then(|item| item.inline);
+            let has_asm = ops.iter().any(|op| match op.val {
+                OpNode::AsmOp(..) => true,
+                _ => false,
+            });
+            let allocates = ops.iter().any(|op| match op.val {
+                OpNode::Alloca(..) => true,
+                _ => false,
+            });
+            candidates.push(Candidate {
+                // Externs have no body to inline.
+                inlinable: ops.len() > 1 && !has_abi && !has_asm && attr != Some(false) &&
+                    !reaches(i, i, &calls),
+                forced: attr == Some(true),
+                allocates: allocates,
+                call_sites: call_sites[i],
+            });
+        }
+
+        let outside: BTreeSet<Name> = globals.keys().chain(funcs.keys()).cloned().collect();
+        let mut order = vec!();
+        let mut visited = BTreeSet::new();
+        for i in 0 .. program.len() {
+            post_order(i, &calls, &mut visited, &mut order);
+        }
+
+        let mut next_label = program_label_count(program);
+        for &i in order.iter() {
+            let ops = replace(&mut program[i], vec!());
+            let mut caller = Caller {
+                outside: &outside,
+                temps: Temps::new(&ops),
+                next_label: next_label,
+                max_gen: max_generation(&ops),
+            };
+            let cfg = CFG::new(&ops);
+            let mut caller_size = size(&ops);
+            let mut result = vec!();
+            for (k, op) in ops.into_iter().enumerate() {
+                let j = match direct_callee(&op.val).and_then(|f| funcs.get(&f)) {
+                    Some(&j) => j,
+                    None => {
+                        result.push(op);
+                        continue;
+                    },
+                };
+                let callee = &program[j];
+                let callee_size = size(callee);
+                let candidate = &candidates[j];
+                let wanted = candidate.forced ||
+                    (caller_size < MAX_CALLER_SIZE &&
+                     (callee_size <= SMALL_SIZE ||
+                      (candidate.call_sites == 1 && callee_size <= SINGLE_CALL_SIZE)));
+                // A function calling itself finds its own body empty here.
+                let arity_matches = match (&op.val, callee.first().map(|op| &op.val)) {
+                    (&OpNode::Call(_, _, ref args), Some(&OpNode::Func(_, ref params, _))) =>
+                        args.len() == params.len(),
+                    _ => false,
+                };
+                let in_loop = cfg.loop_depth(cfg.block_of[k]) > 0;
+                if !candidate.inlinable || !wanted || !arity_matches ||
+                    (candidate.allocates && in_loop) {
+                    result.push(op);
+                    continue;
+                }
+
+                if verbose {
+                    let caller_name = match result[0].val {
+                        OpNode::Func(name, _, _) => name,
+                        _ => unreachable!(),
+                    };
+                    print!("Inlining {} into {}\n", direct_callee(&op.val).unwrap(), caller_name);
+                }
+                caller.inline(&op, callee, &mut result);
+                caller_size += callee_size;
+            }
+            next_label = caller.next_label;
+            program[i] = result;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Inliner;
+    use ir::text::parse_program;
+    use testing::{run_pass, run_asm_source, text, optimize_source, find_func,
+                  called_functions};
+
+    fn inline(src: &str) -> String {
+        run_pass(src, false, true, |program, global_map| {
+            Inliner::inline(program, global_map, false);
+        })
+    }
+
+    #[test]
+    fn test_small_functions() {
+        let result = inline("global %print_int size 0 offset 0 extern func
+fn %__main()
+    %a<1> := 4
+    %b<1> := call %add<0>(%a<1>, %a<1>)
+    %c<1> := call %add<0>(%b<1>, %a<1>)
+    %d<1> := call %print_int<0>(%c<1>)
+    return 0
+fn %add(%x<1>, %y<1>)
+    %z<1> := %x<1> +s %y<1>
+    return %z<1>
+fn %print_int(%x) extern \"C\"
+");
+        assert!(!result.contains("call %add"), "{}", result);
+        assert!(result.contains("call %print_int<0>(%c<1>)"), "{}", result);
+        // Each copy gets its own names.
+        assert!(result.contains("%b<1> := %TEMP$2<1>\n"), "{}", result);
+        assert!(result.contains("%c<1> := %TEMP$5<1>\n"), "{}", result);
+    }
+
+    #[test]
+    fn test_returns_and_labels() {
+        // Early returns jump to the end of the copy, and the copies' labels
+        // don't clash with each other or with the caller's.
+        let result = inline("global %g size 4 offset 0
+global %print_int size 0 offset 4 extern func
+fn %__main()
+    %g<1> := 1
+    %i<1> := 0
+    goto 0(%i<1>)
+    label 0(%i<2>)
+    %r<1> := call %clamp<0>(%i<2>)
+    %p<1> := call %print_int<0>(%r<1>)
+    %s<1> := call %clamp<0>(%r<1>)
+    %i<3> := %i<2> +s 1
+    %c<1> := %i<3> <s 6
+    if %c<1> goto 0(%i<3>)
+    return %g<1>
+fn %clamp(%x<1>)
+    %g<1> := %g<0> +s 1
+    %c<1> := %x<1> >s 3
+    if %c<1> goto 0()
+    return %x<1>
+    label 0()
+    return 3
+fn %print_int(%x) extern \"C\"
+");
+        assert!(!result.contains("call %clamp"), "{}", result);
+        assert!(result.contains("goto 2(%TEMP"), "{}", result);
+        assert!(result.contains("label 4(%TEMP"), "{}", result);
+        assert!(result.contains("%g<4> := %g<0> +s 1"), "{}", result);
+        assert!(result.contains("%g<5> := %g<0> +s 1"), "{}", result);
+    }
+
+    #[test]
+    fn test_global_names() {
+        // The callee's variables don't get the name of a global it uses that
+        // the caller doesn't.
+        let result = inline("global %TEMP0 size 4 offset 0
+global %print_int size 0 offset 4 extern func
+fn %__main()
+    %a<1> := 4
+    %b<1> := call %get<0>(%a<1>)
+    %p<1> := call %print_int<0>(%b<1>)
+    return 0
+fn %get(%x<1>)
+    %y<1> := %TEMP0<0> +s %x<1>
+    return %y<1>
+fn %print_int(%x) extern \"C\"
+");
+        assert!(!result.contains("call %get"), "{}", result);
+        assert!(result.contains(":= %TEMP0<0> +s %TEMP$"), "{}", result);
+    }
+
+    #[test]
+    fn test_asm_labels() {
+        // The asm target doesn't qualify labels with their function, so the
+        // copy of `clamp` in `twice` can't use the labels `clamp` itself does.
+        let result = run_asm_source("
+            fn clamp(x: i32) -> i32 {
+                if x < 0 { return 0; }
+                if x > 9 { return 9; }
+                x
+            }
+
+            fn twice(x: i32) -> i32 {
+                clamp(x) * 2
+            }
+
+            fn main() -> u32 {
+                let i: i32 = -2;
+                while i < 17 {
+                    print_int(twice(i));
+                    i += 5;
+                }
+                0
+            }", &[("opt_level", "1")]);
+        assert_eq!(result, Ok("0\n6\n16\n18\n".to_string()));
+    }
+
+    #[test]
+    fn test_left_alone() {
+        let src = "global %never size 0 offset 0 func noinline
+fn %__main()
+    %a<1> := call %fact<0>(%a<0>)
+    %b<1> := call %never<0>(%a<1>)
+    %c<1> := call %b<1>(%a<1>)
+    return %c<1>
+fn %fact(%n<1>)
+    %m<1> := %n<1> -s 1
+    %r<1> := call %fact<0>(%m<1>)
+    %s<1> := %r<1> *s %n<1>
+    return %s<1>
+fn %never(%x<1>)
+    return %x<1>
+";
+        let (mut program, global_map) = parse_program(src).unwrap();
+        Inliner::inline(&mut program, &global_map, false);
+        let result: String = program.iter().map(text).collect();
+        // Recursive functions, `#[inline(never)]` ones and calls through
+        // pointers aren't inlined.
+        for line in src.lines().skip(1) {
+            assert!(result.contains(line), "{}", result);
+        }
+    }
+
+    #[test]
+    fn test_inlining() {
+        let program = optimize_source("
+            static calls: u32 = 0;
+
+            #[inline(never)]
+            fn square(x: u32) -> u32 { x * x }
+
+            #[inline]
+            fn sum_to(n: u32) -> u32 {
+                let total: u32 = 0;
+                let i: u32 = 0;
+                while i < n {
+                    total += i;
+                    i += 1;
+                }
+                total
+            }
+
+            fn sign(x: i32) -> i32 {
+                calls += 1;
+                if x < 0 { return -1; }
+                if x == 0 { return 0; }
+                1
+            }
+
+            fn main() -> u32 {
+                let i: i32 = -2;
+                while i <= 2 {
+                    print_int(sign(i));
+                    print_uint(square(i as u32) + sum_to((i + 3) as u32));
+                    i += 1;
+                }
+                print_uint(calls);
+                0
+            }", 1);
+        assert_eq!(called_functions(find_func(&program, "__main")),
+                   vec!("print_int", "__square", "print_int", "print_int"));
+    }
+}
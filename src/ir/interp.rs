@@ -0,0 +1,469 @@
+//! An interpreter for the IR, so that passes can be checked for preserving
+//! the meaning of a program without going through C or the simulator.
+//!
+//! It follows the semantics of the IR-to-C target: values are 32 bits,
+//! non-function globals live in memory at `GLOBAL_MEM_START`, and a jump
+//! copies each of its variables into the generation its label expects.
+//! Works both before and after SSA conversion.
+
+use codegen::{GLOBAL_MEM_START, STACK_START};
+use ir::*;
+use ir::passes::PassManager;
+use mc::ast::*;
+use sim::machine::Memory;
+use util::{Name, Width};
+use util::Width::{AnyWidth, Width32, Width16, Width8};
+
+use std::collections::{BTreeMap, BTreeSet};
+
+// Where things that aren't on the stack or in globals go. Functions get
+// made-up addresses so that they can be stored and called through.
+static HEAP_START: u32 = 0x10000000;
+static STRING_START: u32 = 0x20000000;
+static FUNC_START: u32 = 0x30000000;
+
+/// How many ops we'll execute before deciding the program doesn't finish.
+pub static MAX_STEPS: u64 = 100000000;
+
+struct Func<'a> {
+    params: Vec<Var>,
+    ops: &'a Vec<Op>,
+    // Position of each label in ops, and the variables it takes.
+    labels: BTreeMap<usize, (usize, BTreeSet<Var>)>,
+}
+
+pub struct Interpreter<'a> {
+    global_map: &'a BTreeMap<Name, StaticIRItem>,
+    funcs: BTreeMap<Name, Func<'a>>,
+    func_addrs: BTreeMap<Name, u32>,
+    funcs_by_addr: BTreeMap<u32, Name>,
+    mem: Memory,
+    sp: u32,
+    heap: u32,
+    strings: BTreeMap<String, u32>,
+    next_string: u32,
+    steps: u64,
+    /// Everything the program has printed.
+    pub output: Vec<u8>,
+}
+
+impl<'a> Interpreter<'a> {
+    pub fn new(program: &'a Vec<Vec<Op>>,
+               global_map: &'a BTreeMap<Name, StaticIRItem>) -> Interpreter<'a> {
+        let mut funcs = BTreeMap::new();
+        for ops in program.iter() {
+            let (name, params) = match ops.get(0) {
+                Some(&WithId { val: OpNode::Func(ref name, ref params, _), .. }) =>
+                    (*name, params.clone()),
+                _ => continue,
+            };
+            // Externs are just a Func op with nothing after it.
+            if ops.len() <= 1 {
+                continue;
+            }
+            let mut labels = BTreeMap::new();
+            for (pos, op) in ops.iter().enumerate() {
+                match op.val {
+                    OpNode::Label(label, ref vars) => {
+                        labels.insert(label, (pos, vars.clone()));
+                    },
+                    _ => {},
+                }
+            }
+            funcs.insert(name, Func { params: params, ops: ops, labels: labels });
+        }
+
+        let mut names: BTreeSet<Name> = funcs.keys().map(|n| *n).collect();
+        names.extend(global_map.iter().filter(|&(_, item)| item.is_func).map(|(n, _)| *n));
+        let mut func_addrs = BTreeMap::new();
+        let mut funcs_by_addr = BTreeMap::new();
+        for (i, name) in names.into_iter().enumerate() {
+            let addr = FUNC_START + (i as u32) * 16;
+            func_addrs.insert(name, addr);
+            funcs_by_addr.insert(addr, name);
+        }
+
+        Interpreter {
+            global_map: global_map,
+            funcs: funcs,
+            func_addrs: func_addrs,
+            funcs_by_addr: funcs_by_addr,
+            mem: Memory::new(),
+            sp: STACK_START,
+            heap: HEAP_START,
+            strings: BTreeMap::new(),
+            next_string: STRING_START,
+            steps: 0,
+            output: vec!(),
+        }
+    }
+
+    fn find_func(&self, name: &str) -> Option<Name> {
+        self.funcs.keys().filter(|n| format!("{}", n) == name).map(|n| *n).next()
+    }
+
+    /// Initialize the globals and run `__main`, returning its result.
+    pub fn run(&mut self) -> Result<u32, String> {
+        match self.find_func("_INIT_GLOBALS") {
+            Some(init) => { try!(self.call(init, vec!())); },
+            None => {},
+        }
+        match self.find_func("__main") {
+            Some(main) => self.call(main, vec!()),
+            None => Err("no __main to run".to_string()),
+        }
+    }
+
+    fn global_addr(&self, item: &StaticIRItem) -> Result<u32, String> {
+        match item.offset {
+            Some(offs) => Ok(GLOBAL_MEM_START + offs as u32),
+            None => Err(format!("global {} has no storage", item.name)),
+        }
+    }
+
+    fn string_addr(&mut self, s: &String) -> u32 {
+        match self.strings.get(s) {
+            Some(addr) => return *addr,
+            None => {},
+        }
+        let addr = self.next_string;
+        for (i, b) in s.bytes().enumerate() {
+            self.mem.write_u8(addr + i as u32, b);
+        }
+        self.mem.write_u8(addr + s.len() as u32, 0);
+        self.next_string = (addr + s.len() as u32 + 4) & !3;
+        self.strings.insert(s.clone(), addr);
+        addr
+    }
+
+    fn read_var(&self, env: &BTreeMap<Var, u32>, v: &Var) -> Result<u32, String> {
+        match self.global_map.get(&v.name) {
+            Some(item) if item.is_func => return Ok(self.func_addrs[&v.name]),
+            Some(item) if item.is_ref => return self.global_addr(item),
+            Some(item) => return Ok(self.mem.read_u32(try!(self.global_addr(item)))),
+            None => {},
+        }
+        match self.func_addrs.get(&v.name) {
+            Some(addr) => return Ok(*addr),
+            None => {},
+        }
+        // Reading something that was never assigned is undefined; call it 0.
+        Ok(*env.get(v).unwrap_or(&0))
+    }
+
+    fn write_var(&mut self, env: &mut BTreeMap<Var, u32>, v: &Var, val: u32)
+                 -> Result<(), String> {
+        match self.global_map.get(&v.name) {
+            Some(item) if item.is_func || item.is_ref =>
+                return Err(format!("assignment to {}", v)),
+            Some(item) => {
+                let addr = try!(self.global_addr(item));
+                self.mem.write_u32(addr, val);
+                return Ok(());
+            },
+            None => {},
+        }
+        env.insert(*v, val);
+        Ok(())
+    }
+
+    fn read_rve(&mut self, env: &BTreeMap<Var, u32>, rve: &RValueElem) -> Result<u32, String> {
+        match *rve {
+            Variable(ref v) => self.read_var(env, v),
+            Constant(ref lit) => Ok(match *lit {
+                NumLit(n, _) => n as u32,
+                BoolLit(b) => b as u32,
+                NullLit => 0,
+                StringLit(ref s) => self.string_addr(s),
+            }),
+        }
+    }
+
+    fn load(&self, addr: u32, width: Width) -> u32 {
+        match width {
+            AnyWidth |
+            Width32 => self.mem.read(addr, 4),
+            Width16 => self.mem.read(addr, 2),
+            Width8 => self.mem.read(addr, 1),
+        }
+    }
+
+    fn store(&mut self, addr: u32, width: Width, val: u32) {
+        match width {
+            AnyWidth |
+            Width32 => self.mem.write(addr, 4, val),
+            Width16 => self.mem.write(addr, 2, val),
+            Width8 => self.mem.write(addr, 1, val),
+        }
+    }
+
+    fn builtin(&mut self, name: &str, args: &Vec<u32>) -> Result<u32, String> {
+        let arg = |i: usize| *args.get(i).unwrap_or(&0);
+        match name {
+            "print_int" => {
+                let line = format!("{}\n", arg(0) as i32);
+                self.output.extend(line.bytes());
+                Ok(arg(0))
+            },
+            "print_char" => {
+                self.output.push(arg(0) as u8);
+                Ok(arg(0))
+            },
+            "rt_memcpy" => {
+                for i in 0 .. arg(2) {
+                    let b = self.mem.read_u8(arg(1) + i);
+                    self.mem.write_u8(arg(0) + i, b);
+                }
+                Ok(arg(0))
+            },
+            "rt_malloc" => {
+                let addr = self.heap;
+                self.heap = (self.heap + arg(0) + 3) & !3;
+                Ok(addr)
+            },
+            "rt_abort" => Err("rt_abort called".to_string()),
+            "debug_break" => Ok(0),
+            _ => Err(format!("call to unknown extern {}", name)),
+        }
+    }
+
+    fn call(&mut self, name: Name, args: Vec<u32>) -> Result<u32, String> {
+        if !self.funcs.contains_key(&name) {
+            return self.builtin(&format!("{}", name), &args);
+        }
+
+        let (ops, params): (&'a Vec<Op>, Vec<Var>) = {
+            let func = &self.funcs[&name];
+            (func.ops, func.params.clone())
+        };
+        let mut env = BTreeMap::new();
+        for (i, param) in params.iter().enumerate() {
+            env.insert(*param, *args.get(i).unwrap_or(&0));
+        }
+
+        let saved_sp = self.sp;
+        let result = self.execute(name, ops, &mut env);
+        self.sp = saved_sp;
+        result
+    }
+
+    // Copy the variables in a jump into the generations the label wants.
+    fn jump(&mut self, func: Name, env: &mut BTreeMap<Var, u32>,
+            label: usize, vars: &BTreeSet<Var>) -> Result<usize, String> {
+        let (pos, label_vars) = match self.funcs[&func].labels.get(&label) {
+            Some(&(pos, ref label_vars)) => (pos, label_vars.clone()),
+            None => return Err(format!("jump to missing label {}", label)),
+        };
+        let mut assignments = vec!();
+        for var in vars.iter() {
+            if self.global_map.contains_key(&var.name) {
+                continue;
+            }
+            for label_var in label_vars.iter().filter(|lv| lv.name == var.name) {
+                assignments.push((*label_var, try!(self.read_var(env, var))));
+            }
+        }
+        for (var, val) in assignments.into_iter() {
+            env.insert(var, val);
+        }
+        Ok(pos)
+    }
+
+    fn execute(&mut self, func: Name, ops: &'a Vec<Op>,
+               env: &mut BTreeMap<Var, u32>) -> Result<u32, String> {
+        let mut pc = 1;
+        while pc < ops.len() {
+            self.steps += 1;
+            if self.steps > MAX_STEPS {
+                return Err(format!("still running after {} ops", MAX_STEPS));
+            }
+
+            let mut next = pc + 1;
+            match ops[pc].val {
+                OpNode::UnOp(ref v, ref op, ref rve) => {
+                    let val = match *op {
+                        AddrOf => match *rve {
+                            Variable(ref var) => match self.global_map.get(&var.name) {
+                                Some(item) if item.is_func => self.func_addrs[&var.name],
+                                Some(item) => try!(self.global_addr(item)),
+                                None => return Err(format!("address of local {}", var)),
+                            },
+                            _ => return Err(format!("address of constant {}", rve)),
+                        },
+                        _ => {
+                            let x = try!(self.read_rve(env, rve));
+                            match *op {
+                                Deref => self.mem.read_u32(x),
+                                Negate => (x as i32).wrapping_neg() as u32,
+                                LogNot => (x == 0) as u32,
+                                BitNot => !x,
+                                Identity => x,
+                                SxbOp => x as i8 as i32 as u32,
+                                SxhOp => x as i16 as i32 as u32,
+                                AddrOf => unreachable!(),
+                            }
+                        },
+                    };
+                    try!(self.write_var(env, v, val));
+                },
+                OpNode::BinOp(ref v, ref op, ref rve1, ref rve2, signed) => {
+                    let x = try!(self.read_rve(env, rve1));
+                    let y = try!(self.read_rve(env, rve2));
+                    let val = try!(eval_binop(op, x, y, signed));
+                    try!(self.write_var(env, v, val));
+                },
+                OpNode::Alloca(ref v, size) => {
+                    let addr = self.sp;
+                    self.sp = (self.sp + size as u32 + 3) & !3;
+                    try!(self.write_var(env, v, addr));
+                },
+                OpNode::Call(ref v, ref f, ref args) => {
+                    let addr = try!(self.read_rve(env, f));
+                    let callee = match self.funcs_by_addr.get(&addr) {
+                        Some(name) => *name,
+                        None => return Err(format!("call through bad pointer 0x{:x}", addr)),
+                    };
+                    let mut vals = vec!();
+                    for arg in args.iter() {
+                        vals.push(try!(self.read_var(env, arg)));
+                    }
+                    let result = try!(self.call(callee, vals));
+                    try!(self.write_var(env, v, result));
+                },
+                OpNode::Store(ref addr, ref val, width) => {
+                    let addr = try!(self.read_var(env, addr));
+                    let val = try!(self.read_var(env, val));
+                    self.store(addr, width, val);
+                },
+                OpNode::Load(ref v, ref addr, width) => {
+                    let addr = try!(self.read_var(env, addr));
+                    let val = self.load(addr, width);
+                    try!(self.write_var(env, v, val));
+                },
+                OpNode::Goto(label, ref vars) => {
+                    next = try!(self.jump(func, env, label, vars));
+                },
+                OpNode::CondGoto(negated, ref rve, label, ref vars) => {
+                    let cond = try!(self.read_rve(env, rve)) != 0;
+                    if cond != negated {
+                        next = try!(self.jump(func, env, label, vars));
+                    }
+                },
+                OpNode::Return(ref rve) => return self.read_rve(env, rve),
+                OpNode::AsmOp(..) => return Err("can't interpret inline asm".to_string()),
+                OpNode::Label(..) |
+                OpNode::Func(..) |
+                OpNode::Nop => {},
+            }
+            pc = next;
+        }
+        Ok(0)
+    }
+}
+
+fn eval_binop(op: &BinOpNode, x: u32, y: u32, signed: bool) -> Result<u32, String> {
+    let (sx, sy) = (x as i32, y as i32);
+    Ok(match *op {
+        PlusOp => x.wrapping_add(y),
+        MinusOp => x.wrapping_sub(y),
+        TimesOp => x.wrapping_mul(y),
+        DivideOp | ModOp if y == 0 => return Err("division by zero".to_string()),
+        DivideOp => if signed { sx.wrapping_div(sy) as u32 } else { x / y },
+        ModOp => if signed { sx.wrapping_rem(sy) as u32 } else { x % y },
+        EqualsOp => (x == y) as u32,
+        NotEqualsOp => (x != y) as u32,
+        LessOp => (if signed { sx < sy } else { x < y }) as u32,
+        LessEqOp => (if signed { sx <= sy } else { x <= y }) as u32,
+        GreaterOp => (if signed { sx > sy } else { x > y }) as u32,
+        GreaterEqOp => (if signed { sx >= sy } else { x >= y }) as u32,
+        AndAlsoOp => (x != 0 && y != 0) as u32,
+        OrElseOp => (x != 0 || y != 0) as u32,
+        BitAndOp => x & y,
+        BitOrOp => x | y,
+        BitXorOp => x ^ y,
+        LeftShiftOp => if y >= 32 { 0 } else { x << (y as usize) },
+        RightShiftOp => if signed {
+            (sx >> (if y >= 32 { 31 } else { y } as usize)) as u32
+        } else {
+            if y >= 32 { 0 } else { x >> (y as usize) }
+        },
+    })
+}
+
+/// Run a whole program, returning what it printed.
+pub fn run_program(program: &Vec<Vec<Op>>,
+                   global_map: &BTreeMap<Name, StaticIRItem>) -> Result<String, String> {
+    let mut interp = Interpreter::new(program, global_map);
+    try!(interp.run());
+    Ok(String::from_utf8_lossy(&interp.output).into_owned())
+}
+
+/// Run `program` before and after each of `passes`, and panic if any pass
+/// changes what it prints. Programs the interpreter can't run at all
+/// (inline asm, say) are skipped with a note.
+pub fn check_passes(program: &Vec<Vec<Op>>,
+                    global_map: &BTreeMap<Name, StaticIRItem>,
+                    passes: &PassManager) {
+    let expected = match run_program(program, global_map) {
+        Ok(output) => output,
+        Err(e) => {
+            print!("Not checking passes; can't interpret the program: {}\n", e);
+            return;
+        },
+    };
+
+    let mut program = program.clone();
+    for pass in passes.passes.iter() {
+        pass.run(&mut program, global_map, false);
+        check_pass(pass.name, &expected, &program, global_map);
+    }
+}
+
+fn check_pass(pass: &str, expected: &String, program: &Vec<Vec<Op>>,
+              global_map: &BTreeMap<Name, StaticIRItem>) {
+    match run_program(program, global_map) {
+        Ok(ref output) if output == expected => {},
+        Ok(output) => panic!("{} changed the program's output from\n{}\nto\n{}",
+                             pass, expected, output),
+        Err(e) => panic!("after {}, the program fails: {}", pass, e),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{run_program, check_passes};
+    use ir::passes::{PassManager, preset};
+    use testing::lower;
+
+    #[test]
+    fn test_interp() {
+        let (program, global_map) = lower("
+            static total: u32 = 1;
+
+            fn fact(n: u32) -> u32 {
+                if n <= 1 { 1 } else { n * fact(n - 1) }
+            }
+
+            fn neg(x: i32) -> i32 {
+                -x
+            }
+
+            fn main() -> u32 {
+                let a: u32[4];
+                let i: u32 = 0;
+                while i < 4 {
+                    a[i] = fact(i + 2);
+                    total += a[i];
+                    i += 1;
+                }
+                print_uint(a[3]);
+                print_uint(total);
+                print_int(neg(3) / 2);
+                0
+            }");
+
+        assert_eq!(run_program(&program, &global_map),
+                   Ok("120\n153\n-1\n".to_string()));
+        check_passes(&program, &global_map, &PassManager::new(&preset(2)[..]).unwrap());
+    }
+}
@@ -0,0 +1,799 @@
+//! Loop-invariant code motion and induction variable strength reduction.
+//!
+//! Lowering computes everything where it's used, so loops recompute array
+//! base addresses, struct field offsets and constants every time around.
+//! A pure op (as in GVN) whose operands are all defined outside the loop,
+//! or by ops we're already moving, computes the same value each iteration,
+//! and moves to the loop's preheader: a block that runs once, just before
+//! the loop is entered. If the only way in is a goto, the block it ends is
+//! the preheader already; otherwise we add a new block in front of the
+//! header and make the jumps from outside go there. None of the ops we move
+//! can trap, so it's fine to run them even if the loop doesn't run its
+//! body. Inner loops go first, so that what they hoist can then be hoisted
+//! out of the loops around them too.
+//!
+//! Then, in each loop, a header variable that every back edge passes either
+//! unchanged or with a constant added is a basic induction variable, and
+//! anything computed from one with adds and subtracts of invariants and
+//! multiplies and shifts by constants goes up by a constant each iteration
+//! too. Those that take a multiply, like the address of `a[i]`, get a
+//! header variable of their own that starts at their value for the first
+//! iteration and is bumped next to each update of the induction variable,
+//! so they cost an add per iteration rather than a multiply and an add.
+//! Their old computation becomes a copy of the new variable; like in GVN,
+//! uses look through it except in jumps and calls, and DCE cleans up what's
+//! left.
+//!
+//! This needs SSA form. Call arguments and results, and parameters, stay
+//! where they are, since they have to be in particular registers.
+
+use ir::*;
+use ir::cfg::CFG;
+use ir::util::{defs, uses, memory_names, Temps};
+use ir::util::{jump_target, def_sites, resolve, rename_rve, rename_uses};
+use mc::ast::*;
+use util::{IntKind, Name};
+use util::Width::Width32;
+
+use std::collections::{BTreeMap, BTreeSet};
+
+pub struct LoopOptimizer;
+
+struct Context {
+    // These don't hold fixed values; see `memory_names`.
+    memory: BTreeSet<Name>,
+    // Variables that have to be in particular registers.
+    pinned: BTreeSet<Var>,
+    temps: Temps,
+    // The next label no function uses.
+    labels: usize,
+    verbose: bool,
+}
+
+// A variable that's `scale` times the basic induction variable `iv`, plus
+// something invariant. `multiplied` says whether it takes a multiply or a
+// shift to compute.
+#[derive(Clone, Copy)]
+struct Affine {
+    iv: Var,
+    scale: u32,
+    multiplied: bool,
+}
+
+// Where code that should run once before a loop goes.
+struct Preheader {
+    // The loop's header label.
+    header: usize,
+    // The one jump into the loop, if it's a goto: code can go right before
+    // it.
+    jump: Option<usize>,
+    // Otherwise, the jumps into the loop, which go to a new label taking
+    // `vars` instead.
+    entries: Vec<usize>,
+    vars: BTreeSet<Var>,
+    // The value of each of the header's variables on entry, by name.
+    entry: BTreeMap<Name, Var>,
+}
+
+// What to change about a loop.
+struct Edit {
+    // Ops to move to the preheader, in order.
+    hoisted: Vec<usize>,
+    // Ops to add to the preheader after them.
+    before: Vec<Op>,
+    // Ops to put right after others.
+    after: BTreeMap<usize, Vec<Op>>,
+    replaced: BTreeMap<usize, OpNode>,
+    // New header variables, with what they start as.
+    header_vars: Vec<(Var, Var)>,
+    // What the jumps from inside the loop to its header pass for them.
+    back_edge_vars: BTreeMap<usize, Vec<Var>>,
+    // Variables whose uses should use others instead.
+    renamed: BTreeMap<Var, Var>,
+}
+
+impl Edit {
+    fn new() -> Edit {
+        Edit {
+            hoisted: vec!(),
+            before: vec!(),
+            after: BTreeMap::new(),
+            replaced: BTreeMap::new(),
+            header_vars: vec!(),
+            back_edge_vars: BTreeMap::new(),
+            renamed: BTreeMap::new(),
+        }
+    }
+}
+
+fn jump_vars(op: &mut OpNode) -> &mut BTreeSet<Var> {
+    match *op {
+        OpNode::Label(_, ref mut vars) |
+        OpNode::Goto(_, ref mut vars) |
+        OpNode::CondGoto(_, _, _, ref mut vars) => vars,
+        _ => unreachable!(),
+    }
+}
+
+// Whether `op` is pure and can't trap, so it can run where it otherwise
+// wouldn't. Division by a variable might be by zero.
+fn is_movable(op: &OpNode) -> bool {
+    match *op {
+        OpNode::BinOp(_, DivideOp, _, ref rhs, _) |
+        OpNode::BinOp(_, ModOp, _, ref rhs, _) => match *rhs {
+            Constant(NumLit(n, _)) => n != 0,
+            _ => false,
+        },
+        OpNode::BinOp(..) => true,
+        OpNode::UnOp(_, op, _) => op != Deref && op != AddrOf,
+        _ => false,
+    }
+}
+
+fn in_loop(cfg: &CFG, l: usize, i: usize) -> bool {
+    cfg.loops[l].blocks.contains(&cfg.block_of[i])
+}
+
+// Find loop `l`'s preheader, or plan one.
+fn preheader(ops: &Vec<Op>, cfg: &CFG, l: usize) -> Option<Preheader> {
+    let header_start = cfg.blocks[cfg.loops[l].header].start;
+    let (header, header_vars) = match ops[header_start].val {
+        OpNode::Label(label, ref vars) => (label, vars),
+        _ => return None,
+    };
+    let entries: Vec<usize> = (0..ops.len())
+        .filter(|&i| !in_loop(cfg, l, i) && jump_target(&ops[i].val) == Some(header))
+        .collect();
+    if entries.is_empty() {
+        return None;
+    }
+
+    let first = entries[0];
+    match ops[first].val {
+        OpNode::Goto(_, ref vars) if entries.len() == 1 => {
+            return Some(Preheader {
+                header: header,
+                jump: Some(first),
+                entries: entries,
+                vars: BTreeSet::new(),
+                entry: vars.iter().map(|v| (v.name, *v)).collect(),
+            });
+        },
+        _ => {},
+    }
+
+    // The new label's variables are new generations of the header's.
+    let mut last_gen = BTreeMap::new();
+    for op in ops.iter() {
+        for v in defs(&op.val).into_iter().chain(uses(&op.val).into_iter()) {
+            let gen = v.generation.unwrap_or(0);
+            if *last_gen.get(&v.name).unwrap_or(&0) < gen {
+                last_gen.insert(v.name, gen);
+            }
+        }
+    }
+    let vars: BTreeSet<Var> = header_vars.iter().map(|v| {
+        Var { name: v.name, generation: Some(last_gen[&v.name] + 1) }
+    }).collect();
+    Some(Preheader {
+        header: header,
+        jump: None,
+        entries: entries,
+        entry: vars.iter().map(|v| (v.name, *v)).collect(),
+        vars: vars,
+    })
+}
+
+// Make the changes in `edit`. A new preheader label is the next of `labels`.
+fn apply(ops: &mut Vec<Op>, cfg: &CFG, l: usize, pre: Preheader, mut edit: Edit,
+         labels: &mut usize) {
+    let header_start = cfg.blocks[cfg.loops[l].header].start;
+    let label = *labels;
+    if pre.jump.is_none() {
+        *labels += 1;
+    }
+    let mut pre_ops: Vec<Op> = edit.hoisted.iter().map(|&i| ops[i].clone()).collect();
+    let hoisted: BTreeSet<usize> = edit.hoisted.iter().cloned().collect();
+    pre_ops.extend(edit.before.drain(..));
+    let start_vars: Vec<Var> = edit.header_vars.iter().map(|&(_, start)| start).collect();
+
+    let old_ops = ::std::mem::replace(ops, vec!());
+    for (i, mut op) in old_ops.into_iter().enumerate() {
+        if hoisted.contains(&i) {
+            continue;
+        }
+        if let Some(val) = edit.replaced.remove(&i) {
+            op.val = val;
+        }
+        rename_uses(&mut op.val, &edit.renamed);
+
+        if pre.jump == Some(i) {
+            ops.extend(pre_ops.drain(..));
+            jump_vars(&mut op.val).extend(start_vars.iter().cloned());
+        } else if pre.jump.is_none() && pre.entries.contains(&i) {
+            match op.val {
+                OpNode::Goto(ref mut target, _) |
+                OpNode::CondGoto(_, _, ref mut target, _) => *target = label,
+                _ => unreachable!(),
+            }
+        } else if pre.jump.is_none() && i == header_start {
+            ops.push(WithId { id: op.id, val: OpNode::Label(label, pre.vars.clone()) });
+            ops.extend(pre_ops.drain(..));
+            let mut vars = pre.vars.clone();
+            vars.extend(start_vars.iter().cloned());
+            ops.push(WithId { id: op.id, val: OpNode::Goto(pre.header, vars) });
+        }
+
+        if i == header_start {
+            jump_vars(&mut op.val).extend(edit.header_vars.iter().map(|&(v, _)| v));
+        }
+        if let Some(vars) = edit.back_edge_vars.remove(&i) {
+            jump_vars(&mut op.val).extend(vars.into_iter());
+        }
+        ops.push(op);
+        if let Some(after) = edit.after.remove(&i) {
+            ops.extend(after.into_iter());
+        }
+    }
+}
+
+impl Context {
+    // Move what's invariant in loop `l` to its preheader.
+    fn hoist(&mut self, ops: &mut Vec<Op>, cfg: &CFG, l: usize) -> bool {
+        let sites = def_sites(ops);
+        // What we've found, in the order we found it: nothing is hoisted
+        // before the ops defining what it uses, so that's the order they
+        // go in the preheader.
+        let mut hoisted = BTreeSet::new();
+        let mut order = vec!();
+        loop {
+            let mut changed = false;
+            for &b in cfg.rpo.iter().filter(|&&b| cfg.loops[l].blocks.contains(&b)) {
+                for i in cfg.blocks[b].start .. cfg.blocks[b].end {
+                    let ref op = ops[i].val;
+                    if hoisted.contains(&i) || !is_movable(op) ||
+                        defs(op).iter().any(|v| self.memory.contains(&v.name) ||
+                                            self.pinned.contains(v)) {
+                        continue;
+                    }
+                    let invariant = uses(op).iter().all(|v| {
+                        !self.memory.contains(&v.name) && match sites.get(v) {
+                            Some(&d) => !in_loop(cfg, l, d) || hoisted.contains(&d),
+                            None => true,
+                        }
+                    });
+                    if invariant {
+                        hoisted.insert(i);
+                        order.push(i);
+                        changed = true;
+                    }
+                }
+            }
+            if !changed {
+                break;
+            }
+        }
+        if hoisted.is_empty() {
+            return false;
+        }
+
+        let pre = match preheader(ops, cfg, l) {
+            Some(pre) => pre,
+            None => return false,
+        };
+        if self.verbose {
+            print!("hoisting {} ops out of the loop at label {}\n",
+                   hoisted.len(), pre.header);
+        }
+        let mut edit = Edit::new();
+        edit.hoisted = order;
+        apply(ops, cfg, l, pre, edit, &mut self.labels);
+        true
+    }
+
+    // Give the values in loop `l` that go up by a constant each iteration,
+    // and that take a multiply to compute, variables of their own.
+    fn reduce(&mut self, ops: &mut Vec<Op>, cfg: &CFG, l: usize) -> bool {
+        let pre = match preheader(ops, cfg, l) {
+            Some(pre) => pre,
+            None => return false,
+        };
+        let sites = def_sites(ops);
+        let header_vars: Vec<Var> = match ops[cfg.blocks[cfg.loops[l].header].start].val {
+            OpNode::Label(_, ref vars) => vars.iter().cloned().collect(),
+            _ => return false,
+        };
+        let back_edges: Vec<usize> = (0..ops.len())
+            .filter(|&i| in_loop(cfg, l, i) && jump_target(&ops[i].val) == Some(pre.header))
+            .collect();
+
+        // For each basic induction variable, what each back edge adds to it,
+        // and the op that does the adding.
+        let mut basic: BTreeMap<Var, Vec<(usize, u32, Option<usize>)>> = BTreeMap::new();
+        for &iv in header_vars.iter() {
+            if self.memory.contains(&iv.name) {
+                continue;
+            }
+            let mut steps = vec!();
+            for &j in back_edges.iter() {
+                let passed = match ops[j].val {
+                    OpNode::Goto(_, ref vars) |
+                    OpNode::CondGoto(_, _, _, ref vars) =>
+                        *vars.iter().filter(|v| v.name == iv.name).next().unwrap(),
+                    _ => unreachable!(),
+                };
+                let passed = resolve(passed, ops, &sites);
+                if passed == iv {
+                    steps.push((j, 0, None));
+                    continue;
+                }
+                let d = match sites.get(&passed) {
+                    Some(&d) => d,
+                    None => break,
+                };
+                let step = match ops[d].val {
+                    OpNode::BinOp(_, PlusOp, Variable(x), Constant(NumLit(c, _)), _) |
+                    OpNode::BinOp(_, PlusOp, Constant(NumLit(c, _)), Variable(x), _)
+                        if resolve(x, ops, &sites) == iv => c as u32,
+                    OpNode::BinOp(_, MinusOp, Variable(x), Constant(NumLit(c, _)), _)
+                        if resolve(x, ops, &sites) == iv => (c as u32).wrapping_neg(),
+                    _ => break,
+                };
+                steps.push((j, step, Some(d)));
+            }
+            if steps.len() == back_edges.len() {
+                basic.insert(iv, steps);
+            }
+        }
+        if basic.is_empty() {
+            return false;
+        }
+
+        let memory = &self.memory;
+        let invariant = |rve: &RValueElem| match *rve {
+            Variable(ref v) => !memory.contains(&v.name) && match sites.get(v) {
+                Some(&d) => !in_loop(cfg, l, d),
+                None => true,
+            },
+            Constant(NumLit(..)) => true,
+            Constant(..) => false,
+        };
+
+        // The variables that are affine in a basic induction variable, and
+        // the ops that compute them.
+        let mut affine: BTreeMap<Var, Affine> = basic.keys().map(|&iv| {
+            (iv, Affine { iv: iv, scale: 1, multiplied: false })
+        }).collect();
+        let mut chain = BTreeMap::new();
+        for &b in cfg.rpo.iter().filter(|&&b| cfg.loops[l].blocks.contains(&b)) {
+            for i in cfg.blocks[b].start .. cfg.blocks[b].end {
+                let found = match ops[i].val {
+                    OpNode::BinOp(x, binop, ref lhs, ref rhs, _) => {
+                        let get = |rve: &RValueElem| match *rve {
+                            Variable(ref v) => affine.get(v).cloned(),
+                            Constant(..) => None,
+                        };
+                        match (binop, get(lhs), get(rhs)) {
+                            (PlusOp, Some(a), None) |
+                            (MinusOp, Some(a), None) if invariant(rhs) => Some((x, a)),
+                            (PlusOp, None, Some(a)) if invariant(lhs) => Some((x, a)),
+                            (MinusOp, None, Some(a)) if invariant(lhs) =>
+                                Some((x, Affine { scale: a.scale.wrapping_neg(), ..a })),
+                            (TimesOp, Some(a), None) |
+                            (TimesOp, None, Some(a)) => match (lhs, rhs) {
+                                (&Constant(NumLit(k, _)), _) |
+                                (_, &Constant(NumLit(k, _))) => Some((x, Affine {
+                                    scale: a.scale.wrapping_mul(k as u32),
+                                    multiplied: true,
+                                    ..a
+                                })),
+                                _ => None,
+                            },
+                            (LeftShiftOp, Some(a), None) => match *rhs {
+                                Constant(NumLit(k, _)) if k < 32 => Some((x, Affine {
+                                    scale: a.scale << k,
+                                    multiplied: true,
+                                    ..a
+                                })),
+                                _ => None,
+                            },
+                            _ => None,
+                        }
+                    },
+                    OpNode::UnOp(x, Identity, Variable(ref y)) =>
+                        affine.get(y).cloned().map(|a| (x, a)),
+                    _ => None,
+                };
+                match found {
+                    Some((x, a)) if !memory.contains(&x.name) && !self.pinned.contains(&x) => {
+                        affine.insert(x, a);
+                        chain.insert(x, i);
+                    },
+                    _ => {},
+                }
+            }
+        }
+
+        // The ones worth their own variable are those that took a multiply,
+        // and that something other than another of them uses.
+        let mut used_elsewhere = BTreeSet::new();
+        for op in ops.iter() {
+            let defs = defs(&op.val);
+            if defs.is_empty() || defs.iter().any(|v| !chain.contains_key(v)) {
+                used_elsewhere.extend(uses(&op.val).into_iter().cloned());
+            }
+        }
+        let mut reduced: Vec<(usize, Var)> = chain.iter()
+            .filter(|&(x, &i)| {
+                affine[x].multiplied && used_elsewhere.contains(x) && match ops[i].val {
+                    OpNode::BinOp(..) => true,
+                    _ => false,
+                }
+            })
+            .map(|(&x, &i)| (i, x))
+            .collect();
+        reduced.sort();
+
+        let mut edit = Edit::new();
+        for &(i, x) in reduced.iter() {
+            let a = affine[&x];
+            let name = self.temps.name();
+            let var = |generation| Var { name: name, generation: Some(generation) };
+            let id = ops[i].id;
+            let signed = match ops[i].val {
+                OpNode::BinOp(_, _, _, _, signed) => signed,
+                _ => unreachable!(),
+            };
+
+            // Its value on the first iteration: the ops it takes, with the
+            // induction variable's starting value.
+            let mut deps = BTreeSet::new();
+            let mut work = vec!(x);
+            while let Some(v) = work.pop() {
+                match chain.get(&v) {
+                    Some(&d) => if deps.insert(d) {
+                        work.extend(uses(&ops[d].val).into_iter().cloned());
+                    },
+                    None => {},
+                }
+            }
+            let mut starts = BTreeMap::new();
+            starts.insert(a.iv, pre.entry[&a.iv.name]);
+            for &d in deps.iter() {
+                let dest = if d == i { var(1) } else { self.temps.var(true) };
+                let val = rewrite(&ops[d].val, dest, &starts);
+                edit.before.push(WithId { id: ops[d].id, val: val });
+                starts.insert(*defs(&ops[d].val)[0], dest);
+            }
+            edit.header_vars.push((var(2), var(1)));
+
+            // Bumped along with the induction variable.
+            let mut next_gen = 3;
+            let mut bumped = BTreeMap::new();
+            for &(j, step, d) in basic[&a.iv].iter() {
+                let next = match d {
+                    None => var(2),
+                    Some(d) if bumped.contains_key(&d) => bumped[&d],
+                    Some(d) => {
+                        let next = var(next_gen);
+                        next_gen += 1;
+                        let step = step.wrapping_mul(a.scale);
+                        let lit = if signed {
+                            NumLit(step as i32 as i64 as u64, IntKind::GenericInt)
+                        } else {
+                            NumLit(step as u64, IntKind::UnsignedInt(Width32))
+                        };
+                        let bump = OpNode::BinOp(next, PlusOp, Variable(var(2)),
+                                                 Constant(lit), signed);
+                        if !edit.after.contains_key(&d) {
+                            edit.after.insert(d, vec!());
+                        }
+                        edit.after.get_mut(&d).unwrap().push(WithId { id: id, val: bump });
+                        bumped.insert(d, next);
+                        next
+                    },
+                };
+                if !edit.back_edge_vars.contains_key(&j) {
+                    edit.back_edge_vars.insert(j, vec!());
+                }
+                edit.back_edge_vars.get_mut(&j).unwrap().push(next);
+            }
+
+            if self.verbose {
+                print!("{} is an induction variable, now {}\n", x, var(2));
+            }
+            edit.replaced.insert(i, OpNode::UnOp(x, Identity, Variable(var(2))));
+            edit.renamed.insert(x, var(2));
+        }
+        if reduced.is_empty() {
+            return false;
+        }
+        apply(ops, cfg, l, pre, edit, &mut self.labels);
+        true
+    }
+}
+
+// `op`, a unop or binop, assigning `dest` instead, and using the variables
+// in `map` in place of those they stand for.
+fn rewrite(op: &OpNode, dest: Var, map: &BTreeMap<Var, Var>) -> OpNode {
+    let sub = |rve: &RValueElem| {
+        let mut rve = rve.clone();
+        rename_rve(&mut rve, map);
+        rve
+    };
+    match *op {
+        OpNode::UnOp(_, unop, ref rve) => OpNode::UnOp(dest, unop, sub(rve)),
+        OpNode::BinOp(_, binop, ref lhs, ref rhs, signed) =>
+            OpNode::BinOp(dest, binop, sub(lhs), sub(rhs), signed),
+        _ => unreachable!(),
+    }
+}
+
+// The label that starts loop `l`'s header.
+fn header_label(ops: &Vec<Op>, cfg: &CFG, l: usize) -> usize {
+    match ops[cfg.blocks[cfg.loops[l].header].start].val {
+        OpNode::Label(label, _) => label,
+        _ => unreachable!(),
+    }
+}
+
+impl LoopOptimizer {
+    /// Hoist invariant code out of the loops in `ops`, and reduce the
+    /// strength of their induction variables. `labels` is the next label no
+    /// function in the program uses; see `program_label_count`.
+    pub fn optimize(ops: &mut Vec<Op>, globals: &BTreeMap<Name, StaticIRItem>,
+                    labels: &mut usize, verbose: bool) {
+        // Externs have no loops, and we need SSA form.
+        if ops.len() <= 1 ||
+            ops.iter().any(|op| defs(&op.val).iter().any(|v| v.generation.is_none())) {
+            return;
+        }
+
+        let mut pinned = BTreeSet::new();
+        for op in ops.iter() {
+            match op.val {
+                OpNode::Call(v, _, ref args) => {
+                    pinned.insert(v);
+                    pinned.extend(args.iter().cloned());
+                },
+                OpNode::Func(_, ref params, _) => pinned.extend(params.iter().cloned()),
+                _ => {},
+            }
+        }
+        let mut context = Context {
+            memory: memory_names(ops, globals),
+            pinned: pinned,
+            temps: Temps::new(ops),
+            labels: *labels,
+            verbose: verbose,
+        };
+
+        // Each change moves ops and labels around, so we start over with a
+        // new CFG after it. Inner loops come last in `cfg.loops`.
+        loop {
+            let cfg = CFG::new(ops);
+            if !(0..cfg.loops.len()).rev().any(|l| context.hoist(ops, &cfg, l)) {
+                break;
+            }
+        }
+
+        let mut done = BTreeSet::new();
+        loop {
+            let cfg = CFG::new(ops);
+            let mut changed = false;
+            for l in (0..cfg.loops.len()).rev() {
+                if done.insert(header_label(ops, &cfg, l)) && context.reduce(ops, &cfg, l) {
+                    changed = true;
+                    break;
+                }
+            }
+            if !changed {
+                break;
+            }
+        }
+        *labels = context.labels;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::LoopOptimizer;
+    use ir::*;
+    use ir::cfg::CFG;
+    use ir::util::program_label_count;
+    use mc::ast::{TimesOp, LeftShiftOp};
+    use testing::{run_pass, run_asm_source, optimize_source, find_func};
+
+    fn optimize(src: &str) -> String {
+        run_pass(src, true, true, |program, global_map| {
+            let mut labels = program_label_count(program);
+            LoopOptimizer::optimize(&mut program[0], global_map, &mut labels, false);
+        })
+    }
+
+    #[test]
+    fn test_hoisting() {
+        let result = optimize("global %print_int size 0 offset 0 extern func
+global %g size 4 offset 0
+fn %__main()
+    %n<1> := 10
+    %i<1> := 0
+    goto 0(%i<1>)
+    label 0(%i<2>)
+    %c<1> := %i<2> <s %n<1>
+    if !%c<1> goto 1()
+    %k<1> := %n<1> *s 3
+    %l<1> := %k<1> +s 1
+    %d<1> := 100 /s %n<1>
+    %h<1> := %g<0> +s %l<1>
+    %e<1> := %l<1>
+    %r<1> := call %print_int<0>(%e<1>)
+    %i<3> := %i<2> +s %d<1>
+    goto 0(%i<3>)
+    label 1()
+    return 0
+fn %print_int(%x) extern \"C\"
+");
+        let header = result.find("label 0").unwrap();
+        // Invariant, and out of the loop now, in the same order.
+        let k = result.find("%k<1> := %n<1> *s 3").unwrap();
+        let l = result.find("%l<1> := %k<1> +s 1").unwrap();
+        assert!(k < l && l < header, "{}", result);
+        // Division by a variable might trap, globals can change, and call
+        // arguments stay next to their calls.
+        assert!(result.find("%d<1> := 100 /s %n<1>").unwrap() > header, "{}", result);
+        assert!(result.find("%h<1> := %g<0> +s %l<1>").unwrap() > header, "{}", result);
+        assert!(result.find("%e<1> := %l<1>").unwrap() > header, "{}", result);
+    }
+
+    #[test]
+    fn test_hoisting_order() {
+        // The block computing %k<1> comes after the one that uses it.
+        let result = optimize("global %print_int size 0 offset 0 extern func
+fn %__main()
+    %n<1> := 10
+    %i<1> := 0
+    goto 0(%i<1>)
+    label 0(%i<2>)
+    %c<1> := %i<2> <s %n<1>
+    if !%c<1> goto 1()
+    goto 2()
+    label 3()
+    %l<1> := %k<1> +s 1
+    %e<1> := %l<1>
+    %r<1> := call %print_int<0>(%e<1>)
+    %i<3> := %i<2> +s 1
+    goto 0(%i<3>)
+    label 2()
+    %k<1> := %n<1> *s 3
+    goto 3()
+    label 1()
+    return 0
+fn %print_int(%x) extern \"C\"
+");
+        let header = result.find("label 0").unwrap();
+        let k = result.find("%k<1> := %n<1> *s 3").unwrap();
+        let l = result.find("%l<1> := %k<1> +s 1").unwrap();
+        assert!(k < l && l < header, "{}", result);
+    }
+
+    #[test]
+    fn test_preheader() {
+        // Two ways into the loop, so it needs a block of its own in front.
+        let result = optimize("global %print_int size 0 offset 0 extern func
+fn %__main()
+    %n<1> := 2
+    %a<1> := 1
+    %c<1> := %n<1> <s 5
+    if %c<1> goto 0(%a<1>)
+    %a<2> := 3
+    goto 0(%a<2>)
+    label 0(%a<3>)
+    %b<1> := %n<1> *s 7
+    %x<1> := %a<3> +s %b<1>
+    %a<4> := %x<1>
+    %d<1> := %a<4> <s 100
+    if %d<1> goto 0(%a<4>)
+    %r<1> := call %print_int<0>(%a<4>)
+    return 0
+fn %print_int(%x) extern \"C\"
+");
+        assert!(result.contains("if %c<1> goto 1(%a<1>)"), "{}", result);
+        assert!(result.contains("goto 1(%a<2>)"), "{}", result);
+        assert!(result.contains("label 1(%a<5>)\n    %b<1> := %n<1> *s 7\n    goto 0(%a<5>)\n"),
+                "{}", result);
+        assert!(result.contains("if %d<1> goto 0(%a<4>)"), "{}", result);
+    }
+
+    #[test]
+    fn test_asm_labels() {
+        // Jump folding leaves the loop in `scale` with two ways in, so it
+        // gets a preheader label. The asm target doesn't qualify labels with
+        // their function, so that can't be a label `main` uses.
+        let result = run_asm_source("
+            #[inline(never)]
+            fn scale(x: u32, n: u32) -> u32 {
+                let a: u32 = 1;
+                if x > 3 { a = 3; }
+                do {
+                    a += x * 7;
+                } while a < n;
+                a
+            }
+
+            fn main() -> u32 {
+                let i: u32 = 1;
+                while i < 7 {
+                    print_uint(scale(i, 50));
+                    i += 1;
+                }
+                0
+            }", &[("passes", "dce,licm")]);
+        assert_eq!(result, Ok("50\n57\n64\n59\n73\n87\n".to_string()));
+    }
+
+    #[test]
+    fn test_induction_variables() {
+        let result = optimize("global %print_int size 0 offset 0 extern func
+fn %__main()
+    %a<1> := alloca 40
+    %i<1> := 0
+    goto 0(%i<1>)
+    label 0(%i<2>)
+    %c<1> := %i<2> <s 10
+    if !%c<1> goto 1()
+    %t<1> := %i<2> *u 4u32
+    %p<1> := %a<1> +u %t<1>
+    store32 %p<1>, %i<2>
+    %i<3> := %i<2> +s 1
+    %i<4> := %i<3>
+    goto 0(%i<4>)
+    label 1()
+    %q<1> := %a<1> +u 36u32
+    %x<1> := load32 %q<1>
+    %r<1> := call %print_int<0>(%x<1>)
+    return 0
+fn %print_int(%x) extern \"C\"
+");
+        // The address starts at a[0] and goes up by 4 along with %i.
+        assert!(result.contains("%TEMP$1<1> := %i<1> *u 4u32\n    \
+                                 %TEMP$0<1> := %a<1> +u %TEMP$1<1>\n    \
+                                 goto 0(%i<1>, %TEMP$0<1>)"), "{}", result);
+        assert!(result.contains("%i<3> := %i<2> +s 1\n    %TEMP$0<3> := %TEMP$0<2> +u 4u32\n"),
+                "{}", result);
+        assert!(result.contains("goto 0(%i<4>, %TEMP$0<3>)"), "{}", result);
+        assert!(result.contains("label 0(%i<2>, %TEMP$0<2>)"), "{}", result);
+        assert!(result.contains("store32 %TEMP$0<2>, %i<2>"), "{}", result);
+    }
+
+    #[test]
+    fn test_loop_optimization() {
+        let program = optimize_source("
+            fn main() -> u32 {
+                let a: u32[20];
+                let i: u32 = 0;
+                while i < 20 {
+                    a[i] = i * i;
+                    i += 1;
+                }
+                let total: u32 = 0;
+                for (i = 0; i < 20; i += 2) {
+                    total += a[i] + a[i + 1];
+                }
+                print_uint(total);
+                0
+            }", 2);
+        // The element addresses are bumped along with `i` instead of being
+        // computed from it.
+        let main = find_func(&program, "__main");
+        let cfg = CFG::new(main);
+        for (i, op) in main.iter().enumerate() {
+            match op.val {
+                OpNode::BinOp(_, TimesOp, _, Constant(..), _) |
+                OpNode::BinOp(_, LeftShiftOp, _, Constant(..), _) =>
+                    assert!(cfg.loop_of[cfg.block_of[i]].is_none(), "{}", op.val),
+                _ => {},
+            }
+        }
+    }
+}
@@ -1,17 +1,13 @@
 // Liveness analysis
-use time::precise_time_ns;
-
 use std::collections::BTreeSet;
 use std::cmp::Eq;
-use std::iter::FromIterator;
-use std::mem::swap;
 
 use mc::ast::*;
 
 use ir::*;
+use ir::cfg::CFG;
 use values::*;
 
-
 pub struct LivenessAnalyzer;
 
 fn seed_rve(opinfo: &mut OpInfo, rve: &RValueElem) {
@@ -21,7 +17,7 @@ fn seed_rve(opinfo: &mut OpInfo, rve: &RValueElem) {
     }
 }
 
-fn seed(ops: &Vec<Op>, opinfo: &mut Vec<OpInfo>) {
+fn seed(ops: &Vec<Op>, opinfo: &mut Vec<OpInfo>, cfg: &CFG) {
     let len = ops.len();
     if len == 1 { return; } // No instructions; probably extern.
                             // TODO: this is somewhat fragile...
@@ -33,34 +29,18 @@ fn seed(ops: &Vec<Op>, opinfo: &mut Vec<OpInfo>) {
                 opinfo.def.insert(lv.clone());
                 seed_rve(opinfo, rve1);
                 seed_rve(opinfo, rve2);
-
-                if u + 1 < len {
-                    opinfo.succ.insert(u + 1);
-                }
             },
             OpNode::UnOp(ref lv, _, ref rve) => {
                 opinfo.def.insert(lv.clone());
                 seed_rve(opinfo, rve);
-
-                if u + 1 < len {
-                    opinfo.succ.insert(u + 1);
-                }
             },
             OpNode::Load(ref lv, ref rv, _) => {
                 opinfo.def.insert(lv.clone());
                 opinfo.used.insert(rv.clone());
-
-                if u + 1 < len {
-                    opinfo.succ.insert(u + 1);
-                }
             },
             OpNode::Store(ref v1, ref v2, _) => {
                 opinfo.used.insert(v1.clone());
                 opinfo.used.insert(v2.clone());
-
-                if u + 1 < len {
-                    opinfo.succ.insert(u + 1);
-                }
             },
             OpNode::Call(ref lv, ref f, ref args) => {
                 opinfo.def.insert(lv.clone());
@@ -68,62 +48,25 @@ fn seed(ops: &Vec<Op>, opinfo: &mut Vec<OpInfo>) {
                 for arg in args.iter() {
                     opinfo.used.insert(arg.clone());
                 }
-
-                if u + 1 < len {
-                    opinfo.succ.insert(u + 1);
-                }
             },
             OpNode::Alloca(lv, _) => {
                 opinfo.def.insert(lv.clone());
-
-                if u + 1 < len {
-                    opinfo.succ.insert(u + 1);
-                }
             },
-            OpNode::Nop => {
-                if u + 1 < len {
-                    opinfo.succ.insert(u + 1);
-                }
-            }
+            OpNode::Nop => {},
             OpNode::Label(_, ref vars) => {
-                if u + 1 < len {
-                    opinfo.succ.insert(u + 1);
-                }
                 for var in vars.iter() {
                     opinfo.def.insert(var.clone());
                 }
             },
-            OpNode::Goto(ref l, ref vars) => {
-                for u2 in 0..len {
-                    match ops[u2].val {
-                        OpNode::Label(l2, _) if *l == l2 => {
-                            opinfo.succ.insert(u2);
-                            break;
-                        },
-                        _ => {},
-                    }
-                }
+            OpNode::Goto(_, ref vars) => {
                 for var in vars.iter() {
                     opinfo.used.insert(var.clone());
                 }
             },
-            // TODO: get rid of redundant code.
-            OpNode::CondGoto(_, ref rve, ref l, ref vars) => {
-                for u2 in 0..len {
-                    match ops[u2].val {
-                        OpNode::Label(l2, _) if *l == l2 => {
-                            opinfo.succ.insert(u2);
-                            break;
-                        },
-                        _ => {},
-                    }
-                }
+            OpNode::CondGoto(_, ref rve, _, ref vars) => {
                 for var in vars.iter() {
                     opinfo.used.insert(var.clone());
                 }
-                if u + 1 < len {
-                    opinfo.succ.insert(u + 1);
-                }
                 match *rve {
                     Variable(ref v) => { opinfo.used.insert(v.clone()); },
                     _ => {},
@@ -139,101 +82,77 @@ fn seed(ops: &Vec<Op>, opinfo: &mut Vec<OpInfo>) {
                 for v in vars.iter() {
                     opinfo.def.insert(v.clone());
                 }
-                opinfo.succ.insert(u + 1);
             },
             // TODO: fill this in, when ready.
             OpNode::AsmOp(..) => {},
         }
+        opinfo.succ.extend(cfg.op_succ(u).into_iter());
     }
 }
 
-fn propagate_once(opinfo: &mut Vec<OpInfo>,
-                  active_ops: BTreeSet<usize>,
-                  predecessors: &Vec<BTreeSet<usize>>) -> BTreeSet<usize> {
-    let mut new_active_ops = BTreeSet::<usize>::new();
-    for u in active_ops.into_iter() {
-        // Making this dummy structure is sort of unfortunate.
-        // There are ways around it, but it is better than the old
-        // clone!
-        let mut this_opinfo = OpInfo::new();
-        // This working relies on instructions not being able to be their own
-        // successor. If they were, then we'd be emptying information we need.
-        swap(&mut this_opinfo, opinfo.get_mut(u).unwrap());
-
-        for usedvar in this_opinfo.used.iter() {
-            if this_opinfo.live.insert(usedvar.clone()) {
-                new_active_ops.insert(u);
-                for &predecessor in predecessors[u].iter() {
-                    new_active_ops.insert(predecessor);
-                }
-            }
-        }
-        for next_idx in this_opinfo.succ.iter() {
-            let next_opinfo = opinfo.get_mut(*next_idx).unwrap();
-            for livevar in next_opinfo.live.iter() {
-                if !this_opinfo.def.contains(livevar) {
-                    if this_opinfo.live.insert(livevar.clone()) {
-                        for &predecessor in predecessors[u].iter() {
-                            new_active_ops.insert(predecessor);
-                        }
-                    }
-                }
-            }
-        }
-        *opinfo.get_mut(u).unwrap() = this_opinfo;
+// Work out the live variables at each op of block b, given those live on
+// entry to its successors. Returns the ones live on entry to b.
+fn propagate_block(opinfo: &mut Vec<OpInfo>, cfg: &CFG, b: usize,
+                   live_in: &Vec<BTreeSet<Var>>) -> BTreeSet<Var> {
+    let ref block = cfg.blocks[b];
+    let mut live = BTreeSet::new();
+    for &s in block.succ.iter() {
+        live.extend(live_in[s].iter().cloned());
     }
-
-    new_active_ops
-}
-
-static mut seed_time: u64 = 0;
-static mut propagate_time: u64 = 0;
-
-pub fn get_liveness_times() -> (u64, u64) {
-    unsafe {
-        (seed_time, propagate_time)
+    for u in (block.start .. block.end).rev() {
+        let info = opinfo.get_mut(u).unwrap();
+        live = live.difference(&info.def).cloned().collect();
+        live.extend(info.used.iter().cloned());
+        info.live = live.clone();
     }
+    live
 }
 
-fn propagate(ops: &Vec<Op>, opinfo: &mut Vec<OpInfo>) {
-    let start = precise_time_ns();
-
-    let mut active_ops: BTreeSet<usize> = FromIterator::from_iter(0..ops.len());
-    let mut predecessors: Vec<BTreeSet<usize>> = (0..ops.len()).map(|_| BTreeSet::new()).collect();
-    for idx in 0..ops.len() {
-        for &successor in opinfo.get(idx).unwrap().succ.iter() {
-            predecessors[successor].insert(idx);
+fn propagate(opinfo: &mut Vec<OpInfo>, cfg: &CFG) {
+    // Liveness flows backwards, so visiting blocks in postorder means we
+    // usually see a block's successors before it. Unreachable blocks go
+    // last.
+    let mut order: Vec<usize> = cfg.rpo.iter().rev().cloned().collect();
+    order.extend((0 .. cfg.blocks.len()).rev().filter(|&b| !cfg.is_reachable(b)));
+
+    let mut live_in: Vec<BTreeSet<Var>> =
+        (0 .. cfg.blocks.len()).map(|_| BTreeSet::new()).collect();
+    let mut changed = true;
+    while changed {
+        changed = false;
+        for &b in order.iter() {
+            let new_live_in = propagate_block(opinfo, cfg, b, &live_in);
+            if new_live_in != live_in[b] {
+                live_in[b] = new_live_in;
+                changed = true;
+            }
         }
     }
-
-    while active_ops.len() > 0 {
-        active_ops = propagate_once(opinfo, active_ops, &predecessors);
-    }
-    let end = precise_time_ns();
-    unsafe {
-        propagate_time += end-start;
-    }
 }
 
 impl LivenessAnalyzer {
     // Gives back the seeded data. This is mostly useful for getting
     // defs.
     pub fn unanalyzed_opinfo(ops: &Vec<Op>) -> Vec<OpInfo> {
+        LivenessAnalyzer::unanalyzed_opinfo_cfg(ops, &CFG::new(ops))
+    }
+
+    fn unanalyzed_opinfo_cfg(ops: &Vec<Op>, cfg: &CFG) -> Vec<OpInfo> {
         let len = ops.len();
         let mut opinfo = (0..len).map(|_| OpInfo::new()).collect();
-        let start = precise_time_ns();
-        seed(ops, &mut opinfo);
-        let end = precise_time_ns();
-        unsafe {
-            seed_time += end-start;
-        }
+        seed(ops, &mut opinfo, cfg);
 
         opinfo
     }
 
     pub fn analyze(ops: &Vec<Op>) -> Vec<OpInfo> {
-        let mut opinfo = LivenessAnalyzer::unanalyzed_opinfo(ops);
-        propagate(ops, &mut opinfo);
+        LivenessAnalyzer::analyze_cfg(ops, &CFG::new(ops))
+    }
+
+    /// Like `analyze`, for when the caller already has the CFG.
+    pub fn analyze_cfg(ops: &Vec<Op>, cfg: &CFG) -> Vec<OpInfo> {
+        let mut opinfo = LivenessAnalyzer::unanalyzed_opinfo_cfg(ops, cfg);
+        propagate(&mut opinfo, cfg);
 
         opinfo
     }
@@ -14,11 +14,22 @@ pub use self::RValueElem::*;
 use self::OpNode::*;
 
 pub mod ast_to_intermediate;
+pub mod cfg;
 pub mod liveness;
 pub mod constant_fold;
+pub mod dce;
+pub mod gvn;
+pub mod inline;
+pub mod licm;
+pub mod unroll;
 pub mod ssa;
+pub mod strength_reduce;
 pub mod util;
 pub mod conflicts;
+pub mod interp;
+pub mod passes;
+pub mod text;
+pub mod verify;
 
 #[derive(Clone, Eq, PartialEq, Debug)]
 pub struct StaticIRItem {
@@ -31,6 +42,12 @@ pub struct StaticIRItem {
     pub is_func: bool, // Is this a function? (If so, size and is_ref are
                        // ignored).
     pub expr: Option<Expr>,
+    // If nothing writes to this global after initializing it, the value it's
+    // initialized to. See `constant_fold::find_const_globals`.
+    pub const_value: Option<LitNode>,
+    // For functions: Some(true) for `#[inline]`, Some(false) for
+    // `#[inline(never)]`. See `inline`.
+    pub inline: Option<bool>,
 }
 
 allow_string!(StaticIRItem);
@@ -0,0 +1,274 @@
+//! The IR optimization pipeline.
+//!
+//! Passes run in order, each over every function before the next starts;
+//! most of them look at one function at a time, but some need the whole
+//! program: `inline`, and the passes that add labels, since labels are
+//! numbered across the program. `-O` picks one of the
+//! preset lists below and `--passes` replaces it. The targets need SSA
+//! form, so they always start with `ssa` even if it isn't asked for. In
+//! debug builds, each function is verified after every pass.
+
+use ir::*;
+use ir::constant_fold::ConstantFolder;
+use ir::dce::DeadCodeEliminator;
+use ir::gvn::ValueNumberer;
+use ir::inline::Inliner;
+use ir::licm::LoopOptimizer;
+use ir::ssa::ToSSA;
+use ir::strength_reduce::StrengthReducer;
+use ir::text::write_function;
+use ir::unroll::Unroller;
+use ir::util::program_label_count;
+use ir::verify::verify;
+use mc::ast::NodeId;
+use util::Name;
+use util::timing::time;
+
+use std::collections::{BTreeMap, BTreeSet};
+use std::io;
+use std::io::Write;
+
+pub type PassFn = fn(&mut Vec<Op>, &BTreeMap<Name, StaticIRItem>, bool);
+pub type ProgramPassFn = fn(&mut Vec<Vec<Op>>, &BTreeMap<Name, StaticIRItem>, bool);
+
+pub enum PassKind {
+    /// Runs over each function on its own.
+    Function(PassFn),
+    /// Runs over the whole program at once.
+    Program(ProgramPassFn),
+}
+
+pub struct Pass {
+    pub name: &'static str,
+    pub kind: PassKind,
+}
+
+impl Pass {
+    pub fn run(&self, program: &mut Vec<Vec<Op>>, global_map: &BTreeMap<Name, StaticIRItem>,
+               verbose: bool) {
+        match self.kind {
+            PassKind::Function(run) => for ops in program.iter_mut() {
+                run(ops, global_map, verbose);
+            },
+            PassKind::Program(run) => run(program, global_map, verbose),
+        }
+    }
+}
+
+fn ssa(ops: &mut Vec<Op>, _: &BTreeMap<Name, StaticIRItem>, verbose: bool) {
+    ToSSA::to_ssa(ops, verbose);
+}
+
+fn inline(program: &mut Vec<Vec<Op>>, global_map: &BTreeMap<Name, StaticIRItem>,
+          verbose: bool) {
+    Inliner::inline(program, global_map, verbose);
+}
+
+fn constant_fold(ops: &mut Vec<Op>, global_map: &BTreeMap<Name, StaticIRItem>,
+                 verbose: bool) {
+    ConstantFolder::fold(ops, global_map, verbose);
+}
+
+fn gvn(ops: &mut Vec<Op>, global_map: &BTreeMap<Name, StaticIRItem>, verbose: bool) {
+    ValueNumberer::number(ops, global_map, verbose);
+}
+
+fn licm(program: &mut Vec<Vec<Op>>, global_map: &BTreeMap<Name, StaticIRItem>,
+        verbose: bool) {
+    let mut labels = program_label_count(program);
+    for ops in program.iter_mut() {
+        LoopOptimizer::optimize(ops, global_map, &mut labels, verbose);
+    }
+}
+
+fn unroll(program: &mut Vec<Vec<Op>>, global_map: &BTreeMap<Name, StaticIRItem>,
+          verbose: bool) {
+    let mut labels = program_label_count(program);
+    for ops in program.iter_mut() {
+        Unroller::unroll(ops, global_map, &mut labels, verbose);
+    }
+}
+
+fn strength_reduce(ops: &mut Vec<Op>, _: &BTreeMap<Name, StaticIRItem>, verbose: bool) {
+    StrengthReducer::reduce(ops, verbose);
+}
+
+fn dce(ops: &mut Vec<Op>, global_map: &BTreeMap<Name, StaticIRItem>, verbose: bool) {
+    DeadCodeEliminator::eliminate(ops, global_map, verbose);
+}
+
+pub static PASSES: [Pass; 8] = [
+    Pass { name: "ssa", kind: PassKind::Function(ssa) },
+    Pass { name: "inline", kind: PassKind::Program(inline) },
+    Pass { name: "constant_fold", kind: PassKind::Function(constant_fold) },
+    Pass { name: "gvn", kind: PassKind::Function(gvn) },
+    Pass { name: "licm", kind: PassKind::Program(licm) },
+    Pass { name: "unroll", kind: PassKind::Program(unroll) },
+    Pass { name: "strength_reduce", kind: PassKind::Function(strength_reduce) },
+    Pass { name: "dce", kind: PassKind::Function(dce) },
+];
+
+pub static DEFAULT_OPT_LEVEL: u32 = 1;
+
+/// The passes run at each optimization level.
+pub fn preset(level: u32) -> Vec<&'static str> {
+    match level {
+        0 => vec!("ssa"),
+        1 => vec!("ssa", "inline", "constant_fold", "gvn", "licm", "dce"),
+        _ => vec!("ssa", "inline", "constant_fold", "gvn", "licm", "unroll", "constant_fold",
+                  "strength_reduce", "dce"),
+    }
+}
+
+pub fn find_pass(name: &str) -> Option<&'static Pass> {
+    PASSES.iter().filter(|p| p.name == name).next()
+}
+
+pub struct PassManager {
+    pub passes: Vec<&'static Pass>,
+    /// Passes to print the program's functions after, as IR text on stderr.
+    pub print_after: BTreeSet<String>,
+    /// Whether to check the IR after each pass; see `ir::verify`.
+    pub verify: bool,
+    pub verbose: bool,
+}
+
+impl PassManager {
+    pub fn new(names: &[&str]) -> Result<PassManager, String> {
+        let mut passes = vec!();
+        for name in names.iter() {
+            match find_pass(name) {
+                Some(pass) => passes.push(pass),
+                None => return Err(format!("Unknown pass `{}'", name)),
+            }
+        }
+        Ok(PassManager {
+            passes: passes,
+            print_after: BTreeSet::new(),
+            verify: cfg!(debug_assertions),
+            verbose: false,
+        })
+    }
+
+    /// Set up the pipeline a target asked for with `opt_level`, `passes`,
+    /// `print_after` and `verbose`.
+    pub fn from_args(args: &Vec<(String, Option<String>)>) -> PassManager {
+        let mut level = DEFAULT_OPT_LEVEL;
+        let mut names = None;
+        let mut print_after = BTreeSet::new();
+        let mut verbose = false;
+        for arg in args.iter() {
+            if arg.0 == "opt_level" {
+                level = arg.1.clone().unwrap().parse().unwrap_or_else(
+                    |_| panic!("Invalid optimization level {}", arg.1.clone().unwrap()));
+            } else if arg.0 == "passes" {
+                names = arg.1.clone();
+            } else if arg.0 == "print_after" {
+                print_after.insert(arg.1.clone().unwrap());
+            } else if arg.0 == "verbose" {
+                verbose = true;
+            }
+        }
+
+        let mut names: Vec<String> = match names {
+            Some(names) => names.split(',').filter(|n| !n.is_empty())
+                                .map(|n| n.to_string()).collect(),
+            None => preset(level).iter().map(|n| n.to_string()).collect(),
+        };
+        if names.first().map_or(true, |n| *n != "ssa") {
+            names.insert(0, "ssa".to_string());
+        }
+        for name in print_after.iter() {
+            if find_pass(&name[..]).is_none() {
+                panic!("Unknown pass `{}'", name);
+            }
+        }
+
+        let names: Vec<&str> = names.iter().map(|n| &n[..]).collect();
+        let mut manager = PassManager::new(&names[..]).unwrap_or_else(|e| panic!("{}", e));
+        manager.print_after = print_after;
+        manager.verbose = verbose;
+        manager
+    }
+
+    /// Run every pass over the program's functions. `spans` says where in
+    /// the source each op came from, for reporting ops that fail
+    /// verification; the first one stops the pipeline with an error.
+    pub fn run(&self, program: &mut Vec<Vec<Op>>, global_map: &BTreeMap<Name, StaticIRItem>,
+               spans: &BTreeMap<NodeId, String>) -> Result<(), String> {
+        let mut folded = false;
+        for pass in self.passes.iter() {
+            time(pass.name, || pass.run(program, global_map, self.verbose));
+            folded = folded || pass.name == "constant_fold";
+            if self.verbose {
+                print!("After {}:\n", pass.name);
+                for op in program.iter().flat_map(|ops| ops.iter()) {
+                    print!("{}", op);
+                }
+            }
+            if self.print_after.contains(pass.name) {
+                let mut stderr = io::stderr();
+                write!(stderr, "// after {}:\n", pass.name).ok();
+                for ops in program.iter() {
+                    write_function(&mut stderr, ops);
+                }
+            }
+            if self.verify {
+                for ops in program.iter() {
+                    try!(check(pass.name, ops, folded, spans));
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+// Verify a function after `pass`. If it's broken, print it and say which op
+// is wrong and where it came from.
+fn check(pass: &str, ops: &Vec<Op>, folded: bool,
+         spans: &BTreeMap<NodeId, String>) -> Result<(), String> {
+    let error = match time("verify", || verify(ops, folded)) {
+        Ok(()) => return Ok(()),
+        Err(error) => error,
+    };
+    let mut stderr = io::stderr();
+    for op in ops.iter() {
+        write!(stderr, "{}", op).ok();
+    }
+    let op = &ops[error.op];
+    let at = match spans.get(&op.id) {
+        Some(span) => format!("\n   {}", span),
+        None => "".to_string(),
+    };
+    Err(format!("Invalid IR after {}: {}\n   at op {}: {}{}", pass, error.msg, error.op,
+                format!("{}", op).trim(), at))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::PassManager;
+
+    fn names(manager: &PassManager) -> Vec<&'static str> {
+        manager.passes.iter().map(|p| p.name).collect()
+    }
+
+    fn arg(name: &str, val: &str) -> (String, Option<String>) {
+        (name.to_string(), Some(val.to_string()))
+    }
+
+    #[test]
+    fn test_pipelines() {
+        assert_eq!(names(&PassManager::from_args(&vec!())),
+                   vec!("ssa", "inline", "constant_fold", "gvn", "licm", "dce"));
+        assert_eq!(names(&PassManager::from_args(&vec!(arg("opt_level", "0")))),
+                   vec!("ssa"));
+        assert_eq!(names(&PassManager::from_args(&vec!(arg("opt_level", "2")))),
+                   vec!("ssa", "inline", "constant_fold", "gvn", "licm", "unroll",
+                        "constant_fold", "strength_reduce", "dce"));
+        // SSA always goes first.
+        assert_eq!(names(&PassManager::from_args(&vec!(arg("passes", "constant_fold")))),
+                   vec!("ssa", "constant_fold"));
+        assert_eq!(PassManager::new(&["ssa", "bogus"]).err(),
+                   Some("Unknown pass `bogus'".to_string()));
+    }
+}
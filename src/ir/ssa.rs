@@ -1,27 +1,38 @@
-use time::precise_time_ns;
-
 use ir::*;
 use ir::util::subst;
+use ir::cfg::CFG;
 use ir::liveness::LivenessAnalyzer;
 use util::Name;
 use std::collections::{BTreeMap, BTreeSet};
 
 pub struct ToSSA;
 
-// Get the generation of a variable and increment it.
-fn next_gen(generations: &mut BTreeMap<Name, usize>, name: Name) -> Option<usize> {
-    let gen = *generations.get(&name).unwrap_or(&0);
-    generations.insert(name, gen+1);
-    Some(gen+1)
+// The generations handed out so far, and those of the variables in scope
+// at the current point of the walk over the dominator tree.
+struct Generations {
+    last: BTreeMap<Name, usize>,
+    current: BTreeMap<Name, usize>,
+    // What `current` held before each definition, so that we can put it back
+    // when we leave the block.
+    undo: Vec<(Name, Option<usize>)>,
+}
+
+// Give a variable a new generation.
+fn next_gen(generations: &mut Generations, name: Name) -> Option<usize> {
+    let gen = *generations.last.get(&name).unwrap_or(&0) + 1;
+    generations.last.insert(name, gen);
+    let old = generations.current.insert(name, gen);
+    generations.undo.push((name, old));
+    Some(gen)
 }
 
 // Get the generation of a variable.
-fn gen_of(generations: &mut BTreeMap<Name, usize>, name: Name) -> Option<usize> {
-    Some(*generations.get(&name).unwrap_or(&0))
+fn gen_of(generations: &mut Generations, name: Name) -> Option<usize> {
+    Some(*generations.current.get(&name).unwrap_or(&0))
 }
 
 // Fill in the generation of an RValElem.
-fn ssa_rvalelem(generations: &mut BTreeMap<Name, usize>,
+fn ssa_rvalelem(generations: &mut Generations,
                 rv_elem: &mut RValueElem) {
     match *rv_elem {
         Variable(ref mut var) =>
@@ -32,9 +43,9 @@ fn ssa_rvalelem(generations: &mut BTreeMap<Name, usize>,
 
 // Fill in the generations of variables in the given BTreeSet, using the given
 // gen_of function.
-fn ssa_vars<F>(generations: &mut BTreeMap<Name, usize>, vars: &mut BTreeSet<Var>,
+fn ssa_vars<F>(generations: &mut Generations, vars: &mut BTreeSet<Var>,
                gen_of: F)
-    where F: Fn(&mut BTreeMap<Name, usize>, Name) -> Option<usize>
+    where F: Fn(&mut Generations, Name) -> Option<usize>
 {
     let mut new_vars = BTreeSet::new();
     for var in vars.iter() {
@@ -45,47 +56,42 @@ fn ssa_vars<F>(generations: &mut BTreeMap<Name, usize>, vars: &mut BTreeSet<Var>
     *vars = new_vars;
 }
 
-static mut opinfo_time: u64 = 0;
-static mut param_time_1: u64 = 0;
-static mut param_time_2: u64 = 0;
-
-pub fn get_param_times() -> (u64, u64, u64) {
-    unsafe {
-        (opinfo_time, param_time_1, param_time_2)
-    }
-}
-
-fn parameterize_labels(ops: &mut Vec<Op>) {
-    // TODO: make it so we don't have to clone these.
+// Give each label the variables that need merging there: those live at it
+// that are assigned somewhere it's in the iterated dominance frontier of.
+fn parameterize_labels(ops: &mut Vec<Op>, cfg: &CFG) {
+    let opinfo = LivenessAnalyzer::analyze_cfg(ops, cfg);
 
-    // TODO: we have to include assigned variables in the labels, too.
-    let start = precise_time_ns();
-    let opinfo = LivenessAnalyzer::analyze(ops);
-    let end = precise_time_ns();
-    unsafe {
-        opinfo_time += end-start;
+    let mut def_blocks = BTreeMap::<Name, BTreeSet<usize>>::new();
+    for (i, info) in opinfo.iter().enumerate() {
+        for var in info.def.iter() {
+            if !def_blocks.contains_key(&var.name) {
+                def_blocks.insert(var.name, BTreeSet::new());
+            }
+            def_blocks.get_mut(&var.name).unwrap().insert(cfg.block_of[i]);
+        }
     }
+    let merge_blocks: BTreeMap<Name, BTreeSet<usize>> = def_blocks.iter()
+        .map(|(name, blocks)| (*name, cfg.iterated_frontier(blocks)))
+        .collect();
 
-    let start = precise_time_ns();
     let mut label_vars = BTreeMap::new();
     let len = ops.len();
     for i in 0 .. len {
         match ops.get_mut(i) {
             Some(&mut Op { id: _, val: OpNode::Label(ref label, ref mut vars)}) => {
-                let ref live_vars = opinfo[i].live;
-                label_vars.insert(*label,
-                                  live_vars.clone());
+                let block = cfg.block_of[i];
+                let live_vars: BTreeSet<Var> = opinfo[i].live.iter()
+                    .filter(|v| merge_blocks.get(&v.name)
+                                            .map_or(false, |bs| bs.contains(&block)))
+                    .cloned()
+                    .collect();
                 vars.extend(live_vars.iter().map(|x| (*x).clone()));
+                label_vars.insert(*label, live_vars);
             },
             _ => {},
         }
     }
-    let end = precise_time_ns();
-    unsafe {
-        param_time_1 += end-start;
-    }
 
-    let start = precise_time_ns();
     for op in ops.iter_mut() {
         match op.val {
             OpNode::Goto(i, ref mut vars) |
@@ -96,9 +102,77 @@ fn parameterize_labels(ops: &mut Vec<Op>) {
             _ => {}
         }
     }
-    let end = precise_time_ns();
-    unsafe {
-        param_time_2 += end-start;
+}
+
+// Number the variables in block b, and then in the blocks it dominates, so
+// that every use sees the generation of the definition that dominates it.
+fn rename_block(ops: &mut Vec<Op>, cfg: &CFG, b: usize, gens: &mut Generations) {
+    let undo_len = gens.undo.len();
+
+    for op in ops[cfg.blocks[b].start .. cfg.blocks[b].end].iter_mut() {
+        match op.val {
+            OpNode::UnOp(ref mut v, _, ref mut rve) => {
+                ssa_rvalelem(gens, rve);
+                v.generation = next_gen(gens, v.name);
+            },
+            OpNode::BinOp(ref mut v, _, ref mut rve1, ref mut rve2, _) => {
+                ssa_rvalelem(gens, rve1);
+                ssa_rvalelem(gens, rve2);
+                v.generation = next_gen(gens, v.name);
+            },
+            OpNode::Call(ref mut v, ref mut f, ref mut args) => {
+                ssa_rvalelem(gens, f);
+                for arg in args.iter_mut() {
+                    arg.generation = gen_of(gens, arg.name);
+                }
+                v.generation = next_gen(gens, v.name);
+            },
+            OpNode::Store(ref mut v, ref mut other_v, _) => {
+                other_v.generation = gen_of(gens, other_v.name);
+                v.generation = gen_of(gens, v.name);
+            },
+            OpNode::Load(ref mut v, ref mut other_v, _) => {
+                other_v.generation = gen_of(gens, other_v.name);
+                v.generation = next_gen(gens, v.name);
+            },
+            OpNode::Label(_, ref mut vars) => {
+                ssa_vars(gens, vars, |x, y| next_gen(x, y));
+            }
+            OpNode::CondGoto(_, ref mut rv, _, ref mut vars) => {
+                ssa_rvalelem(gens, rv);
+                ssa_vars(gens, vars, |x, y| gen_of(x, y));
+            },
+            OpNode::Goto(_, ref mut vars) => {
+                ssa_vars(gens, vars, |x, y| gen_of(x, y));
+            },
+            OpNode::Return(ref mut rv) => {
+                ssa_rvalelem(gens, rv);
+            },
+            OpNode::Func(_, ref mut vars, _) => {
+                for var in vars.iter_mut() {
+                    *var = Var {
+                        name: var.name.clone(),
+                        generation: next_gen(gens, var.name),
+                    }
+                }
+            }
+            OpNode::Alloca(ref mut v, _) => {
+                v.generation = next_gen(gens, v.name);
+            }
+            _ => {}
+        }
+    }
+
+    for &child in cfg.dom_children[b].iter() {
+        rename_block(ops, cfg, child, gens);
+    }
+
+    while gens.undo.len() > undo_len {
+        let (name, old) = gens.undo.pop().unwrap();
+        match old {
+            Some(gen) => { gens.current.insert(name, gen); },
+            None => { gens.current.remove(&name); },
+        }
     }
 }
 
@@ -310,87 +384,27 @@ fn minimize(ops: &mut Vec<Op>, verbose: bool) {
     }
 }
 
-static mut label_time: u64 = 0;
-static mut gen_time: u64 = 0;
-static mut minimize_time: u64 = 0;
-
-pub fn get_times() -> (u64, u64, u64) {
-    unsafe {
-        (label_time, gen_time, minimize_time)
-    }
-}
-
 impl ToSSA {
     pub fn to_ssa(ops: &mut Vec<Op>, verbose: bool) {
         if ops.len() == 1 { return; } // No instructions; probably extern.
                                       // TODO: this is somewhat fragile...
-        let start = precise_time_ns();
-        parameterize_labels(ops);
-        let end = precise_time_ns();
-        unsafe {
-            label_time += end-start;
-        }
+        let cfg = CFG::new(ops);
+        parameterize_labels(ops, &cfg);
 
-        let ref mut gens = BTreeMap::<Name, usize>::new();
-
-        let start = precise_time_ns();
-        for op in ops.iter_mut() {
-            match op.val {
-                OpNode::UnOp(ref mut v, _, ref mut rve) => {
-                    ssa_rvalelem(gens, rve);
-                    v.generation = next_gen(gens, v.name);
-                },
-                OpNode::BinOp(ref mut v, _, ref mut rve1, ref mut rve2, _) => {
-                    ssa_rvalelem(gens, rve1);
-                    ssa_rvalelem(gens, rve2);
-                    v.generation = next_gen(gens, v.name);
-                },
-                OpNode::Call(ref mut v, ref mut f, ref mut args) => {
-                    ssa_rvalelem(gens, f);
-                    for arg in args.iter_mut() {
-                        arg.generation = gen_of(gens, arg.name);
-                    }
-                    v.generation = next_gen(gens, v.name);
-                },
-                OpNode::Store(ref mut v, ref mut other_v, _) => {
-                    other_v.generation = gen_of(gens, other_v.name);
-                    v.generation = gen_of(gens, v.name);
-                },
-                OpNode::Load(ref mut v, ref mut other_v, _) => {
-                    other_v.generation = gen_of(gens, other_v.name);
-                    v.generation = next_gen(gens, v.name);
-                },
-                OpNode::Label(_, ref mut vars) => {
-                    ssa_vars(gens, vars, |x, y| next_gen(x, y));
-                }
-                OpNode::CondGoto(_, ref mut rv, _, ref mut vars) => {
-                    ssa_rvalelem(gens, rv);
-                    ssa_vars(gens, vars, |x, y| gen_of(x, y));
-                },
-                OpNode::Goto(_, ref mut vars) => {
-                    ssa_vars(gens, vars, |x, y| gen_of(x, y));
-                },
-                OpNode::Return(ref mut rv) => {
-                    ssa_rvalelem(gens, rv);
-                },
-                OpNode::Func(_, ref mut vars, _) => {
-                    for var in vars.iter_mut() {
-                        *var = Var {
-                            name: var.name.clone(),
-                            generation: next_gen(gens, var.name),
-                        }
-                    }
-                }
-                OpNode::Alloca(ref mut v, _) => {
-                    v.generation = next_gen(gens, v.name);;
-                }
-                _ => {}
+        let ref mut gens = Generations {
+            last: BTreeMap::new(),
+            current: BTreeMap::new(),
+            undo: vec!(),
+        };
+
+        rename_block(ops, &cfg, 0, gens);
+        // Unreachable blocks aren't in the dominator tree; they only see
+        // what they define themselves.
+        for b in 0 .. cfg.blocks.len() {
+            if !cfg.is_reachable(b) {
+                rename_block(ops, &cfg, b, gens);
             }
         }
-        let end = precise_time_ns();
-        unsafe {
-            gen_time += end-start;
-        }
 
         if verbose {
             print!("Before minimizing:\n");
@@ -399,12 +413,7 @@ impl ToSSA {
             }
         }
 
-        let start = precise_time_ns();
         minimize(ops, verbose);
-        let end = precise_time_ns();
-        unsafe {
-            minimize_time = end-start;
-        }
     }
 
 }
@@ -0,0 +1,290 @@
+//! Strength reduction of multiplies, divides and remainders by constants.
+//!
+//! Powers of two become shifts and masks. Signed division by 2^k needs a
+//! fix-up first, since shifting rounds toward negative infinity and division
+//! rounds toward zero: a negative dividend gets 2^k - 1 added to it. Other
+//! multipliers become shifts and adds or subtracts, when there are few enough
+//! of them to beat a multiply. Division by any other constant is left for the
+//! backend, which multiplies by a magic number instead (see
+//! `codegen::magic`); when it will, a remainder by that constant becomes a
+//! division, a multiply and a subtract, so that it gets the same treatment.
+
+use codegen::{MULT_COST, DIV_COST};
+use codegen::magic::divide_cost;
+use ir::*;
+use ir::util::Temps;
+use mc::ast::*;
+use util::IntKind;
+
+use std::mem::replace;
+
+pub struct StrengthReducer;
+
+// The signed-digit form of `c` with no two adjacent nonzero digits, as
+// (shift, negative) pairs, highest first: c is the sum of the 2^shift, each
+// negated if its flag is set. The highest term is always positive.
+fn naf(mut c: u64) -> Vec<(u64, bool)> {
+    let mut terms = vec!();
+    let mut shift = 0;
+    while c != 0 {
+        if c & 1 == 1 {
+            let negative = c & 3 == 3;
+            terms.push((shift, negative));
+            c = if negative { c + 1 } else { c - 1 };
+        }
+        c >>= 1;
+        shift += 1;
+    }
+    terms.reverse();
+    terms
+}
+
+// How many ops multiplying by `c` with shifts and adds takes.
+fn multiply_ops(c: u64) -> usize {
+    let terms = naf(c);
+    terms.len() - 1 + terms.iter().filter(|&&(shift, _)| shift > 0).count()
+}
+
+// Multiplying by a constant takes a long load as well as the multiply.
+fn multiply_cost(c: u64) -> usize {
+    let ops = multiply_ops(c);
+    if ops < MULT_COST + 2 { ops } else { MULT_COST + 2 }
+}
+
+fn log2(c: u64) -> Option<u64> {
+    if c & (c - 1) == 0 {
+        Some(c.trailing_zeros() as u64)
+    } else {
+        None
+    }
+}
+
+struct Builder<'a> {
+    temps: &'a mut Temps,
+    ssa: bool,
+    kind: IntKind,
+    signed: bool,
+    ops: Vec<OpNode>,
+}
+
+impl<'a> Builder<'a> {
+    fn num(&self, n: u64) -> RValueElem {
+        Constant(NumLit(n, self.kind))
+    }
+
+    // Emit `dest := lhs op rhs`, into a new temporary if there's no `dest`.
+    fn binop(&mut self, dest: Option<Var>, op: BinOpNode, lhs: RValueElem,
+             rhs: RValueElem) -> Var {
+        let dest = match dest {
+            Some(dest) => dest,
+            None => self.temps.var(self.ssa),
+        };
+        self.ops.push(OpNode::BinOp(dest, op, lhs, rhs, self.signed));
+        dest
+    }
+
+    fn shift(&mut self, dest: Option<Var>, op: BinOpNode, x: Var, k: u64) -> Var {
+        let k = self.num(k);
+        self.binop(dest, op, Variable(x), k)
+    }
+
+    // dest := x * c, as shifts and adds.
+    fn multiply(&mut self, dest: Option<Var>, x: Var, c: u64) -> Var {
+        let terms = naf(c);
+        let last = terms.len() - 1;
+        let mut acc = self.shift(if last == 0 { dest } else { None }, LeftShiftOp, x, terms[0].0);
+        for (i, &(shift, negative)) in terms.iter().enumerate().skip(1) {
+            let into = if i == last { dest } else { None };
+            let term = if shift == 0 {
+                x
+            } else {
+                self.shift(None, LeftShiftOp, x, shift)
+            };
+            let op = if negative { MinusOp } else { PlusOp };
+            acc = self.binop(into, op, Variable(acc), Variable(term));
+        }
+        acc
+    }
+
+    // The signed quotient x / 2^k: (x + (x < 0 ? 2^k - 1 : 0)) >>s k.
+    fn signed_divide(&mut self, dest: Option<Var>, x: Var, k: u64) -> Var {
+        let sign = self.shift(None, RightShiftOp, x, 31);
+        let mask = self.num((1 << k) - 1);
+        let bias = self.binop(None, BitAndOp, Variable(sign), mask);
+        let biased = self.binop(None, PlusOp, Variable(x), Variable(bias));
+        self.shift(dest, RightShiftOp, biased, k)
+    }
+}
+
+// Replacement ops for `dest := x op c`, if there's a cheaper way.
+fn reduce(temps: &mut Temps, dest: Var, op: BinOpNode, x: Var, c: u64, kind: IntKind,
+          signed: bool) -> Option<Vec<OpNode>> {
+    let limit = if signed { 1 << 31 } else { 1 << 32 };
+    if c < 2 || c >= limit {
+        return None;
+    }
+
+    let mut b = Builder {
+        temps: temps,
+        ssa: dest.generation.is_some(),
+        kind: kind,
+        signed: signed,
+        ops: vec!(),
+    };
+    match (op, log2(c)) {
+        (TimesOp, _) if multiply_ops(c) < MULT_COST + 2 => {
+            b.multiply(Some(dest), x, c);
+        },
+        (DivideOp, Some(k)) =>
+            if signed {
+                b.signed_divide(Some(dest), x, k);
+            } else {
+                b.shift(Some(dest), RightShiftOp, x, k);
+            },
+        (ModOp, Some(k)) =>
+            if signed {
+                let q = b.signed_divide(None, x, k);
+                let multiple = b.shift(None, LeftShiftOp, q, k);
+                b.binop(Some(dest), MinusOp, Variable(x), Variable(multiple));
+            } else {
+                let mask = b.num(c - 1);
+                b.binop(Some(dest), BitAndOp, Variable(x), mask);
+            },
+        (ModOp, None) => {
+            // Compared with a long load, a divide and an mfhi.
+            match divide_cost(c as u32, signed) {
+                Some(cost) if cost + multiply_cost(c) + 1 < DIV_COST + 3 => {},
+                _ => return None,
+            }
+            let num = b.num(c);
+            let q = b.binop(None, DivideOp, Variable(x), num);
+            let multiple = if multiply_ops(c) < MULT_COST + 2 {
+                b.multiply(None, q, c)
+            } else {
+                let num = b.num(c);
+                b.binop(None, TimesOp, Variable(q), num)
+            };
+            b.binop(Some(dest), MinusOp, Variable(x), Variable(multiple));
+        },
+        _ => return None,
+    }
+    Some(b.ops)
+}
+
+impl StrengthReducer {
+    pub fn reduce(ops: &mut Vec<Op>, verbose: bool) {
+        let mut temps = Temps::new(ops);
+        let mut new_ops = vec!();
+        for op in replace(ops, vec!()).into_iter() {
+            let reduced = match op.val {
+                OpNode::BinOp(dest, binop, Variable(x), Constant(NumLit(c, kind)), signed) |
+                OpNode::BinOp(dest, binop @ TimesOp, Constant(NumLit(c, kind)), Variable(x),
+                              signed) =>
+                    reduce(&mut temps, dest, binop, x, c, kind, signed),
+                _ => None,
+            };
+            match reduced {
+                Some(reduced) => {
+                    if verbose {
+                        print!("Reducing {}", op);
+                    }
+                    // The new ops stand in for the old one, so they keep its
+                    // id, and its place in the source.
+                    new_ops.extend(reduced.into_iter().map(|val| WithId { id: op.id, val: val }));
+                },
+                None => new_ops.push(op),
+            }
+        }
+        *ops = new_ops;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{StrengthReducer, naf};
+    use testing::{run_pass, optimize_source};
+
+    fn reduce(src: &str) -> String {
+        run_pass(src, true, false, |program, _| {
+            StrengthReducer::reduce(&mut program[0], false);
+        })
+    }
+
+    #[test]
+    fn test_naf() {
+        assert_eq!(naf(7), vec!((3, false), (0, true)));
+        assert_eq!(naf(10), vec!((3, false), (1, false)));
+        assert_eq!(naf(8), vec!((3, false)));
+    }
+
+    #[test]
+    fn test_powers_of_two() {
+        let result = reduce("fn %f(%x<1>)
+    %a<1> := %x<1> *u 8u32
+    %b<1> := %a<1> /u 4u32
+    %c<1> := %b<1> %u 16u32
+    %d<1> := 2 *s %x<1>
+    return %c<1>
+");
+        assert!(result.contains("%a<1> := %x<1> <<u 3u32"), "{}", result);
+        assert!(result.contains("%b<1> := %a<1> >>u 2u32"), "{}", result);
+        assert!(result.contains("%c<1> := %b<1> &u 15u32"), "{}", result);
+        assert!(result.contains("%d<1> := %x<1> <<s 1"), "{}", result);
+    }
+
+    #[test]
+    fn test_signed_and_other_constants() {
+        let result = reduce("fn %f(%x<1>)
+    %a<1> := %x<1> /s 4
+    %b<1> := %x<1> %s 4
+    %c<1> := %x<1> *s 10
+    %d<1> := %x<1> %u 10u32
+    return %a<1>
+");
+        // Negative dividends are rounded toward zero first.
+        assert!(result.contains(":= %x<1> >>s 31\n"), "{}", result);
+        assert!(result.contains(" &s 3\n"), "{}", result);
+        assert!(result.contains("%a<1> := %TEMP"), "{}", result);
+        assert!(result.contains("%b<1> := %x<1> -s %TEMP"), "{}", result);
+        assert!(result.contains("%c<1> := %TEMP"), "{}", result);
+        // The division is left for the backend.
+        assert!(result.contains(":= %x<1> /u 10u32\n"), "{}", result);
+        assert!(result.contains("%d<1> := %x<1> -u %TEMP"), "{}", result);
+    }
+
+    #[test]
+    fn test_left_alone() {
+        let src = "fn %f(%x<1>)
+    %a<1> := %x<1> *s 12345
+    %b<1> := %x<1> /s 7
+    %c<1> := %x<1> /s 1
+    %d<1> := 16 /s %x<1>
+    return %a<1>
+";
+        let result = reduce(src);
+        for line in src.lines() {
+            assert!(result.contains(line), "{}", result);
+        }
+        assert!(!result.contains("TEMP"), "{}", result);
+    }
+
+    #[test]
+    fn test_constant_operands() {
+        // Strength reduction rewrites all of these, and has to get negative
+        // dividends right.
+        optimize_source("
+            fn main() -> u32 {
+                let i: i32 = -20;
+                while i <= 20 {
+                    print_int(i / 4);
+                    print_int(i % 8);
+                    print_int(i * 10);
+                    print_int(i % 7);
+                    print_uint((i as u32) / 16);
+                    print_uint((i as u32) % 10);
+                    i += 3;
+                }
+                0
+            }", 2);
+    }
+}
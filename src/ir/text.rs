@@ -0,0 +1,742 @@
+//! A textual form of the IR that can be read back in.
+//!
+//! A program is a list of globals followed by functions, one op per line:
+//!
+//! ```text
+//! global %counter size 4 offset 0
+//! global %print_int size 0 offset 4 extern func
+//! fn %print_int(%x) extern "C"
+//! fn %__main()
+//!     %i<1> := 0u32
+//!     goto 0(%i<1>)
+//!     label 0(%i<2>)
+//!     %c<1> := %i<2> <u 10u32
+//!     if !%c<1> goto 1()
+//!     %t<1> := call %print_int(%i<2>)
+//!     %i<3> := %i<2> +u 1u32
+//!     goto 0(%i<3>)
+//!     label 1()
+//!     return 0u32
+//! ```
+//!
+//! Variables are written with a `%`, and with their generation in angle
+//! brackets once they have one. Binary operators carry an `s` or `u` for
+//! their signedness, and loads and stores their width (none for any
+//! width). The unary operators are `deref`, `addrof`, `neg`, `not`,
+//! `bitnot`, `sxb` and `sxh`; a plain copy has no operator. `//` starts a
+//! comment. A function with no ops is an extern. Global initializers aren't
+//! written; they're in `_INIT_GLOBALS` already. A global that nothing else
+//! writes to is marked `const`, followed by its value. Functions marked
+//! `#[inline]` or `#[inline(never)]` are `inline` or `noinline`. Inline
+//! asm is `asm` followed by a string holding its packets.
+
+use ir::*;
+use mas::ast::InstPacket;
+use mas::lexer::asm_lexer_from_str;
+use mas::parser::AsmParser;
+use mc::ast::*;
+use mc::session::INTERNER;
+use util::{IntKind, Name, Width};
+use util::Width::{AnyWidth, Width32, Width16, Width8};
+
+use std::collections::{BTreeMap, BTreeSet};
+use std::io::Write;
+
+pub fn var_to_string(v: &Var) -> String {
+    match v.generation {
+        Some(g) => format!("%{}<{}>", v.name, g),
+        None => format!("%{}", v.name),
+    }
+}
+
+pub fn vars_to_string<'a, T: Iterator<Item=&'a Var>>(vars: T) -> String {
+    let vars: Vec<String> = vars.map(var_to_string).collect();
+    format!("({})", vars.join(", "))
+}
+
+fn rve_to_string(rve: &RValueElem) -> String {
+    match *rve {
+        Variable(ref v) => var_to_string(v),
+        Constant(NumLit(n, kind)) => format!("{}{}", n, kind),
+        Constant(StringLit(ref s)) => format!("\"{}\"", s.escape_default()),
+        Constant(BoolLit(b)) => format!("{}", b),
+        Constant(NullLit) => "null".to_string(),
+    }
+}
+
+fn unop_name(op: UnOpNode) -> &'static str {
+    match op {
+        Deref => "deref ",
+        AddrOf => "addrof ",
+        Negate => "neg ",
+        LogNot => "not ",
+        BitNot => "bitnot ",
+        Identity => "",
+        SxbOp => "sxb ",
+        SxhOp => "sxh ",
+    }
+}
+
+/// The text of a single op, without indentation or a newline.
+pub fn op_to_string(op: &OpNode) -> String {
+    match *op {
+        OpNode::UnOp(ref v, op, ref rve) =>
+            format!("{} := {}{}", var_to_string(v), unop_name(op), rve_to_string(rve)),
+        OpNode::BinOp(ref v, op, ref rve1, ref rve2, signed) =>
+            format!("{} := {} {}{} {}", var_to_string(v), rve_to_string(rve1), op,
+                    if signed { "s" } else { "u" }, rve_to_string(rve2)),
+        OpNode::Alloca(ref v, size) =>
+            format!("{} := alloca {}", var_to_string(v), size),
+        OpNode::Call(ref v, ref f, ref args) =>
+            format!("{} := call {}{}", var_to_string(v), rve_to_string(f),
+                    vars_to_string(args.iter())),
+        OpNode::Store(ref addr, ref val, width) =>
+            format!("store{} {}, {}", width, var_to_string(addr), var_to_string(val)),
+        OpNode::Load(ref v, ref addr, width) =>
+            format!("{} := load{} {}", var_to_string(v), width, var_to_string(addr)),
+        OpNode::Label(label, ref vars) =>
+            format!("label {}{}", label, vars_to_string(vars.iter())),
+        OpNode::Goto(label, ref vars) =>
+            format!("goto {}{}", label, vars_to_string(vars.iter())),
+        OpNode::CondGoto(negated, ref rve, label, ref vars) =>
+            format!("if {}{} goto {}{}", if negated { "!" } else { "" }, rve_to_string(rve),
+                    label, vars_to_string(vars.iter())),
+        OpNode::Return(ref rve) => format!("return {}", rve_to_string(rve)),
+        OpNode::Func(name, ref params, ref abi) =>
+            format!("fn %{}{}{}", name, vars_to_string(params.iter()),
+                    match *abi {
+                        Some(ref abi) => format!(" extern \"{}\"", abi),
+                        None => "".to_string(),
+                    }),
+        OpNode::AsmOp(ref packets) => {
+            let packets: Vec<String> = packets.iter().map(|packet| {
+                let insts: Vec<String> = packet.iter().map(|inst| format!("{}", inst)).collect();
+                format!("{{ {} }}", insts.join("; "))
+            }).collect();
+            format!("asm \"{}\"", packets.join(" ").escape_default())
+        },
+        OpNode::Nop => "nop".to_string(),
+    }
+}
+
+pub fn write_global(f: &mut Write, item: &StaticIRItem) {
+    write!(f, "global %{} size {}", item.name, item.size).ok();
+    match item.offset {
+        Some(offs) => { write!(f, " offset {}", offs).ok(); },
+        None => {},
+    }
+    if item.is_extern { write!(f, " extern").ok(); }
+    if item.is_ref { write!(f, " ref").ok(); }
+    if item.is_func { write!(f, " func").ok(); }
+    match item.inline {
+        Some(true) => { write!(f, " inline").ok(); },
+        Some(false) => { write!(f, " noinline").ok(); },
+        None => {},
+    }
+    match item.const_value {
+        Some(ref c) => { write!(f, " const {}", rve_to_string(&Constant(c.clone()))).ok(); },
+        None => {},
+    }
+    write!(f, "\n").ok();
+}
+
+pub fn write_function(f: &mut Write, ops: &Vec<Op>) {
+    write_function_with_notes(f, ops, &vec!());
+}
+
+/// Like `write_function`, but `notes[i]` (if it's there and not empty) goes
+/// in a comment after op i.
+pub fn write_function_with_notes(f: &mut Write, ops: &Vec<Op>, notes: &Vec<String>) {
+    for (i, op) in ops.iter().enumerate() {
+        let indent = if i == 0 { "" } else { "    " };
+        write!(f, "{}{}", indent, op_to_string(&op.val)).ok();
+        match notes.get(i) {
+            Some(note) if !note.is_empty() => { write!(f, " // {}", note).ok(); },
+            _ => {},
+        }
+        write!(f, "\n").ok();
+    }
+}
+
+pub fn write_program(f: &mut Write, program: &Vec<Vec<Op>>,
+                     global_map: &BTreeMap<Name, StaticIRItem>) {
+    for (_, item) in global_map.iter() {
+        write_global(f, item);
+    }
+    for ops in program.iter() {
+        write_function(f, ops);
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+enum Token {
+    Word(String),
+    Var(String, Option<usize>),
+    Num(u64, IntKind),
+    Str(String),
+    Punct(&'static str),
+}
+
+static PUNCTS: [&'static str; 25] = [
+    ":=", "==", "!=", "<=", ">=", "&&", "||", "<<", ">>",
+    "+", "-", "*", "/", "%", "<", ">", "&", "|", "^", "!", "(", ")", ",",
+    "{", "}",
+];
+
+fn is_name_char(c: char) -> bool {
+    c.is_alphanumeric() || c == '_' || c == '.' || c == '$'
+}
+
+fn tokenize(line: &str) -> Result<Vec<Token>, String> {
+    let chars: Vec<char> = line.chars().collect();
+    let mut tokens = vec!();
+    let mut i = 0;
+
+    // Take characters from i while pred holds.
+    let take = |i: &mut usize, pred: &Fn(char) -> bool| -> String {
+        let start = *i;
+        while *i < chars.len() && pred(chars[*i]) {
+            *i += 1;
+        }
+        chars[start .. *i].iter().cloned().collect()
+    };
+
+    while i < chars.len() {
+        let c = chars[i];
+        if c.is_whitespace() {
+            i += 1;
+        } else if c == '/' && i + 1 < chars.len() && chars[i + 1] == '/' {
+            break;
+        } else if c == '%' && i + 1 < chars.len() && is_name_char(chars[i + 1]) {
+            i += 1;
+            let name = take(&mut i, &is_name_char);
+            // A generation must follow the name directly, as in %x<3>.
+            let mut gen = None;
+            if i + 2 < chars.len() && chars[i] == '<' && chars[i + 1].is_digit(10) {
+                let mut j = i + 1;
+                let digits = take(&mut j, &|c: char| c.is_digit(10));
+                if j < chars.len() && chars[j] == '>' {
+                    gen = Some(digits.parse().unwrap());
+                    i = j + 1;
+                }
+            }
+            tokens.push(Token::Var(name, gen));
+        } else if c.is_digit(10) {
+            let digits = take(&mut i, &|c: char| c.is_digit(10));
+            let n = match digits.parse() {
+                Ok(n) => n,
+                Err(_) => return Err(format!("number {} is too big", digits)),
+            };
+            let kind = if i < chars.len() && (chars[i] == 'i' || chars[i] == 'u') {
+                let signed = chars[i] == 'i';
+                i += 1;
+                let width = match &take(&mut i, &|c: char| c.is_digit(10))[..] {
+                    "" => AnyWidth,
+                    "32" => Width32,
+                    "16" => Width16,
+                    "8" => Width8,
+                    w => return Err(format!("bad integer width {}", w)),
+                };
+                if signed { IntKind::SignedInt(width) } else { IntKind::UnsignedInt(width) }
+            } else {
+                IntKind::GenericInt
+            };
+            tokens.push(Token::Num(n, kind));
+        } else if c == '"' {
+            i += 1;
+            let mut s = String::new();
+            loop {
+                if i >= chars.len() {
+                    return Err("unterminated string".to_string());
+                }
+                let c = chars[i];
+                i += 1;
+                match c {
+                    '"' => break,
+                    '\\' => {
+                        let esc = match chars.get(i) {
+                            Some(&c) => c,
+                            None => return Err("unterminated string".to_string()),
+                        };
+                        i += 1;
+                        match esc {
+                            'n' => s.push('\n'),
+                            't' => s.push('\t'),
+                            'r' => s.push('\r'),
+                            '0' => s.push('\0'),
+                            'u' => {
+                                // \u{XXXX}, as written by escape_default.
+                                if chars.get(i) != Some(&'{') {
+                                    return Err("bad unicode escape".to_string());
+                                }
+                                i += 1;
+                                let hex = take(&mut i, &|c: char| c.is_digit(16));
+                                if chars.get(i) != Some(&'}') {
+                                    return Err("bad unicode escape".to_string());
+                                }
+                                i += 1;
+                                match u32::from_str_radix(&hex[..], 16).ok()
+                                    .and_then(::std::char::from_u32) {
+                                    Some(c) => s.push(c),
+                                    None => return Err("bad unicode escape".to_string()),
+                                }
+                            },
+                            c => s.push(c),
+                        }
+                    },
+                    c => s.push(c),
+                }
+            }
+            tokens.push(Token::Str(s));
+        } else if c.is_alphabetic() || c == '_' {
+            tokens.push(Token::Word(take(&mut i, &is_name_char)));
+        } else {
+            let rest: String = chars[i..].iter().cloned().collect();
+            match PUNCTS.iter().filter(|p| rest.starts_with(**p)).next() {
+                Some(p) => {
+                    tokens.push(Token::Punct(*p));
+                    i += p.len();
+                },
+                None => return Err(format!("unexpected character `{}'", c)),
+            }
+        }
+    }
+
+    Ok(tokens)
+}
+
+fn intern(s: &str) -> Name {
+    INTERNER.with(|x| x.intern(s.to_string()))
+}
+
+fn parse_asm(s: &str) -> OpNode {
+    let mut parser = AsmParser::new(asm_lexer_from_str(s).peekable());
+    let (packets, _) = parser.parse_toplevel();
+    OpNode::AsmOp(packets.into_iter().map(|x: InstPacket| vec!(x[0].clone(),
+                                                               x[1].clone(),
+                                                               x[2].clone(),
+                                                               x[3].clone())).collect())
+}
+
+// Parses the tokens of a single line.
+struct LineParser {
+    tokens: Vec<Token>,
+    pos: usize,
+}
+
+impl LineParser {
+    fn peek(&self) -> Option<&Token> {
+        self.tokens.get(self.pos)
+    }
+
+    fn next(&mut self) -> Result<Token, String> {
+        match self.tokens.get(self.pos) {
+            Some(tok) => {
+                self.pos += 1;
+                Ok(tok.clone())
+            },
+            None => Err("unexpected end of line".to_string()),
+        }
+    }
+
+    fn at_end(&self) -> bool {
+        self.pos >= self.tokens.len()
+    }
+
+    fn at_punct(&self, p: &str) -> bool {
+        match self.peek() {
+            Some(&Token::Punct(q)) => p == q,
+            _ => false,
+        }
+    }
+
+    fn at_word(&self, w: &str) -> bool {
+        match self.peek() {
+            Some(&Token::Word(ref word)) => w == &word[..],
+            _ => false,
+        }
+    }
+
+    fn expect_punct(&mut self, p: &str) -> Result<(), String> {
+        match try!(self.next()) {
+            Token::Punct(q) if p == q => Ok(()),
+            tok => Err(format!("expected `{}', found {:?}", p, tok)),
+        }
+    }
+
+    fn expect_word(&mut self, w: &str) -> Result<(), String> {
+        match try!(self.next()) {
+            Token::Word(ref word) if w == &word[..] => Ok(()),
+            tok => Err(format!("expected `{}', found {:?}", w, tok)),
+        }
+    }
+
+    fn expect_end(&self) -> Result<(), String> {
+        match self.peek() {
+            None => Ok(()),
+            Some(tok) => Err(format!("unexpected {:?} at end of line", tok)),
+        }
+    }
+
+    fn number(&mut self) -> Result<u64, String> {
+        match try!(self.next()) {
+            Token::Num(n, IntKind::GenericInt) => Ok(n),
+            tok => Err(format!("expected a number, found {:?}", tok)),
+        }
+    }
+
+    fn var(&mut self) -> Result<Var, String> {
+        match try!(self.next()) {
+            Token::Var(name, gen) => Ok(Var { name: intern(&name[..]), generation: gen }),
+            tok => Err(format!("expected a variable, found {:?}", tok)),
+        }
+    }
+
+    fn rve(&mut self) -> Result<RValueElem, String> {
+        match try!(self.next()) {
+            Token::Var(name, gen) =>
+                Ok(Variable(Var { name: intern(&name[..]), generation: gen })),
+            Token::Num(n, kind) => Ok(Constant(NumLit(n, kind))),
+            Token::Str(s) => Ok(Constant(StringLit(s))),
+            Token::Word(ref w) if &w[..] == "true" => Ok(Constant(BoolLit(true))),
+            Token::Word(ref w) if &w[..] == "false" => Ok(Constant(BoolLit(false))),
+            Token::Word(ref w) if &w[..] == "null" => Ok(Constant(NullLit)),
+            tok => Err(format!("expected a value, found {:?}", tok)),
+        }
+    }
+
+    fn var_list(&mut self) -> Result<Vec<Var>, String> {
+        try!(self.expect_punct("("));
+        let mut vars = vec!();
+        if !self.at_punct(")") {
+            loop {
+                vars.push(try!(self.var()));
+                if !self.at_punct(",") {
+                    break;
+                }
+                try!(self.expect_punct(","));
+            }
+        }
+        try!(self.expect_punct(")"));
+        Ok(vars)
+    }
+
+    fn var_set(&mut self) -> Result<BTreeSet<Var>, String> {
+        Ok(try!(self.var_list()).into_iter().collect())
+    }
+
+    fn width(&self, word: &str, prefix: &str) -> Option<Width> {
+        if !word.starts_with(prefix) {
+            return None;
+        }
+        match &word[prefix.len()..] {
+            "" => Some(AnyWidth),
+            "32" => Some(Width32),
+            "16" => Some(Width16),
+            "8" => Some(Width8),
+            _ => None,
+        }
+    }
+
+    fn binop(&mut self) -> Result<(BinOpNode, bool), String> {
+        let op = match try!(self.next()) {
+            // `%s` and `%u` look like variables, but there can't be one here.
+            Token::Var(ref w, None) if &w[..] == "s" => return Ok((ModOp, true)),
+            Token::Var(ref w, None) if &w[..] == "u" => return Ok((ModOp, false)),
+            Token::Punct(p) => match p {
+                "+" => PlusOp,
+                "-" => MinusOp,
+                "*" => TimesOp,
+                "/" => DivideOp,
+                "%" => ModOp,
+                "==" => EqualsOp,
+                "!=" => NotEqualsOp,
+                "<" => LessOp,
+                "<=" => LessEqOp,
+                ">" => GreaterOp,
+                ">=" => GreaterEqOp,
+                "&&" => AndAlsoOp,
+                "||" => OrElseOp,
+                "&" => BitAndOp,
+                "|" => BitOrOp,
+                "^" => BitXorOp,
+                "<<" => LeftShiftOp,
+                ">>" => RightShiftOp,
+                _ => return Err(format!("unknown operator {}", p)),
+            },
+            tok => return Err(format!("expected an operator, found {:?}", tok)),
+        };
+        let signed = match try!(self.next()) {
+            Token::Word(ref w) if &w[..] == "s" => true,
+            Token::Word(ref w) if &w[..] == "u" => false,
+            tok => return Err(format!("expected s or u after {}, found {:?}", op, tok)),
+        };
+        Ok((op, signed))
+    }
+
+    // Everything after `%v :=`.
+    fn assignment(&mut self, v: Var) -> Result<OpNode, String> {
+        let word = match self.peek() {
+            Some(&Token::Word(ref w)) => Some(w.clone()),
+            _ => None,
+        };
+        let unop = match word.as_ref().map(|w| &w[..]) {
+            Some("deref") => Some(Deref),
+            Some("addrof") => Some(AddrOf),
+            Some("neg") => Some(Negate),
+            Some("not") => Some(LogNot),
+            Some("bitnot") => Some(BitNot),
+            Some("sxb") => Some(SxbOp),
+            Some("sxh") => Some(SxhOp),
+            _ => None,
+        };
+        if let Some(op) = unop {
+            try!(self.next());
+            return Ok(OpNode::UnOp(v, op, try!(self.rve())));
+        }
+
+        match word.as_ref().map(|w| &w[..]) {
+            Some("alloca") => {
+                try!(self.next());
+                return Ok(OpNode::Alloca(v, try!(self.number())));
+            },
+            Some("call") => {
+                try!(self.next());
+                let f = try!(self.rve());
+                return Ok(OpNode::Call(v, f, try!(self.var_list())));
+            },
+            Some(w) => match self.width(w, "load") {
+                Some(width) => {
+                    try!(self.next());
+                    return Ok(OpNode::Load(v, try!(self.var()), width));
+                },
+                None => {},
+            },
+            None => {},
+        }
+
+        let rve1 = try!(self.rve());
+        if self.at_end() {
+            return Ok(OpNode::UnOp(v, Identity, rve1));
+        }
+        let (op, signed) = try!(self.binop());
+        let rve2 = try!(self.rve());
+        Ok(OpNode::BinOp(v, op, rve1, rve2, signed))
+    }
+
+    fn op(&mut self) -> Result<OpNode, String> {
+        let op = match try!(self.next()) {
+            Token::Var(name, gen) => {
+                let v = Var { name: intern(&name[..]), generation: gen };
+                try!(self.expect_punct(":="));
+                try!(self.assignment(v))
+            },
+            Token::Word(ref w) => match &w[..] {
+                "label" => {
+                    let label = try!(self.number()) as usize;
+                    OpNode::Label(label, try!(self.var_set()))
+                },
+                "goto" => {
+                    let label = try!(self.number()) as usize;
+                    OpNode::Goto(label, try!(self.var_set()))
+                },
+                "if" => {
+                    let negated = self.at_punct("!");
+                    if negated {
+                        try!(self.expect_punct("!"));
+                    }
+                    let cond = try!(self.rve());
+                    try!(self.expect_word("goto"));
+                    let label = try!(self.number()) as usize;
+                    OpNode::CondGoto(negated, cond, label, try!(self.var_set()))
+                },
+                "return" => OpNode::Return(try!(self.rve())),
+                "nop" => OpNode::Nop,
+                "asm" => match try!(self.next()) {
+                    Token::Str(s) => parse_asm(&s[..]),
+                    tok => return Err(format!("expected asm in a string, found {:?}", tok)),
+                },
+                "fn" => {
+                    let name = match try!(self.next()) {
+                        Token::Var(name, None) => intern(&name[..]),
+                        tok => return Err(format!("expected a function name, found {:?}",
+                                                  tok)),
+                    };
+                    let params = try!(self.var_list());
+                    let abi = if self.at_word("extern") {
+                        try!(self.expect_word("extern"));
+                        match try!(self.next()) {
+                            Token::Str(abi) => Some(intern(&abi[..])),
+                            tok => return Err(format!("expected an ABI, found {:?}", tok)),
+                        }
+                    } else {
+                        None
+                    };
+                    OpNode::Func(name, params, abi)
+                },
+                w => match self.width(w, "store") {
+                    Some(width) => {
+                        let addr = try!(self.var());
+                        try!(self.expect_punct(","));
+                        OpNode::Store(addr, try!(self.var()), width)
+                    },
+                    None => return Err(format!("unknown op {}", w)),
+                },
+            },
+            tok => return Err(format!("expected an op, found {:?}", tok)),
+        };
+        try!(self.expect_end());
+        Ok(op)
+    }
+
+    fn global(&mut self) -> Result<StaticIRItem, String> {
+        let name = match try!(self.next()) {
+            Token::Var(name, None) => intern(&name[..]),
+            tok => return Err(format!("expected a global name, found {:?}", tok)),
+        };
+        try!(self.expect_word("size"));
+        let mut item = StaticIRItem {
+            name: name,
+            size: try!(self.number()) as usize,
+            offset: None,
+            is_extern: false,
+            is_ref: false,
+            is_func: false,
+            expr: None,
+            const_value: None,
+            inline: None,
+        };
+        while !self.at_end() {
+            match try!(self.next()) {
+                Token::Word(ref w) => match &w[..] {
+                    "offset" => item.offset = Some(try!(self.number()) as usize),
+                    "extern" => item.is_extern = true,
+                    "ref" => item.is_ref = true,
+                    "func" => item.is_func = true,
+                    "inline" => item.inline = Some(true),
+                    "noinline" => item.inline = Some(false),
+                    "const" => item.const_value = match try!(self.rve()) {
+                        Constant(c) => Some(c),
+                        rve => return Err(format!("expected a constant, found {}",
+                                                  rve_to_string(&rve))),
+                    },
+                    _ => return Err(format!("unknown global attribute {}", w)),
+                },
+                tok => return Err(format!("unexpected {:?}", tok)),
+            }
+        }
+        Ok(item)
+    }
+}
+
+/// Parse a whole program. Errors say which line they're on.
+pub fn parse_program(src: &str)
+                     -> Result<(Vec<Vec<Op>>, BTreeMap<Name, StaticIRItem>), String> {
+    let mut program: Vec<Vec<Op>> = vec!();
+    let mut global_map = BTreeMap::new();
+    let mut next_id = 0;
+
+    for (lineno, line) in src.lines().enumerate() {
+        let fail = |e: String| format!("line {}: {}", lineno + 1, e);
+        let tokens = try!(tokenize(line).map_err(&fail));
+        if tokens.is_empty() {
+            continue;
+        }
+        let mut parser = LineParser { tokens: tokens, pos: 0 };
+
+        if parser.at_word("global") {
+            parser.pos += 1;
+            let item = try!(parser.global().map_err(&fail));
+            global_map.insert(item.name, item);
+            continue;
+        }
+
+        let op = try!(parser.op().map_err(&fail));
+        let op = WithId { id: NodeId(next_id), val: op };
+        next_id += 1;
+        match op.val {
+            OpNode::Func(..) => program.push(vec!(op)),
+            _ => match program.last_mut() {
+                Some(ops) => ops.push(op),
+                None => return Err(fail("op outside of a function".to_string())),
+            },
+        }
+    }
+
+    Ok((program, global_map))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{parse_program, write_program};
+    use ir::constant_fold::ConstantFolder;
+    use ir::interp::run_program;
+    use ir::ssa::ToSSA;
+
+    static PROGRAM: &'static str = r#"global %print_int size 0 offset 0 extern func
+global %msg size 4 offset 0 ref
+fn %print_int(%x) extern "C"
+fn %__main()
+    %i := 0u32
+    %s := "hi\n"
+    goto 0()
+    label 0()
+    %c := %i <u 3u32
+    if !%c goto 1()
+    %t := call %print_int(%i)
+    %i := %i +u 1u32
+    goto 0()
+    label 1()
+    %b := load8 %s
+    store32 %msg, %b
+    %n := neg %b
+    %t := call %print_int(%n)
+    return 0u32
+"#;
+
+    #[test]
+    fn test_round_trip() {
+        let (program, global_map) = parse_program(PROGRAM).unwrap();
+        let mut out = vec!();
+        write_program(&mut out, &program, &global_map);
+        assert_eq!(String::from_utf8(out).unwrap(), PROGRAM);
+    }
+
+    #[test]
+    fn test_passes_on_text() {
+        let (mut program, global_map) = parse_program(PROGRAM).unwrap();
+        assert_eq!(run_program(&program, &global_map),
+                   Ok("0\n1\n2\n-104\n".to_string()));
+
+        for ops in program.iter_mut() {
+            ToSSA::to_ssa(ops, false);
+            ConstantFolder::fold(ops, &global_map, false);
+        }
+        let mut out = vec!();
+        write_program(&mut out, &program, &global_map);
+        let text = String::from_utf8(out).unwrap();
+        // The loop counter now goes through the loop label.
+        let label = text.lines().filter(|l| l.contains("label 0(")).next().unwrap();
+        assert!(label.contains("%i<"), "{}", text);
+        let (reparsed, global_map) = parse_program(&text[..]).unwrap();
+        assert_eq!(run_program(&reparsed, &global_map),
+                   Ok("0\n1\n2\n-104\n".to_string()));
+    }
+
+    #[test]
+    fn test_asm_round_trip() {
+        let text = "fn %f()\n    asm \"{ r0 <- long; long 1000000; r1 <- r2 + 3; nop }\"\n";
+        let (program, global_map) = parse_program(text).unwrap();
+        let mut out = vec!();
+        write_program(&mut out, &program, &global_map);
+        assert_eq!(String::from_utf8(out).unwrap(), text);
+    }
+
+    #[test]
+    fn test_errors() {
+        assert_eq!(parse_program("%x := 1").err(),
+                   Some("line 1: op outside of a function".to_string()));
+        assert_eq!(parse_program("fn %f()\n    %x := 1 +q 2").err(),
+                   Some("line 2: expected s or u after +, found Word(\"q\")".to_string()));
+    }
+}
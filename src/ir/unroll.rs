@@ -0,0 +1,801 @@
+//! Loop unrolling.
+//!
+//! The scheduler packs independent instructions into each packet, but only
+//! within a basic block, and a loop over a small array is mostly a block of
+//! a handful of dependent ops ending in a jump back. Unrolling puts several
+//! iterations in one block, so there's more to choose from.
+//!
+//! We only unroll counted loops: innermost loops with nothing but the test
+//! in the header leaving them, and a single back edge at the end, where the
+//! test compares a basic induction variable (see `licm`) with something
+//! invariant. If the induction variable starts at a constant and the bound
+//! is one too, we know the trip count, and a loop that's small enough
+//! becomes that many copies of its body one after the other. Otherwise, a
+//! new loop in front runs `FACTOR` iterations each time around, as long as
+//! there are at least that many left, and the original loop does the rest.
+//! The copies of an update of an induction variable add a multiple of the
+//! step to its value at the top rather than adding the step to the last
+//! copy's value, so that they don't depend on each other, and the blocks
+//! the copies end up split into just to go on to the next are joined.
+//!
+//! This needs SSA form, and runs after `licm`, which leaves the loops with
+//! less to copy and more induction variables for us to use. It's only done
+//! at `-O2`, since it makes the code bigger, and `constant_fold` runs again
+//! after it to clean up the copies of completely unrolled loops.
+
+use ir::*;
+use ir::cfg::CFG;
+use ir::util::{defs, uses, memory_names, Temps};
+use ir::util::{jump_target, def_sites, resolve};
+use mc::ast::*;
+use util::{IntKind, Name};
+use util::Width::Width32;
+
+use std::collections::{BTreeMap, BTreeSet};
+
+pub struct Unroller;
+
+// Loops that run at most this many times are unrolled completely...
+static MAX_TRIPS: u32 = 16;
+// ...if that comes to at most this many ops.
+static FULL_SIZE: usize = 128;
+// Others have this many iterations put together, or half as many if that
+// would take more than `PARTIAL_SIZE` ops.
+static FACTOR: usize = 4;
+static PARTIAL_SIZE: usize = 64;
+
+// A loop we can unroll. The indices are into the function's ops; the loop
+// is `start .. back + 1`.
+struct Counted {
+    // The header's label, and its number.
+    start: usize,
+    header: usize,
+    // The test at the end of the header, and where it jumps to leave.
+    test: usize,
+    exit: usize,
+    // The jump back to the header.
+    back: usize,
+    // The one jump into the loop.
+    entry: usize,
+    // The test is `iv op bound`, with `iv` going up (or down) by `step`.
+    iv: Var,
+    op: BinOpNode,
+    bound: RValueElem,
+    signed: bool,
+    step: u32,
+    // The ops updating basic induction variables: which variable, and by
+    // how much.
+    updates: BTreeMap<usize, (Name, u32)>,
+    // How many times the body runs, if we know.
+    trips: Option<u32>,
+    // The number of ops in the loop, not counting labels.
+    size: usize,
+}
+
+// The variables and labels of one copy of a loop.
+struct Copy {
+    vars: BTreeMap<Var, Var>,
+    labels: BTreeMap<usize, usize>,
+}
+
+impl Copy {
+    fn var(&self, v: &Var) -> Var {
+        *self.vars.get(v).unwrap_or(v)
+    }
+
+    fn vars(&self, vars: &BTreeSet<Var>) -> BTreeSet<Var> {
+        vars.iter().map(|v| self.var(v)).collect()
+    }
+
+    fn rve(&self, rve: &RValueElem) -> RValueElem {
+        match *rve {
+            Variable(ref v) => Variable(self.var(v)),
+            Constant(ref c) => Constant(c.clone()),
+        }
+    }
+
+    fn label(&self, label: usize) -> usize {
+        *self.labels.get(&label).unwrap_or(&label)
+    }
+
+    fn op(&self, op: &OpNode) -> OpNode {
+        match *op {
+            OpNode::UnOp(ref v, unop, ref rve) =>
+                OpNode::UnOp(self.var(v), unop, self.rve(rve)),
+            OpNode::BinOp(ref v, binop, ref rve1, ref rve2, signed) =>
+                OpNode::BinOp(self.var(v), binop, self.rve(rve1), self.rve(rve2), signed),
+            OpNode::Alloca(ref v, size) => OpNode::Alloca(self.var(v), size),
+            OpNode::Call(ref v, ref f, ref args) => {
+                let args = args.iter().map(|a| self.var(a)).collect();
+                OpNode::Call(self.var(v), self.rve(f), args)
+            },
+            OpNode::Store(ref ptr, ref v, width) =>
+                OpNode::Store(self.var(ptr), self.var(v), width),
+            OpNode::Load(ref v, ref ptr, width) =>
+                OpNode::Load(self.var(v), self.var(ptr), width),
+            OpNode::Label(label, ref vars) => OpNode::Label(self.label(label), self.vars(vars)),
+            OpNode::Goto(label, ref vars) => OpNode::Goto(self.label(label), self.vars(vars)),
+            OpNode::CondGoto(negated, ref rve, label, ref vars) =>
+                OpNode::CondGoto(negated, self.rve(rve), self.label(label), self.vars(vars)),
+            OpNode::Return(ref rve) => OpNode::Return(self.rve(rve)),
+            OpNode::Func(..) |
+            OpNode::AsmOp(..) |
+            OpNode::Nop => op.clone(),
+        }
+    }
+}
+
+struct Context {
+    // The next generation of each name, and the next label.
+    gens: BTreeMap<Name, usize>,
+    labels: usize,
+    // Labels that may now only be reached from right before them.
+    straight: BTreeSet<usize>,
+    temps: Temps,
+    verbose: bool,
+}
+
+impl Context {
+    fn gen(&mut self, name: Name) -> Var {
+        let gen = *self.gens.get(&name).unwrap_or(&1);
+        self.gens.insert(name, gen + 1);
+        Var { name: name, generation: Some(gen) }
+    }
+
+    // A new copy of `ops[from .. to]`, where the header's variables are
+    // `header` by name.
+    fn copy(&mut self, ops: &Vec<Op>, l: &Counted, from: usize, to: usize,
+            header: &BTreeMap<Name, Var>) -> Copy {
+        let mut copy = Copy { vars: BTreeMap::new(), labels: BTreeMap::new() };
+        if let OpNode::Label(_, ref vars) = ops[l.start].val {
+            for v in vars.iter() {
+                copy.vars.insert(*v, header[&v.name]);
+            }
+        }
+        for op in ops[from..to].iter() {
+            for v in defs(&op.val).into_iter() {
+                let new = self.gen(v.name);
+                copy.vars.insert(*v, new);
+            }
+            if let OpNode::Label(label, _) = op.val {
+                copy.labels.insert(label, self.labels);
+                self.straight.insert(self.labels);
+                self.labels += 1;
+            }
+        }
+        copy
+    }
+}
+
+fn by_name(vars: &BTreeSet<Var>) -> BTreeMap<Name, Var> {
+    vars.iter().map(|v| (v.name, *v)).collect()
+}
+
+fn label_vars(op: &OpNode) -> &BTreeSet<Var> {
+    match *op {
+        OpNode::Label(_, ref vars) |
+        OpNode::Goto(_, ref vars) |
+        OpNode::CondGoto(_, _, _, ref vars) => vars,
+        _ => unreachable!(),
+    }
+}
+
+fn constant(rve: &RValueElem, ops: &Vec<Op>, sites: &BTreeMap<Var, usize>) -> Option<u32> {
+    match *rve {
+        Constant(NumLit(n, _)) => Some(n as u32),
+        Variable(v) => match sites.get(&resolve(v, ops, sites)).map(|&i| &ops[i].val) {
+            Some(&OpNode::UnOp(_, Identity, Constant(NumLit(n, _)))) => Some(n as u32),
+            _ => None,
+        },
+        _ => None,
+    }
+}
+
+fn compare(op: BinOpNode, signed: bool, x: u32, y: u32) -> bool {
+    let (x, y) = if signed {
+        (x as i32 as i64, y as i32 as i64)
+    } else {
+        (x as i64, y as i64)
+    };
+    match op {
+        LessOp => x < y,
+        LessEqOp => x <= y,
+        GreaterOp => x > y,
+        GreaterEqOp => x >= y,
+        _ => unreachable!(),
+    }
+}
+
+fn literal(n: u32, signed: bool) -> RValueElem {
+    if signed {
+        Constant(NumLit(n as i32 as i64 as u64, IntKind::GenericInt))
+    } else {
+        Constant(NumLit(n as u64, IntKind::UnsignedInt(Width32)))
+    }
+}
+
+// Whether loop `l` is one we can unroll, and what we need to know about it.
+fn counted(ops: &Vec<Op>, cfg: &CFG, l: usize, memory: &BTreeSet<Name>,
+           globals: &BTreeMap<Name, StaticIRItem>) -> Option<Counted> {
+    let lp = &cfg.loops[l];
+    if cfg.loops.iter().any(|other| other.parent == Some(l)) {
+        return None;
+    }
+    // The loop has to be all in one piece, starting with the header and
+    // ending with the jump back.
+    let start = cfg.blocks[lp.header].start;
+    let end = lp.blocks.iter().map(|&b| cfg.blocks[b].end).max().unwrap();
+    if (start..end).any(|i| !lp.blocks.contains(&cfg.block_of[i])) {
+        return None;
+    }
+    let header = match ops[start].val {
+        OpNode::Label(label, _) => label,
+        _ => return None,
+    };
+    let back = end - 1;
+    let test = cfg.blocks[lp.header].end - 1;
+    let (c, exit) = match ops[test].val {
+        OpNode::CondGoto(true, Variable(c), exit, _) if test < back => (c, exit),
+        _ => return None,
+    };
+    match ops[back].val {
+        OpNode::Goto(label, _) if label == header => {},
+        _ => return None,
+    }
+
+    let inside: BTreeSet<usize> = ops[start..end].iter().filter_map(|op| match op.val {
+        OpNode::Label(label, _) => Some(label),
+        _ => None,
+    }).collect();
+    if inside.contains(&exit) {
+        return None;
+    }
+    for i in start..end {
+        match ops[i].val {
+            OpNode::Goto(label, _) |
+            OpNode::CondGoto(_, _, label, _) => {
+                if (label == header && i != back) || (!inside.contains(&label) && i != test) {
+                    return None;
+                }
+            },
+            // Calls leave little to schedule around them, and we'd rather
+            // not have more than one copy of anything else here.
+            OpNode::Call(..) |
+            OpNode::Alloca(..) |
+            OpNode::Return(..) |
+            OpNode::AsmOp(..) => return None,
+            _ => {},
+        }
+        if defs(&ops[i].val).iter().any(|v| memory.contains(&v.name) &&
+                                         !globals.contains_key(&v.name)) {
+            return None;
+        }
+    }
+    let entries: Vec<usize> = (0..ops.len())
+        .filter(|&i| (i < start || i >= end) && jump_target(&ops[i].val) == Some(header))
+        .collect();
+    if entries.len() != 1 {
+        return None;
+    }
+    let entry = entries[0];
+    match ops[entry].val {
+        OpNode::Goto(..) => {},
+        _ => return None,
+    }
+
+    // The basic induction variables, and what their updates add.
+    let sites = def_sites(ops);
+    let mut steps = BTreeMap::new();
+    let mut updates = BTreeMap::new();
+    for &iv in label_vars(&ops[start].val).iter() {
+        if memory.contains(&iv.name) {
+            continue;
+        }
+        let passed = *label_vars(&ops[back].val).iter().filter(|v| v.name == iv.name)
+                                                .next().unwrap();
+        let d = match sites.get(&resolve(passed, ops, &sites)) {
+            Some(&d) if d > start && d < back => d,
+            _ => continue,
+        };
+        let step = match ops[d].val {
+            OpNode::BinOp(_, PlusOp, Variable(x), Constant(NumLit(n, _)), _) |
+            OpNode::BinOp(_, PlusOp, Constant(NumLit(n, _)), Variable(x), _)
+                if resolve(x, ops, &sites) == iv => n as u32,
+            OpNode::BinOp(_, MinusOp, Variable(x), Constant(NumLit(n, _)), _)
+                if resolve(x, ops, &sites) == iv => (n as u32).wrapping_neg(),
+            _ => continue,
+        };
+        steps.insert(iv, step);
+        updates.insert(d, (iv.name, step));
+    }
+
+    // The test, as `iv op bound`.
+    let invariant = |rve: &RValueElem| match *rve {
+        Variable(ref v) => !memory.contains(&v.name) && match sites.get(v) {
+            Some(&d) => d < start || d >= end,
+            None => true,
+        },
+        Constant(NumLit(..)) => true,
+        Constant(..) => false,
+    };
+    let is_iv = |rve: &RValueElem| match *rve {
+        Variable(v) => {
+            let v = resolve(v, ops, &sites);
+            if steps.contains_key(&v) { Some(v) } else { None }
+        },
+        _ => None,
+    };
+    let c = resolve(c, ops, &sites);
+    let (iv, op, bound, signed) = match sites.get(&c).map(|&i| &ops[i].val) {
+        Some(&OpNode::BinOp(_, op, ref lhs, ref rhs, signed)) => {
+            let flipped = match op {
+                LessOp => GreaterOp,
+                LessEqOp => GreaterEqOp,
+                GreaterOp => LessOp,
+                GreaterEqOp => LessEqOp,
+                _ => return None,
+            };
+            match (is_iv(lhs), is_iv(rhs)) {
+                (Some(iv), _) if invariant(rhs) => (iv, op, rhs.clone(), signed),
+                (_, Some(iv)) if invariant(lhs) => (iv, flipped, lhs.clone(), signed),
+                _ => return None,
+            }
+        },
+        _ => return None,
+    };
+    let step = steps[&iv];
+    let up = match op {
+        LessOp | LessEqOp => true,
+        _ => false,
+    };
+    if step == 0 || (step as i32 > 0) != up {
+        return None;
+    }
+
+    let entry_var = *label_vars(&ops[entry].val).iter().filter(|v| v.name == iv.name)
+                                                  .next().unwrap();
+    let trips = match (constant(&Variable(entry_var), ops, &sites),
+                       constant(&bound, ops, &sites)) {
+        (Some(first), Some(last)) => {
+            let mut trips = 0;
+            let mut x = first;
+            while trips <= MAX_TRIPS && compare(op, signed, x, last) {
+                trips += 1;
+                x = x.wrapping_add(step);
+            }
+            Some(trips)
+        },
+        _ => None,
+    };
+
+    let size = ops[start..end].iter().filter(|op| match op.val {
+        OpNode::Label(..) | OpNode::Nop => false,
+        _ => true,
+    }).count();
+
+    Some(Counted {
+        start: start,
+        header: header,
+        test: test,
+        exit: exit,
+        back: back,
+        entry: entry,
+        iv: iv,
+        op: op,
+        bound: bound,
+        signed: signed,
+        step: step,
+        updates: updates,
+        trips: trips,
+        size: size,
+    })
+}
+
+// Add `copy`'s version of `ops[from .. to]` to `out`, for iteration `t` of
+// those put together, where the header's variables were `first` in the
+// first of them. The test is left out: the copies after the first can't
+// leave the loop.
+fn emit(ops: &Vec<Op>, l: &Counted, copy: &Copy, t: usize, from: usize, to: usize,
+        first: &BTreeMap<Name, Var>, out: &mut Vec<Op>) {
+    for i in from..to {
+        if i == l.test {
+            continue;
+        }
+        let val = match (&ops[i].val, l.updates.get(&i)) {
+            (&OpNode::BinOp(v, _, _, _, signed), Some(&(name, step))) if t > 0 => {
+                let step = step.wrapping_mul(t as u32 + 1);
+                OpNode::BinOp(copy.var(&v), PlusOp, Variable(first[&name]),
+                              literal(step, signed), signed)
+            },
+            (val, _) => copy.op(val),
+        };
+        out.push(WithId { id: ops[i].id, val: val });
+    }
+}
+
+impl Context {
+    // Add a copy of the whole loop but the header's label and the jump back
+    // to `out`; see `emit`. Returns what it passes back to the header.
+    fn body(&mut self, ops: &Vec<Op>, l: &Counted, t: usize, header: &BTreeMap<Name, Var>,
+            first: &BTreeMap<Name, Var>, out: &mut Vec<Op>) -> BTreeMap<Name, Var> {
+        let copy = self.copy(ops, l, l.start + 1, l.back, header);
+        emit(ops, l, &copy, t, l.start + 1, l.back, first, out);
+        by_name(&copy.vars(label_vars(&ops[l.back].val)))
+    }
+
+    // Replace the loop with `trips` copies of its body.
+    fn unroll_fully(&mut self, ops: &mut Vec<Op>, l: &Counted, trips: usize) {
+        let first = by_name(label_vars(&ops[l.start].val));
+        let mut out = ops[..l.start + 1].to_vec();
+        let mut header = first.clone();
+        for t in 0..trips {
+            header = self.body(ops, l, t, &header, &first, &mut out);
+        }
+
+        // The test fails after the last of them.
+        let last = self.copy(ops, l, l.start + 1, l.test, &header);
+        for i in l.start + 1 .. l.test {
+            out.push(WithId { id: ops[i].id, val: last.op(&ops[i].val) });
+        }
+        out.push(WithId {
+            id: ops[l.test].id,
+            val: OpNode::Goto(l.exit, last.vars(label_vars(&ops[l.test].val))),
+        });
+
+        // What's after the loop sees the header's variables as the last
+        // copy has them.
+        let after = Copy { vars: last.vars, labels: BTreeMap::new() };
+        self.straight.insert(l.header);
+        self.straight.insert(l.exit);
+        for op in ops[l.back + 1 ..].iter() {
+            out.push(WithId { id: op.id, val: after.op(&op.val) });
+        }
+        *ops = out;
+    }
+
+    // Put a loop running `factor` iterations at a time in front of the
+    // loop, which does what's left over.
+    fn unroll(&mut self, ops: &mut Vec<Op>, l: &Counted, factor: usize) -> bool {
+        let room = match (factor as u32 - 1).checked_mul(if l.step as i32 > 0 {
+            l.step
+        } else {
+            l.step.wrapping_neg()
+        }) {
+            Some(room) => room,
+            None => return false,
+        };
+
+        let vars: BTreeSet<Var> = label_vars(&ops[l.start].val).iter()
+                                                                .map(|v| self.gen(v.name))
+                                                                .collect();
+        let first = by_name(&vars);
+        let label = self.labels;
+        self.labels += 1;
+
+        let mut out = vec!();
+        for (i, op) in ops[..l.start].iter().enumerate() {
+            let val = match op.val {
+                OpNode::Goto(_, ref vars) if i == l.entry => OpNode::Goto(label, vars.clone()),
+                ref val => val.clone(),
+            };
+            out.push(WithId { id: op.id, val: val });
+        }
+        let id = ops[l.start].id;
+        out.push(WithId { id: id, val: OpNode::Label(label, vars.clone()) });
+
+        // The first copy has the test, which goes to the old loop to leave,
+        // and then goes there too unless there's room for the others.
+        let copy = self.copy(ops, l, l.start + 1, l.back, &first);
+        emit(ops, l, &copy, 0, l.start + 1, l.test, &first, &mut out);
+        let test = match ops[l.test].val {
+            OpNode::CondGoto(_, ref rve, _, _) => copy.rve(rve),
+            _ => unreachable!(),
+        };
+        out.push(WithId {
+            id: ops[l.test].id,
+            val: OpNode::CondGoto(true, test, l.header, vars.clone()),
+        });
+        let iv = Variable(first[&l.iv.name]);
+        let (lhs, rhs) = if l.step as i32 > 0 {
+            (l.bound.clone(), iv)
+        } else {
+            (iv, l.bound.clone())
+        };
+        let left = self.temps.var(true);
+        let enough = self.temps.var(true);
+        let compare = match l.op {
+            LessOp | GreaterOp => GreaterOp,
+            _ => GreaterEqOp,
+        };
+        out.push(WithId { id: id, val: OpNode::BinOp(left, MinusOp, lhs, rhs, l.signed) });
+        out.push(WithId {
+            id: id,
+            val: OpNode::BinOp(enough, compare, Variable(left), literal(room, false), false),
+        });
+        out.push(WithId {
+            id: id,
+            val: OpNode::CondGoto(true, Variable(enough), l.header, vars.clone()),
+        });
+
+        emit(ops, l, &copy, 0, l.test + 1, l.back, &first, &mut out);
+        let mut header = by_name(&copy.vars(label_vars(&ops[l.back].val)));
+        for t in 1..factor {
+            header = self.body(ops, l, t, &header, &first, &mut out);
+        }
+        out.push(WithId {
+            id: ops[l.back].id,
+            val: OpNode::Goto(label, header.values().cloned().collect()),
+        });
+
+        out.extend(ops[l.start..].iter().cloned());
+        *ops = out;
+        true
+    }
+}
+
+// Join the blocks the copies are split into where they just go on to the
+// next: a label in `labels` whose only jump is a goto right before it goes,
+// along with the goto, and its variables become what the goto passes.
+fn straighten(ops: &mut Vec<Op>, labels: &BTreeSet<usize>) {
+    let mut jumps = BTreeMap::new();
+    for op in ops.iter() {
+        if let Some(label) = jump_target(&op.val) {
+            let count = *jumps.get(&label).unwrap_or(&0);
+            jumps.insert(label, count + 1);
+        }
+    }
+
+    let mut joined = BTreeSet::new();
+    let mut renamed = Copy { vars: BTreeMap::new(), labels: BTreeMap::new() };
+    let mut prev = 0;
+    for (i, op) in ops.iter().enumerate() {
+        match (&ops[prev].val, &op.val) {
+            (&OpNode::Goto(target, ref passed), &OpNode::Label(label, ref vars))
+                if target == label && labels.contains(&label) && jumps[&label] == 1 => {
+                let passed = by_name(&renamed.vars(passed));
+                for v in vars.iter() {
+                    renamed.vars.insert(*v, passed[&v.name]);
+                }
+                joined.insert(prev);
+                joined.insert(i);
+            },
+            _ => {},
+        }
+        match op.val {
+            OpNode::Nop => {},
+            _ => prev = i,
+        }
+    }
+
+    let old_ops = ::std::mem::replace(ops, vec!());
+    for (i, op) in old_ops.into_iter().enumerate() {
+        if !joined.contains(&i) {
+            ops.push(WithId { id: op.id, val: renamed.op(&op.val) });
+        }
+    }
+}
+
+impl Unroller {
+    /// Unroll the counted loops in `ops`. `labels` is the next label no
+    /// function in the program uses; see `program_label_count`.
+    pub fn unroll(ops: &mut Vec<Op>, globals: &BTreeMap<Name, StaticIRItem>,
+                  labels: &mut usize, verbose: bool) {
+        // Externs have no loops, and we need SSA form.
+        if ops.len() <= 1 ||
+            ops.iter().any(|op| defs(&op.val).iter().any(|v| v.generation.is_none())) {
+            return;
+        }
+
+        let mut gens = BTreeMap::new();
+        for op in ops.iter() {
+            for v in defs(&op.val).into_iter().chain(uses(&op.val).into_iter()) {
+                let gen = v.generation.unwrap_or(0) + 1;
+                if *gens.get(&v.name).unwrap_or(&0) < gen {
+                    gens.insert(v.name, gen);
+                }
+            }
+        }
+        let memory = memory_names(ops, globals);
+        let mut context = Context {
+            gens: gens,
+            labels: *labels,
+            straight: BTreeSet::new(),
+            temps: Temps::new(ops),
+            verbose: verbose,
+        };
+
+        // Each loop we unroll moves everything after it, so we start over
+        // with a new CFG. The loops we add in front aren't counted ones, and
+        // what's left of the old ones is too big to unroll again.
+        let mut done = BTreeSet::new();
+        loop {
+            let cfg = CFG::new(ops);
+            let found = (0..cfg.loops.len()).filter_map(|l| {
+                counted(ops, &cfg, l, &memory, globals)
+            }).filter(|l| !done.contains(&l.header)).next();
+            let l = match found {
+                Some(l) => l,
+                None => break,
+            };
+            done.insert(l.header);
+
+            match l.trips {
+                Some(trips) if trips <= MAX_TRIPS &&
+                               trips as usize * l.size <= FULL_SIZE => {
+                    if context.verbose {
+                        print!("Unrolling the loop at label {} {} times\n", l.header, trips);
+                    }
+                    context.unroll_fully(ops, &l, trips as usize);
+                },
+                _ => {
+                    let factor = if l.size * FACTOR <= PARTIAL_SIZE {
+                        FACTOR
+                    } else if l.size * FACTOR / 2 <= PARTIAL_SIZE {
+                        FACTOR / 2
+                    } else {
+                        continue;
+                    };
+                    if context.unroll(ops, &l, factor) && context.verbose {
+                        print!("Unrolling the loop at label {} by {}\n", l.header, factor);
+                    }
+                },
+            }
+        }
+        straighten(ops, &context.straight);
+        *labels = context.labels;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Unroller;
+    use ir::cfg::CFG;
+    use ir::util::program_label_count;
+    use testing::{run_pass, run_asm_source, optimize_source, find_func};
+
+    fn unroll(src: &str) -> String {
+        run_pass(src, true, true, |program, global_map| {
+            let mut labels = program_label_count(program);
+            Unroller::unroll(&mut program[0], global_map, &mut labels, false);
+        })
+    }
+
+    #[test]
+    fn test_full_unrolling() {
+        let result = unroll("global %print_int size 0 offset 0 extern func
+fn %__main()
+    %s<1> := 0
+    %i<1> := 0
+    goto 0(%s<1>, %i<1>)
+    label 0(%s<2>, %i<2>)
+    %c<1> := %i<2> <s 4
+    if !%c<1> goto 1()
+    %t<1> := %i<2> *s %i<2>
+    %s<3> := %s<2> +s %t<1>
+    %i<3> := %i<2> +s 1
+    goto 0(%s<3>, %i<3>)
+    label 1()
+    %r<1> := call %print_int<0>(%s<2>)
+    %r<2> := call %print_int<0>(%i<2>)
+    return 0
+fn %print_int(%x) extern \"C\"
+");
+        // Four copies in one block, each adding to the first value of `i`.
+        assert!(!result.contains("goto") && !result.contains("label"), "{}", result);
+        assert!(result.contains("%i<5> := %i<1> +s 2\n"), "{}", result);
+        assert!(result.contains("%i<7> := %i<1> +s 4\n"), "{}", result);
+        assert!(result.contains("call %print_int<0>(%s<7>)"), "{}", result);
+        assert!(result.contains("call %print_int<0>(%i<7>)"), "{}", result);
+    }
+
+    #[test]
+    fn test_partial_unrolling() {
+        let result = unroll("global %print_int size 0 offset 0 extern func
+fn %f(%n<1>)
+    %s<1> := 0
+    %i<1> := 0
+    goto 0(%s<1>, %i<1>)
+    label 0(%s<2>, %i<2>)
+    %c<1> := %i<2> <s %n<1>
+    if !%c<1> goto 1()
+    %t<1> := %i<2> *s %i<2>
+    %s<3> := %s<2> +s %t<1>
+    %i<3> := %i<2> +s 1
+    goto 0(%s<3>, %i<3>)
+    label 1()
+    %r<1> := call %print_int<0>(%s<2>)
+    return 0
+fn %__main()
+    %k<1> := 0
+    goto 0(%k<1>)
+    label 0(%k<2>)
+    %c<1> := %k<2> <s 10
+    if !%c<1> goto 1()
+    %r<1> := call %f<0>(%k<2>)
+    %k<3> := %k<2> +s 1
+    goto 0(%k<3>)
+    label 1()
+    return 0
+fn %print_int(%x) extern \"C\"
+");
+        // A new loop, doing four iterations at a time while there's room,
+        // in front of the old one.
+        assert!(result.contains("goto 2(%s<1>, %i<1>)\n    label 2(%s<4>, %i<4>)\n"),
+                "{}", result);
+        assert!(result.contains("if !%c<2> goto 0(%s<4>, %i<4>)\n"), "{}", result);
+        assert!(result.contains("%TEMP$0<1> := %n<1> -s %i<4>\n"), "{}", result);
+        assert!(result.contains("%TEMP$1<1> := %TEMP$0<1> >u 3u32\n"), "{}", result);
+        assert!(result.contains("%i<6> := %i<4> +s 2\n"), "{}", result);
+        assert!(result.contains("goto 2(%s<8>, %i<8>)\n    label 0(%s<2>, %i<2>)\n"),
+                "{}", result);
+        assert_eq!(result.matches("if ").count(), 3, "{}", result);
+    }
+
+    #[test]
+    fn test_asm_labels() {
+        // The asm target doesn't qualify labels with their function, so the
+        // new loop in `sum` can't use labels `main` does.
+        let result = run_asm_source("
+            #[inline(never)]
+            fn sum(a: *u32, n: u32) -> u32 {
+                let total: u32 = 0;
+                let i: u32 = 0;
+                while i < n {
+                    total += *(a + i);
+                    i += 1;
+                }
+                total
+            }
+
+            fn main() -> u32 {
+                let a: u32[8];
+                let i: u32 = 0;
+                while i < 8 {
+                    a[i] = i * 3 + 1;
+                    print_uint(sum(&a[0], i + 1));
+                    i += 1;
+                }
+                0
+            }", &[("opt_level", "2")]);
+        assert_eq!(result, Ok("1\n5\n12\n22\n35\n51\n70\n92\n".to_string()));
+    }
+
+    #[test]
+    fn test_unrolling() {
+        let program = optimize_source("
+            #[inline(never)]
+            fn sum(a: *u32, n: u32) -> u32 {
+                let total: u32 = 0;
+                let i: u32 = 0;
+                while i < n {
+                    total += *(a + i);
+                    i += 1;
+                }
+                total
+            }
+
+            fn main() -> u32 {
+                let a: u32[8];
+                let i: u32 = 0;
+                while i < 8 {
+                    a[i] = i * 3 + 1;
+                    i += 1;
+                }
+                let total: u32 = 0;
+                for (i = 0; i < 8; i += 1) {
+                    total += a[i];
+                }
+                print_uint(total);
+                i = 0;
+                while i <= 8 {
+                    print_uint(sum(&a[0], i));
+                    i += 1;
+                }
+                0
+            }", 2);
+        // The loops over the whole array are gone, and the one in `sum` has
+        // another in front of it doing four iterations at a time.
+        assert_eq!(CFG::new(find_func(&program, "__main")).loops.len(), 1);
+        assert_eq!(CFG::new(find_func(&program, "__sum")).loops.len(), 2);
+    }
+}
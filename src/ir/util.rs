@@ -1,7 +1,9 @@
-use std::collections::BTreeSet;
+use std::collections::{BTreeMap, BTreeSet};
 use std::iter::Map;
 
 use mc::ast::*;
+use mc::session::INTERNER;
+use util::Name;
 
 use ir::*;
 use values::*;
@@ -170,3 +172,193 @@ pub fn subst(ops: &mut Vec<Op>,
         op.val = temp;
     }
 }
+
+fn push_rve<'a>(vars: &mut Vec<&'a Var>, rve: &'a RValueElem) {
+    match *rve {
+        Variable(ref v) => vars.push(v),
+        Constant(..) => {},
+    }
+}
+
+/// The variables an op assigns.
+pub fn defs(op: &OpNode) -> Vec<&Var> {
+    match *op {
+        OpNode::UnOp(ref v, _, _) |
+        OpNode::BinOp(ref v, _, _, _, _) |
+        OpNode::Alloca(ref v, _) |
+        OpNode::Call(ref v, _, _) |
+        OpNode::Load(ref v, _, _) => vec!(v),
+        OpNode::Label(_, ref vars) => vars.iter().collect(),
+        OpNode::Func(_, ref vars, _) => vars.iter().collect(),
+        _ => vec!(),
+    }
+}
+
+/// The variables an op reads.
+pub fn uses(op: &OpNode) -> Vec<&Var> {
+    let mut vars = vec!();
+    match *op {
+        OpNode::UnOp(_, _, ref rve) |
+        OpNode::Return(ref rve) => push_rve(&mut vars, rve),
+        OpNode::BinOp(_, _, ref rve1, ref rve2, _) => {
+            push_rve(&mut vars, rve1);
+            push_rve(&mut vars, rve2);
+        },
+        OpNode::Call(_, ref f, ref args) => {
+            push_rve(&mut vars, f);
+            vars.extend(args.iter());
+        },
+        OpNode::Store(ref ptr, ref v, _) => {
+            vars.push(ptr);
+            vars.push(v);
+        },
+        OpNode::Load(_, ref ptr, _) => vars.push(ptr),
+        OpNode::Goto(_, ref args) => vars.extend(args.iter()),
+        OpNode::CondGoto(_, ref rve, _, ref args) => {
+            push_rve(&mut vars, rve);
+            vars.extend(args.iter());
+        },
+        _ => {},
+    }
+    vars
+}
+
+/// Variables that live in memory rather than in registers: globals, which
+/// calls and stores can change behind our backs, and anything whose address
+/// is taken. Their generations don't stand for fixed values.
+pub fn memory_names(ops: &Vec<Op>, globals: &BTreeMap<Name, StaticIRItem>) -> BTreeSet<Name> {
+    let mut names: BTreeSet<Name> = globals.keys().cloned().collect();
+    for op in ops.iter() {
+        match op.val {
+            OpNode::UnOp(_, AddrOf, Variable(ref v)) => { names.insert(v.name); },
+            _ => {},
+        }
+    }
+    names
+}
+
+/// One more than the highest label number in `ops`.
+pub fn label_count(ops: &Vec<Op>) -> usize {
+    ops.iter().map(|op| match op.val {
+        OpNode::Label(label, _) |
+        OpNode::Goto(label, _) |
+        OpNode::CondGoto(_, _, label, _) => label + 1,
+        _ => 0,
+    }).max().unwrap_or(0)
+}
+
+/// One more than the highest label number in any function of `program`.
+/// Labels are numbered across the whole program, since the asm target
+/// doesn't qualify them with their function's name, so passes that add
+/// labels take them from here rather than from the function's own
+/// `label_count`.
+pub fn program_label_count(program: &Vec<Vec<Op>>) -> usize {
+    program.iter().map(label_count).max().unwrap_or(0)
+}
+
+pub fn is_nop(op: &Op) -> bool {
+    match op.val {
+        OpNode::Nop => true,
+        _ => false,
+    }
+}
+
+/// The label a jump goes to.
+pub fn jump_target(op: &OpNode) -> Option<usize> {
+    match *op {
+        OpNode::Goto(label, _) |
+        OpNode::CondGoto(_, _, label, _) => Some(label),
+        _ => None,
+    }
+}
+
+/// The index of the op that assigns each variable.
+pub fn def_sites(ops: &Vec<Op>) -> BTreeMap<Var, usize> {
+    let mut sites = BTreeMap::new();
+    for (i, op) in ops.iter().enumerate() {
+        for v in defs(&op.val).into_iter() {
+            sites.insert(*v, i);
+        }
+    }
+    sites
+}
+
+/// Follow copies back to the variable they copy.
+pub fn resolve(v: Var, ops: &Vec<Op>, sites: &BTreeMap<Var, usize>) -> Var {
+    let mut v = v;
+    for _ in 0..ops.len() {
+        match sites.get(&v).map(|&i| &ops[i].val) {
+            Some(&OpNode::UnOp(_, Identity, Variable(x))) => v = x,
+            _ => break,
+        }
+    }
+    v
+}
+
+pub fn rename(v: &mut Var, renamed: &BTreeMap<Var, Var>) {
+    *v = *renamed.get(v).unwrap_or(v);
+}
+
+pub fn rename_rve(rve: &mut RValueElem, renamed: &BTreeMap<Var, Var>) {
+    match *rve {
+        Variable(ref mut v) => rename(v, renamed),
+        Constant(..) => {},
+    }
+}
+
+/// Rename the uses in `op`, except in the variables jumps and calls pass.
+pub fn rename_uses(op: &mut OpNode, renamed: &BTreeMap<Var, Var>) {
+    match *op {
+        OpNode::UnOp(_, _, ref mut rve) |
+        OpNode::Return(ref mut rve) |
+        OpNode::CondGoto(_, ref mut rve, _, _) => rename_rve(rve, renamed),
+        OpNode::BinOp(_, _, ref mut rve1, ref mut rve2, _) => {
+            rename_rve(rve1, renamed);
+            rename_rve(rve2, renamed);
+        },
+        OpNode::Call(_, ref mut f, _) => rename_rve(f, renamed),
+        OpNode::Store(ref mut ptr, ref mut v, _) => {
+            rename(ptr, renamed);
+            rename(v, renamed);
+        },
+        OpNode::Load(_, ref mut ptr, _) => rename(ptr, renamed),
+        _ => {},
+    }
+}
+
+/// Hands out variables whose names aren't used anywhere in a function yet,
+/// for passes that need new temporaries.
+pub struct Temps {
+    used: BTreeSet<Name>,
+    next: usize,
+}
+
+impl Temps {
+    pub fn new(ops: &Vec<Op>) -> Temps {
+        let mut used = BTreeSet::new();
+        for op in ops.iter() {
+            for var in defs(&op.val).into_iter().chain(uses(&op.val).into_iter()) {
+                used.insert(var.name);
+            }
+        }
+        Temps { used: used, next: 0 }
+    }
+
+    /// A name nothing in the function uses yet. Identifiers in the source
+    /// can't contain a `$`, so it can't be a global's name either.
+    pub fn name(&mut self) -> Name {
+        loop {
+            let name = INTERNER.with(|x| x.intern(format!("TEMP${}", self.next)));
+            self.next += 1;
+            if !self.used.contains(&name) {
+                self.used.insert(name);
+                return name;
+            }
+        }
+    }
+
+    /// A new variable. In SSA form it's the first generation of its name.
+    pub fn var(&mut self, ssa: bool) -> Var {
+        Var { name: self.name(), generation: if ssa { Some(1) } else { None } }
+    }
+}
@@ -0,0 +1,230 @@
+//! Checks that a function's ops are well-formed.
+//!
+//! The pass manager runs this after every pass in debug builds, so that a
+//! pass that breaks the IR is caught right away rather than by whichever
+//! pass or backend trips over it next. Before SSA conversion only the shape
+//! of the function is checked; once variables have generations, every
+//! generation must also be defined exactly once, before it's used. A use of
+//! generation 0 is a value from outside the function, like a global or a
+//! variable that was never assigned, and needs no definition.
+
+use ir::*;
+use mc::ast::*;
+use ir::cfg::CFG;
+use ir::text::{var_to_string, vars_to_string};
+use ir::util::{defs, uses};
+use util::Name;
+
+use std::collections::{BTreeMap, BTreeSet};
+
+pub struct VerifyError {
+    /// The index of the offending op.
+    pub op: usize,
+    pub msg: String,
+}
+
+fn error<T>(op: usize, msg: String) -> Result<T, VerifyError> {
+    Err(VerifyError { op: op, msg: msg })
+}
+
+fn names(vars: &BTreeSet<Var>) -> BTreeSet<Name> {
+    vars.iter().map(|v| v.name).collect()
+}
+
+// Func first and only first; labels defined once, reached only by jumps,
+// and jumped to with the variables they take.
+fn verify_shape(ops: &Vec<Op>) -> Result<(), VerifyError> {
+    match ops.first() {
+        Some(&Op { val: OpNode::Func(..), .. }) => {},
+        Some(..) => return error(0, "function doesn't start with a Func".to_string()),
+        None => return error(0, "function has no ops".to_string()),
+    }
+
+    let mut labels = BTreeMap::<usize, &BTreeSet<Var>>::new();
+    let mut prev = 0;
+    for (i, op) in ops.iter().enumerate().skip(1) {
+        match op.val {
+            OpNode::Func(..) => return error(i, "Func after the start of a function".to_string()),
+            OpNode::Label(label, ref vars) => {
+                if labels.contains_key(&label) {
+                    return error(i, format!("label {} defined twice", label));
+                }
+                labels.insert(label, vars);
+                match ops[prev].val {
+                    OpNode::Goto(..) | OpNode::Return(..) => {},
+                    _ => return error(i, format!("label {} is reached by falling through",
+                                                 label)),
+                }
+            },
+            _ => {},
+        }
+        match op.val {
+            OpNode::Nop => {},
+            _ => prev = i,
+        }
+    }
+
+    for (i, op) in ops.iter().enumerate() {
+        match op.val {
+            OpNode::Label(_, ref vars) |
+            OpNode::Goto(_, ref vars) |
+            OpNode::CondGoto(_, _, _, ref vars) => {
+                if names(vars).len() != vars.len() {
+                    return error(i, format!("{} has a variable more than once",
+                                            vars_to_string(vars.iter())));
+                }
+            },
+            _ => {},
+        }
+        match op.val {
+            OpNode::Goto(label, ref vars) |
+            OpNode::CondGoto(_, _, label, ref vars) => {
+                let label_vars = match labels.get(&label) {
+                    Some(label_vars) => label_vars,
+                    None => return error(i, format!("jump to nonexistent label {}", label)),
+                };
+                if names(vars) != names(label_vars) {
+                    return error(i, format!("jump passes {} to label {}, which takes {}",
+                                            vars_to_string(vars.iter()), label,
+                                            vars_to_string(label_vars.iter())));
+                }
+            },
+            _ => {},
+        }
+    }
+
+    Ok(())
+}
+
+// Every generation is defined once, and its definition dominates its uses.
+fn verify_ssa(ops: &Vec<Op>) -> Result<(), VerifyError> {
+    let mut def_of = BTreeMap::<Var, usize>::new();
+    for (i, op) in ops.iter().enumerate() {
+        for var in defs(&op.val).into_iter().chain(uses(&op.val).into_iter()) {
+            if var.generation.is_none() {
+                return error(i, format!("{} has no generation in SSA form",
+                                        var_to_string(var)));
+            }
+        }
+        for var in defs(&op.val).into_iter() {
+            if def_of.contains_key(var) {
+                return error(i, format!("{} is defined more than once", var_to_string(var)));
+            }
+            def_of.insert(var.clone(), i);
+        }
+    }
+
+    let cfg = CFG::new(ops);
+    for (i, op) in ops.iter().enumerate() {
+        let block = cfg.block_of[i];
+        for var in uses(&op.val).into_iter() {
+            let d = match def_of.get(var) {
+                Some(&d) => d,
+                None if var.generation == Some(0) => continue,
+                None => return error(i, format!("{} is used but never defined",
+                                                var_to_string(var))),
+            };
+            // Unreachable code never runs, so anything goes there.
+            if !cfg.is_reachable(block) {
+                continue;
+            }
+            let def_block = cfg.block_of[d];
+            let before = if def_block == block {
+                d < i
+            } else {
+                cfg.dominates(def_block, block)
+            };
+            if !before {
+                return error(i, format!("{} is used before it's defined",
+                                        var_to_string(var)));
+            }
+        }
+    }
+
+    Ok(())
+}
+
+/// Check `ops`. `folded` says whether constant folding has run, after which
+/// no binop should have two constant operands, unless it divides by zero.
+pub fn verify(ops: &Vec<Op>, folded: bool) -> Result<(), VerifyError> {
+    try!(verify_shape(ops));
+
+    let in_ssa = ops.iter().any(|op| {
+        defs(&op.val).into_iter().chain(uses(&op.val).into_iter())
+            .any(|v| v.generation.is_some())
+    });
+    if in_ssa {
+        try!(verify_ssa(ops));
+    }
+
+    if folded {
+        for (i, op) in ops.iter().enumerate() {
+            match op.val {
+                // Division by zero is left for run time.
+                OpNode::BinOp(_, DivideOp, Constant(..), Constant(NumLit(0, _)), _) |
+                OpNode::BinOp(_, ModOp, Constant(..), Constant(NumLit(0, _)), _) => {},
+                OpNode::BinOp(_, _, Constant(..), Constant(..), _) =>
+                    return error(i, "binop on two constants after constant folding"
+                                 .to_string()),
+                _ => {},
+            }
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::verify;
+    use ir::text::parse_program;
+
+    fn check(src: &str, folded: bool) -> Result<(), (usize, String)> {
+        let (program, _) = parse_program(src).unwrap();
+        verify(&program[0], folded).map_err(|e| (e.op, e.msg))
+    }
+
+    #[test]
+    fn test_well_formed() {
+        let src = "fn %f(%n<1>)
+    %i<1> := 0
+    goto 0(%i<1>)
+    label 0(%i<2>)
+    %c<1> := %i<2> <s %n<1>
+    if !%c<1> goto 1(%i<2>)
+    %i<3> := %i<2> +s 1
+    goto 0(%i<3>)
+    label 1(%i<4>)
+    return %i<4>
+";
+        assert_eq!(check(src, true), Ok(()));
+        // Before SSA, only the shape is checked.
+        assert_eq!(check("fn %f(%n)\n    %n := %n +s 1\n    %n := 1 +s 2\n    return %n\n",
+                         false),
+                   Ok(()));
+    }
+
+    #[test]
+    fn test_errors() {
+        assert_eq!(check("fn %f()\n    %x<1> := 1\n    %x<1> := 2\n    return %x<1>\n", false),
+                   Err((2, "%x<1> is defined more than once".to_string())));
+        assert_eq!(check("fn %f()\n    %y<1> := %x<1>\n    %x<1> := 2\n    return %y<1>\n",
+                         false),
+                   Err((1, "%x<1> is used before it's defined".to_string())));
+        assert_eq!(check("fn %f(%a<1>)
+    if %a<1> goto 0()
+    %x<1> := 1
+    goto 0()
+    label 0()
+    return %x<1>
+", false),
+                   Err((5, "%x<1> is used before it's defined".to_string())));
+        assert_eq!(check("fn %f()\n    %x<1> := 1\n    goto 0()\n    label 0(%x<2>)\n\
+                          return 0\n", false),
+                   Err((2, "jump passes () to label 0, which takes (%x<2>)".to_string())));
+        assert_eq!(check("fn %f()\n    %x := 1\n    label 0()\n    return %x\n", false),
+                   Err((2, "label 0 is reached by falling through".to_string())));
+        assert_eq!(check("fn %f()\n    %x := 1 +s 2\n    return %x\n", true),
+                   Err((1, "binop on two constants after constant folding".to_string())));
+    }
+}
@@ -29,5 +29,10 @@ mod target;
 mod values;
 mod codegen;
 
+#[cfg(test)]
+mod testing;
+
 pub mod mc;
 pub mod mas;
+pub mod sim;
+pub mod mir;
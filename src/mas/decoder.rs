@@ -0,0 +1,265 @@
+//! Turn encoded Moroso instruction words back into `InstNode`s.
+//!
+//! This is the inverse of `mas::encoder::encode`. A lone word can't always
+//! be decoded on its own: a word following an instruction that takes a long
+//! constant is the constant itself, so most callers want `decode_packet`.
+
+use mas::ast::*;
+
+fn bits(word: u32, hi: u32, lo: u32) -> u32 {
+    (word >> (lo as usize)) & ((1u32 << ((hi - lo + 1) as usize)) - 1)
+}
+
+fn sign_extend(val: u32, width: u32) -> i32 {
+    let shift = (32 - width) as usize;
+    ((val << shift) as i32) >> shift
+}
+
+fn decode_pred(word: u32) -> Pred {
+    Pred {
+        inverted: bits(word, 29, 29) != 0,
+        reg: bits(word, 31, 30) as u8,
+    }
+}
+
+fn decode_reg(word: u32, lo: u32) -> Reg {
+    Reg { index: bits(word, lo + 4, lo) as u8 }
+}
+
+fn decode_aluop(word: u32) -> Option<AluOp> {
+    match bits(word, 13, 10) {
+        0 => Some(AddAluOp),
+        1 => Some(AndAluOp),
+        2 => Some(NorAluOp),
+        3 => Some(OrAluOp),
+        4 => Some(SubAluOp),
+        5 => Some(RsbAluOp),
+        6 => Some(XorAluOp),
+        7 => Some(CompareAluOp),
+        8 => Some(MovAluOp),
+        9 => Some(MvnAluOp),
+        10 => Some(SxbAluOp),
+        11 => Some(SxhAluOp),
+        _ => None,
+    }
+}
+
+fn decode_comparetype(word: u32) -> CompareType {
+    match bits(word, 9, 7) {
+        0 => CmpLTU,
+        1 => CmpLEU,
+        2 => CmpEQ,
+        3 => CmpRESERVED,
+        4 => CmpLTS,
+        5 => CmpLES,
+        6 => CmpBS,
+        _ => CmpBC,
+    }
+}
+
+fn decode_shifttype(word: u32) -> ShiftType {
+    match bits(word, 20, 19) {
+        0 => SllShift,
+        1 => SrlShift,
+        2 => SraShift,
+        _ => RorShift,
+    }
+}
+
+fn decode_lsuop(word: u32) -> LsuOp {
+    LsuOp {
+        store: bits(word, 12, 12) != 0,
+        width: match bits(word, 11, 10) {
+            0 => LsuWidthB,
+            1 => LsuWidthH,
+            2 => LsuWidthL,
+            _ => LsuLLSC,
+        },
+    }
+}
+
+fn decode_coreg(n: u32) -> Option<CoReg> {
+    match n {
+        0 => Some(PFLAGS),
+        1 => Some(PTB),
+        2 => Some(EHA),
+        3 => Some(EPC),
+        4 => Some(EC0),
+        5 => Some(EC1),
+        6 => Some(EC2),
+        7 => Some(EC3),
+        8 => Some(EA0),
+        9 => Some(EA1),
+        16 => Some(SP0),
+        17 => Some(SP1),
+        18 => Some(SP2),
+        19 => Some(SP3),
+        _ => None,
+    }
+}
+
+fn dest_pred(word: u32) -> Pred {
+    Pred { inverted: false, reg: bits(word, 6, 5) as u8 }
+}
+
+/// Returns whether the instruction expects the next slot to hold a long.
+pub fn takes_long(inst: &InstNode) -> bool {
+    match *inst {
+        ALU2LongInst(..) |
+        ALU1LongInst(..) |
+        CompareLongInst(..) => true,
+        _ => false,
+    }
+}
+
+/// Decode a single instruction word. Returns None for encodings that don't
+/// correspond to any instruction.
+pub fn decode(word: u32) -> Option<InstNode> {
+    let pred = decode_pred(word);
+
+    // An instruction predicated on !p3 can never run; that's how the
+    // encoder writes nops.
+    if pred.reg == 3 && pred.inverted {
+        return Some(NopInst);
+    }
+
+    if bits(word, 28, 28) == 0 {
+        // Short-form ALU and compare instructions.
+        let op = match decode_aluop(word) {
+            Some(op) => op,
+            None => return None,
+        };
+        let rot = bits(word, 17, 14) as u8;
+        let low = bits(word, 27, 18);
+        return Some(if op.is_compare() {
+            CompareShortInst(pred, dest_pred(word), decode_reg(word, 0),
+                             decode_comparetype(word), low, rot)
+        } else if op.is_unary() {
+            ALU1ShortInst(pred, op, decode_reg(word, 5),
+                          low | (bits(word, 4, 0) << 10), rot)
+        } else {
+            ALU2ShortInst(pred, op, decode_reg(word, 5), decode_reg(word, 0),
+                          low, rot)
+        });
+    }
+
+    match bits(word, 27, 26) {
+        0b01 => {
+            // Register-form ALU and compare instructions.
+            let op = match decode_aluop(word) {
+                Some(op) => op,
+                None => return None,
+            };
+            let amt = bits(word, 25, 21) as u8;
+            let shifttype = decode_shifttype(word);
+            Some(if op.is_compare() {
+                CompareRegInst(pred, dest_pred(word), decode_reg(word, 0),
+                               decode_comparetype(word), decode_reg(word, 14),
+                               shifttype, amt)
+            } else if op.is_unary() {
+                ALU1RegInst(pred, op, decode_reg(word, 5), decode_reg(word, 14),
+                            shifttype, amt)
+            } else {
+                ALU2RegInst(pred, op, decode_reg(word, 5), decode_reg(word, 0),
+                            decode_reg(word, 14), shifttype, amt)
+            })
+        },
+        0b10 => Some(BranchImmInst(pred,
+                                   bits(word, 25, 25) != 0,
+                                   JumpOffs(sign_extend(bits(word, 24, 0), 25)))),
+        0b11 => Some(BranchRegInst(pred,
+                                   bits(word, 25, 25) != 0,
+                                   decode_reg(word, 0),
+                                   sign_extend(bits(word, 24, 5), 20))),
+        _ => {
+            if bits(word, 25, 25) != 0 {
+                // Loads and stores.
+                let lsuop = decode_lsuop(word);
+                return Some(if lsuop.store {
+                    let offs = (bits(word, 24, 19) << 6) |
+                               (bits(word, 13, 13) << 5) |
+                               bits(word, 9, 5);
+                    StoreInst(pred, lsuop, decode_reg(word, 0),
+                              sign_extend(offs, 12), decode_reg(word, 14))
+                } else {
+                    LoadInst(pred, lsuop, decode_reg(word, 5), decode_reg(word, 0),
+                             sign_extend(bits(word, 24, 13), 12))
+                });
+            }
+
+            if bits(word, 24, 24) != 0 {
+                // Everything that isn't an ALU op or a memory access.
+                let val = (bits(word, 19, 19) << 4) | bits(word, 13, 10);
+                let signed = bits(word, 19, 19) != 0;
+                return match bits(word, 23, 20) {
+                    0b0001 => Some(BreakInst(pred, val)),
+                    0b0010 => Some(SyscallInst(pred, val)),
+                    0b0011 => Some(FenceInst(pred)),
+                    0b0100 => Some(EretInst(pred)),
+                    0b0101 => Some(FlushInst(pred,
+                                             match bits(word, 11, 10) {
+                                                 0 => DataFlush,
+                                                 1 => InstFlush,
+                                                 2 => DtlbFlush,
+                                                 _ => ItlbFlush,
+                                             },
+                                             decode_reg(word, 0))),
+                    0b0110 => decode_coreg(bits(word, 4, 0)).map(
+                        |coreg| MfcInst(pred, decode_reg(word, 5), coreg)),
+                    0b0111 => decode_coreg(bits(word, 9, 5)).map(
+                        |coreg| MtcInst(pred, coreg, decode_reg(word, 0))),
+                    0b1000 => Some(MultInst(pred, signed, decode_reg(word, 5),
+                                            decode_reg(word, 0), decode_reg(word, 14))),
+                    0b1001 => Some(DivInst(pred, signed, decode_reg(word, 5),
+                                           decode_reg(word, 0), decode_reg(word, 14))),
+                    0b1010 => Some(MfhiInst(pred, decode_reg(word, 5))),
+                    0b1011 => Some(MthiInst(pred, decode_reg(word, 0))),
+                    _ => None,
+                };
+            }
+
+            // Long-form ALU and compare instructions, which take their
+            // constant from the following slot.
+            let op = match decode_aluop(word) {
+                Some(op) => op,
+                None => return None,
+            };
+            Some(if bits(word, 21, 21) != 0 {
+                ALU1RegShInst(pred, decode_reg(word, 5), op, decode_reg(word, 14),
+                              decode_shifttype(word), decode_reg(word, 0))
+            } else if op.is_compare() {
+                CompareLongInst(pred, dest_pred(word), decode_reg(word, 0),
+                                decode_comparetype(word))
+            } else if op.is_unary() {
+                ALU1LongInst(pred, op, decode_reg(word, 5))
+            } else {
+                ALU2LongInst(pred, op, decode_reg(word, 5), decode_reg(word, 0))
+            })
+        },
+    }
+}
+
+/// Decode a whole packet. Slots following an instruction that takes a long
+/// are decoded as `LongInst`s. Returns None if any slot is not a valid
+/// instruction.
+pub fn decode_packet(words: &[u32; 4]) -> Option<InstPacket> {
+    let mut packet = [NopInst, NopInst, NopInst, NopInst];
+    let mut i = 0;
+    while i < 4 {
+        let inst = match decode(words[i]) {
+            Some(inst) => inst,
+            None => return None,
+        };
+        let long = takes_long(&inst);
+        packet[i] = inst;
+        if long {
+            if i == 3 {
+                return None;
+            }
+            packet[i + 1] = LongInst(Immediate(words[i + 1]));
+            i += 1;
+        }
+        i += 1;
+    }
+    Some(packet)
+}
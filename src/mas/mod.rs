@@ -16,9 +16,11 @@ pub mod lexer;
 pub mod parser;
 pub mod ast;
 pub mod encoder;
+pub mod decoder;
 pub mod util;
 pub mod labels;
 pub mod scheduler;
+pub mod pipeline;
 
 fn print_bin(n: u32, stream: &mut Write) {
     // Write in little-endian format.
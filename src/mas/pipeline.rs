@@ -0,0 +1,581 @@
+//! Software pipelining.
+//!
+//! A loop that's a single superblock -- a label, straight-line code that
+//! conditional branches might leave, and a jump back to the label -- spends
+//! most of each iteration waiting: the test at the top is a chain of
+//! instructions that each need the one before, and the rest of the body
+//! can't start until the test is done. Modulo scheduling overlaps the
+//! iterations instead. We find a schedule for a single iteration that still
+//! works when the next one starts `ii` packets later; then each packet of
+//! the kernel, the `ii` packets we loop over, runs parts of several
+//! iterations. An instruction at time t in the iteration's schedule is in
+//! the iteration's stage t / ii, and goes in packet t % ii of the kernel.
+//!
+//! We never run anything the loop itself might not have run. Every exit is
+//! in the first stage, so the next iteration doesn't start until the exits
+//! are decided, and the instructions after an exit only go above it if they
+//! can take its predicate, so that they don't run if it's taken. The last
+//! exit goes at the end of the kernel, with its predicate reversed to jump
+//! back to the kernel's start rather than to leave. Leaving a loop leaves the
+//! earlier iterations unfinished, though, so the exits go to epilogues that
+//! finish them before going where the exit went. To start the loop, the
+//! prologue runs the kernel a few times without the stages of iterations
+//! that haven't started, and its exits go to epilogues that don't finish
+//! those.
+//!
+//! Reusing a register for several values within an iteration would keep
+//! iterations from overlapping their uses of it, so we give each value that
+//! doesn't live past its iteration a register of its own, out of those the
+//! function never touches. If we run out of them, what's left usually keeps
+//! the loop from going any quicker, and we fall back to the regular
+//! schedule.
+
+use codegen::FIRST_CALLEE_SAVED_REG;
+use mas::ast::*;
+use mas::decoder::takes_long;
+use mas::parser::{classify_inst, InstType};
+use mas::scheduler::{destreg, srcreg1, srcreg2, pred, destpred, predicable,
+                     speculation_window, commutes_};
+
+use std::cmp;
+use std::collections::{BTreeMap, BTreeSet};
+
+// The most stages we spread an iteration over. Each one makes the prologue
+// and the epilogues longer.
+static MAX_STAGES: usize = 4;
+
+// Whether we know how to move an instruction into another iteration: one
+// the scheduler could predicate, as long as it doesn't need a long after it.
+fn is_pipelinable(inst: &InstNode) -> bool {
+    predicable(inst) && !takes_long(inst)
+}
+
+// Where a branch that might leave the loop goes.
+fn exit_target(inst: &InstNode) -> Option<&String> {
+    match *inst {
+        BranchImmInst(p, false, JumpLabel(ref target)) if p != TRUE_PRED => Some(target),
+        _ => None
+    }
+}
+
+// The last slot an instruction can go in.
+fn top_slot(inst: &InstNode) -> usize {
+    match classify_inst(inst) {
+        InstType::ControlType => 0,
+        InstType::MemoryType => 1,
+        _ => 3
+    }
+}
+
+fn reads(inst: &InstNode, reg: Reg) -> bool {
+    srcreg1(inst) == Some(reg) || srcreg2(inst) == Some(reg)
+}
+
+// Return the instruction with its registers replaced according to dests,
+// for the register it writes, and srcs, for those it reads.
+fn with_regs(inst: &InstNode, dests: &BTreeMap<Reg, Reg>, srcs: &BTreeMap<Reg, Reg>) -> InstNode {
+    let d = |r: Reg| *dests.get(&r).unwrap_or(&r);
+    let s = |r: Reg| *srcs.get(&r).unwrap_or(&r);
+    match *inst {
+        ALU1ShortInst(p, op, rd, val, rot) => ALU1ShortInst(p, op, d(rd), val, rot),
+        ALU2ShortInst(p, op, rd, rs, val, rot) => ALU2ShortInst(p, op, d(rd), s(rs), val, rot),
+        ALU1RegInst(p, op, rd, rt, st, amt) => ALU1RegInst(p, op, d(rd), s(rt), st, amt),
+        ALU2RegInst(p, op, rd, rs, rt, st, amt) => ALU2RegInst(p, op, d(rd), s(rs), s(rt), st, amt),
+        ALU1RegShInst(p, rd, op, rt, st, rs) => ALU1RegShInst(p, d(rd), op, s(rt), st, s(rs)),
+        LoadInst(p, op, rd, rs, offs) => LoadInst(p, op, d(rd), s(rs), offs),
+        StoreInst(p, op, rs, offs, rt) => StoreInst(p, op, s(rs), offs, s(rt)),
+        CompareShortInst(p, dp, rs, ct, val, rot) => CompareShortInst(p, dp, s(rs), ct, val, rot),
+        CompareRegInst(p, dp, rs, ct, rt, st, amt) => CompareRegInst(p, dp, s(rs), ct, s(rt), st, amt),
+        MthiInst(p, rs) => MthiInst(p, s(rs)),
+        MfhiInst(p, rd) => MfhiInst(p, d(rd)),
+        MultInst(p, signed, rd, rs, rt) => MultInst(p, signed, d(rd), s(rs), s(rt)),
+        DivInst(p, signed, rd, rs, rt) => DivInst(p, signed, d(rd), s(rs), s(rt)),
+        ref inst => inst.clone(),
+    }
+}
+
+fn note_regs(inst: &InstNode, regs: &mut BTreeSet<Reg>) {
+    match *inst {
+        PacketsInst(ref packets) => {
+            for packet in packets.iter() {
+                for inst in packet.iter() {
+                    note_regs(inst, regs);
+                }
+            }
+        },
+        _ => {
+            for reg in vec!(destreg(inst), srcreg1(inst), srcreg2(inst)).into_iter() {
+                match reg {
+                    Some(reg) => { regs.insert(reg); },
+                    None => {},
+                }
+            }
+        },
+    }
+}
+
+// Whether body[i] and the instruction after it are a pair like
+//     p0? r0 <- 1; !p0? r0 <- 0
+// that between them always set the register, without reading it.
+fn is_full_pair(body: &Vec<InstNode>, i: usize) -> bool {
+    let next = &body[(i + 1) % body.len()];
+    match (pred(&body[i]), pred(next)) {
+        (Some(p1), Some(p2)) => p1.reg == p2.reg && p1.inverted != p2.inverted &&
+            destpred(&body[i]).is_none() && destreg(&body[i]).is_some() &&
+            destreg(next) == destreg(&body[i]) &&
+            !reads(next, destreg(next).unwrap()),
+        _ => false,
+    }
+}
+
+// Give the values that only live within an iteration registers of their
+// own, out of the ones nothing else in the function uses. Registers below
+// FIRST_CALLEE_SAVED_REG we're allowed to change without saving them.
+fn rename_locals(insts: &Vec<InstNode>, body: &Vec<InstNode>) -> Vec<InstNode> {
+    let mut used = BTreeSet::new();
+    for inst in insts.iter() {
+        note_regs(inst, &mut used);
+    }
+    // The registers we can use, and the position in the body after which
+    // each is free again.
+    let mut free: Vec<(usize, Reg)> = (0 .. FIRST_CALLEE_SAVED_REG.index)
+        .map(|index| Reg { index: index })
+        .filter(|reg| !used.contains(reg))
+        .map(|reg| (0, reg))
+        .collect();
+
+    let n = body.len();
+    let mut dests: Vec<BTreeMap<Reg, Reg>> = body.iter().map(|_| BTreeMap::new()).collect();
+    let mut srcs: Vec<BTreeMap<Reg, Reg>> = body.iter().map(|_| BTreeMap::new()).collect();
+    for i in 0 .. n {
+        let reg = match destreg(&body[i]) {
+            Some(reg) if pred(&body[i]) == Some(TRUE_PRED) => reg,
+            _ => continue,
+        };
+        // Find the uses of the value, up to where the register gets set
+        // again. It can't reach an exit, where we don't know who else uses
+        // it, or the next iteration's uses before its own definition.
+        let mut uses = vec!();
+        let mut local = false;
+        for k in 1 .. n + 1 {
+            let j = (i + k) % n;
+            let inst = &body[j];
+            if exit_target(inst).is_some() || j == i {
+                break;
+            }
+            if reads(inst, reg) {
+                if j < i {
+                    break;
+                }
+                uses.push(j);
+            }
+            if destreg(inst) == Some(reg) {
+                local = pred(inst) == Some(TRUE_PRED) || is_full_pair(body, j);
+                break;
+            }
+        }
+        if !local {
+            continue;
+        }
+
+        // Use whichever register has been free longest.
+        let last = uses.iter().fold(i, |last, &j| cmp::max(last, j));
+        let choice = free.iter().enumerate()
+            .filter(|&(_, &(pos, _))| pos <= i)
+            .fold(None, |best: Option<(usize, usize)>, (idx, &(pos, _))| match best {
+                Some((_, best_pos)) if best_pos <= pos => best,
+                _ => Some((idx, pos)),
+            });
+        match choice {
+            Some((idx, _)) => {
+                let new_reg = free[idx].1;
+                free[idx].0 = last;
+                dests[i].insert(reg, new_reg);
+                for &j in uses.iter() {
+                    srcs[j].insert(reg, new_reg);
+                }
+            },
+            None => {},
+        }
+    }
+
+    body.iter().enumerate().map(|(i, inst)| with_regs(inst, &dests[i], &srcs[i])).collect()
+}
+
+// The loop, as we schedule it.
+struct Loop {
+    // The instructions of an iteration, without the jump back.
+    insts: Vec<InstNode>,
+    // The same, but with the predicate of the exit before them, for those
+    // we can run above it.
+    spec: Vec<InstNode>,
+    // Whether we can run each instruction above the exit before it.
+    speculable: Vec<bool>,
+    // The exit before each instruction, if there is one.
+    exit_before: Vec<Option<usize>>,
+    // Where the exits are.
+    exits: Vec<usize>,
+}
+
+impl Loop {
+    fn new(insts: Vec<InstNode>) -> Loop {
+        let n = insts.len();
+        let mut spec = insts.clone();
+        let mut speculable: Vec<bool> = (0 .. n).map(|_| false).collect();
+        let mut exit_before = vec!();
+        let mut exits = vec!();
+        for i in 0 .. n {
+            exit_before.push(exits.last().map(|&exit| exit));
+            if exit_target(&insts[i]).is_some() {
+                exits.push(i);
+            }
+        }
+        for (i, (_, inst)) in speculation_window(&insts).into_iter() {
+            spec[i] = inst;
+            speculable[i] = true;
+        }
+        Loop {
+            insts: insts,
+            spec: spec,
+            speculable: speculable,
+            exit_before: exit_before,
+            exits: exits,
+        }
+    }
+
+    fn is_exit(&self, i: usize) -> bool {
+        exit_target(&self.insts[i]).is_some()
+    }
+
+    // What instruction i looks like at time t: it needs the predicate of
+    // the exit before it if it goes above that.
+    fn form(&self, placed: &Vec<(usize, usize)>, i: usize, t: usize) -> &InstNode {
+        match self.exit_before[i] {
+            Some(exit) if self.speculable[i] && t <= placed[exit].0 => &self.spec[i],
+            _ => &self.insts[i],
+        }
+    }
+
+    // Whether instruction i can go at time t, given where the instructions
+    // before it went.
+    fn fits(&self, placed: &Vec<(usize, usize)>, i: usize, t: usize, ii: usize) -> bool {
+        let inst = self.form(placed, i, t);
+        let (t, ii) = (t as isize, ii as isize);
+        placed.iter().enumerate().all(|(j, &(tj, _))| {
+            let other = self.form(placed, j, tj);
+            let tj = tj as isize;
+            if self.is_exit(i) {
+                // Everything before an exit has to have run by the time we
+                // take it.
+                tj < t || (tj == t && commutes_(other, inst))
+            } else if self.is_exit(j) {
+                // Nothing after it can run before we know it isn't taken,
+                // unless it has the exit's predicate. That mustn't change
+                // before the exit reads it, though.
+                t > tj || (self.exit_before[i] == Some(j) && self.speculable[i] &&
+                           (t == tj || destpred(&self.insts[i]).map(|p| p.reg) !=
+                            pred(&self.insts[j]).map(|p| p.reg)))
+            } else {
+                // Check against the instruction in each iteration this one
+                // might overlap with.
+                let max = MAX_STAGES as isize;
+                (-max .. max + 1).all(|d| {
+                    let ti = t + d * ii;
+                    if d >= 0 {
+                        in_order(other, tj, inst, ti)
+                    } else {
+                        in_order(inst, ti, other, tj)
+                    }
+                })
+            }
+        })
+    }
+
+    // Find when each instruction of an iteration runs, and in what slot,
+    // if the iterations start `ii` packets apart.
+    fn place(&self, ii: usize) -> Option<Vec<(usize, usize)>> {
+        let last_exit = *self.exits.last().unwrap();
+        let mut free: Vec<[bool; 4]> = (0 .. ii).map(|_| [true, true, true, true]).collect();
+        // The last exit goes at the end of the kernel.
+        free[ii - 1][0] = false;
+        let mut placed = vec!();
+        for i in 0 .. self.insts.len() {
+            let inst = &self.insts[i];
+            let (first, limit) = if i == last_exit {
+                (ii - 1, ii)
+            } else if i < last_exit {
+                (0, ii)
+            } else {
+                (0, ii * MAX_STAGES)
+            };
+            let top = top_slot(inst);
+            let mut found = None;
+            for t in first .. limit {
+                if !self.fits(&placed, i, t, ii) {
+                    continue;
+                }
+                if i == last_exit {
+                    found = Some((t, 0));
+                    break;
+                }
+                match (0 .. top + 1).rev().filter(|&slot| free[t % ii][slot]).next() {
+                    Some(slot) => {
+                        found = Some((t, slot));
+                        break;
+                    },
+                    None => {},
+                }
+            }
+            match found {
+                Some((t, slot)) => {
+                    free[t % ii][slot] = false;
+                    placed.push((t, slot));
+                },
+                None => return None,
+            }
+        }
+        Some(placed)
+    }
+}
+
+// Whether inst1 at time t1 and inst2 at time t2 can go in that order, inst1
+// coming first in the loop.
+fn in_order(inst1: &InstNode, t1: isize, inst2: &InstNode, t2: isize) -> bool {
+    t1 < t2 || (t1 == t2 && commutes_(inst1, inst2)) ||
+        (commutes_(inst1, inst2) && commutes_(inst2, inst1))
+}
+
+// The code for a pipelined loop.
+struct Code<'a> {
+    l: &'a Loop,
+    ii: usize,
+    stages: usize,
+    placed: Vec<(usize, usize)>,
+    // What we put in the code for each instruction other than the exits.
+    insts: Vec<InstNode>,
+}
+
+impl<'a> Code<'a> {
+    fn new(l: &'a Loop, ii: usize, placed: Vec<(usize, usize)>) -> Code<'a> {
+        let stages = placed.iter().map(|&(t, _)| t / ii).max().unwrap() + 1;
+        // Whatever didn't end up above its exit doesn't need its predicate.
+        let insts = (0 .. l.insts.len()).map(|i| l.form(&placed, i, placed[i].0).clone())
+            .collect();
+        Code {
+            l: l,
+            ii: ii,
+            stages: stages,
+            placed: placed,
+            insts: insts,
+        }
+    }
+
+    // Packets `from` onwards of a round of the kernel, with only the stages
+    // from `low` to `high`, and the exits replaced by `exits`.
+    fn round(&self, from: usize, low: usize, high: usize,
+             exits: &BTreeMap<usize, InstNode>) -> Vec<[InstNode; 4]> {
+        let mut packets: Vec<[InstNode; 4]> = (from .. self.ii)
+            .map(|_| [NopInst, NopInst, NopInst, NopInst]).collect();
+        for (i, &(t, slot)) in self.placed.iter().enumerate() {
+            let stage = t / self.ii;
+            if t % self.ii < from || stage < low || stage > high {
+                continue;
+            }
+            packets[t % self.ii - from][slot] = match exits.get(&i) {
+                Some(inst) => inst.clone(),
+                None => self.insts[i].clone(),
+            };
+        }
+        packets
+    }
+
+    // What's left of the iterations before one that takes `exit`, in a round
+    // that ran stages up to `active`.
+    fn drain(&self, exit: usize, active: usize) -> Vec<[InstNode; 4]> {
+        let no_exits = BTreeMap::new();
+        let mut packets = self.round(self.placed[exit].0 + 1, 1, active, &no_exits);
+        for round in 1 .. self.stages - 1 {
+            packets.extend(self.round(0, round + 1, active + round, &no_exits).into_iter());
+        }
+        packets.retain(|packet| packet.iter().any(|inst| *inst != NopInst));
+        packets
+    }
+}
+
+/// Pipeline the loop that the superblock insts[start .. end] makes up, if
+/// that gets an iteration down to fewer than `max_ii` packets. We return the
+/// packets for the loop, and where in them the labels we add go.
+pub fn pipeline(insts: &Vec<InstNode>,
+                labels: &BTreeMap<String, usize>,
+                start: usize,
+                end: usize,
+                max_ii: usize) -> Option<(Vec<[InstNode; 4]>, BTreeMap<String, usize>)> {
+    // The superblock has to end by jumping back to its start.
+    let name = match insts[end - 1] {
+        BranchImmInst(p, false, JumpLabel(ref name))
+            if p == TRUE_PRED && labels.get(name) == Some(&start) => name.clone(),
+        _ => return None,
+    };
+    let body: Vec<InstNode> = insts[start .. end - 1].iter()
+        .filter(|inst| **inst != NopInst)
+        .map(|inst| inst.clone())
+        .collect();
+    for inst in body.iter() {
+        match exit_target(inst) {
+            // An exit to somewhere in the loop is really another way round it.
+            Some(target) => match labels.get(target) {
+                Some(&pos) if pos < start || pos >= end => {},
+                _ => return None,
+            },
+            None => if !is_pipelinable(inst) {
+                return None;
+            },
+        }
+    }
+    if !body.iter().any(|inst| exit_target(inst).is_some()) {
+        return None;
+    }
+
+    let l = Loop::new(rename_locals(insts, &body));
+    let count = |top: usize| l.insts.iter().filter(|inst| top_slot(inst) == top).count();
+    let (control, memory) = (count(0), count(1));
+    let min_ii = cmp::max(cmp::max(control, (control + memory + 1) / 2),
+                          (l.insts.len() + 3) / 4);
+    let (ii, placed) = match (min_ii .. max_ii)
+        .filter_map(|ii| l.place(ii).map(|placed| (ii, placed))).next() {
+        Some(found) => found,
+        None => return None,
+    };
+    let code = Code::new(&l, ii, placed);
+    let stages = code.stages;
+    let last_exit = *l.exits.last().unwrap();
+    let exit_pred = |exit: usize| pred(&l.insts[exit]).unwrap();
+    let branch = |p: Pred, target: &String| BranchImmInst(p, false, JumpLabel(target.clone()));
+    let target = |exit: usize| exit_target(&l.insts[exit]).unwrap().clone();
+
+    // Work out where each exit goes, in each round of the prologue (where
+    // the stages up to the round's number run) and in the kernel.
+    let mut epilogues: Vec<(String, Vec<[InstNode; 4]>, String)> = vec!();
+    let mut exit_labels: BTreeMap<(usize, usize), String> = BTreeMap::new();
+    for active in 0 .. stages {
+        for &exit in l.exits.iter() {
+            if exit == last_exit && active == stages - 1 {
+                continue;
+            }
+            let packets = code.drain(exit, active);
+            let label = if packets.is_empty() {
+                target(exit)
+            } else {
+                let label = format!("{}_exit{}", name, epilogues.len());
+                epilogues.push((label.clone(), packets, target(exit)));
+                label
+            };
+            exit_labels.insert((exit, active), label);
+        }
+    }
+
+    let mut packets = vec!();
+    let mut new_labels = BTreeMap::new();
+    for active in 0 .. stages - 1 {
+        let exits = l.exits.iter().map(|&exit| {
+            (exit, branch(exit_pred(exit), &exit_labels[&(exit, active)]))
+        }).collect();
+        let mut round = code.round(0, 0, active, &exits);
+        round.retain(|packet| packet.iter().any(|inst| *inst != NopInst));
+        packets.extend(round.into_iter());
+    }
+
+    let kernel = format!("{}_kernel", name);
+    new_labels.insert(kernel.clone(), packets.len());
+    let exits = l.exits.iter().map(|&exit| {
+        if exit == last_exit {
+            let p = exit_pred(exit);
+            (exit, branch(Pred { inverted: !p.inverted, reg: p.reg }, &kernel))
+        } else {
+            (exit, branch(exit_pred(exit), &exit_labels[&(exit, stages - 1)]))
+        }
+    }).collect();
+    packets.extend(code.round(0, 0, stages - 1, &exits).into_iter());
+
+    // Falling out of the kernel is taking the last exit.
+    let mut tails = vec!((None, code.drain(last_exit, stages - 1), target(last_exit)));
+    tails.extend(epilogues.into_iter().map(|(label, packets, target)| (Some(label), packets, target)));
+    let num_tails = tails.len();
+    for (idx, (label, tail, target)) in tails.into_iter().enumerate() {
+        match label {
+            Some(label) => { new_labels.insert(label, packets.len()); },
+            None => {},
+        }
+        let empty = tail.is_empty();
+        packets.extend(tail.into_iter());
+        // The code after the loop might be where we're going anyway.
+        if idx == num_tails - 1 && labels.get(&target) == Some(&end) {
+            continue;
+        }
+        let jump = branch(TRUE_PRED, &target);
+        if !empty && packets[packets.len() - 1][0] == NopInst {
+            let last = packets.len() - 1;
+            packets[last][0] = jump;
+        } else {
+            packets.push([jump, NopInst, NopInst, NopInst]);
+        }
+    }
+
+    Some((packets, new_labels))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::pipeline;
+    use mas::ast::*;
+    use testing::{p, r, labels};
+
+    // A loop adding x + 5 to r3 for each of r1 words x where r2 points,
+    // working it out a step at a time in r5.
+    fn sum_loop() -> Vec<InstNode> {
+        let mut insts = vec!(
+            InstNode::compareshort(TRUE_PRED, p(false, 0), r(1), CmpEQ, 0, 0),
+            InstNode::branchimm(p(false, 0), false, JumpLabel("out".to_string())),
+            InstNode::load(TRUE_PRED, LsuOp { store: false, width: LsuWidthL }, r(4), r(2), 0),
+            InstNode::alu2short(TRUE_PRED, AddAluOp, r(5), r(4), 1, 0),
+            );
+        for _ in 0 .. 4 {
+            insts.push(InstNode::alu2short(TRUE_PRED, AddAluOp, r(5), r(5), 1, 0));
+        }
+        insts.push(InstNode::alu2reg(TRUE_PRED, AddAluOp, r(3), r(3), r(5), SllShift, 0));
+        insts.push(InstNode::alu2short(TRUE_PRED, AddAluOp, r(2), r(2), 4, 0));
+        insts.push(InstNode::alu2short(TRUE_PRED, SubAluOp, r(1), r(1), 1, 0));
+        insts.push(InstNode::branchimm(TRUE_PRED, false, JumpLabel("loop".to_string())));
+        insts.push(InstNode::branchreg(TRUE_PRED, false, r(31), 1));
+        insts
+    }
+
+    #[test]
+    fn test_pipeline() {
+        let insts = sum_loop();
+        let (packets, new_labels) =
+            pipeline(&insts, &labels(&[("loop", 0), ("out", 12)]), 0, 12, 9).unwrap();
+        // Each step gets a register of its own, so a new iteration can start
+        // every three packets, once the previous one has decided not to
+        // leave. Leaving the loop from the prologue or the kernel has to
+        // finish the iteration before first.
+        assert_eq!(new_labels, labels(&[("loop_kernel", 6), ("loop_exit0", 11)]));
+        assert_eq!(packets.len(), 13);
+        assert_eq!(packets[8][0],
+                   InstNode::branchimm(p(true, 0), false, JumpLabel("loop_kernel".to_string())));
+        assert_eq!(packets[10][0],
+                   InstNode::branchimm(TRUE_PRED, false, JumpLabel("out".to_string())));
+    }
+
+    #[test]
+    fn test_out_of_registers() {
+        // With every register we could have used taken, the next iteration
+        // can't start its steps until this one is done with r5, and we're
+        // no better than the regular schedule.
+        let mut insts = sum_loop();
+        for reg in 0 .. 11 {
+            insts.push(InstNode::alu2short(TRUE_PRED, AddAluOp, r(reg), r(reg), 1, 0));
+        }
+        assert!(pipeline(&insts, &labels(&[("loop", 0), ("out", 12)]), 0, 12, 5).is_none());
+    }
+}
@@ -1,10 +1,12 @@
 use mas::ast::*;
 use mas::parser::{classify_inst, InstType};
+use mas::pipeline::pipeline;
+use codegen::cycles;
 use std::collections::{BTreeSet, BTreeMap, BinaryHeap};
 use std::iter::FromIterator;
 
 // Return Rd.
-fn destreg(inst: &InstNode) -> Option<Reg> {
+pub fn destreg(inst: &InstNode) -> Option<Reg> {
     match *inst {
         ALU1ShortInst(_, _, r, _, _) |
         ALU2ShortInst(_, _, r, _, _, _) |
@@ -22,7 +24,7 @@ fn destreg(inst: &InstNode) -> Option<Reg> {
     }
 }
 
-fn pred(inst: &InstNode) -> Option<Pred> {
+pub fn pred(inst: &InstNode) -> Option<Pred> {
     match *inst {
         ALU1ShortInst(p, _, _, _, _) |
         ALU2ShortInst(p, _, _, _, _, _) |
@@ -55,7 +57,7 @@ fn pred(inst: &InstNode) -> Option<Pred> {
     }
 }
 
-fn destpred(inst: &InstNode) -> Option<Pred> {
+pub fn destpred(inst: &InstNode) -> Option<Pred> {
     match *inst {
         CompareShortInst(_, p, _, _, _, _) |
         CompareRegInst(_, p, _, _, _, _, _) |
@@ -65,7 +67,7 @@ fn destpred(inst: &InstNode) -> Option<Pred> {
 }
 
 // Return Rs.
-fn srcreg1(inst: &InstNode) -> Option<Reg> {
+pub fn srcreg1(inst: &InstNode) -> Option<Reg> {
     match *inst {
         ALU2ShortInst(_, _, _, r, _, _) |
         ALU2RegInst(_, _, _, r, _, _, _) |
@@ -87,7 +89,7 @@ fn srcreg1(inst: &InstNode) -> Option<Reg> {
 }
 
 // Return Rt
-fn srcreg2(inst: &InstNode) -> Option<Reg> {
+pub fn srcreg2(inst: &InstNode) -> Option<Reg> {
     match *inst {
         ALU1RegInst(_, _, _, r, _, _) |
         ALU2RegInst(_, _, _, _, r, _, _) |
@@ -101,7 +103,7 @@ fn srcreg2(inst: &InstNode) -> Option<Reg> {
 }
 
 // Return which coprocessor register we write to.
-fn destcoreg(inst: &InstNode) -> Option<CoReg> {
+pub fn destcoreg(inst: &InstNode) -> Option<CoReg> {
     match *inst {
         MtcInst(_, r, _) => Some(r),
         _ => None
@@ -109,19 +111,143 @@ fn destcoreg(inst: &InstNode) -> Option<CoReg> {
 }
 
 // Return which coprocessor register we read from.
-fn srccoreg(inst: &InstNode) -> Option<CoReg> {
+pub fn srccoreg(inst: &InstNode) -> Option<CoReg> {
     match *inst {
         MfcInst(_, _, r) => Some(r),
         _ => None
     }
 }
 
+// Whether we write to the overflow register.
+fn writes_ovf(inst: &InstNode) -> bool {
+    match *inst {
+        MthiInst(..) |
+        MultInst(..) |
+        DivInst(..) => true,
+        _ => false
+    }
+}
+
+// Whether we read from the overflow register.
+fn reads_ovf(inst: &InstNode) -> bool {
+    match *inst {
+        MfhiInst(..) => true,
+        _ => false
+    }
+}
+
+// Whether we can change an instruction's predicate with with_pred.
+pub fn predicable(inst: &InstNode) -> bool {
+    match *inst {
+        ALU1ShortInst(..) |
+        ALU2ShortInst(..) |
+        ALU1RegInst(..) |
+        ALU2RegInst(..) |
+        ALU2LongInst(..) |
+        ALU1LongInst(..) |
+        ALU1RegShInst(..) |
+        LoadInst(..) |
+        StoreInst(..) |
+        CompareShortInst(..) |
+        CompareRegInst(..) |
+        CompareLongInst(..) |
+        MthiInst(..) |
+        MfhiInst(..) |
+        MultInst(..) |
+        DivInst(..) => true,
+        _ => false
+    }
+}
+
+// Return the instruction with its predicate replaced by p.
+pub fn with_pred(inst: &InstNode, p: Pred) -> InstNode {
+    match *inst {
+        ALU1ShortInst(_, op, rd, val, rot) => ALU1ShortInst(p, op, rd, val, rot),
+        ALU2ShortInst(_, op, rd, rs, val, rot) => ALU2ShortInst(p, op, rd, rs, val, rot),
+        ALU1RegInst(_, op, rd, rt, st, amt) => ALU1RegInst(p, op, rd, rt, st, amt),
+        ALU2RegInst(_, op, rd, rs, rt, st, amt) => ALU2RegInst(p, op, rd, rs, rt, st, amt),
+        ALU2LongInst(_, op, rd, rs) => ALU2LongInst(p, op, rd, rs),
+        ALU1LongInst(_, op, rd) => ALU1LongInst(p, op, rd),
+        ALU1RegShInst(_, rd, op, rt, st, rs) => ALU1RegShInst(p, rd, op, rt, st, rs),
+        LoadInst(_, op, rd, rs, offs) => LoadInst(p, op, rd, rs, offs),
+        StoreInst(_, op, rs, offs, rt) => StoreInst(p, op, rs, offs, rt),
+        CompareShortInst(_, dp, rs, ct, val, rot) => CompareShortInst(p, dp, rs, ct, val, rot),
+        CompareRegInst(_, dp, rs, ct, rt, st, amt) => CompareRegInst(p, dp, rs, ct, rt, st, amt),
+        CompareLongInst(_, dp, rs, ct) => CompareLongInst(p, dp, rs, ct),
+        MthiInst(_, rs) => MthiInst(p, rs),
+        MfhiInst(_, rd) => MfhiInst(p, rd),
+        MultInst(_, signed, rd, rs, rt) => MultInst(p, signed, rd, rs, rt),
+        DivInst(_, signed, rd, rs, rt) => DivInst(p, signed, rd, rs, rt),
+        ref inst => panic!("Cannot predicate {}", inst),
+    }
+}
+
+// Loads go through the cache, so their results take an extra cycle.
+static LOAD_LATENCY: usize = 2;
+
+// Roughly how many cycles until an instruction's result can be used. The
+// simulator has every result ready for the next packet, but the hardware
+// doesn't, so we start long chains early.
+fn latency(inst: &InstNode) -> usize {
+    match *inst {
+        LoadInst(..) => LOAD_LATENCY,
+        _ => cycles(inst),
+    }
+}
+
+// Whether a branch can leave from the middle of a superblock: it might not
+// be taken, and if it is, we don't come back to the packet after it.
+fn is_side_exit(inst: &InstNode) -> bool {
+    match *inst {
+        BranchImmInst(p, false, _) |
+        BranchRegInst(p, false, _, _) => p != TRUE_PRED,
+        _ => false
+    }
+}
+
+// The instructions in `insts` that can move above the side exit before
+// them, each with that exit and with the predicate it needs to do nothing if
+// the exit is taken. That's the same condition as the branch's for as long
+// as nothing changes its predicate, and we don't know what anything we can't
+// predicate does to it.
+pub fn speculation_window(insts: &[InstNode]) -> BTreeMap<usize, (usize, InstNode)> {
+    let mut speculated = BTreeMap::new();
+    let mut exit: Option<usize> = None;
+    for (idx, inst) in insts.iter().enumerate() {
+        let exit_pred = exit.and_then(|branch| pred(&insts[branch]));
+        match exit_pred {
+            Some(p) if predicable(inst) && pred(inst) == Some(TRUE_PRED) => {
+                speculated.insert(idx, (exit.unwrap(),
+                                        with_pred(inst, Pred { inverted: !p.inverted,
+                                                               reg: p.reg })));
+            },
+            _ => {},
+        }
+        if is_side_exit(inst) {
+            exit = Some(idx);
+        } else if (!predicable(inst) && *inst != NopInst && !is_long_value(inst)) ||
+            (exit_pred.is_some() &&
+             destpred(inst).map(|p| p.reg) == exit_pred.map(|p| p.reg)) {
+            exit = None;
+        }
+    }
+    speculated
+}
+
+// Whether an instruction is the long value for the one before it.
+fn is_long_value(inst: &InstNode) -> bool {
+    match *inst {
+        LongInst(..) => true,
+        _ => false
+    }
+}
+
 // Returns whether or not the order of inst1 and inst2 can be swapped.
 fn commutes(inst1: &InstNode, inst2: &InstNode) -> bool {
     commutes_(inst1, inst2) && commutes_(inst2, inst1)
 }
 
-fn commutes_(inst1: &InstNode, inst2: &InstNode) -> bool {
+pub fn commutes_(inst1: &InstNode, inst2: &InstNode) -> bool {
     // Nop always commutes.
     if *inst1 == NopInst {
         return true;
@@ -150,10 +276,6 @@ fn commutes_(inst1: &InstNode, inst2: &InstNode) -> bool {
         _ => {},
     }
     match *inst2 {
-        // TODO: this is more conservative than it needs to be, with LongInst.
-        // We really only need to prevent it from commuting with its immediate
-        // predecessor.
-        LongInst(..) |
         BreakInst(..) |
         FenceInst(..) |
         PacketsInst(..) => return false,
@@ -162,12 +284,15 @@ fn commutes_(inst1: &InstNode, inst2: &InstNode) -> bool {
 
     let pred1 = pred(inst1);
     let pred2 = pred(inst2);
-    // If two instructions have opposite predicates, we can commute them.
+    // If two instructions have opposite predicates, we can commute them, as
+    // long as neither changes the predicate.
     match pred1 {
         Some(ref p1) => match pred2 {
             Some(ref p2) => {
                 if p1.reg == p2.reg &&
-                    p1.inverted != p2.inverted {
+                    p1.inverted != p2.inverted &&
+                    destpred(inst1).map(|p| p.reg) != Some(p1.reg) &&
+                    destpred(inst2).map(|p| p.reg) != Some(p1.reg) {
                         return true;
                     }
             },
@@ -185,20 +310,23 @@ fn commutes_(inst1: &InstNode, inst2: &InstNode) -> bool {
             return false;
         }
 
-    // inst1 writes to a predicate that inst2 uses.
+    // inst1 writes to a predicate that inst2 uses or writes.
     let destpred1 = destpred(inst1);
     match destpred1 {
-        Some(ref p1) => match pred(inst2) {
-            Some(ref p2) => {
-                if p1.reg == p2.reg {
+        Some(ref p1) => {
+            if pred(inst2).map(|p| p.reg) == Some(p1.reg) ||
+                destpred(inst2).map(|p| p.reg) == Some(p1.reg) {
                     return false;
                 }
-            },
-            _ => {},
         },
         _ => {},
     }
 
+    // inst1 writes the overflow register, and inst2 uses or writes it.
+    if writes_ovf(inst1) && (reads_ovf(inst2) || writes_ovf(inst2)) {
+        return false;
+    }
+
     // coregs conflict
     if destcoreg(inst1).is_some() &&
         destcoreg(inst1) == srccoreg(inst2) {
@@ -302,8 +430,14 @@ pub fn schedule_dummy(insts: &Vec<InstNode>,
     (packets, modified_labels)
 }
 
+/// Pack instructions into packets. We schedule a superblock at a time: a
+/// stretch of code that's only entered at the top, but that conditional
+/// branches might leave early. Instructions can move above such a branch by
+/// running only when it isn't taken. With `pipelining`, superblocks that are
+/// loops may also be software-pipelined.
 pub fn schedule(insts: &Vec<InstNode>,
                 labels: &BTreeMap<String, usize>,
+                pipelining: bool,
                 debug: bool) -> (Vec<[InstNode; 4]>,
                                  BTreeMap<String, usize>) {
     let mut packets: Vec<[InstNode; 4]> = vec!();
@@ -321,11 +455,11 @@ pub fn schedule(insts: &Vec<InstNode>,
     // Include all jump targets...
     let mut jump_targets: Vec<usize> =
         FromIterator::from_iter(labels.iter().map(|(_, &b)| b));
-    // .. and all jumps.
+    // .. and all jumps that we don't fall through or come back from.
     for (idx, inst) in insts.iter().enumerate() {
         match *inst {
             BranchImmInst(..) |
-            BranchRegInst(..) => jump_targets.push(idx+1),
+            BranchRegInst(..) if !is_side_exit(inst) => jump_targets.push(idx+1),
             _ => {},
         }
     }
@@ -340,26 +474,54 @@ pub fn schedule(insts: &Vec<InstNode>,
         }
     }
 
+    // The instructions as we schedule them, with the predicates we give
+    // them to move above side exits.
+    let mut scheduled = insts.clone();
+    // Where each instruction went: its packet, and its slot in the packet.
+    let mut placed: BTreeMap<usize, (usize, usize)> = BTreeMap::new();
+
     let mut start = 0;
     for end in jump_targets.into_iter() {
         if debug {
             print!("Scheduling ({}, {})\n", start, end);
         }
 
-        // Start by building the instruction DAG.
+        // Anything jumping here goes to the first packet of the superblock,
+        // whichever instructions end up in it.
+        update_labels(&jump_target_dict,
+                      &mut modified_labels,
+                      start,
+                      packets.len());
+        let block_start = packets.len();
+
+        // Predicate everything we can after a side exit on the branch not
+        // being taken.
+        let mut speculated: BTreeMap<usize, usize> = BTreeMap::new();
+        for (i, (exit, inst)) in speculation_window(&insts[start .. end]).into_iter() {
+            scheduled[start + i] = inst;
+            speculated.insert(start + i, start + exit);
+        }
+
+        // Start by building the instruction DAG. Long values aren't in it;
+        // they go wherever the instruction before them does.
         let mut edges: BTreeSet<(usize, usize)> = BTreeSet::new();
+        let mut succs: Vec<Vec<usize>> = (start .. end).map(|_| vec!()).collect();
         let mut all: BTreeSet<usize> =
             FromIterator::from_iter(start .. end);
         let mut this_packet: [InstNode; 4] =
             [NopInst, NopInst, NopInst, NopInst];
         let mut packet_added = false;
         for idx in start .. end {
-            let inst = &insts[idx];
+            let inst = &scheduled[idx];
+            if is_long_value(inst) {
+                continue;
+            }
 
             for prev_idx in start .. idx {
-                let prev_inst = &insts[prev_idx];
-                if !commutes(prev_inst, inst) {
+                let prev_inst = &scheduled[prev_idx];
+                if !is_long_value(prev_inst) && !commutes(prev_inst, inst) {
                     edges.insert((prev_idx, idx));
+                    succs[prev_idx - start].push(idx);
                 }
             }
         }
@@ -368,19 +530,31 @@ pub fn schedule(insts: &Vec<InstNode>,
             print!("edges: {:?}\n", edges);
         }
 
+        // How long the longest chain of instructions depending on each one
+        // takes. Scheduling the longest chains first keeps the rest from
+        // waiting on them.
+        let mut height: Vec<usize> = (start .. end).map(|_| 0).collect();
+        for idx in (start .. end).rev() {
+            height[idx - start] = latency(&scheduled[idx]) +
+                succs[idx - start].iter().map(|&succ| height[succ - start])
+                .max().unwrap_or(0);
+        }
+
         // We now have a DAG, corresponding to dependencies among instructions.
         // Scheduling involves essentially a topological sort of this DAG.
 
         while !all.is_empty() {
             let non_schedulables: BTreeSet<usize> = FromIterator::from_iter(
                 edges.iter().map(|&(_, x)| x));
-            let leaves: BTreeSet<usize> = FromIterator::from_iter(
-                all.iter()
-                    .map(|&x| x)
-                    .filter(|x| !non_schedulables.contains(x)));
+            let mut leaves: Vec<usize> = all.iter()
+                .map(|&x| x)
+                .filter(|&x| !non_schedulables.contains(&x) &&
+                        !is_long_value(&scheduled[x]))
+                .collect();
+            leaves.sort_by(|&a, &b| (height[b - start], a).cmp(&(height[a - start], b)));
             let mut added = false;
             for leaf in leaves.iter() {
-                let inst = &insts[*leaf];
+                let inst = &scheduled[*leaf];
                 match *inst {
                     PacketsInst(ref inline_packets) => {
                         // Flush the current packet.
@@ -391,10 +565,6 @@ pub fn schedule(insts: &Vec<InstNode>,
                             added = false;
                         }
 
-                        update_labels(&jump_target_dict,
-                                      &mut modified_labels,
-                                      *leaf,
-                                      packets.len());
                         // TODO label support in inline asm.
                         for packet in inline_packets.iter() {
                             packets.push([packet[0].clone(),
@@ -435,12 +605,9 @@ pub fn schedule(insts: &Vec<InstNode>,
                             // into the packet, but for instructions expecting
                             // a long we have to move two.
                             for offs in 0 .. if is_long { 2 } else { 1 } {
-                                let inst = &insts[*leaf+offs];
+                                let inst = &scheduled[*leaf+offs];
                                 all.remove(&(*leaf+offs));
-                                update_labels(&jump_target_dict,
-                                              &mut modified_labels,
-                                              *leaf+offs,
-                                              packets.len());
+                                placed.insert(*leaf+offs, (packets.len(), idx+offs));
                                 this_packet[idx+offs] = inst.clone();
                                 added = true;
                                 packet_added = true;
@@ -469,6 +636,33 @@ pub fn schedule(insts: &Vec<InstNode>,
             packets.push(this_packet);
         }
 
+        // Whatever didn't end up above its branch doesn't need the
+        // predicate we gave it.
+        for (idx, branch) in speculated.into_iter() {
+            let (pos, slot) = placed[&idx];
+            if pos > placed[&branch].0 {
+                packets[pos][slot] = insts[idx].clone();
+            }
+        }
+
+        // If the superblock is a loop, overlapping its iterations might
+        // beat the schedule we just found for it.
+        if pipelining && end > start {
+            match pipeline(insts, labels, start, end, packets.len() - block_start) {
+                Some((loop_packets, loop_labels)) => {
+                    if debug {
+                        print!("Pipelined ({}, {})\n", start, end);
+                    }
+                    packets.truncate(block_start);
+                    for (label, pos) in loop_labels.into_iter() {
+                        modified_labels.insert(label, block_start + pos);
+                    }
+                    packets.extend(loop_packets.into_iter());
+                },
+                None => {},
+            }
+        }
+
         start = end;
     }
 
@@ -486,3 +680,71 @@ pub fn schedule(insts: &Vec<InstNode>,
     }
     (packets, modified_labels)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::schedule;
+    use mas::ast::*;
+    use testing::{p, r, labels};
+
+    fn add(pred: Pred, rd: u8, rs: u8) -> InstNode {
+        InstNode::alu2short(pred, AddAluOp, r(rd), r(rs), 1, 0)
+    }
+
+    #[test]
+    fn test_side_exit() {
+        let cmp = InstNode::compareshort(TRUE_PRED, p(false, 0), r(1), CmpBS, 1, 0);
+        let branch = InstNode::branchimm(p(false, 0), false, JumpLabel("L".to_string()));
+        let ret = InstNode::branchreg(TRUE_PRED, false, r(31), 1);
+        let insts = vec!(
+            cmp.clone(),
+            branch.clone(),
+            add(TRUE_PRED, 2, 2),
+            add(TRUE_PRED, 3, 2),
+            ret.clone(),
+            add(TRUE_PRED, 4, 4),
+            );
+        let (packets, new_labels) = schedule(&insts, &labels(&[("f", 0), ("L", 5)]), true, false);
+        // The first add goes with the branch, so it only runs if the branch
+        // isn't taken. The second has to wait for it, and doesn't need the
+        // predicate after the branch.
+        assert_eq!(packets, vec!(
+            [NopInst, NopInst, NopInst, cmp],
+            [branch, NopInst, NopInst, add(p(true, 0), 2, 2)],
+            [ret, NopInst, NopInst, add(TRUE_PRED, 3, 2)],
+            [NopInst, NopInst, NopInst, add(TRUE_PRED, 4, 4)],
+            ));
+        assert_eq!(new_labels, labels(&[("f", 0), ("L", 3)]));
+    }
+
+    #[test]
+    fn test_long_chains_first() {
+        let load = InstNode::load(TRUE_PRED, LsuOp { store: false, width: LsuWidthL },
+                                  r(5), r(6), 0);
+        let mut insts: Vec<InstNode> = (1 .. 5).map(|reg| add(TRUE_PRED, reg, reg)).collect();
+        insts.push(load.clone());
+        insts.push(add(TRUE_PRED, 7, 5));
+        let (packets, _) = schedule(&insts, &labels(&[("f", 0)]), true, false);
+        // The load starts first, so that what uses it can go in the next
+        // packet along with the add that didn't fit.
+        assert_eq!(packets, vec!(
+            [add(TRUE_PRED, 3, 3), load, add(TRUE_PRED, 2, 2), add(TRUE_PRED, 1, 1)],
+            [NopInst, NopInst, add(TRUE_PRED, 7, 5), add(TRUE_PRED, 4, 4)],
+            ));
+    }
+
+    #[test]
+    fn test_overflow_order() {
+        // Each remainder has to be read before the next divide replaces it,
+        // even though starting the second divide early would be faster.
+        let insts = vec!(
+            InstNode::div(TRUE_PRED, true, r(1), r(2), r(3)),
+            InstNode::mfhi(TRUE_PRED, r(1)),
+            InstNode::div(TRUE_PRED, true, r(4), r(5), r(6)),
+            InstNode::mfhi(TRUE_PRED, r(4)),
+            );
+        let (packets, _) = schedule(&insts, &labels(&[("f", 0)]), true, false);
+        let firsts: Vec<InstNode> = packets.iter().map(|packet| packet[0].clone()).collect();
+        assert_eq!(firsts, insts);
+    }
+}
@@ -130,7 +130,7 @@ impl<'a, 'b> Visitor for DefMapVisitor<'a, 'b> {
 
     fn visit_item(&mut self, item: &Item) {
         match item.val {
-            FuncItem(ref ident, ref args, ref t, ref def, ref tps) => {
+            FuncItem(ref ident, ref args, ref t, ref def, ref tps, _) => {
                 let arg_def_ids = args.iter().map(|arg| {
                     self.session.defmap.table.insert(arg.ident.id, Def::FuncArgDef(arg.argtype.clone()));
                     arg.ident.id
@@ -535,7 +535,8 @@ impl FuncDef {
 
 #[derive(Eq, PartialEq, Clone, Debug)]
 pub enum ItemNode {
-    FuncItem(Ident, Vec<FuncArg>, Type, FuncDef, Vec<Ident>),
+    FuncItem(Ident, Vec<FuncArg>, Type, FuncDef, Vec<Ident>,
+             Option<bool> /* #[inline] (true) or #[inline(never)] (false)? */),
     StructItem(Ident, Vec<Field>, Vec<Ident>),
     EnumItem(Ident, Vec<Variant>, Vec<Ident>),
     TypeItem(Ident, Type, Vec<Ident>),
@@ -546,17 +547,28 @@ pub enum ItemNode {
     ConstItem(Ident, Type, Expr),
 }
 
+fn write_inline(f: &mut Formatter, inline: Option<bool>) -> fmt::Result {
+    match inline {
+        Some(true) => write!(f, "#[inline] "),
+        Some(false) => write!(f, "#[inline(never)] "),
+        None => Ok(()),
+    }
+}
+
 impl Display for ItemNode {
     fn fmt(&self, f: &mut Formatter) -> fmt::Result {
         match *self {
-            FuncItem(ref id, ref args, ref t, LocalFn(ref block), ref tps) => {
+            FuncItem(ref id, ref args, ref t, LocalFn(ref block), ref tps, inline) => {
+                try!(write_inline(f, inline));
                 try!(write!(f, "fn {}", id));
                 if tps.len() > 0 {
                     try!(write!(f, "<{:?}>", tps));
                 }
                 write!(f, "({:?}) -> {} {}", args, t, block)
             },
-            FuncItem(ref id, ref args, ref t, ExternFn(ref abi, ref block_opt), ref tps) => {
+            FuncItem(ref id, ref args, ref t, ExternFn(ref abi, ref block_opt), ref tps,
+                     inline) => {
+                try!(write_inline(f, inline));
                 try!(write!(f, "extern \"{}\" fn {}", abi, id));
                 if tps.len() > 0 {
                     try!(write!(f, "<{:?}>", tps));
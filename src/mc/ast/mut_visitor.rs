@@ -20,7 +20,7 @@ pub trait MutVisitor: Sized {
 
 pub fn walk_item<T: MutVisitor>(visitor: &mut T, item: &mut Item) {
     match item.val {
-        FuncItem(ref mut id, ref mut args, ref mut t, ref mut def, ref mut tps) => {
+        FuncItem(ref mut id, ref mut args, ref mut t, ref mut def, ref mut tps, _) => {
             visitor.visit_ident(id);
             for arg in args.iter_mut() { visitor.visit_func_arg(arg); }
             visitor.visit_type(t);
@@ -63,8 +63,8 @@ impl<'a, 'b> Visitor for PathMapVisitor<'a, 'b> {
             StructItem(ref id, _, _) |
             EnumItem(ref id, _, _) |
             ConstItem(ref id, _, _) |
-            FuncItem(ref id, _, _, LocalFn(..), _) |
-            FuncItem(ref id, _, _, ExternFn(..), _) |
+            FuncItem(ref id, _, _, LocalFn(..), _, _) |
+            FuncItem(ref id, _, _, ExternFn(..), _, _) |
             StaticItem(ref id, _, None, true) => {
                 self.insert(id, item);
             },
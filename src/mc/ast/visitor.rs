@@ -20,7 +20,7 @@ pub trait Visitor: Sized {
 
 pub fn walk_item<T: Visitor>(visitor: &mut T, item: &Item) {
     match item.val {
-        FuncItem(ref id, ref args, ref t, ref def, ref tps) => {
+        FuncItem(ref id, ref args, ref t, ref def, ref tps, _) => {
             visitor.visit_ident(id);
             for arg in args.iter() { visitor.visit_func_arg(arg); }
             visitor.visit_type(t);
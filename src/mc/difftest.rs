@@ -0,0 +1,159 @@
+//! Differential testing: run one program through every backend that can be
+//! executed here, and report where their printed output first disagrees.
+//!
+//! The c and ir targets produce C, which we can only run if `gcc -m32`
+//! works. Asm output runs in `sim`.
+
+use mc::session::Options;
+use sim::machine::{Machine, Stop};
+use super::make_target;
+use time::precise_time_ns;
+
+use std::any::Any;
+use std::env;
+use std::fs;
+use std::fs::File;
+use std::io::Write;
+use std::path::Path;
+use std::process::Command;
+use std::thread;
+
+pub static MAX_CYCLES: u64 = 100000000;
+
+/// Compile `path` with the named target. Compiler panics are caught and
+/// returned as errors.
+pub fn compile(target: &'static str, args: &Vec<(String, Option<String>)>,
+               options: &Options, path: &Path) -> Result<Vec<u8>, String> {
+    let args = args.clone();
+    let options = options.clone();
+    let path = path.to_path_buf();
+    thread::spawn(move || {
+        let mut out = vec!();
+        super::compile_path(target, &args, options, &path, &mut out);
+        out
+    }).join().map_err(panic_message)
+}
+
+fn panic_message(e: Box<Any + Send>) -> String {
+    match e.downcast::<String>() {
+        Ok(s) => *s,
+        Err(e) => match e.downcast::<&'static str>() {
+            Ok(s) => s.to_string(),
+            Err(_) => "compiler panicked".to_string(),
+        },
+    }
+}
+
+/// Run an asm image, returning what it printed.
+pub fn run_asm(image: &[u8], code_start: u32) -> Result<String, String> {
+    let mut machine = Machine::new();
+    machine.load_image(image, code_start);
+    loop {
+        match machine.run(Some(MAX_CYCLES)) {
+            Some(Stop::DebugBreak) => continue,
+            Some(Stop::Exit(_)) => break,
+            Some(stop) => return Err(format!("stopped at 0x{:08x}: {:?}", machine.pc, stop)),
+            None => return Err(format!("still running after {} packets", MAX_CYCLES)),
+        }
+    }
+    Ok(String::from_utf8_lossy(&machine.take_output()).into_owned())
+}
+
+/// Build C output with `gcc -m32` and run it, returning what it printed.
+/// `name` keeps the temporary files of concurrent runs apart.
+pub fn run_c(source: &[u8], name: &str) -> Result<String, String> {
+    let dir = env::temp_dir();
+    let c_file = dir.join(format!("moroso-{}.c", name));
+    let exe = dir.join(format!("moroso-{}", name));
+    File::create(&c_file).and_then(|mut f| f.write_all(source))
+        .unwrap_or_else(|e| panic!("{}", e));
+
+    let gcc = Command::new("gcc").arg("-m32").arg(&c_file).arg("-o").arg(&exe).output()
+        .unwrap_or_else(|e| panic!("{}", e));
+    if !gcc.status.success() {
+        return Err(format!("gcc failed:\n{}", String::from_utf8_lossy(&gcc.stderr)));
+    }
+    let run = Command::new(&exe).output().unwrap_or_else(|e| panic!("{}", e));
+    Ok(String::from_utf8_lossy(&run.stdout).into_owned())
+}
+
+/// Whether we can build 32-bit C programs here.
+pub fn have_gcc_m32() -> bool {
+    // Test binaries run this concurrently, so each probe needs its own files.
+    let name = format!("moroso-gcc-probe-{}", precise_time_ns());
+    let c_file = env::temp_dir().join(format!("{}.c", name));
+    let exe = env::temp_dir().join(name);
+    File::create(&c_file).and_then(|mut f| f.write_all(b"int main() { return 0; }\n"))
+        .unwrap_or_else(|e| panic!("{}", e));
+    let ok = match Command::new("gcc").arg("-m32").arg(&c_file).arg("-o").arg(&exe).output() {
+        Ok(out) => out.status.success(),
+        Err(_) => false,
+    };
+    fs::remove_file(&c_file).ok();
+    fs::remove_file(&exe).ok();
+    ok
+}
+
+/// Run the program at `path` through every backend we can, and print a
+/// report. Returns whether all the backends that ran agreed.
+pub fn difftest(path: &Path, args: &Vec<(String, Option<String>)>, options: &Options) -> bool {
+    let code_start = args.iter()
+        .filter(|&&(ref name, _)| *name == "code_start")
+        .map(|&(_, ref val)| u32::from_str_radix(&val.clone().unwrap()[..], 16).unwrap())
+        .next()
+        .unwrap_or(0);
+    let gcc = have_gcc_m32();
+    let stem = path.file_stem().unwrap().to_string_lossy().into_owned();
+
+    let mut results = vec!();
+    let mut ok = true;
+    for &target in ["c", "ir", "asm"].iter() {
+        if target != "asm" && !gcc {
+            println!("{}: skipped, since gcc -m32 doesn't work here", target);
+            continue;
+        }
+        let output = compile(target, args, options, path).and_then(|out| {
+            if target == "asm" {
+                run_asm(&out, code_start)
+            } else {
+                run_c(&out, &format!("difftest-{}-{}", stem, target))
+            }
+        });
+        match output {
+            Ok(output) => {
+                println!("{}: {} lines of output", target, output.lines().count());
+                results.push((target, output));
+            },
+            Err(e) => {
+                println!("{}: failed: {}", target, e);
+                ok = false;
+            },
+        }
+    }
+
+    if results.len() < 2 {
+        println!("fewer than two backends ran; nothing to compare");
+        return ok;
+    }
+
+    let lines: Vec<Vec<&str>> = results.iter().map(|&(_, ref out)| out.lines().collect())
+                                       .collect();
+    let longest = lines.iter().map(|l| l.len()).max().unwrap();
+    for i in 0 .. longest {
+        let line_of = |l: &Vec<&str>| l.get(i).map(|s| *s);
+        if lines.iter().all(|l| line_of(l) == line_of(&lines[0])) {
+            continue;
+        }
+        println!("first divergence at line {}:", i + 1);
+        for (&(target, _), l) in results.iter().zip(lines.iter()) {
+            match line_of(l) {
+                Some(line) => println!("    {:>4}: {}", target, line),
+                None => println!("    {:>4}: <end of output>", target),
+            }
+        }
+        return false;
+    }
+
+    println!("all {} backends agree", results.len());
+    ok
+}
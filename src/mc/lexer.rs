@@ -93,6 +93,7 @@ pub enum Token {
     RshEq,
     PercentEq,
     Dollar,
+    Hash,
     DotDotDot,
 
     // Literals
@@ -190,6 +191,7 @@ impl fmt::Display for Token {
             Token::RshEq                  => ">>=".to_string(),
             Token::PercentEq              => "%=".to_string(),
             Token::Dollar                 => "$".to_string(),
+            Token::Hash                   => "#".to_string(),
             Token::DotDotDot              => "...".to_string(),
 
             Token::IdentTok(ref id)       => format!("{}", id),
@@ -439,6 +441,7 @@ pub fn new_mb_lexer<'a, S: ?Sized + ToString, B: BufReader>(name: &S, buffer: B)
             Token::RshEq        => ">>=",
             Token::PercentEq    => "%=",
             Token::Dollar       => "$",
+            Token::Hash         => "#",
             Token::DotDotDot    => "...",
 
             // Literals
@@ -3,6 +3,7 @@ use target::{MkTarget, Target, CTarget, IRTarget, AsmTarget};
 
 use self::ast::visitor::Visitor;
 use self::session::{Session, Options};
+use util::timing;
 
 use getopts;
 use getopts::{getopts, reqopt, optopt, optflag, optmulti};
@@ -22,6 +23,7 @@ pub mod ast;
 pub mod session;
 pub mod resolver;
 pub mod deps;
+pub mod difftest;
 
 struct NullTarget;
 impl MkTarget for NullTarget {
@@ -49,6 +51,40 @@ macro_rules! targets {
 }
 
 
+fn make_target(name: &str, args: &Vec<(String, Option<String>)>) -> Option<Box<Target>> {
+    let targets = targets! {
+        "c" => CTarget,
+        "ir" => IRTarget,
+        "null" => NullTarget,
+        "asm" => AsmTarget,
+    };
+
+    targets.into_iter()
+           .filter(|&(ref t, _)| t.eq_ignore_ascii_case(name))
+           .map(|(_, ctor)| ctor(args))
+           .next()
+}
+
+/// Compile the file at `path` with the named target, writing the output to
+/// `out`. `args` are the same target options `main` passes along.
+pub fn compile_path(target_name: &str, args: &Vec<(String, Option<String>)>,
+                    options: Options, path: &Path, out: &mut Write) {
+    let target = make_target(target_name, args).unwrap_or_else(
+        || panic!("Unrecognized target `{}'", target_name));
+    let package = Package::from_path(options, path);
+    target.compile(package, out);
+}
+
+/// Find the preludes in the `lib` directory under `root`.
+pub fn setup_search_paths_from(opts: &mut Options, root: &Path) {
+    let prelude_location = root.join(Path::new("lib/prelude.mb"));
+    opts.search_paths.insert("prelude".to_string(), prelude_location);
+    let prelude_ma_location = root.join(Path::new("lib/prelude.ma"));
+    opts.search_paths.insert("prelude.ma".to_string(), prelude_ma_location);
+    let prelude_bsld_ma_location = root.join(Path::new("lib/prelude_bsld.ma"));
+    opts.search_paths.insert("prelude_bsld.ma".to_string(), prelude_bsld_ma_location);
+}
+
 pub fn setup_builtin_search_paths(opts: &mut Options) {
     // Unless it gets overridden, pull out a prelude based on the
     // install location of the binary. This is kind of dubious.
@@ -63,12 +99,7 @@ pub fn setup_builtin_search_paths(opts: &mut Options) {
                 install_path.pop();
                 install_path.pop();
             }
-            let prelude_location = install_path.join(Path::new("lib/prelude.mb"));
-            opts.search_paths.insert("prelude".to_string(), prelude_location);
-            let prelude_ma_location = install_path.join(Path::new("lib/prelude.ma"));
-            opts.search_paths.insert("prelude.ma".to_string(), prelude_ma_location);
-            let prelude_bsld_ma_location = install_path.join(Path::new("lib/prelude_bsld.ma"));
-            opts.search_paths.insert("prelude_bsld.ma".to_string(), prelude_bsld_ma_location);
+            setup_search_paths_from(opts, &install_path);
         }
     }
 }
@@ -92,6 +123,8 @@ pub fn main() {
     let opts = [
         optopt("", "target", "Set the output target format.", "[c|null|asm|ir]"),
         optopt("o", "output", "Output file", "<filename>"),
+        optopt("", "emit", "Write out the lowered IR as text instead (ir target only)",
+               "[ir-text]"),
         optopt("", "list", "List file (asm target only)", "<filename>"),
         optopt("", "debug", "Debug file (asm target only)", "<filename>"),
         optopt("f", "format", "Output file format (asm target only)", "[flat|bsld]"),
@@ -102,8 +135,23 @@ pub fn main() {
         optopt("", "code_start", "Address in hex of the start of the code (asm target only)",
                "<hex value, no 0x>"),
         optflag("d", "dep-files", "Generate dependency files"),
+        optflag("", "difftest", "Run the program through every backend that can run here \
+                                 and compare their output"),
         optflag("v", "verbose", "Enable verbose output."),
         optflag("", "disable_scheduler", "Disable instruction scheduler (asm target only)"),
+        optflag("", "disable_if_convert", "Disable if-conversion (asm target only; \
+                                           always disabled at -O0)"),
+        optflag("", "disable_pipeline", "Disable software pipelining of loops (asm target \
+                                         only; always disabled at -O0)"),
+        optflag("", "check_passes", "Interpret the IR before and after each pass, and \
+                                     check the output doesn't change (ir and asm targets)"),
+        optopt("O", "", "Optimization level (default 1; ir and asm targets)", "[0|1|2]"),
+        optopt("", "regalloc", "Register allocator (default linear at -O0, briggs otherwise; \
+                                 asm target only)", "[briggs|linear]"),
+        optopt("", "passes", "Run these IR passes instead of the -O preset (ir and asm targets)",
+               "<pass,...>"),
+        optmulti("", "print-after", "Print the IR after this pass to stderr", "<pass>"),
+        optflag("", "time-passes", "Report how long each phase of compilation takes"),
         optflag("h", "help", "Show this help message."),
         optmulti("l", "lib", "Specify a library location", "<foo:/path/to/foo.mb>"),
     ];
@@ -131,16 +179,10 @@ pub fn main() {
         bail(None);
     }
 
-    let targets = targets! {
-        "c" => CTarget,
-        "ir" => IRTarget,
-        "null" => NullTarget,
-        "asm" => AsmTarget,
-    };
-
     let mut opts = vec!();
 
-    for opt in vec!("verbose", "disable_scheduler").into_iter() {
+    for opt in vec!("verbose", "disable_scheduler", "disable_if_convert",
+                    "disable_pipeline", "check_passes").into_iter() {
         let val = matches.opt_present(opt);
         if val {
             opts.push((opt.to_string(), None));
@@ -148,19 +190,39 @@ pub fn main() {
     }
 
     for opt in vec!("list", "format", "code_start", "stack_start", "global_start",
-                    "debug").into_iter() {
+                    "debug", "emit", "passes", "regalloc").into_iter() {
         let val = matches.opt_str(opt);
         if val.is_some() {
             opts.push((opt.to_string(), val));
         }
     }
 
-    let target_arg = matches.opt_str("target").unwrap_or("null".to_string());
-    let target = match targets.into_iter()
-                        .filter(|&(ref t, _)| t.eq_ignore_ascii_case(&target_arg[..]))
-                        .map(|(_, ctor)| ctor(&opts))
-                        .next()
-    {
+    match matches.opt_str("O") {
+        Some(level) => opts.push(("opt_level".to_string(), Some(level))),
+        None => {},
+    }
+    for pass in matches.opt_strs("print-after").into_iter() {
+        opts.push(("print_after".to_string(), Some(pass)));
+    }
+
+    if matches.opt_present("difftest") {
+        let mut options = Options::new();
+        setup_builtin_search_paths(&mut options);
+        if !parse_search_paths(&mut options, &matches) {
+            bail(Some("Bogus library specification"));
+        }
+        if matches.free.len() != 1 {
+            bail(Some("--difftest needs exactly one file"));
+        }
+        let path = Path::new(&matches.free[0]);
+        let ok = difftest::difftest(path, &opts, &options);
+        process::exit(if ok { 0 } else { 1 });
+    }
+
+    // There's only the one thing to emit, so --emit picks its target.
+    let default_target = if matches.opt_present("emit") { "ir" } else { "null" };
+    let target_arg = matches.opt_str("target").unwrap_or(default_target.to_string());
+    let target = match make_target(&target_arg[..], &opts) {
         Some(t) => t,
         None => {
             let msg = format!("Unrecognized target `{}'", target_arg);
@@ -210,6 +272,10 @@ pub fn main() {
     }
 
     target.compile(package, &mut *writer);
+
+    if matches.opt_present("time-passes") {
+        timing::report(&mut io::stderr());
+    }
 }
 
 #[cfg(test)]
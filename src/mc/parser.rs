@@ -100,7 +100,7 @@ fn can_start_item(t: &Token) -> bool {
     match *t {
         Token::Fn | Token::Static | Token::Extern |
         Token::Enum | Token::Struct | Token::Mod |
-        Token::Macro | Token::Const | Token::Type
+        Token::Macro | Token::Const | Token::Type | Token::Hash
             => true,
         _   => false
     }
@@ -1415,7 +1415,29 @@ impl<'a, 'b, T: Iterator<Item=SourceToken<Token>>> StreamParser<'a, 'b, T> {
         }
     }
 
-    fn parse_extern_item(&mut self) -> Item {
+    // Parse `#[inline]` or `#[inline(never)]`, if there is one, before a
+    // function.
+    fn parse_inline_attr(&mut self) -> Option<bool> {
+        if *self.peek() != Token::Hash {
+            return None;
+        }
+        self.expect(Token::Hash);
+        self.expect(Token::LBracket);
+        self.expect(Token::IdentTok("inline".to_string()));
+        let inline = match *self.peek() {
+            Token::LParen => {
+                self.expect(Token::LParen);
+                self.expect(Token::IdentTok("never".to_string()));
+                self.expect(Token::RParen);
+                false
+            }
+            _ => true,
+        };
+        self.expect(Token::RBracket);
+        Some(inline)
+    }
+
+    fn parse_extern_item(&mut self, inline: Option<bool>) -> Item {
         let start_span = self.cur_span();
         self.expect(Token::Extern);
 
@@ -1434,13 +1456,16 @@ impl<'a, 'b, T: Iterator<Item=SourceToken<Token>>> StreamParser<'a, 'b, T> {
                 let end_span = self.cur_span();
                 let abi = self.session.interner.intern(abi.unwrap_or("C".to_string()));
                 self.add_id_and_span(FuncItem(funcname, args, return_type,
-                                              ExternFn(abi, body_opt), type_params),
+                                              ExternFn(abi, body_opt), type_params, inline),
                                      start_span.to(end_span))
             }
             Token::Static => {
                 if abi != None {
                     self.peek_error("ABI specifiers are invalid on extern static items");
                 }
+                if inline != None {
+                    self.peek_error("#[inline] is invalid on extern static items");
+                }
 
                 let (name, ty) = self.parse_static_decl();
                 self.expect(Token::Semicolon);
@@ -1480,12 +1505,13 @@ impl<'a, 'b, T: Iterator<Item=SourceToken<Token>>> StreamParser<'a, 'b, T> {
         (funcname, args, return_type, type_params)
     }
 
-    fn parse_func_item(&mut self) -> Item {
+    fn parse_func_item(&mut self, inline: Option<bool>) -> Item {
         let start_span = self.cur_span();
         let (funcname, args, return_type, type_params) = self.parse_func_prototype();
         let body = self.parse_block();
         let end_span = self.cur_span();
-        self.add_id_and_span(FuncItem(funcname, args, return_type, LocalFn(body), type_params),
+        self.add_id_and_span(FuncItem(funcname, args, return_type, LocalFn(body), type_params,
+                                      inline),
                              start_span.to(end_span))
     }
 
@@ -1744,14 +1770,16 @@ impl<'a, 'b, T: Iterator<Item=SourceToken<Token>>> StreamParser<'a, 'b, T> {
     }
 
     fn parse_item(&mut self) -> Item {
+        let inline = self.parse_inline_attr();
         match *self.peek() {
-            Token::Fn => self.parse_func_item(),
+            Token::Fn => self.parse_func_item(inline),
+            Token::Extern => self.parse_extern_item(inline),
+            _ if inline.is_some() => self.peek_error("Expected a function after #[inline]"),
             Token::Struct => self.parse_struct_item(),
             Token::Enum => self.parse_enum_item(),
             Token::Type => self.parse_type_item(),
             Token::Mod => self.parse_mod_item(),
             Token::Static => self.parse_static_item(),
-            Token::Extern => self.parse_extern_item(),
             Token::Macro => self.parse_macro_item(),
             Token::Const => self.parse_const_item(),
             _ => self.peek_error("Expected an item definition (fn, struct, enum, mod)"),
@@ -1769,7 +1797,7 @@ impl<'a, 'b, T: Iterator<Item=SourceToken<Token>>> StreamParser<'a, 'b, T> {
 
 #[cfg(test)]
 mod tests {
-    use super::super::ast::Expr;
+    use super::super::ast::{Expr, FuncItem};
 
     use super::*;
 
@@ -1811,6 +1839,17 @@ mod tests {
                    "((1+((3*5)/2))-((2*3)*((5+6))))");
     }
 
+    #[test]
+    fn test_inline_attrs() {
+        let (_, tree) = ast_from_str("#[inline] fn f() {} #[inline(never)] fn g() {} fn h() {}",
+                                     |p| p.parse_module());
+        let inlines: Vec<Option<bool>> = tree.val.items.iter().map(|item| match item.val {
+            FuncItem(_, _, _, _, _, inline) => inline,
+            _ => panic!(),
+        }).collect();
+        assert_eq!(inlines, vec!(Some(true), Some(false), None));
+    }
+
     // These tests disabled until we have a pretty printer
     /*
     fn compare_canonicalized(raw: &str, parsed: &str) {
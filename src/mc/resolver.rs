@@ -79,7 +79,7 @@ impl Subscope {
                         }
                     }
                 }
-                FuncItem(ref ident, _, _, _, _) => {
+                FuncItem(ref ident, _, _, _, _, _) => {
                     self.insert_ident(ValNS, ident);
                 }
                 StructItem(ref ident, _, _) => {
@@ -453,7 +453,7 @@ impl<'a, 'b> Visitor for ModuleResolver<'a, 'b> {
             UseItem(ref import) => {
                 self.handle_use(import);
             }
-            FuncItem(_, ref args, ref t, ref def, ref tps) => {
+            FuncItem(_, ref args, ref t, ref def, ref tps, _) => {
                 self.descend(None, |me| {
                     for tp in tps.iter() {
                         me.add_ident_to_scope(TypeAndModNS, tp);
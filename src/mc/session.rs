@@ -6,6 +6,7 @@
 
 use span::Span;
 use util::Name;
+use util::timing::time;
 use mc::ast::NodeId;
 use util::lexer::BufReader;
 
@@ -39,6 +40,7 @@ pub fn get_cur_rel_path() -> PathBuf {
     CUR_REL_PATH.with(|p| p.borrow().clone())
 }
 
+#[derive(Clone)]
 pub struct Options {
     pub search_paths: HashMap<String, PathBuf>,
 }
@@ -205,19 +207,24 @@ impl<'a> Session<'a> {
             }
         }
 
-        let mut module = self.parse_buffer(name, buffer);
+        let mut module = time("parse", || {
+            let mut module = self.parse_buffer(name, buffer);
 
-        {
-            let mut injector = PreludeInjector { session: self };
-            injector.visit_module(&mut module);
-        }
+            {
+                let mut injector = PreludeInjector { session: self };
+                injector.visit_module(&mut module);
+            }
 
-        self.inject_std(&mut module);
+            self.inject_std(&mut module);
+            module
+        });
 
-        MacroExpander::expand_macros(self, &mut module);
-        DefMap::record(self, &module);
-        PathMap::record(self, &module);
-        Resolver::resolve(self, &module);
+        time("macro expand", || MacroExpander::expand_macros(self, &mut module));
+        time("resolve", || {
+            DefMap::record(self, &module);
+            PathMap::record(self, &module);
+            Resolver::resolve(self, &module);
+        });
         module
     }
 
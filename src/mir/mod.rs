@@ -0,0 +1,182 @@
+//! Runs IR passes over a program written as IR text (see `ir::text`), so
+//! that passes can be tried out and tested without the MC frontend.
+
+use std::{process, io, env};
+use std::collections::BTreeMap;
+use std::path::Path;
+use std::fs::File;
+use std::io::{Read, Write};
+
+use getopts;
+use getopts::{getopts, optopt, optflag};
+
+use ir::interp::run_program;
+use ir::liveness::LivenessAnalyzer;
+use ir::passes::PassManager;
+use ir::text::{parse_program, var_to_string, write_global, write_function_with_notes};
+
+/// Parse `src`, run `passes` over it, and give back the resulting program as
+/// text. With `liveness`, each op is followed by a comment listing the
+/// variables live at it.
+pub fn run_passes(src: &str, passes: &PassManager,
+                  liveness: bool) -> Result<String, String> {
+    let (mut program, global_map) = try!(parse_program(src));
+    try!(passes.run(&mut program, &global_map, &BTreeMap::new()));
+
+    let mut out = vec!();
+    for (_, item) in global_map.iter() {
+        write_global(&mut out, item);
+    }
+    for ops in program.iter() {
+        let notes = if liveness {
+            LivenessAnalyzer::analyze(ops).iter().map(|info| {
+                let live: Vec<String> = info.live.iter().map(var_to_string).collect();
+                format!("live: {}", live.join(", "))
+            }).collect()
+        } else {
+            vec!()
+        };
+        write_function_with_notes(&mut out, ops, &notes);
+    }
+    Ok(String::from_utf8(out).unwrap())
+}
+
+/// Parse `src` and interpret it, giving back what it printed.
+pub fn run(src: &str) -> Result<String, String> {
+    let (program, global_map) = try!(parse_program(src));
+    run_program(&program, &global_map)
+}
+
+pub fn main() {
+    let args: Vec<String> = env::args().collect();
+    let arg0 = &args[0];
+
+    let opts = [
+        optopt("", "passes", "Comma-separated passes to run, in order", "<pass,...>"),
+        optflag("", "liveness", "Note the live variables after each op"),
+        optflag("", "run", "Interpret the program after the passes, and print its output"),
+        optopt("o", "output", "Output file", "<filename>"),
+        optflag("v", "verbose", "Enable verbose output."),
+        optflag("h", "help", "Show this help message."),
+    ];
+
+    let bail = |error: Option<&str>| {
+        let error = match error {
+            Some(e) => {
+                println!("{}: fatal error: {}", arg0, e);
+                1
+            }
+            None => 0,
+        };
+
+        let brief = format!("Usage: {} [OPTIONS] <file.mir>", arg0);
+        println!("{}", getopts::usage(&brief[..], &opts));
+        process::exit(error)
+    };
+
+    let matches = match getopts(&args[1..], &opts) {
+        Ok(m) => m,
+        Err(e) => return bail(Some(&format!("{}", e)[..])),
+    };
+
+    if matches.opt_present("help") {
+        return bail(None);
+    }
+
+    let mut src = String::new();
+    if matches.free.len() == 0 || matches.free[0] == "-" {
+        io::stdin().read_to_string(&mut src).unwrap_or_else(|e| panic!("{}", e));
+    } else if matches.free.len() == 1 {
+        let path = Path::new(&matches.free[0]);
+        File::open(&path).and_then(|mut f| f.read_to_string(&mut src))
+            .unwrap_or_else(|e| panic!("{}", e));
+    } else {
+        return bail(Some("too many arguments"));
+    }
+
+    let passes = matches.opt_str("passes").unwrap_or(String::new());
+    let passes: Vec<&str> = passes.split(',').filter(|p| !p.is_empty()).collect();
+    let mut passes = match PassManager::new(&passes[..]) {
+        Ok(passes) => passes,
+        Err(e) => return bail(Some(&e[..])),
+    };
+    // Check every pass's output, even in release builds.
+    passes.verify = true;
+    passes.verbose = matches.opt_present("verbose");
+
+    let result = match run_passes(&src[..], &passes, matches.opt_present("liveness")) {
+        Ok(result) => result,
+        Err(e) => {
+            println!("{}: {}", arg0, e);
+            process::exit(1);
+        },
+    };
+
+    let mut writer = match matches.opt_str("output") {
+        None => Box::new(io::stdout()) as Box<Write>,
+        Some(name) => {
+            let path = Path::new(&name);
+            let file = File::create(&path).unwrap_or_else(|e| panic!("{}", e));
+            Box::new(file) as Box<Write>
+        }
+    };
+
+    if matches.opt_present("run") {
+        match run(&result[..]) {
+            Ok(output) => { writer.write_all(output.as_bytes()).ok(); },
+            Err(e) => {
+                println!("{}: {}", arg0, e);
+                process::exit(1);
+            },
+        }
+    } else {
+        writer.write_all(result.as_bytes()).ok();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{run, run_passes};
+    use ir::passes::PassManager;
+
+    static COUNT: &'static str = "
+global %print_int size 0 offset 0 extern func
+fn %print_int(%x) extern \"C\"
+fn %__main()
+    %n := 3u32
+    %i := 0u32
+    goto 0()
+    label 0()
+    %c := %i <u %n
+    if !%c goto 1()
+    %t := call %print_int(%i)
+    %i := %i +u 1u32
+    goto 0()
+    label 1()
+    return 0u32
+";
+
+    #[test]
+    fn test_ssa_and_fold() {
+        let passes = PassManager::new(&["ssa", "constant_fold"]).unwrap();
+        let result = run_passes(COUNT, &passes, false).unwrap();
+        // Every assignment now has a generation, and the loop counter is
+        // passed around through the label.
+        assert!(!result.contains("%i :="), "{}", result);
+        let label = result.lines().filter(|l| l.contains("label 0(")).next().unwrap();
+        assert!(label.contains("%i<"), "{}", result);
+        assert_eq!(run(&result[..]), Ok("0\n1\n2\n".to_string()));
+    }
+
+    #[test]
+    fn test_liveness() {
+        let src = "fn %f(%a, %b)
+    %c := %a +s 1
+    return %c
+";
+        let result = run_passes(src, &PassManager::new(&[]).unwrap(), true).unwrap();
+        let lines: Vec<&str> = result.lines().collect();
+        assert_eq!(lines[1..].to_vec(), vec!("    %c := %a +s 1 // live: %a",
+                                             "    return %c // live: %c"));
+    }
+}
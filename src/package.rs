@@ -3,6 +3,7 @@ use mc::ast::visitor::Visitor;
 use mc::session::{Session, Options};
 use typechecker::{Typechecker, Typemap};
 use util::lexer::BufReader;
+use util::timing::time;
 
 pub struct Package<'a> {
     pub module:  Module,
@@ -49,11 +50,11 @@ impl<'a> Package<'a> {
     fn new<T: Parsable>(opts: Options, parsable: T) -> Package<'a> {
         let mut session = Session::new(opts);
         let module = parsable.parse(&mut session);
-        let typemap = {
+        let typemap = time("typecheck", || {
             let mut typeck = Typechecker::new(&session);
             typeck.typecheck(&module);
             typeck.get_typemap()
-        };
+        });
 
         Package {
             module:  module,
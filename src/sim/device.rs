@@ -0,0 +1,285 @@
+//! Memory-mapped devices.
+//!
+//! A device answers loads and stores to its own address range instead of
+//! memory, and gets ticked once per packet so it can raise an interrupt.
+//! Registers are all 32 bits wide; narrower accesses see the low bits.
+
+use std::io::{Read, Write};
+use std::sync::mpsc::{channel, Receiver, TryRecvError};
+use std::thread;
+
+pub trait Device {
+    /// The first address the device answers to, and how many bytes it covers.
+    fn range(&self) -> (u32, u32);
+
+    fn read(&mut self, offset: u32, bytes: u32) -> u32;
+
+    fn write(&mut self, offset: u32, bytes: u32, val: u32);
+
+    /// Advance the device by one packet. Returns whether it wants to raise
+    /// its interrupt.
+    fn tick(&mut self) -> bool {
+        false
+    }
+
+    fn contains(&self, addr: u32) -> bool {
+        let (base, len) = self.range();
+        addr >= base && addr - base < len
+    }
+}
+
+pub static INTERRUPT_BASE: u32 = 0xf0000000;
+pub static UART_BASE: u32 = 0xf0001000;
+pub static TIMER_BASE: u32 = 0xf0002000;
+
+/// Interrupt numbers the standard devices are wired to.
+pub static TIMER_IRQ: u32 = 0;
+pub static UART_IRQ: u32 = 1;
+
+/// The interrupt line, shared by every device.
+///
+/// Offset 0 holds a bit per interrupt that has fired and not yet been
+/// acknowledged; writing ones to it clears those bits. Offset 4 is the mask
+/// of interrupts that may be delivered.
+pub struct InterruptLine {
+    pub pending: u32,
+    pub enabled: u32,
+}
+
+impl InterruptLine {
+    pub fn new() -> InterruptLine {
+        InterruptLine { pending: 0, enabled: 0 }
+    }
+
+    pub fn raise(&mut self, irq: u32) {
+        self.pending |= 1 << (irq as usize);
+    }
+
+    /// The lowest-numbered interrupt that's pending and enabled.
+    pub fn next(&self) -> Option<u32> {
+        let active = self.pending & self.enabled;
+        if active == 0 {
+            None
+        } else {
+            Some(active.trailing_zeros())
+        }
+    }
+}
+
+impl Device for InterruptLine {
+    fn range(&self) -> (u32, u32) {
+        (INTERRUPT_BASE, 8)
+    }
+
+    fn read(&mut self, offset: u32, _: u32) -> u32 {
+        match offset {
+            0 => self.pending,
+            4 => self.enabled,
+            _ => 0,
+        }
+    }
+
+    fn write(&mut self, offset: u32, _: u32, val: u32) {
+        match offset {
+            0 => self.pending &= !val,
+            4 => self.enabled = val,
+            _ => {},
+        }
+    }
+}
+
+pub static UART_STATUS_RX_READY: u32 = 1;
+pub static UART_STATUS_TX_READY: u32 = 2;
+pub static UART_CTRL_RX_INT: u32 = 1;
+
+/// A serial port.
+///
+/// Offset 0 is the data register: loads take the next input byte (or all
+/// ones if there isn't one) and stores send a byte. Offset 4 is the status
+/// register, and offset 8 the control register; setting `UART_CTRL_RX_INT`
+/// there raises an interrupt whenever input is waiting.
+pub struct Uart {
+    // Input is read on another thread, so the machine never blocks on it.
+    input: Receiver<u8>,
+    output: Box<Write>,
+    // A byte we've taken from the channel but the program hasn't read yet.
+    next: Option<u8>,
+    ctrl: u32,
+}
+
+impl Uart {
+    pub fn new(input: Box<Read + Send>, output: Box<Write>) -> Uart {
+        let (tx, rx) = channel();
+        thread::spawn(move || {
+            let mut input = input;
+            let mut buf = [0u8];
+            loop {
+                match input.read(&mut buf) {
+                    Ok(1) => if tx.send(buf[0]).is_err() { break },
+                    _ => break,
+                }
+            }
+        });
+
+        Uart {
+            input: rx,
+            output: output,
+            next: None,
+            ctrl: 0,
+        }
+    }
+
+    fn poll(&mut self) {
+        if self.next.is_none() {
+            match self.input.try_recv() {
+                Ok(b) => self.next = Some(b),
+                Err(TryRecvError::Empty) |
+                Err(TryRecvError::Disconnected) => {},
+            }
+        }
+    }
+}
+
+impl Device for Uart {
+    fn range(&self) -> (u32, u32) {
+        (UART_BASE, 12)
+    }
+
+    fn read(&mut self, offset: u32, _: u32) -> u32 {
+        self.poll();
+        match offset {
+            0 => match self.next.take() {
+                Some(b) => b as u32,
+                None => !0,
+            },
+            4 => UART_STATUS_TX_READY |
+                 if self.next.is_some() { UART_STATUS_RX_READY } else { 0 },
+            8 => self.ctrl,
+            _ => 0,
+        }
+    }
+
+    fn write(&mut self, offset: u32, _: u32, val: u32) {
+        match offset {
+            0 => {
+                self.output.write_all(&[val as u8]).ok();
+                self.output.flush().ok();
+            },
+            8 => self.ctrl = val,
+            _ => {},
+        }
+    }
+
+    fn tick(&mut self) -> bool {
+        if self.ctrl & UART_CTRL_RX_INT == 0 {
+            return false;
+        }
+        self.poll();
+        self.next.is_some()
+    }
+}
+
+pub static TIMER_CTRL_ENABLE: u32 = 1;
+
+/// A countdown timer.
+///
+/// Offset 0 is the count, which goes down by one every packet while the
+/// timer is enabled. When it reaches zero the timer interrupts and starts
+/// again from the reload value at offset 4, or stops if that's zero.
+/// Offset 8 is the control register.
+pub struct Timer {
+    count: u32,
+    reload: u32,
+    ctrl: u32,
+}
+
+impl Timer {
+    pub fn new() -> Timer {
+        Timer { count: 0, reload: 0, ctrl: 0 }
+    }
+}
+
+impl Device for Timer {
+    fn range(&self) -> (u32, u32) {
+        (TIMER_BASE, 12)
+    }
+
+    fn read(&mut self, offset: u32, _: u32) -> u32 {
+        match offset {
+            0 => self.count,
+            4 => self.reload,
+            8 => self.ctrl,
+            _ => 0,
+        }
+    }
+
+    fn write(&mut self, offset: u32, _: u32, val: u32) {
+        match offset {
+            0 => self.count = val,
+            4 => self.reload = val,
+            8 => self.ctrl = val,
+            _ => {},
+        }
+    }
+
+    fn tick(&mut self) -> bool {
+        if self.ctrl & TIMER_CTRL_ENABLE == 0 || self.count == 0 {
+            return false;
+        }
+        self.count -= 1;
+        if self.count != 0 {
+            return false;
+        }
+        self.count = self.reload;
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use sim::machine::{Stop, machine_from_asm};
+    use mas::ast::*;
+    use super::{Timer, TIMER_IRQ};
+
+    #[test]
+    fn test_timer_interrupt() {
+        let mut machine = machine_from_asm(
+            "{ r1 <- long; long handler; r2 <- long; long 0xf0002000; }
+             { EHA <- r1; r3 <- 5; r4 <- 1; }
+             { *l(r2) <- r3; r5 <- long; long 0xf0000000; }
+             { *l(r2 + 8) <- r4; *l(r5 + 4) <- r4; }
+             { PFLAGS <- r4; }
+             spin: { b spin; r6 <- r6 + 1; }
+             handler: { r7 <- EC0; *l(r5) <- r4; }
+             { r8 <- EC1; }
+             { r0 <- r6; r30 <- 0; }
+             { break 0x1f; }");
+        machine.add_device(Box::new(Timer::new()), Some(TIMER_IRQ));
+
+        // The timer is started by the fourth packet and counts down once
+        // per packet, so the loop runs three times before it fires.
+        assert_eq!(machine.run(Some(100)), Some(Stop::Exit(3)));
+        assert_eq!(machine.regs[7], 1);
+        assert_eq!(machine.regs[8], TIMER_IRQ);
+        assert_eq!(machine.coreg(EPC), 0x50);
+        assert_eq!(machine.coreg(EC2), 1);
+        assert_eq!(machine.coreg(PFLAGS), 0);
+        assert_eq!(machine.interrupts.pending, 0);
+    }
+
+    #[test]
+    fn test_syscall_trap() {
+        let mut machine = machine_from_asm(
+            "{ r1 <- long; long handler; }
+             { EHA <- r1; }
+             { syscall 7; r0 <- 1; }
+             { r30 <- 0; }
+             { break 0x1f; }
+             handler: { r0 <- EC1; }
+             { r2 <- EPC; }
+             { eret; }");
+
+        assert_eq!(machine.run(Some(100)), Some(Stop::Exit(7)));
+        assert_eq!(machine.regs[2], 0x30);
+    }
+}
@@ -0,0 +1,453 @@
+//! A stub speaking the GDB remote serial protocol, so that debuggers and
+//! editors can drive a `Machine`.
+//!
+//! Registers are numbered r0-r31, then the pc (32), the predicate registers
+//! as a bitmask with p0 in bit 0 (33), and the overflow register (34). All of
+//! them are 32 bits wide and sent little-endian, as GDB expects.
+
+use sim::machine::{Machine, Stop};
+
+use std::collections::BTreeMap;
+use std::io;
+use std::io::{Read, Write};
+
+pub static NUM_REGS: usize = 35;
+pub static PC_REGNUM: usize = 32;
+pub static PREDS_REGNUM: usize = 33;
+pub static OVF_REGNUM: usize = 34;
+
+// Signal numbers used in stop replies.
+static SIGILL: u8 = 4;
+static SIGTRAP: u8 = 5;
+
+fn hex_u32_le(val: u32) -> String {
+    format!("{:02x}{:02x}{:02x}{:02x}",
+            val & 0xff, (val >> 8) & 0xff, (val >> 16) & 0xff, val >> 24)
+}
+
+fn parse_hex(s: &str) -> Option<u32> {
+    u32::from_str_radix(s, 16).ok()
+}
+
+fn parse_hex_u32_le(s: &str) -> Option<u32> {
+    if s.len() != 8 {
+        return None;
+    }
+    let mut val = 0;
+    for i in 0 .. 4 {
+        match parse_hex(&s[i * 2 .. i * 2 + 2]) {
+            Some(b) => val |= b << ((i * 8) as usize),
+            None => return None,
+        }
+    }
+    Some(val)
+}
+
+// Parse "addr,len", as used by the memory and breakpoint packets.
+fn parse_addr_len(s: &str) -> Option<(u32, u32)> {
+    let parts: Vec<&str> = s.split(',').collect();
+    if parts.len() != 2 {
+        return None;
+    }
+    match (parse_hex(parts[0]), parse_hex(parts[1])) {
+        (Some(addr), Some(len)) => Some((addr, len)),
+        _ => None,
+    }
+}
+
+pub fn checksum(data: &str) -> u8 {
+    data.bytes().fold(0u8, |sum, b| sum.wrapping_add(b))
+}
+
+/// Frame `data` as a protocol packet.
+pub fn frame(data: &str) -> String {
+    format!("${}#{:02x}", data, checksum(data))
+}
+
+pub struct GdbStub<'a, T> {
+    conn: T,
+    machine: &'a mut Machine,
+    // Installed breakpoints, and the packet each one replaced.
+    breakpoints: BTreeMap<u32, [u32; 4]>,
+    last_stop: Option<Stop>,
+    ack: bool,
+}
+
+impl<'a, T: Read + Write> GdbStub<'a, T> {
+    pub fn new(conn: T, machine: &'a mut Machine) -> GdbStub<'a, T> {
+        GdbStub {
+            conn: conn,
+            machine: machine,
+            breakpoints: BTreeMap::new(),
+            last_stop: None,
+            ack: true,
+        }
+    }
+
+    pub fn into_inner(self) -> T {
+        self.conn
+    }
+
+    fn read_byte(&mut self) -> io::Result<Option<u8>> {
+        let mut buf = [0u8];
+        match try!(self.conn.read(&mut buf)) {
+            0 => Ok(None),
+            _ => Ok(Some(buf[0])),
+        }
+    }
+
+    /// Read the next packet, returning its contents. Returns None once the
+    /// connection is closed.
+    fn read_packet(&mut self) -> io::Result<Option<String>> {
+        loop {
+            // Skip acks and anything else between packets.
+            loop {
+                match try!(self.read_byte()) {
+                    Some(b'$') => break,
+                    Some(_) => {},
+                    None => return Ok(None),
+                }
+            }
+
+            let mut data = vec!();
+            loop {
+                match try!(self.read_byte()) {
+                    Some(b'#') => break,
+                    Some(b) => data.push(b),
+                    None => return Ok(None),
+                }
+            }
+
+            let mut sum = [0u8; 2];
+            for i in 0 .. 2 {
+                match try!(self.read_byte()) {
+                    Some(b) => sum[i] = b,
+                    None => return Ok(None),
+                }
+            }
+
+            let data = String::from_utf8_lossy(&data).into_owned();
+            let expected = String::from_utf8_lossy(&sum).into_owned();
+            if parse_hex(&expected) == Some(checksum(&data) as u32) {
+                if self.ack {
+                    try!(self.conn.write_all(b"+"));
+                }
+                return Ok(Some(data));
+            } else if self.ack {
+                try!(self.conn.write_all(b"-"));
+            }
+        }
+    }
+
+    fn send_packet(&mut self, data: &str) -> io::Result<()> {
+        try!(self.conn.write_all(frame(data).as_bytes()));
+        self.conn.flush()
+    }
+
+    /// Answer requests until the debugger kills or detaches from us, or
+    /// hangs up.
+    pub fn serve(&mut self) -> io::Result<()> {
+        loop {
+            let packet = match try!(self.read_packet()) {
+                Some(packet) => packet,
+                None => return Ok(()),
+            };
+            match &packet[..] {
+                "k" => return Ok(()),
+                "D" => return self.send_packet("OK"),
+                _ => {},
+            }
+            let reply = self.handle(&packet);
+            try!(self.send_packet(&reply));
+        }
+    }
+
+    fn stop_reply(&self) -> String {
+        match self.last_stop {
+            Some(Stop::Exit(code)) => format!("W{:02x}", code & 0xff),
+            Some(Stop::IllegalInstruction) => format!("S{:02x}", SIGILL),
+            _ => format!("S{:02x}", SIGTRAP),
+        }
+    }
+
+    fn read_reg(&self, n: usize) -> Option<u32> {
+        if n < 32 {
+            Some(self.machine.regs[n])
+        } else if n == PC_REGNUM {
+            Some(self.machine.pc)
+        } else if n == PREDS_REGNUM {
+            Some(self.machine.preds.iter().enumerate()
+                 .fold(0, |acc, (i, &p)| if p { acc | (1 << i) } else { acc }))
+        } else if n == OVF_REGNUM {
+            Some(self.machine.ovf)
+        } else {
+            None
+        }
+    }
+
+    fn write_reg(&mut self, n: usize, val: u32) -> bool {
+        if n < 32 {
+            self.machine.regs[n] = val;
+        } else if n == PC_REGNUM {
+            self.machine.pc = val;
+        } else if n == PREDS_REGNUM {
+            for i in 0 .. 3 {
+                self.machine.preds[i] = val & (1 << i) != 0;
+            }
+        } else if n == OVF_REGNUM {
+            self.machine.ovf = val;
+        } else {
+            return false;
+        }
+        true
+    }
+
+    // Execute one packet. If we're sitting on one of our own breakpoints,
+    // put the original instruction back while it runs.
+    fn step_once(&mut self) -> Option<Stop> {
+        let pc = self.machine.pc;
+        match self.breakpoints.get(&pc).map(|&x| x) {
+            Some(orig) => {
+                self.machine.remove_breakpoint(pc, orig);
+                let stop = self.machine.step();
+                self.machine.insert_breakpoint(pc);
+                stop
+            },
+            None => self.machine.step(),
+        }
+    }
+
+    fn resume(&mut self, args: &str, single: bool) -> String {
+        if args.len() > 0 {
+            match parse_hex(args) {
+                Some(addr) => self.machine.pc = addr,
+                None => return "E01".to_string(),
+            }
+        }
+
+        let stop = if single {
+            match self.step_once() {
+                Some(stop) => stop,
+                None => Stop::Breakpoint,
+            }
+        } else {
+            let mut stop = self.step_once();
+            while stop.is_none() {
+                stop = self.machine.step();
+            }
+            stop.unwrap()
+        };
+        self.last_stop = Some(stop);
+        self.stop_reply()
+    }
+
+    /// Produce the reply to a single packet.
+    pub fn handle(&mut self, packet: &str) -> String {
+        if packet.len() == 0 {
+            return "".to_string();
+        }
+        let (cmd, args) = packet.split_at(1);
+        match cmd {
+            "?" => self.stop_reply(),
+            "g" => (0 .. NUM_REGS).map(|n| hex_u32_le(self.read_reg(n).unwrap()))
+                .collect::<Vec<String>>().concat(),
+            "G" => {
+                if args.len() != NUM_REGS * 8 {
+                    return "E01".to_string();
+                }
+                for n in 0 .. NUM_REGS {
+                    match parse_hex_u32_le(&args[n * 8 .. n * 8 + 8]) {
+                        Some(val) => { self.write_reg(n, val); },
+                        None => return "E01".to_string(),
+                    }
+                }
+                "OK".to_string()
+            },
+            "p" => match parse_hex(args).and_then(|n| self.read_reg(n as usize)) {
+                Some(val) => hex_u32_le(val),
+                None => "E01".to_string(),
+            },
+            "P" => {
+                let parts: Vec<&str> = args.split('=').collect();
+                if parts.len() != 2 {
+                    return "E01".to_string();
+                }
+                match (parse_hex(parts[0]), parse_hex_u32_le(parts[1])) {
+                    (Some(n), Some(val)) if self.write_reg(n as usize, val) =>
+                        "OK".to_string(),
+                    _ => "E01".to_string(),
+                }
+            },
+            "m" => match parse_addr_len(args) {
+                Some((addr, len)) =>
+                    (0 .. len).map(|i| format!("{:02x}",
+                                               self.machine.mem.read_u8(addr + i)))
+                    .collect::<Vec<String>>().concat(),
+                None => "E01".to_string(),
+            },
+            "M" => {
+                let parts: Vec<&str> = args.split(':').collect();
+                if parts.len() != 2 {
+                    return "E01".to_string();
+                }
+                match parse_addr_len(parts[0]) {
+                    Some((addr, len)) if parts[1].len() == len as usize * 2 => {
+                        for i in 0 .. len {
+                            let i = i as usize;
+                            match parse_hex(&parts[1][i * 2 .. i * 2 + 2]) {
+                                Some(b) => self.machine.mem.write_u8(addr + i as u32,
+                                                                     b as u8),
+                                None => return "E01".to_string(),
+                            }
+                        }
+                        "OK".to_string()
+                    },
+                    _ => "E01".to_string(),
+                }
+            },
+            "c" => self.resume(args, false),
+            "s" => self.resume(args, true),
+            "Z" | "z" => {
+                // Only software breakpoints ("Z0,addr,kind") are supported.
+                if !args.starts_with("0,") {
+                    return "".to_string();
+                }
+                let parts: Vec<&str> = args[2..].split(',').collect();
+                let addr = match parse_hex(parts[0]) {
+                    // Breakpoints cover a whole packet.
+                    Some(addr) => addr & !0xf,
+                    None => return "E01".to_string(),
+                };
+                if cmd == "Z" {
+                    if !self.breakpoints.contains_key(&addr) {
+                        let orig = self.machine.insert_breakpoint(addr);
+                        self.breakpoints.insert(addr, orig);
+                    }
+                } else {
+                    match self.breakpoints.remove(&addr) {
+                        Some(orig) => self.machine.remove_breakpoint(addr, orig),
+                        None => {},
+                    }
+                }
+                "OK".to_string()
+            },
+            "H" => "OK".to_string(),
+            _ => {
+                if packet.starts_with("qSupported") {
+                    "PacketSize=1000;QStartNoAckMode+".to_string()
+                } else if packet == "QStartNoAckMode" {
+                    self.ack = false;
+                    "OK".to_string()
+                } else if packet == "qAttached" {
+                    "1".to_string()
+                } else if packet == "qC" {
+                    "QC1".to_string()
+                } else if packet == "qfThreadInfo" {
+                    "m1".to_string()
+                } else if packet == "qsThreadInfo" {
+                    "l".to_string()
+                } else {
+                    // The empty reply tells the debugger we don't know
+                    // this one.
+                    "".to_string()
+                }
+            },
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{GdbStub, frame};
+    use sim::machine::{Machine, machine_from_asm};
+
+    use std::io;
+    use std::io::{Cursor, Read, Write};
+
+    // A scripted client: everything the debugger will send is queued up
+    // front, and everything the stub says is collected.
+    struct ScriptedConn {
+        input: Cursor<Vec<u8>>,
+        output: Vec<u8>,
+    }
+
+    impl Read for ScriptedConn {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            self.input.read(buf)
+        }
+    }
+
+    impl Write for ScriptedConn {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.output.write(buf)
+        }
+        fn flush(&mut self) -> io::Result<()> { Ok(()) }
+    }
+
+    // Run a session, and return the stub's replies with acks stripped.
+    fn session(machine: &mut Machine, requests: &[&str]) -> Vec<String> {
+        let mut input = String::new();
+        for request in requests.iter() {
+            input.push_str(&frame(request));
+        }
+        let conn = ScriptedConn {
+            input: Cursor::new(input.into_bytes()),
+            output: vec!(),
+        };
+        let mut stub = GdbStub::new(conn, machine);
+        stub.serve().unwrap();
+        let output = String::from_utf8(stub.into_inner().output).unwrap();
+        output.split('$').skip(1)
+            .map(|p| p.split('#').next().unwrap().to_string())
+            .collect()
+    }
+
+    static PROGRAM: &'static str = "
+        { r0 <- 5; r2 <- 0x100; }
+        { r1 <- r0 + 3; }
+        { *l(r2) <- r1; }
+        { r30 <- 0; }
+        { break 0x1f; }
+    ";
+
+    #[test]
+    fn test_step_and_read_registers() {
+        let mut machine = machine_from_asm(PROGRAM);
+        let replies = session(&mut machine, &["?", "s", "s", "p1", "p20", "k"]);
+        assert_eq!(replies, vec!("S05", "S05", "S05", "08000000", "20000000"));
+    }
+
+    #[test]
+    fn test_breakpoint_and_continue() {
+        let mut machine = machine_from_asm(PROGRAM);
+        let replies = session(&mut machine,
+                              &["Z0,30,4", "c", "p20", "m100,4", "z0,30,4", "c", "k"]);
+        assert_eq!(replies, vec!("OK", "S05", "30000000", "08000000", "OK", "W05"));
+    }
+
+    #[test]
+    fn test_breakpoint_on_long() {
+        // The packet with the breakpoint starts with an instruction taking
+        // a long, whose constant isn't an instruction itself.
+        let mut machine = machine_from_asm("
+            { r2 <- 0x100; }
+            { r0 <- long; long 0x3c00; r1 <- 3; }
+            { *l(r2) <- r0; }
+            { r0 <- r1 + 2; r30 <- 0; }
+            { break 0x1f; }
+        ");
+        let replies = session(&mut machine, &["Z0,10,4", "c", "p20", "c", "k"]);
+        assert_eq!(replies, vec!("OK", "S05", "10000000", "W05"));
+        assert_eq!(machine.mem.read_u32(0x100), 0x3c00);
+    }
+
+    #[test]
+    fn test_write_registers_and_memory() {
+        let mut machine = machine_from_asm(PROGRAM);
+        let replies = session(&mut machine,
+                              &["s", "P0=07000000", "M200,2:abcd", "m200,2",
+                                "c", "D"]);
+        assert_eq!(replies, vec!("S05", "OK", "OK", "abcd", "W07", "OK"));
+        assert_eq!(machine.mem.read_u32(0x100), 10);
+    }
+}
@@ -0,0 +1,561 @@
+//! A packet-at-a-time executor for Moroso images.
+//!
+//! Every instruction in a packet sees the machine state from before the
+//! packet started; all of the packet's writes land together once it's done.
+//!
+//! Traps go to the handler at `EHA`, if there is one. On a trap, `EPC` gets
+//! the address to resume at, `EC0` the cause, `EC1` the interrupt or
+//! syscall number and `EC2` the old `PFLAGS`, and interrupts are disabled.
+//! `eret` jumps back to `EPC` and puts `PFLAGS` back from `EC2`.
+
+use mas::ast::*;
+use mas::decoder::decode_packet;
+use mas::encoder::encode;
+use mas::scheduler::pred;
+use mas::util::ror;
+use sim::device::{Device, InterruptLine};
+
+use std::collections::BTreeMap;
+
+#[cfg(test)]
+use mas::labels::resolve_labels;
+#[cfg(test)]
+use mas::lexer::asm_lexer_from_str;
+#[cfg(test)]
+use mas::parser::AsmParser;
+
+/// The break number the runtime uses to ask the simulator for a service.
+/// Which service is selected by the value in r30; see `lib/prelude.ma`.
+pub static MAGIC_BREAK: u32 = 0x1f;
+
+/// Interrupts are only delivered while this bit of `PFLAGS` is set.
+pub static PFLAGS_INT_ENABLE: u32 = 1;
+
+/// Trap causes, as left in `EC0`.
+pub static TRAP_INTERRUPT: u32 = 1;
+pub static TRAP_SYSCALL: u32 = 2;
+
+static PAGE_SIZE: u32 = 4096;
+
+/// Sparse, byte-addressed, little-endian memory.
+pub struct Memory {
+    pages: BTreeMap<u32, Vec<u8>>,
+}
+
+impl Memory {
+    pub fn new() -> Memory {
+        Memory { pages: BTreeMap::new() }
+    }
+
+    pub fn read_u8(&self, addr: u32) -> u8 {
+        match self.pages.get(&(addr / PAGE_SIZE)) {
+            Some(page) => page[(addr % PAGE_SIZE) as usize],
+            None => 0,
+        }
+    }
+
+    pub fn write_u8(&mut self, addr: u32, val: u8) {
+        let page_num = addr / PAGE_SIZE;
+        if !self.pages.contains_key(&page_num) {
+            self.pages.insert(page_num, vec!(0u8; PAGE_SIZE as usize));
+        }
+        self.pages.get_mut(&page_num).unwrap()[(addr % PAGE_SIZE) as usize] = val;
+    }
+
+    pub fn read(&self, addr: u32, bytes: u32) -> u32 {
+        let mut result = 0;
+        for i in 0 .. bytes {
+            result |= (self.read_u8(addr.wrapping_add(i)) as u32) << ((i * 8) as usize);
+        }
+        result
+    }
+
+    pub fn write(&mut self, addr: u32, bytes: u32, val: u32) {
+        for i in 0 .. bytes {
+            self.write_u8(addr.wrapping_add(i), (val >> ((i * 8) as usize)) as u8);
+        }
+    }
+
+    pub fn read_u32(&self, addr: u32) -> u32 {
+        self.read(addr, 4)
+    }
+
+    pub fn write_u32(&mut self, addr: u32, val: u32) {
+        self.write(addr, 4, val)
+    }
+}
+
+/// Why the machine stopped running.
+#[derive(Clone, Eq, PartialEq, Debug)]
+pub enum Stop {
+    /// The program finished; this is the value left in r0.
+    Exit(u32),
+    /// A `break` other than the runtime's magic one. The packet containing
+    /// it has not been executed, and the pc still points at it.
+    Breakpoint,
+    /// The program called `debug_break()`.
+    DebugBreak,
+    /// The packet at the pc doesn't decode.
+    IllegalInstruction,
+    /// A `syscall` with no trap handler.
+    Syscall(u32),
+}
+
+// A write that an instruction wants to make once the packet is done.
+enum Effect {
+    RegWrite(Reg, u32),
+    PredWrite(u8, bool),
+    OvfWrite(u32),
+    CoRegWrite(CoReg, u32),
+    MemWrite(u32, u32, u32), // address, bytes, value
+    Jump(u32),
+}
+
+pub fn lsu_bytes(width: LsuWidth) -> u32 {
+    match width {
+        LsuWidthB => 1,
+        LsuWidthH => 2,
+        LsuWidthL |
+        LsuLLSC => 4,
+    }
+}
+
+fn shift(shifttype: ShiftType, val: u32, amt: u32) -> u32 {
+    match shifttype {
+        SllShift => if amt >= 32 { 0 } else { val << (amt as usize) },
+        SrlShift => if amt >= 32 { 0 } else { val >> (amt as usize) },
+        SraShift => ((val as i32) >> (if amt >= 32 { 31 } else { amt } as usize)) as u32,
+        RorShift => ror(val, (amt % 32) as u8),
+    }
+}
+
+pub fn alu(op: AluOp, rs: u32, op2: u32) -> u32 {
+    match op {
+        AddAluOp => rs.wrapping_add(op2),
+        AndAluOp => rs & op2,
+        NorAluOp => !(rs | op2),
+        OrAluOp => rs | op2,
+        SubAluOp => rs.wrapping_sub(op2),
+        RsbAluOp => op2.wrapping_sub(rs),
+        XorAluOp => rs ^ op2,
+        MovAluOp => op2,
+        MvnAluOp => !op2,
+        SxbAluOp => (op2 as i8) as i32 as u32,
+        SxhAluOp => (op2 as i16) as i32 as u32,
+        CompareAluOp => panic!("Compares don't produce a register value"),
+    }
+}
+
+pub fn compare(comparetype: CompareType, rs: u32, op2: u32) -> bool {
+    match comparetype {
+        CmpLTU => rs < op2,
+        CmpLEU => rs <= op2,
+        CmpEQ => rs == op2,
+        CmpRESERVED => false,
+        CmpLTS => (rs as i32) < (op2 as i32),
+        CmpLES => (rs as i32) <= (op2 as i32),
+        CmpBS => rs & op2 != 0,
+        CmpBC => rs & op2 == 0,
+    }
+}
+
+// Short immediates are stored rotated left by twice the rotate amount.
+fn short_val(val: u32, rot: u8) -> u32 {
+    ror(val, rot * 2)
+}
+
+fn long_val(packet: &InstPacket, slot: usize) -> u32 {
+    match packet[slot + 1] {
+        LongInst(Immediate(val)) => val,
+        _ => panic!("Missing long after slot {}", slot),
+    }
+}
+
+pub struct Machine {
+    pub regs: [u32; 32],
+    // p3 is hardwired to true.
+    pub preds: [bool; 4],
+    pub ovf: u32,
+    pub coregs: BTreeMap<CoReg, u32>,
+    pub pc: u32,
+    pub mem: Memory,
+    /// Number of packets executed so far.
+    pub cycles: u64,
+    /// Anything the program printed through the runtime that hasn't been
+    /// collected with `take_output` yet.
+    pub output: Vec<u8>,
+    pub interrupts: InterruptLine,
+    // Each device, and the interrupt it's wired to.
+    devices: Vec<(Box<Device>, Option<u32>)>,
+}
+
+impl Machine {
+    pub fn new() -> Machine {
+        Machine {
+            regs: [0; 32],
+            preds: [false, false, false, true],
+            ovf: 0,
+            coregs: BTreeMap::new(),
+            pc: 0,
+            mem: Memory::new(),
+            cycles: 0,
+            output: vec!(),
+            interrupts: InterruptLine::new(),
+            devices: vec!(),
+        }
+    }
+
+    pub fn add_device(&mut self, device: Box<Device>, irq: Option<u32>) {
+        self.devices.push((device, irq));
+    }
+
+    /// Load an image produced by `mbc --target asm`. Both the flat and the
+    /// bs-ld formats are understood; flat images are placed at `code_start`.
+    /// Returns the address the code was placed at.
+    pub fn load_image(&mut self, image: &[u8], code_start: u32) -> u32 {
+        let word = |i: usize| -> u32 {
+            (image[i] as u32) | ((image[i + 1] as u32) << 8) |
+            ((image[i + 2] as u32) << 16) | ((image[i + 3] as u32) << 24)
+        };
+
+        let (body, start, entry) = if image.len() >= 24 && &image[0..4] == &b"MROE"[..] {
+            let binary_size = word(4) as usize;
+            let binary_start = word(12);
+            let entry = word(20);
+            (&image[24 .. 24 + binary_size], binary_start, entry)
+        } else {
+            (image, code_start, code_start)
+        };
+
+        for (i, &b) in body.iter().enumerate() {
+            self.mem.write_u8(start + i as u32, b);
+        }
+        self.pc = entry;
+        start
+    }
+
+    pub fn take_output(&mut self) -> Vec<u8> {
+        let mut output = vec!();
+        ::std::mem::swap(&mut output, &mut self.output);
+        output
+    }
+
+    pub fn fetch(&self, addr: u32) -> [u32; 4] {
+        [self.mem.read_u32(addr),
+         self.mem.read_u32(addr + 4),
+         self.mem.read_u32(addr + 8),
+         self.mem.read_u32(addr + 12)]
+    }
+
+    /// The decoded packet at the pc, if it decodes.
+    pub fn current_packet(&self) -> Option<InstPacket> {
+        decode_packet(&self.fetch(self.pc))
+    }
+
+    pub fn coreg(&self, coreg: CoReg) -> u32 {
+        *self.coregs.get(&coreg).unwrap_or(&0)
+    }
+
+    pub fn pred_holds(&self, pred: Pred) -> bool {
+        self.preds[pred.reg as usize] != pred.inverted
+    }
+
+    pub fn load(&mut self, addr: u32, bytes: u32) -> u32 {
+        if self.interrupts.contains(addr) {
+            let offset = addr - self.interrupts.range().0;
+            return self.interrupts.read(offset, bytes);
+        }
+        for &mut (ref mut device, _) in self.devices.iter_mut() {
+            if device.contains(addr) {
+                let offset = addr - device.range().0;
+                return device.read(offset, bytes);
+            }
+        }
+        self.mem.read(addr, bytes)
+    }
+
+    pub fn store(&mut self, addr: u32, bytes: u32, val: u32) {
+        if self.interrupts.contains(addr) {
+            let offset = addr - self.interrupts.range().0;
+            return self.interrupts.write(offset, bytes, val);
+        }
+        for &mut (ref mut device, _) in self.devices.iter_mut() {
+            if device.contains(addr) {
+                let offset = addr - device.range().0;
+                return device.write(offset, bytes, val);
+            }
+        }
+        self.mem.write(addr, bytes, val)
+    }
+
+    fn trap(&mut self, cause: u32, num: u32, epc: u32) {
+        let pflags = self.coreg(PFLAGS);
+        self.coregs.insert(EPC, epc);
+        self.coregs.insert(EC0, cause);
+        self.coregs.insert(EC1, num);
+        self.coregs.insert(EC2, pflags);
+        self.coregs.insert(PFLAGS, pflags & !PFLAGS_INT_ENABLE);
+        self.pc = self.coreg(EHA);
+    }
+
+    /// If an interrupt can be delivered, send the machine to the trap
+    /// handler. Returns whether it did.
+    pub fn take_interrupt(&mut self) -> bool {
+        if self.coreg(PFLAGS) & PFLAGS_INT_ENABLE == 0 || self.coreg(EHA) == 0 {
+            return false;
+        }
+        match self.interrupts.next() {
+            Some(irq) => {
+                let pc = self.pc;
+                self.trap(TRAP_INTERRUPT, irq, pc);
+                true
+            },
+            None => false,
+        }
+    }
+
+    // Handle a call into the runtime. Returns whether to stop.
+    fn magic_break(&mut self) -> Option<Stop> {
+        match self.regs[30] {
+            0 => Some(Stop::Exit(self.regs[0])),
+            1 => {
+                let line = format!("{}\n", self.regs[0] as i32);
+                self.output.extend(line.bytes());
+                None
+            },
+            2 => {
+                self.output.push(self.regs[0] as u8);
+                None
+            },
+            3 => Some(Stop::DebugBreak),
+            _ => None,
+        }
+    }
+
+    /// Execute the packet at the pc.
+    pub fn step(&mut self) -> Option<Stop> {
+        self.take_interrupt();
+        let packet = match self.current_packet() {
+            Some(packet) => packet,
+            None => return Some(Stop::IllegalInstruction),
+        };
+        self.execute(&packet)
+    }
+
+    /// Execute `packet` as if it were the packet at the pc.
+    pub fn execute(&mut self, packet: &InstPacket) -> Option<Stop> {
+        // Breakpoints stop us before anything in the packet happens.
+        for inst in packet.iter() {
+            match *inst {
+                BreakInst(pred, val) if val != MAGIC_BREAK && self.pred_holds(pred) =>
+                    return Some(Stop::Breakpoint),
+                _ => {},
+            }
+        }
+
+        let pc = self.pc;
+        let mut effects = vec!();
+        let mut stop = None;
+        let mut magic = false;
+        let mut syscall = None;
+
+        let regs = self.regs;
+        let r = |reg: Reg| regs[reg.index as usize];
+
+        for (slot, inst) in packet.iter().enumerate() {
+            match *inst {
+                NopInst |
+                LongInst(..) => continue,
+                _ => {},
+            }
+            if !self.pred_holds(pred(inst).unwrap()) {
+                continue;
+            }
+
+            match *inst {
+                ALU1ShortInst(_, op, rd, val, rot) =>
+                    effects.push(Effect::RegWrite(rd, alu(op, 0, short_val(val, rot)))),
+                ALU2ShortInst(_, op, rd, rs, val, rot) =>
+                    effects.push(Effect::RegWrite(rd, alu(op, r(rs), short_val(val, rot)))),
+                ALU1RegInst(_, op, rd, rt, shifttype, amt) =>
+                    effects.push(Effect::RegWrite(
+                        rd, alu(op, 0, shift(shifttype, r(rt), amt as u32)))),
+                ALU2RegInst(_, op, rd, rs, rt, shifttype, amt) =>
+                    effects.push(Effect::RegWrite(
+                        rd, alu(op, r(rs), shift(shifttype, r(rt), amt as u32)))),
+                ALU2LongInst(_, op, rd, rs) =>
+                    effects.push(Effect::RegWrite(rd, alu(op, r(rs), long_val(packet, slot)))),
+                ALU1LongInst(_, op, rd) =>
+                    effects.push(Effect::RegWrite(rd, alu(op, 0, long_val(packet, slot)))),
+                ALU1RegShInst(_, rd, op, rt, shifttype, rs) =>
+                    effects.push(Effect::RegWrite(
+                        rd, alu(op, 0, shift(shifttype, r(rt), r(rs))))),
+                LoadInst(_, lsuop, rd, rs, offs) => {
+                    let addr = r(rs).wrapping_add(offs as u32);
+                    let val = self.load(addr, lsu_bytes(lsuop.width));
+                    effects.push(Effect::RegWrite(rd, val));
+                },
+                StoreInst(_, lsuop, rs, offs, rt) =>
+                    effects.push(Effect::MemWrite(r(rs).wrapping_add(offs as u32),
+                                                  lsu_bytes(lsuop.width), r(rt))),
+                CompareShortInst(_, dest, rs, comparetype, val, rot) =>
+                    effects.push(Effect::PredWrite(
+                        dest.reg, compare(comparetype, r(rs), short_val(val, rot)))),
+                CompareRegInst(_, dest, rs, comparetype, rt, shifttype, amt) =>
+                    effects.push(Effect::PredWrite(
+                        dest.reg, compare(comparetype, r(rs),
+                                          shift(shifttype, r(rt), amt as u32)))),
+                CompareLongInst(_, dest, rs, comparetype) =>
+                    effects.push(Effect::PredWrite(
+                        dest.reg, compare(comparetype, r(rs), long_val(packet, slot)))),
+                BranchImmInst(_, link, ref target) => {
+                    let offs = match *target {
+                        JumpOffs(offs) => offs,
+                        JumpLabel(ref label) => panic!("Unresolved label {}", label),
+                    };
+                    if link {
+                        effects.push(Effect::RegWrite(LINK_REG, pc));
+                    }
+                    effects.push(Effect::Jump(pc.wrapping_add((offs * 16) as u32)));
+                },
+                BranchRegInst(_, link, rs, offs) => {
+                    if link {
+                        effects.push(Effect::RegWrite(LINK_REG, pc));
+                    }
+                    effects.push(Effect::Jump(r(rs).wrapping_add((offs * 16) as u32)));
+                },
+                BreakInst(..) => magic = true,
+                SyscallInst(_, val) => syscall = Some(val),
+                MtcInst(_, coreg, rs) => effects.push(Effect::CoRegWrite(coreg, r(rs))),
+                MfcInst(_, rd, coreg) => effects.push(Effect::RegWrite(rd, self.coreg(coreg))),
+                EretInst(..) => {
+                    effects.push(Effect::CoRegWrite(PFLAGS, self.coreg(EC2)));
+                    effects.push(Effect::Jump(self.coreg(EPC)));
+                },
+                FenceInst(..) |
+                FlushInst(..) => {},
+                MthiInst(_, rs) => effects.push(Effect::OvfWrite(r(rs))),
+                MfhiInst(_, rd) => effects.push(Effect::RegWrite(rd, self.ovf)),
+                MultInst(_, signed, rd, rs, rt) => {
+                    let product = if signed {
+                        ((r(rs) as i32 as i64) * (r(rt) as i32 as i64)) as u64
+                    } else {
+                        (r(rs) as u64) * (r(rt) as u64)
+                    };
+                    effects.push(Effect::RegWrite(rd, product as u32));
+                    effects.push(Effect::OvfWrite((product >> 32) as u32));
+                },
+                DivInst(_, signed, rd, rs, rt) => {
+                    // Division by zero gives all ones, with the dividend
+                    // left over as the remainder.
+                    let (quot, rem) = if r(rt) == 0 {
+                        (!0u32, r(rs))
+                    } else if signed {
+                        let (a, b) = (r(rs) as i32, r(rt) as i32);
+                        (a.wrapping_div(b) as u32, a.wrapping_rem(b) as u32)
+                    } else {
+                        (r(rs) / r(rt), r(rs) % r(rt))
+                    };
+                    effects.push(Effect::RegWrite(rd, quot));
+                    effects.push(Effect::OvfWrite(rem));
+                },
+                NopInst |
+                LongInst(..) => {},
+                PacketsInst(..) => panic!("Packets pseudo-instruction in an image"),
+            }
+        }
+
+        let mut next_pc = pc.wrapping_add(16);
+        for effect in effects.into_iter() {
+            match effect {
+                Effect::RegWrite(reg, val) => self.regs[reg.index as usize] = val,
+                Effect::PredWrite(reg, val) => if reg != 3 { self.preds[reg as usize] = val },
+                Effect::OvfWrite(val) => self.ovf = val,
+                Effect::CoRegWrite(coreg, val) => { self.coregs.insert(coreg, val); },
+                Effect::MemWrite(addr, bytes, val) => self.store(addr, bytes, val),
+                Effect::Jump(target) => next_pc = target,
+            }
+        }
+        self.pc = next_pc;
+        self.cycles += 1;
+
+        for &mut (ref mut device, irq) in self.devices.iter_mut() {
+            if device.tick() {
+                match irq {
+                    Some(irq) => self.interrupts.raise(irq),
+                    None => {},
+                }
+            }
+        }
+
+        // Syscalls return to the packet after the one that made them.
+        match syscall {
+            Some(num) if self.coreg(EHA) != 0 => self.trap(TRAP_SYSCALL, num, next_pc),
+            Some(num) => stop = Some(Stop::Syscall(num)),
+            None => {},
+        }
+
+        // The runtime sets r30 up in an earlier packet, so the service we
+        // provide sees the registers as they are after this one.
+        if magic {
+            stop = self.magic_break();
+        }
+
+        stop
+    }
+
+    /// Run until the machine stops, or until `max_cycles` packets have run.
+    pub fn run(&mut self, max_cycles: Option<u64>) -> Option<Stop> {
+        loop {
+            match max_cycles {
+                Some(max) if self.cycles >= max => return None,
+                _ => {},
+            }
+            match self.step() {
+                Some(stop) => return Some(stop),
+                None => {},
+            }
+        }
+    }
+
+    /// Replace the packet at `addr` with one that only breaks, returning the
+    /// words that were there. Overwriting a single slot could leave the long
+    /// of the instruction it held to be decoded as an instruction.
+    pub fn insert_breakpoint(&mut self, addr: u32) -> [u32; 4] {
+        let orig = self.fetch(addr);
+        let brk = encode(&InstNode::breaknum(TRUE_PRED, 0));
+        let nop = encode(&NopInst);
+        self.store_packet(addr, &[brk, nop, nop, nop]);
+        orig
+    }
+
+    pub fn remove_breakpoint(&mut self, addr: u32, orig: [u32; 4]) {
+        self.store_packet(addr, &orig);
+    }
+
+    fn store_packet(&mut self, addr: u32, words: &[u32; 4]) {
+        for (i, &word) in words.iter().enumerate() {
+            self.mem.write_u32(addr + 4 * i as u32, word);
+        }
+    }
+}
+
+/// Assemble `src` and load it at address 0.
+#[cfg(test)]
+pub fn machine_from_asm(src: &str) -> Machine {
+    let mut parser = AsmParser::new(asm_lexer_from_str(src).peekable());
+    let (mut packets, labels) = parser.parse_toplevel();
+    resolve_labels(&mut packets, &labels, 0);
+    let mut image = vec!();
+    for packet in packets.iter() {
+        for inst in packet.iter() {
+            let word = encode(inst);
+            for i in 0 .. 4 {
+                image.push((word >> (i * 8)) as u8);
+            }
+        }
+    }
+    let mut machine = Machine::new();
+    machine.load_image(&image, 0);
+    machine
+}
@@ -0,0 +1,3 @@
+extern crate moroso;
+#[cfg(not(test))]
+fn main() { moroso::sim::main() }
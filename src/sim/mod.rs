@@ -0,0 +1,169 @@
+use std::{process, io, env};
+use std::path::Path;
+use std::fs::File;
+use std::io::{Read, Write};
+use std::net::TcpListener;
+use std::os::unix::net::UnixListener;
+
+use getopts;
+use getopts::{getopts, optopt, optflag};
+
+use self::device::{Uart, Timer, UART_IRQ, TIMER_IRQ};
+use self::machine::{Machine, Stop};
+use self::gdb::GdbStub;
+use self::trace::{Labels, Tracer};
+
+pub mod machine;
+pub mod device;
+pub mod gdb;
+pub mod trace;
+
+// How many packets to run between flushes of the program's output.
+static RUN_CHUNK: u64 = 10000;
+
+fn serve_gdb(machine: &mut Machine, addr: &str) -> io::Result<()> {
+    if addr.starts_with("unix:") {
+        let listener = try!(UnixListener::bind(&addr[5..]));
+        let (conn, _) = try!(listener.accept());
+        GdbStub::new(conn, machine).serve()
+    } else {
+        let port: u16 = match addr.parse() {
+            Ok(port) => port,
+            Err(_) => return Err(io::Error::new(io::ErrorKind::InvalidInput,
+                                                "bad port number")),
+        };
+        let listener = try!(TcpListener::bind(("127.0.0.1", port)));
+        let (conn, _) = try!(listener.accept());
+        GdbStub::new(conn, machine).serve()
+    }
+}
+
+pub fn main() {
+    let args: Vec<String> = env::args().collect();
+    let arg0 = &args[0];
+
+    let opts = [
+        optopt("", "gdb", "Wait for a debugger on a local TCP port or a Unix socket",
+               "<port|unix:/path/to/socket>"),
+        optopt("", "code_start", "Address in hex to load flat images at",
+               "<hex value, no 0x>"),
+        optopt("", "trace", "Log every executed packet to a file (- for stdout)",
+               "<filename>"),
+        optflag("", "stats", "Print execution statistics when the program stops"),
+        optopt("", "debug", "Debug file from mbc --debug, for function names",
+               "<filename>"),
+        optflag("", "devices", "Attach a UART on stdin and stdout, and a timer"),
+        optopt("", "max_cycles", "Give up after running this many packets", "<n>"),
+        optflag("h", "help", "Show this help message."),
+    ];
+
+    let bail = |error: Option<&str>| {
+        let error = match error {
+            Some(e) => {
+                println!("{}: fatal error: {}", arg0, e);
+                1
+            }
+            None => 0,
+        };
+
+        let brief = format!("Usage: {} [OPTIONS] <image>", arg0);
+        println!("{}", getopts::usage(&brief[..], &opts));
+        process::exit(error)
+    };
+
+    let matches = match getopts(&args[1..], &opts) {
+        Ok(m) => m,
+        Err(e) => return bail(Some(&format!("{}", e)[..])),
+    };
+
+    if matches.opt_present("help") {
+        return bail(None);
+    }
+
+    if matches.free.len() != 1 {
+        return bail(Some("expected exactly one image"));
+    }
+
+    let code_start = match matches.opt_str("code_start") {
+        Some(s) => u32::from_str_radix(&s[..], 16).unwrap(),
+        None => 0,
+    };
+    let max_cycles = matches.opt_str("max_cycles").map(|s| s.parse::<u64>().unwrap());
+
+    if matches.opt_present("gdb") &&
+       (matches.opt_present("trace") || matches.opt_present("stats")) {
+        return bail(Some("--trace and --stats can't be used with --gdb"));
+    }
+
+    let read_file = |name: &String| {
+        let mut data = vec!();
+        let path = Path::new(name);
+        File::open(&path).and_then(|mut f| f.read_to_end(&mut data))
+            .unwrap_or_else(|e| panic!("{}", e));
+        data
+    };
+
+    let image = read_file(&matches.free[0]);
+    let mut machine = Machine::new();
+    let code_start = machine.load_image(&image, code_start);
+    if matches.opt_present("devices") {
+        machine.add_device(Box::new(Uart::new(Box::new(io::stdin()), Box::new(io::stdout()))),
+                           Some(UART_IRQ));
+        machine.add_device(Box::new(Timer::new()), Some(TIMER_IRQ));
+    }
+
+    let labels = match matches.opt_str("debug") {
+        Some(name) => Labels::from_debug_file(&read_file(&name), code_start),
+        None => Labels::new(),
+    };
+    let trace = matches.opt_str("trace").map(|name| -> Box<Write> {
+        if name == "-" {
+            Box::new(io::stdout())
+        } else {
+            let path = Path::new(&name);
+            Box::new(File::create(&path).unwrap_or_else(|e| panic!("{}", e)))
+        }
+    });
+    let mut tracer = Tracer::new(labels, trace);
+
+    match matches.opt_str("gdb") {
+        Some(addr) => {
+            serve_gdb(&mut machine, &addr[..]).unwrap_or_else(|e| panic!("{}", e));
+            io::stdout().write_all(&machine.take_output()).ok();
+        },
+        None => {
+            let mut stdout = io::stdout();
+            let mut stop;
+            loop {
+                // Run in chunks, so that output shows up as it's produced.
+                let limit = match max_cycles {
+                    Some(max) if max < machine.cycles + RUN_CHUNK => max,
+                    _ => machine.cycles + RUN_CHUNK,
+                };
+                stop = tracer.run(&mut machine, Some(limit));
+                stdout.write_all(&machine.take_output()).ok();
+                match stop {
+                    Some(Stop::DebugBreak) => {},
+                    Some(_) => break,
+                    None => if max_cycles.map_or(false, |max| machine.cycles >= max) {
+                        break;
+                    },
+                }
+            }
+            if matches.opt_present("stats") {
+                tracer.stats.print(&mut io::stderr());
+            }
+            match stop {
+                Some(Stop::Exit(_)) => {},
+                Some(stop) => {
+                    println!("{}: stopped at 0x{:08x}: {:?}", arg0, machine.pc, stop);
+                    process::exit(1);
+                },
+                None => {
+                    println!("{}: gave up after {} packets", arg0, machine.cycles);
+                    process::exit(1);
+                },
+            }
+        },
+    }
+}
@@ -0,0 +1,319 @@
+//! Execution tracing and statistics, for measuring what the scheduler
+//! actually produces.
+
+use mas::ast::*;
+use mas::scheduler::{destreg, destpred, pred};
+use sim::machine::{Machine, Stop};
+
+use std::collections::BTreeMap;
+use std::io::Write;
+
+/// Function names, read from the debug file `mbc --debug` writes.
+pub struct Labels {
+    // Sorted by address.
+    labels: Vec<(u32, String)>,
+}
+
+impl Labels {
+    pub fn new() -> Labels {
+        Labels { labels: vec!() }
+    }
+
+    /// Parse a debug file for an image whose code starts at `code_start`.
+    pub fn from_debug_file(data: &[u8], code_start: u32) -> Labels {
+        let word = |i: usize| -> u32 {
+            (data[i] as u32) | ((data[i + 1] as u32) << 8) |
+            ((data[i + 2] as u32) << 16) | ((data[i + 3] as u32) << 24)
+        };
+
+        if data.len() < 12 || &data[0..4] != &b"MROD"[..] || &data[4..8] != &b"LBEL"[..] {
+            panic!("Not a debug file");
+        }
+
+        let count = word(8);
+        let mut labels = vec!();
+        let mut pos = 12;
+        for _ in 0 .. count {
+            let packet = word(pos);
+            let len = word(pos + 4) as usize;
+            // The name is NUL-terminated, and the NUL is counted in len.
+            let name = String::from_utf8_lossy(&data[pos + 8 .. pos + 8 + len - 1]);
+            labels.push((code_start + packet * 16, name.into_owned()));
+            pos += 8 + len;
+        }
+        labels.sort();
+
+        Labels { labels: labels }
+    }
+
+    /// The label at or most closely preceding `addr`, and the offset from it.
+    pub fn lookup(&self, addr: u32) -> Option<(&str, u32)> {
+        let idx = match self.labels.binary_search_by(|&(a, _)| a.cmp(&addr)) {
+            Ok(mut idx) => {
+                // Several labels can share an address; use the last one,
+                // which is the one closest to the code.
+                while idx + 1 < self.labels.len() && self.labels[idx + 1].0 == addr {
+                    idx += 1;
+                }
+                idx
+            },
+            Err(0) => return None,
+            Err(idx) => idx - 1,
+        };
+        let (label_addr, ref name) = self.labels[idx];
+        Some((&name[..], addr - label_addr))
+    }
+}
+
+pub struct Stats {
+    /// Packets executed.
+    pub cycles: u64,
+    /// Number of packets executed with each number of non-nop slots.
+    pub slots_used: [u64; 5],
+    /// Branches whose predicate held.
+    pub branches_taken: u64,
+    /// Branches whose predicate was false.
+    pub branches_not_taken: u64,
+    /// Packets executed in each function, by the label preceding the pc.
+    pub func_cycles: BTreeMap<String, u64>,
+}
+
+impl Stats {
+    pub fn new() -> Stats {
+        Stats {
+            cycles: 0,
+            slots_used: [0; 5],
+            branches_taken: 0,
+            branches_not_taken: 0,
+            func_cycles: BTreeMap::new(),
+        }
+    }
+
+    pub fn print(&self, f: &mut Write) {
+        let used = self.slots_used.iter().enumerate()
+            .fold(0, |sum, (n, &count)| sum + n as u64 * count);
+        let percent = |n: u64, total: u64| {
+            if total == 0 { 0.0 } else { 100.0 * n as f64 / total as f64 }
+        };
+
+        write!(f, "cycles: {}\n", self.cycles).ok();
+        write!(f, "slot utilisation: {}/{} ({:.1}%)\n",
+               used, self.cycles * 4, percent(used, self.cycles * 4)).ok();
+        for (n, &count) in self.slots_used.iter().enumerate() {
+            write!(f, "  {} slots: {} packets ({:.1}%)\n",
+                   n, count, percent(count, self.cycles)).ok();
+        }
+        let branches = self.branches_taken + self.branches_not_taken;
+        write!(f, "branches: {} ({} taken, {} not taken)\n",
+               branches, self.branches_taken, self.branches_not_taken).ok();
+
+        let mut funcs: Vec<(&String, &u64)> = self.func_cycles.iter().collect();
+        funcs.sort_by(|a, b| b.1.cmp(a.1));
+        write!(f, "cycles by function:\n").ok();
+        for (name, &count) in funcs.into_iter() {
+            write!(f, "  {:>10} {:5.1}%  {}\n", count, percent(count, self.cycles), name).ok();
+        }
+    }
+}
+
+fn is_branch(inst: &InstNode) -> bool {
+    match *inst {
+        BranchImmInst(..) |
+        BranchRegInst(..) => true,
+        _ => false,
+    }
+}
+
+fn links(inst: &InstNode) -> bool {
+    match *inst {
+        BranchImmInst(_, link, _) |
+        BranchRegInst(_, link, _, _) => link,
+        _ => false,
+    }
+}
+
+fn writes_ovf(inst: &InstNode) -> bool {
+    match *inst {
+        MultInst(..) |
+        DivInst(..) |
+        MthiInst(..) => true,
+        _ => false,
+    }
+}
+
+/// Steps a machine, keeping statistics and optionally logging every packet.
+pub struct Tracer {
+    labels: Labels,
+    trace: Option<Box<Write>>,
+    pub stats: Stats,
+}
+
+impl Tracer {
+    pub fn new(labels: Labels, trace: Option<Box<Write>>) -> Tracer {
+        Tracer {
+            labels: labels,
+            trace: trace,
+            stats: Stats::new(),
+        }
+    }
+
+    fn location(&self, addr: u32) -> String {
+        match self.labels.lookup(addr) {
+            Some((name, 0)) => format!("<{}>", name),
+            Some((name, offs)) => format!("<{}+0x{:x}>", name, offs),
+            None => "".to_string(),
+        }
+    }
+
+    /// Execute the packet at the machine's pc.
+    pub fn step(&mut self, machine: &mut Machine) -> Option<Stop> {
+        if machine.take_interrupt() && self.trace.is_some() {
+            let line = format!("         interrupt {} -> 0x{:08x} {}",
+                               machine.coreg(EC1), machine.pc, self.location(machine.pc));
+            write!(self.trace.as_mut().unwrap(), "{}\n", line).ok();
+        }
+        let packet = match machine.current_packet() {
+            Some(packet) => packet,
+            None => return machine.step(),
+        };
+
+        let pc = machine.pc;
+        let cycle = machine.cycles;
+        // Predicates are evaluated against the state before the packet.
+        let holds: Vec<bool> = packet.iter().map(|inst| {
+            match pred(inst) {
+                Some(p) => machine.pred_holds(p),
+                None => false,
+            }
+        }).collect();
+
+        let stop = machine.execute(&packet);
+        if machine.cycles == cycle {
+            // A breakpoint; nothing ran.
+            return stop;
+        }
+
+        self.stats.cycles += 1;
+        let used = packet.iter().filter(|inst| match **inst {
+            NopInst => false,
+            _ => true,
+        }).count();
+        self.stats.slots_used[used] += 1;
+        for (inst, &held) in packet.iter().zip(holds.iter()) {
+            if is_branch(inst) {
+                if held {
+                    self.stats.branches_taken += 1;
+                } else {
+                    self.stats.branches_not_taken += 1;
+                }
+            }
+        }
+        let func = match self.labels.lookup(pc) {
+            Some((name, _)) => name.to_string(),
+            None => "<unknown>".to_string(),
+        };
+        *self.stats.func_cycles.entry(func).or_insert(0) += 1;
+
+        if self.trace.is_some() {
+            let mut line = format!("{:>8} 0x{:08x} {}", cycle, pc, self.location(pc));
+            let mut skipped = vec!();
+            for (inst, &held) in packet.iter().zip(holds.iter()) {
+                match *inst {
+                    NopInst |
+                    LongInst(..) => continue,
+                    _ => {},
+                }
+                if !held {
+                    skipped.push(inst);
+                    continue;
+                }
+                // Everything in the packet has been committed, so these
+                // are the values the packet wrote.
+                match destreg(inst) {
+                    Some(reg) => line.push_str(
+                        &format!(" {}=0x{:x}", reg, machine.regs[reg.index as usize])),
+                    None => {},
+                }
+                if links(inst) {
+                    line.push_str(&format!(" {}=0x{:x}", LINK_REG,
+                                           machine.regs[LINK_REG.index as usize]));
+                }
+                match destpred(inst) {
+                    Some(p) => line.push_str(
+                        &format!(" {}={}", p, machine.preds[p.reg as usize] as u8)),
+                    None => {},
+                }
+                if writes_ovf(inst) {
+                    line.push_str(&format!(" ovf=0x{:x}", machine.ovf));
+                }
+            }
+            let trace = self.trace.as_mut().unwrap();
+            write!(trace, "{}\n", line).ok();
+            for inst in skipped.into_iter() {
+                write!(trace, "                    skipped: {}\n", inst).ok();
+            }
+        }
+
+        stop
+    }
+
+    /// Run until the machine stops, or until `max_cycles` packets have run.
+    pub fn run(&mut self, machine: &mut Machine, max_cycles: Option<u64>) -> Option<Stop> {
+        loop {
+            match max_cycles {
+                Some(max) if machine.cycles >= max => return None,
+                _ => {},
+            }
+            match self.step(machine) {
+                Some(stop) => return Some(stop),
+                None => {},
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Labels, Tracer};
+    use sim::machine::{Stop, machine_from_asm};
+
+    #[test]
+    fn test_debug_file_labels() {
+        let mut data = b"MROD".to_vec();
+        data.extend(b"LBEL".iter());
+        data.extend([2, 0, 0, 0].iter());
+        data.extend([0, 0, 0, 0, 7, 0, 0, 0].iter());
+        data.extend(b"_start\0".iter());
+        data.extend([3, 0, 0, 0, 7, 0, 0, 0].iter());
+        data.extend(b"__main\0".iter());
+        let labels = Labels::from_debug_file(&data, 0x1000);
+
+        assert_eq!(labels.lookup(0xfff), None);
+        assert_eq!(labels.lookup(0x1000), Some(("_start", 0)));
+        assert_eq!(labels.lookup(0x1020), Some(("_start", 0x20)));
+        assert_eq!(labels.lookup(0x1030), Some(("__main", 0)));
+        assert_eq!(labels.lookup(0x1100), Some(("__main", 0xd0)));
+    }
+
+    #[test]
+    fn test_stats() {
+        // Counts r0 down from 3; the loop branch is taken twice.
+        let mut machine = machine_from_asm(
+            "{ r0 <- 3; r1 <- 0; }
+             loop: { r0 <- r0 - 1; p0 <- r0 == 1; r1 <- r1 + 1; }
+             { !p0? b loop; }
+             { r30 <- 0; }
+             { break 0x1f; }");
+        let mut tracer = Tracer::new(Labels::new(), None);
+
+        assert_eq!(tracer.run(&mut machine, Some(100)), Some(Stop::Exit(0)));
+        assert_eq!(machine.regs[1], 3);
+
+        let stats = &tracer.stats;
+        assert_eq!(stats.cycles, 9);
+        assert_eq!(stats.slots_used, [0, 5, 1, 3, 0]);
+        assert_eq!(stats.branches_taken, 2);
+        assert_eq!(stats.branches_not_taken, 1);
+        assert_eq!(stats.func_cycles.get("<unknown>"), Some(&9));
+    }
+}
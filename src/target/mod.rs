@@ -0,0 +1,20 @@
+use package::Package;
+
+pub use self::ir::IRTarget;
+pub use self::asm::AsmTarget;
+pub use self::util::{NameMangler, source_spans};
+pub use self::ccross::CTarget;
+
+use std::io::Write;
+
+mod ccross;
+mod ir;
+mod asm;
+mod util;
+
+pub trait Target {
+    fn compile(&self, p: Package, f: &mut Write);
+}
+pub trait MkTarget: Target {
+    fn new<'a>(args: &Vec<(String, Option<String>)>) -> Box<Self>;
+}
@@ -0,0 +1,173 @@
+//! Fixtures shared by the unit tests of the IR passes, the register
+//! allocators, and the passes over asm instructions.
+
+use codegen::*;
+use codegen::briggs::BriggsColorer;
+use codegen::linear_scan::LinearScanColorer;
+use ir::*;
+use ir::ast_to_intermediate::ASTToIntermediate;
+use ir::cfg::CFG;
+use ir::conflicts::ConflictAnalyzer;
+use ir::constant_fold::find_const_globals;
+use ir::interp::{check_passes, run_program};
+use ir::liveness::LivenessAnalyzer;
+use ir::passes::{PassManager, preset};
+use ir::text::{parse_program, write_function};
+use ir::verify::verify;
+use mas::ast::{Pred, Reg};
+use mc::ast::NodeId;
+use mc::difftest::run_asm;
+use mc::session::Options;
+use mc::setup_search_paths_from;
+use package::Package;
+use target::{AsmTarget, MkTarget, NameMangler, Target};
+use util::Name;
+
+use std::collections::BTreeMap;
+use std::io;
+use std::path::Path;
+
+/// `ops` in the IR's text form.
+pub fn text(ops: &Vec<Op>) -> String {
+    let mut out = vec!();
+    write_function(&mut out, ops);
+    String::from_utf8(out).unwrap()
+}
+
+/// Parse `src`, run `pass` on it, check that every function still passes
+/// `verify` (`folded` is passed along) and, with `run`, that the program
+/// prints what it did before. Returns the first function.
+pub fn run_pass<F>(src: &str, folded: bool, run: bool, pass: F) -> String
+    where F: FnOnce(&mut Vec<Vec<Op>>, &BTreeMap<Name, StaticIRItem>) {
+    let (mut program, global_map) = parse_program(src).unwrap();
+    let expected = if run {
+        let expected = run_program(&program, &global_map);
+        assert!(expected.is_ok(), "{:?}", expected);
+        Some(expected)
+    } else {
+        None
+    };
+    pass(&mut program, &global_map);
+    for ops in program.iter() {
+        assert!(verify(ops, folded).is_ok(), "{}", text(ops));
+    }
+    if let Some(expected) = expected {
+        assert_eq!(run_program(&program, &global_map), expected);
+    }
+    text(&program[0])
+}
+
+/// Lower `src` to IR, as the targets do before running passes.
+pub fn lower(src: &str) -> (Vec<Vec<Op>>, BTreeMap<Name, StaticIRItem>) {
+    let mut opts = Options::new();
+    setup_search_paths_from(&mut opts, Path::new(env!("CARGO_MANIFEST_DIR")));
+    let Package { module, session, mut typemap } =
+        Package::from_buffer(opts, "<input>", io::BufReader::new(src.as_bytes()));
+    let mangler = NameMangler::new(session, &module, true, false);
+    let mut session = mangler.session;
+    let mut sourcemap = BTreeMap::<NodeId, NodeId>::new();
+
+    let (mut program, staticitems) = {
+        let mut converter = ASTToIntermediate::new(&mut session, &mut typemap,
+                                                   &mangler.names, &mut sourcemap);
+        converter.convert_module(&module)
+    };
+    let mut global_map = ASTToIntermediate::allocate_globals(staticitems);
+    let init = {
+        let mut converter = ASTToIntermediate::new(&mut session, &mut typemap,
+                                                   &mangler.names, &mut sourcemap);
+        converter.convert_globals(&global_map)
+    };
+    program.push(init);
+    find_const_globals(&program, &mut global_map);
+    (program, global_map)
+}
+
+/// Lower `src`, check that the passes of `opt_level` don't change what it
+/// prints, and return the program they make of it.
+pub fn optimize_source(src: &str, opt_level: u32) -> Vec<Vec<Op>> {
+    let (mut program, global_map) = lower(src);
+    let passes = PassManager::new(&preset(opt_level)[..]).unwrap();
+    check_passes(&program, &global_map, &passes);
+    passes.run(&mut program, &global_map, &BTreeMap::new()).unwrap();
+    program
+}
+
+/// The function called `name` (mangled, as in `__main`) in `program`.
+pub fn find_func<'a>(program: &'a Vec<Vec<Op>>, name: &str) -> &'a Vec<Op> {
+    program.iter().filter(|ops| match ops[0].val {
+        OpNode::Func(f, _, _) => format!("{}", f) == name,
+        _ => false,
+    }).next().unwrap_or_else(|| panic!("no function {}", name))
+}
+
+/// The names of the functions `ops` calls directly, in order.
+pub fn called_functions(ops: &Vec<Op>) -> Vec<String> {
+    ops.iter().filter_map(|op| match op.val {
+        OpNode::Call(_, Variable(f), _) => Some(format!("{}", f.name)),
+        _ => None,
+    }).collect()
+}
+
+/// Compile `src` with target `T`, given `args` like `("opt_level", "2")`.
+pub fn compile_source<T: MkTarget>(src: &str, args: &[(&str, &str)]) -> Vec<u8> {
+    let mut opts = Options::new();
+    setup_search_paths_from(&mut opts, Path::new(env!("CARGO_MANIFEST_DIR")));
+    let package = Package::from_buffer(opts, "<input>", io::BufReader::new(src.as_bytes()));
+    let args = args.iter().map(|&(name, val)| (name.to_string(), Some(val.to_string())))
+                   .collect();
+    let mut out = vec!();
+    T::new(&args).compile(package, &mut out);
+    out
+}
+
+/// Compile `src` with the asm target, given `args` like `("opt_level", "2")`,
+/// and run it in the simulator. Returns what it printed.
+pub fn run_asm_source(src: &str, args: &[(&str, &str)]) -> Result<String, String> {
+    run_asm(&compile_source::<AsmTarget>(src, args), 0)
+}
+
+/// Color the first function in `src` with `regalloc`, and check that no two
+/// variables that interfere got the same register or place on the stack.
+pub fn color(src: &str, regalloc: RegAlloc, num_colors: usize)
+             -> BTreeMap<String, RegisterColor> {
+    let (program, global_map) = parse_program(src).unwrap();
+    let ops = &program[0];
+    let cfg = CFG::new(ops);
+    let opinfo = LivenessAnalyzer::analyze_cfg(ops, &cfg);
+    let (conflicts, _, must_colors, mem_vars) = ConflictAnalyzer::conflicts(ops, &opinfo);
+    let coloring = match regalloc {
+        RegAlloc::BriggsAlloc =>
+            BriggsColorer::color(ops, &cfg, conflicts.clone(), must_colors, mem_vars,
+                                 &global_map, num_colors),
+        RegAlloc::LinearAlloc =>
+            LinearScanColorer::color(ops, &opinfo, must_colors, mem_vars,
+                                     &global_map, num_colors),
+    };
+    for (a, adj) in conflicts.iter() {
+        for b in adj.iter() {
+            match coloring[a] {
+                RegColor(..) | StackColor(..) =>
+                    assert!(coloring[a] != coloring[b], "{} and {} are both {}",
+                            a, b, coloring[a]),
+                _ => {},
+            }
+        }
+    }
+    coloring.into_iter().map(|(v, c)| (format!("{}", v), c)).collect()
+}
+
+/// The predicate on `reg`, or on its negation with `inverted`.
+pub fn p(inverted: bool, reg: u8) -> Pred {
+    Pred { inverted: inverted, reg: reg }
+}
+
+/// Register `index`.
+pub fn r(index: u8) -> Reg {
+    Reg { index: index }
+}
+
+/// A label table, from (label, position) pairs.
+pub fn labels(labels: &[(&str, usize)]) -> BTreeMap<String, usize> {
+    labels.iter().map(|&(label, pos)| (label.to_string(), pos)).collect()
+}
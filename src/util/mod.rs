@@ -5,6 +5,7 @@ use std::fmt;
 
 pub mod lexer;
 pub mod graph;
+pub mod timing;
 
 /// I copied a bunch of simple unstable library functions out so I
 /// don't need to say feature(core).
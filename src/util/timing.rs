@@ -0,0 +1,39 @@
+//! How long each phase of compilation takes, for `--time-passes`.
+
+use time::precise_time_ns;
+
+use std::cell::RefCell;
+use std::io::Write;
+
+thread_local! {
+    // Phases in the order they first ran, with their total time in ns.
+    static TIMES: RefCell<Vec<(String, u64)>> = RefCell::new(vec!())
+}
+
+/// Run `f`, adding the time it takes to the total for `name`.
+pub fn time<T, F: FnOnce() -> T>(name: &str, f: F) -> T {
+    let start = precise_time_ns();
+    let result = f();
+    let elapsed = precise_time_ns() - start;
+    TIMES.with(|times| {
+        let mut times = times.borrow_mut();
+        match times.iter().position(|&(ref n, _)| n == name) {
+            Some(i) => times[i].1 += elapsed,
+            None => times.push((name.to_string(), elapsed)),
+        }
+    });
+    result
+}
+
+pub fn report(f: &mut Write) {
+    TIMES.with(|times| {
+        let times = times.borrow();
+        let total = times.iter().fold(0, |sum, &(_, t)| sum + t);
+        let secs = |t: u64| t as f64 / 1000000000f64;
+        for &(ref name, t) in times.iter() {
+            let percent = if total == 0 { 0f64 } else { t as f64 * 100f64 / total as f64 };
+            write!(f, "{:>10.6}s {:>5.1}%  {}\n", secs(t), percent, name).ok();
+        }
+        write!(f, "{:>10.6}s         total\n", secs(total)).ok();
+    });
+}
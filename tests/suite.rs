@@ -0,0 +1,133 @@
+//! Runs the programs in `test/` through every target and checks what they
+//! print.
+//!
+//! A test's expected output is the `.txt` file next to it, or failing that
+//! its `// expect: ` comments, one per line of output. Asm output is run in
+//! `moroso::sim`. C output from the c and ir targets is only built and run
+//! if `gcc -m32` works on this machine; otherwise we just check that it
+//! compiles. `mbc --difftest` is the tool for comparing backends on a
+//! single program.
+
+extern crate moroso;
+
+use moroso::mc::setup_search_paths_from;
+use moroso::mc::difftest::{compile, run_asm, run_c, have_gcc_m32};
+use moroso::mc::session::Options;
+
+use std::fs::{self, File};
+use std::io::Read;
+use std::path::{Path, PathBuf};
+
+fn root() -> PathBuf {
+    Path::new(env!("CARGO_MANIFEST_DIR")).to_path_buf()
+}
+
+fn read_to_string(path: &Path) -> String {
+    let mut s = String::new();
+    File::open(path).and_then(|mut f| f.read_to_string(&mut s))
+        .unwrap_or_else(|e| panic!("{}: {}", path.display(), e));
+    s
+}
+
+fn expected_output(test: &Path) -> Option<String> {
+    let txt = test.with_extension("txt");
+    if fs::metadata(&txt).is_ok() {
+        return Some(read_to_string(&txt));
+    }
+
+    let prefix = "// expect: ";
+    let mut expected = String::new();
+    let mut found = false;
+    for line in read_to_string(test).lines() {
+        let line = line.trim_left();
+        if line.starts_with(prefix) {
+            expected.push_str(&line[prefix.len()..]);
+            expected.push('\n');
+            found = true;
+        }
+    }
+    if found { Some(expected) } else { None }
+}
+
+fn diff(expected: &str, actual: &str) -> String {
+    let expected: Vec<&str> = expected.lines().collect();
+    let actual: Vec<&str> = actual.lines().collect();
+    let mut result = String::new();
+    for i in 0 .. ::std::cmp::max(expected.len(), actual.len()) {
+        match (expected.get(i), actual.get(i)) {
+            (Some(e), Some(a)) if e == a => result.push_str(&format!("     {}\n", e)),
+            (e, a) => {
+                if let Some(e) = e { result.push_str(&format!("    -{}\n", e)); }
+                if let Some(a) = a { result.push_str(&format!("    +{}\n", a)); }
+            },
+        }
+    }
+    result
+}
+
+#[test]
+fn test_suite() {
+    let mut tests = vec!();
+    for entry in fs::read_dir(&root().join("test")).unwrap() {
+        let path = entry.unwrap().path();
+        let name = path.file_name().unwrap().to_str().unwrap().to_string();
+        if !name.ends_with(".mb") {
+            continue;
+        }
+        if name.starts_with("test_") || name.starts_with("asm_test_") {
+            tests.push((name, path));
+        }
+    }
+    tests.sort();
+
+    let mut options = Options::new();
+    setup_search_paths_from(&mut options, &root());
+    let gcc = have_gcc_m32();
+    if !gcc {
+        println!("gcc -m32 doesn't work here; only checking that C output compiles");
+    }
+
+    let mut failures = vec!();
+    let mut ran = 0;
+    for &(ref name, ref path) in tests.iter() {
+        let expected = match expected_output(path) {
+            Some(expected) => expected,
+            None => continue,
+        };
+        let targets: &[&'static str] = if name.starts_with("asm_test_") {
+            &["asm"]
+        } else {
+            &["asm", "ir", "c"]
+        };
+
+        for &target in targets.iter() {
+            ran += 1;
+            let stem = format!("suite-{}-{}", &name[..name.len() - 3], target);
+            let actual = compile(target, &vec!(), &options, path).and_then(|out| {
+                if target == "asm" {
+                    run_asm(&out, 0).map(Some)
+                } else if gcc {
+                    run_c(&out, &stem).map(Some)
+                } else {
+                    Ok(None)
+                }
+            });
+            match actual {
+                Ok(None) => {},
+                Ok(Some(ref actual)) if *actual == expected => {},
+                Ok(Some(actual)) =>
+                    failures.push(format!("{} [{}]: wrong output\n{}",
+                                          name, target, diff(&expected, &actual))),
+                Err(e) =>
+                    failures.push(format!("{} [{}]: {}", name, target, e)),
+            }
+        }
+    }
+
+    for failure in failures.iter() {
+        println!("{}", failure);
+    }
+    if !failures.is_empty() {
+        panic!("{} of {} test runs failed", failures.len(), ran);
+    }
+}